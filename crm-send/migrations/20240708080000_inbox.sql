@@ -0,0 +1,26 @@
+-- the in-app messages of each device, seq is the key of pagination
+CREATE TABLE IF NOT EXISTS inbox (
+    message_id VARCHAR(128) PRIMARY KEY,
+    seq BIGSERIAL NOT NULL,
+    device_id VARCHAR(128) NOT NULL,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    read_at TIMESTAMPTZ,
+    -- the dismissed messages are kept, but never returned
+    dismissed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS inbox_device_id_seq_idx ON inbox (device_id, seq DESC)
+WHERE dismissed_at IS NULL;
+
+-- notify the id of the added message, the Inbox subscriptions listen on the channel
+CREATE OR REPLACE FUNCTION notify_inbox_added() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('inbox_added', NEW.message_id);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER inbox_added AFTER INSERT ON inbox
+FOR EACH ROW EXECUTE FUNCTION notify_inbox_added();
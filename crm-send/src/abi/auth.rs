@@ -0,0 +1,105 @@
+use jwt_simple::prelude::*;
+use tonic::{service::Interceptor, Request, Status};
+
+const JWT_ISS: &str = "crm";
+const JWT_AUD: &str = "crm-client";
+
+/// the device the token is issued to, only its own inbox is served
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodingKey(Ed25519PublicKey);
+
+impl DecodingKey {
+    pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
+        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Device, jwt_simple::Error> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
+            ..Default::default()
+        };
+        let claims = self.0.verify_token::<Device>(token, Some(opts))?;
+        Ok(claims.custom)
+    }
+}
+
+/// the device of a valid token is kept in the extensions of the request, the requests without
+/// a token pass, e.g. the messages sent by crm, the inbox methods require one
+impl Interceptor for DecodingKey {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let token = req
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        if let Some(token) = token {
+            let token = token
+                .strip_prefix("Bearer ")
+                .ok_or_else(|| Status::unauthenticated("invalid token"))?;
+            let device = self
+                .verify(token)
+                .map_err(|e| Status::unauthenticated(e.to_string()))?;
+            req.extensions_mut().insert(device);
+        }
+        Ok(req)
+    }
+}
+
+/// the token of the request must be issued to the device
+#[allow(clippy::result_large_err)]
+pub(crate) fn require_owner<T>(req: &Request<T>, device_id: &str) -> Result<(), Status> {
+    match req.extensions().get::<Device>() {
+        Some(device) if device.device_id == device_id => Ok(()),
+        Some(device) => Err(Status::permission_denied(format!(
+            "device {} could not access the inbox of {}",
+            device.device_id, device_id
+        ))),
+        None => Err(Status::unauthenticated("missing token")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestKey;
+    use anyhow::Result;
+
+    #[test]
+    fn token_should_be_verified() -> Result<()> {
+        let key = TestKey::new();
+        let dk = DecodingKey::load(&key.pk())?;
+        assert_eq!(dk.verify(&key.token("d1"))?.device_id, "d1");
+        assert!(dk.verify(&TestKey::new().token("d1")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn inbox_should_be_accessed_by_its_device() -> Result<()> {
+        let key = TestKey::new();
+        let mut dk = DecodingKey::load(&key.pk())?;
+        let req = |token: Option<&str>| {
+            let mut req = Request::new(());
+            if let Some(token) = token {
+                let value = format!("Bearer {token}").parse().unwrap();
+                req.metadata_mut().insert("authorization", value);
+            }
+            req
+        };
+
+        let status = dk.call(req(Some("not a token"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let anonymous = dk.call(req(None))?;
+        let status = require_owner(&anonymous, "d1").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let owner = dk.call(req(Some(&key.token("d1"))))?;
+        require_owner(&owner, "d1")?;
+        let status = require_owner(&owner, "d2").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        Ok(())
+    }
+}
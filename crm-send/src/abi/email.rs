@@ -1,7 +1,92 @@
+use std::time::Duration;
+
+use anyhow::Result;
 use crm_metadata::{pb::Content, Tpl};
+use lettre::{
+    message::{header::ContentType, Mailbox, SinglePart},
+    transport::smtp::{authentication::Credentials, Error as SmtpError},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tonic::Status;
+use tracing::info;
 use uuid::Uuid;
 
-use crate::pb::{send_request::Msg, EmailMessage, SendRequest};
+use super::Provider;
+use crate::{
+    config::{SmtpConfig, SmtpTls},
+    pb::{send_request::Msg, EmailMessage, SendRequest},
+};
+
+/// deliver the emails to the smtp server
+pub struct SmtpProvider {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpProvider {
+    pub fn new(config: &SmtpConfig) -> Result<Self> {
+        let mut builder = match config.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+            SmtpTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        }
+        .port(config.port)
+        .timeout(Some(Duration::from_millis(config.timeout_ms)));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            transport: builder.build(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Provider<EmailMessage> for SmtpProvider {
+    async fn send(&self, email: &EmailMessage) -> Result<(), Status> {
+        let message = email.to_mime()?;
+        let ret = self.transport.send(message).await.map_err(to_status)?;
+        info!(
+            "Email {} is delivered: {}",
+            email.message_id,
+            ret.message().collect::<Vec<_>>().join(" ")
+        );
+        Ok(())
+    }
+}
+
+impl EmailMessage {
+    /// build the MIME message, the body is sent as html if it looks like html, otherwise as
+    /// plain text
+    #[allow(clippy::result_large_err)]
+    fn to_mime(&self) -> Result<Message, Status> {
+        let sender = parse_mailbox(&self.sender)?;
+        let domain = sender.email.domain().to_string();
+        let mut builder = Message::builder()
+            .message_id(Some(format!("<{}@{}>", self.message_id, domain)))
+            .from(sender)
+            .subject(&self.subject);
+        if self.recipients.is_empty() {
+            return Err(Status::invalid_argument("recipients are required"));
+        }
+        for recipient in &self.recipients {
+            builder = builder.to(parse_mailbox(recipient)?);
+        }
+
+        let body = self.body.clone();
+        let part = if body.trim_start().starts_with('<') {
+            SinglePart::builder()
+                .header(ContentType::TEXT_HTML)
+                .body(body)
+        } else {
+            SinglePart::plain(body)
+        };
+        builder
+            .singlepart(part)
+            .map_err(|e| Status::invalid_argument(format!("Invalid email: {}", e)))
+    }
+}
 
 impl SendRequest {
     pub fn new_email_msg(
@@ -11,18 +96,43 @@ impl SendRequest {
         contents: &[Content],
     ) -> Self {
         let tpl = Tpl(contents);
+        Self::new_email_body(subject, sender, recipients, tpl.to_body())
+    }
+
+    pub fn new_email_body(
+        subject: String,
+        sender: String,
+        recipients: &[String],
+        body: String,
+    ) -> Self {
         let msg = Msg::Email(EmailMessage {
             message_id: Uuid::new_v4().to_string(),
             subject,
             sender,
             recipients: recipients.to_vec(),
-            body: tpl.to_body(),
+            body,
         });
 
         Self { msg: Some(msg) }
     }
 }
 
+#[allow(clippy::result_large_err)]
+fn parse_mailbox(address: &str) -> Result<Mailbox, Status> {
+    address
+        .parse()
+        .map_err(|e| Status::invalid_argument(format!("Invalid address {}: {}", address, e)))
+}
+
+/// the rejections of the server are not retried, the others might be
+fn to_status(e: SmtpError) -> Status {
+    if e.is_permanent() {
+        Status::failed_precondition(format!("Email is rejected: {}", e))
+    } else {
+        Status::unavailable(format!("Failed to deliver email: {}", e))
+    }
+}
+
 #[cfg(feature = "test_utils")]
 impl EmailMessage {
     pub fn fake() -> Self {
@@ -37,3 +147,49 @@ impl EmailMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tonic::Code;
+
+    use super::*;
+    use crate::test_utils::SmtpSink;
+
+    #[test]
+    fn email_should_be_built_as_mime() -> Result<()> {
+        let mut email = EmailMessage::fake();
+        email.sender = "CRM <crm@acme.org>".to_string();
+        email.subject = "Welcome 👋".to_string();
+        let data = String::from_utf8(email.to_mime()?.formatted())?;
+        assert!(data.contains("From: CRM <crm@acme.org>"));
+        assert!(data.contains(&format!("Message-ID: <{}@acme.org>", email.message_id)));
+        assert!(data.contains("Subject: Welcome =?utf-8?b?8J+Riw==?="));
+        assert!(data.contains("Content-Type: text/plain; charset=utf-8"));
+
+        email.body = "<p>Hello</p>".to_string();
+        let data = String::from_utf8(email.to_mime()?.formatted())?;
+        assert!(data.contains("Content-Type: text/html; charset=utf-8"));
+
+        email.recipients = vec!["not an address".to_string()];
+        let status = email.to_mime().unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn smtp_sender_should_deliver_email() -> Result<()> {
+        let sink = SmtpSink::start().await?;
+        let sender = SmtpProvider::new(&sink.config())?;
+        let email = EmailMessage::fake();
+        sender.send(&email).await?;
+
+        let mails = sink.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].from, email.sender);
+        assert_eq!(mails[0].recipients, email.recipients);
+        assert!(mails[0].data.contains("Subject: Hello"));
+        assert!(mails[0].data.contains("Hello world"));
+        Ok(())
+    }
+}
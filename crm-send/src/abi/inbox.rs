@@ -0,0 +1,729 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgListener, FromRow, PgPool, QueryBuilder};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, OnceCell,
+    },
+    time::sleep,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Response, Status};
+use tracing::{info, warn};
+
+use super::{utc_to_ts, Provider};
+use crate::{
+    pb::{
+        DismissRequest, DismissResponse, InAppMessage, InboxItem, InboxRequest, ListInboxRequest,
+        ListInboxResponse, MarkReadRequest, MarkReadResponse,
+    },
+    InboxStream, NotificationService, ServiceResult,
+};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+/// number of added messages buffered for the subscriptions, a lagging one misses the oldest
+pub(crate) const ADDED_BUFFER: usize = 1024;
+/// number of messages buffered for each device
+const INBOX_BUFFER: usize = 128;
+/// the channel notified by the trigger of inbox, the payload is the id of the added message
+const INBOX_ADDED_CHANNEL: &str = "inbox_added";
+const INBOX_COLUMNS: &str = "seq, message_id, device_id, title, body, created_at, read_at";
+
+/// the storage of the in-app messages of the devices
+#[tonic::async_trait]
+pub trait InboxStore: Send + Sync + 'static {
+    /// add the message to the inbox of its device, None if it is added already
+    async fn add(&self, msg: &InAppMessage) -> Result<Option<InboxItem>, Status>;
+
+    /// the messages of the page from the newest, with their seq
+    async fn list(&self, page: &InboxPage) -> Result<Vec<(i64, InboxItem)>, Status>;
+
+    /// number of unread messages of the device
+    async fn unread(&self, device_id: &str) -> Result<u64, Status>;
+
+    /// mark the messages as read, all the unread ones of the device if message_ids is empty
+    async fn mark_read(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status>;
+
+    async fn dismiss(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status>;
+
+    /// the messages added to the inbox of any device from now on. None when the store missed
+    /// some of them, e.g. while reconnecting
+    async fn subscribe(&self) -> Result<broadcast::Receiver<Option<InboxItem>>, Status>;
+}
+
+/// keyset pagination of an inbox on seq, from the newest
+#[derive(Debug, Clone, Default)]
+pub struct InboxPage {
+    pub device_id: String,
+    /// only the messages older than it
+    pub before: Option<i64>,
+    pub limit: u32,
+    pub unread_only: bool,
+}
+
+/// the inbox table in postgres, the added messages are notified by its trigger, so that the
+/// subscriptions on every server get them
+pub struct PgInbox {
+    pool: PgPool,
+    /// the listener shared by the subscriptions, started by the first one
+    added: OnceCell<broadcast::Sender<Option<InboxItem>>>,
+}
+
+/// deliver the in-app messages to the inbox of their devices
+pub struct InboxProvider {
+    store: Arc<dyn InboxStore>,
+}
+
+#[derive(Debug, FromRow)]
+struct InboxRow {
+    seq: i64,
+    message_id: String,
+    device_id: String,
+    title: String,
+    body: String,
+    created_at: DateTime<Utc>,
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl NotificationService {
+    /// stream the messages delivered to the device, after its unread messages if asked
+    pub async fn inbox(&self, req: InboxRequest) -> ServiceResult<InboxStream> {
+        require_device(&req.device_id)?;
+        // subscribe before listing the unread messages, so no message is missed in between
+        let mut added = self.inbox.subscribe().await?;
+        let unread = if req.include_unread {
+            self.list_unread(&req.device_id).await?
+        } else {
+            vec![]
+        };
+        info!("Subscribe inbox of {}", req.device_id);
+
+        let (tx, rx) = mpsc::channel(INBOX_BUFFER);
+        tokio::spawn(async move {
+            let mut sent = HashSet::new();
+            for (_, item) in unread.into_iter().rev() {
+                sent.insert(item.message_id.clone());
+                if tx.send(Ok(item)).await.is_err() {
+                    return;
+                }
+            }
+            let closed = tx.closed();
+            let forward = forward_added(&mut added, &req.device_id, &sent, &tx);
+            tokio::select! {
+                _ = forward => {}
+                _ = closed => info!("Inbox of {} is dropped by client", req.device_id),
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    /// all the unread messages of the device from the newest, page by page
+    async fn list_unread(&self, device_id: &str) -> Result<Vec<(i64, InboxItem)>, Status> {
+        let mut page = InboxPage {
+            device_id: device_id.to_string(),
+            limit: MAX_PAGE_SIZE,
+            unread_only: true,
+            ..Default::default()
+        };
+        let mut unread = vec![];
+        loop {
+            let items = self.inbox.list(&page).await?;
+            let full = items.len() == MAX_PAGE_SIZE as usize;
+            page.before = items.last().map(|(seq, _)| *seq);
+            unread.extend(items);
+            if !full {
+                return Ok(unread);
+            }
+        }
+    }
+
+    pub async fn list_inbox(&self, req: ListInboxRequest) -> ServiceResult<ListInboxResponse> {
+        require_device(&req.device_id)?;
+        let page_size = page_size_or_default(req.page_size) as usize;
+        let page = InboxPage {
+            device_id: req.device_id.clone(),
+            before: decode_page_token(&req.page_token)?,
+            // one more message to know if there is a next page
+            limit: page_size as u32 + 1,
+            unread_only: req.unread_only,
+        };
+        let mut items = self.inbox.list(&page).await?;
+        let next_page_token = if items.len() > page_size {
+            items.truncate(page_size);
+            items
+                .last()
+                .map(|(seq, _)| encode_page_token(*seq))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        let unread = self.inbox.unread(&req.device_id).await?;
+        Ok(Response::new(ListInboxResponse {
+            items: items.into_iter().map(|(_, item)| item).collect(),
+            next_page_token,
+            unread,
+        }))
+    }
+
+    pub async fn mark_read(&self, req: MarkReadRequest) -> ServiceResult<MarkReadResponse> {
+        require_device(&req.device_id)?;
+        let updated = self
+            .inbox
+            .mark_read(&req.device_id, &req.message_ids)
+            .await?;
+        Ok(Response::new(MarkReadResponse { updated }))
+    }
+
+    pub async fn dismiss(&self, req: DismissRequest) -> ServiceResult<DismissResponse> {
+        require_device(&req.device_id)?;
+        if req.message_ids.is_empty() {
+            return Err(Status::invalid_argument("message_ids are required"));
+        }
+        let dismissed = self.inbox.dismiss(&req.device_id, &req.message_ids).await?;
+        Ok(Response::new(DismissResponse { dismissed }))
+    }
+}
+
+/// send the messages added to the inbox of the device, except the ones sent already
+async fn forward_added(
+    added: &mut broadcast::Receiver<Option<InboxItem>>,
+    device_id: &str,
+    sent: &HashSet<String>,
+    tx: &mpsc::Sender<Result<InboxItem, Status>>,
+) {
+    loop {
+        match added.recv().await {
+            Ok(Some(item)) if item.device_id == device_id && !sent.contains(&item.message_id) => {
+                if tx.send(Ok(item)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                warn!("Inbox of {} missed messages while reconnecting", device_id);
+                let status = Status::data_loss(
+                    "missed messages while reconnecting, list the inbox and subscribe again",
+                );
+                let _ = tx.send(Err(status)).await;
+                break;
+            }
+            // the missed messages are still in the inbox, end the stream so the client lists
+            // them and subscribes again, instead of silently skipping them
+            Err(RecvError::Lagged(n)) => {
+                warn!("Inbox of {} missed {} messages", device_id, n);
+                let status = Status::data_loss(format!(
+                    "missed {} messages, list the inbox and subscribe again",
+                    n
+                ));
+                let _ = tx.send(Err(status)).await;
+                break;
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+impl PgInbox {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            added: OnceCell::new(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl InboxStore for PgInbox {
+    async fn add(&self, msg: &InAppMessage) -> Result<Option<InboxItem>, Status> {
+        let row: Option<InboxRow> = sqlx::query_as(&format!(
+            "INSERT INTO inbox (message_id, device_id, title, body) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (message_id) DO NOTHING RETURNING {}",
+            INBOX_COLUMNS
+        ))
+        .bind(&msg.message_id)
+        .bind(&msg.device_id)
+        .bind(&msg.title)
+        .bind(&msg.body)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(to_status)?;
+        Ok(row.map(|row| row.into_item().1))
+    }
+
+    async fn list(&self, page: &InboxPage) -> Result<Vec<(i64, InboxItem)>, Status> {
+        let mut builder = QueryBuilder::new(format!(
+            "SELECT {} FROM inbox WHERE dismissed_at IS NULL AND device_id = ",
+            INBOX_COLUMNS
+        ));
+        builder.push_bind(&page.device_id);
+        if page.unread_only {
+            builder.push(" AND read_at IS NULL");
+        }
+        if let Some(before) = page.before {
+            builder.push(" AND seq < ").push_bind(before);
+        }
+        builder
+            .push(" ORDER BY seq DESC LIMIT ")
+            .push_bind(page.limit as i64);
+        let rows: Vec<InboxRow> = builder
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(to_status)?;
+        Ok(rows.into_iter().map(InboxRow::into_item).collect())
+    }
+
+    async fn unread(&self, device_id: &str) -> Result<u64, Status> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM inbox \
+             WHERE device_id = $1 AND dismissed_at IS NULL AND read_at IS NULL",
+        )
+        .bind(device_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(to_status)?;
+        Ok(count as u64)
+    }
+
+    async fn mark_read(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status> {
+        let mut builder = QueryBuilder::new(
+            "UPDATE inbox SET read_at = now() \
+             WHERE dismissed_at IS NULL AND read_at IS NULL AND device_id = ",
+        );
+        builder.push_bind(device_id);
+        if !message_ids.is_empty() {
+            builder
+                .push(" AND message_id = ANY(")
+                .push_bind(message_ids)
+                .push(")");
+        }
+        let ret = builder
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(to_status)?;
+        Ok(ret.rows_affected())
+    }
+
+    async fn dismiss(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status> {
+        let ret = sqlx::query(
+            "UPDATE inbox SET dismissed_at = now() \
+             WHERE dismissed_at IS NULL AND device_id = $1 AND message_id = ANY($2)",
+        )
+        .bind(device_id)
+        .bind(message_ids)
+        .execute(&self.pool)
+        .await
+        .map_err(to_status)?;
+        Ok(ret.rows_affected())
+    }
+
+    async fn subscribe(&self) -> Result<broadcast::Receiver<Option<InboxItem>>, Status> {
+        let added = self
+            .added
+            .get_or_try_init(|| listen_added(self.pool.clone()))
+            .await?;
+        Ok(added.subscribe())
+    }
+}
+
+/// listen on the notifications of the inbox table, and broadcast the added messages
+async fn listen_added(pool: PgPool) -> Result<broadcast::Sender<Option<InboxItem>>, Status> {
+    let mut listener = PgListener::connect_with(&pool).await.map_err(to_status)?;
+    listener
+        .listen(INBOX_ADDED_CHANNEL)
+        .await
+        .map_err(to_status)?;
+
+    let (tx, _) = broadcast::channel(ADDED_BUFFER);
+    let added = tx.clone();
+    tokio::spawn(async move {
+        loop {
+            // the listener reconnects on the next try_recv after losing the connection, the
+            // subscriptions end as they miss the notifications in between
+            let notification = match listener.try_recv().await {
+                Ok(Some(notification)) => notification,
+                Ok(None) => {
+                    warn!("Lost the connection listening on inbox");
+                    let _ = added.send(None);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to listen on inbox: {:?}", e);
+                    let _ = added.send(None);
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let row: Result<Option<InboxRow>, _> = sqlx::query_as(&format!(
+                "SELECT {} FROM inbox WHERE message_id = $1",
+                INBOX_COLUMNS
+            ))
+            .bind(notification.payload())
+            .fetch_optional(&pool)
+            .await;
+            match row {
+                // no subscription is not an error
+                Ok(Some(row)) => {
+                    let _ = added.send(Some(row.into_item().1));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to fetch inbox {}: {:?}", notification.payload(), e);
+                    let _ = added.send(None);
+                }
+            }
+        }
+    });
+    Ok(tx)
+}
+
+impl InboxRow {
+    fn into_item(self) -> (i64, InboxItem) {
+        let item = InboxItem {
+            message_id: self.message_id,
+            device_id: self.device_id,
+            title: self.title,
+            body: self.body,
+            created_at: Some(utc_to_ts(self.created_at)),
+            read_at: self.read_at.map(utc_to_ts),
+        };
+        (self.seq, item)
+    }
+}
+
+impl InboxProvider {
+    pub fn new(store: Arc<dyn InboxStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[tonic::async_trait]
+impl Provider<InAppMessage> for InboxProvider {
+    /// a message delivered again is ignored
+    async fn send(&self, msg: &InAppMessage) -> Result<(), Status> {
+        require_device(&msg.device_id)?;
+        match self.store.add(msg).await? {
+            Some(_) => info!("In-app message {} is added to inbox", msg.message_id),
+            None => info!("In-app message {} is in inbox already", msg.message_id),
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn require_device(device_id: &str) -> Result<(), Status> {
+    if device_id.is_empty() {
+        return Err(Status::invalid_argument("device_id is required"));
+    }
+    Ok(())
+}
+
+fn page_size_or_default(page_size: u32) -> u32 {
+    match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    }
+}
+
+fn encode_page_token(seq: i64) -> String {
+    URL_SAFE_NO_PAD.encode(seq.to_string())
+}
+
+#[allow(clippy::result_large_err)]
+fn decode_page_token(token: &str) -> Result<Option<i64>, Status> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+
+    URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .ok_or_else(|| Status::invalid_argument(format!("invalid page token {}", token)))
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to access inbox: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::time::timeout;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{abi::MemoryInbox, test_utils::get_test_pool, AppConfig, Providers};
+
+    fn message(id: &str, device_id: &str) -> InAppMessage {
+        InAppMessage {
+            message_id: id.to_string(),
+            device_id: device_id.to_string(),
+            title: format!("title {}", id),
+            body: format!("body {}", id),
+        }
+    }
+
+    fn ids(items: &[(i64, InboxItem)]) -> Vec<&str> {
+        items.iter().map(|(_, i)| i.message_id.as_str()).collect()
+    }
+
+    fn page(device_id: &str, before: Option<i64>, limit: u32, unread_only: bool) -> InboxPage {
+        InboxPage {
+            device_id: device_id.to_string(),
+            before,
+            limit,
+            unread_only,
+        }
+    }
+
+    /// the same behavior is expected from every store
+    async fn store_should_work(store: &dyn InboxStore) -> Result<()> {
+        let mut added = store.subscribe().await?;
+        for (id, device) in [("m1", "d1"), ("m2", "d1"), ("m3", "d2"), ("m4", "d1")] {
+            assert!(store.add(&message(id, device)).await?.is_some());
+        }
+        assert!(store.add(&message("m1", "d1")).await?.is_none());
+        let item = timeout(Duration::from_secs(5), added.recv())
+            .await??
+            .unwrap();
+        assert_eq!(item.message_id, "m1");
+        assert_eq!(item.title, "title m1");
+        assert!(item.created_at.is_some());
+
+        let items = store.list(&page("d1", None, 2, false)).await?;
+        assert_eq!(ids(&items), ["m4", "m2"]);
+        let items = store.list(&page("d1", Some(items[1].0), 2, false)).await?;
+        assert_eq!(ids(&items), ["m1"]);
+        assert_eq!(store.unread("d1").await?, 3);
+
+        // the messages of other devices are untouched
+        let read = ["m2".to_string(), "m3".to_string()];
+        assert_eq!(store.mark_read("d1", &read).await?, 1);
+        assert_eq!(store.mark_read("d1", &read).await?, 0);
+        let items = store.list(&page("d1", None, 10, true)).await?;
+        assert_eq!(ids(&items), ["m4", "m1"]);
+        let items = store.list(&page("d1", None, 10, false)).await?;
+        assert!(items[1].1.read_at.is_some());
+
+        assert_eq!(store.dismiss("d1", &["m4".to_string()]).await?, 1);
+        assert_eq!(store.dismiss("d1", &["m4".to_string()]).await?, 0);
+        assert_eq!(store.mark_read("d1", &[]).await?, 1);
+        assert_eq!(store.unread("d1").await?, 0);
+        assert_eq!(store.unread("d2").await?, 1);
+        let items = store.list(&page("d1", None, 10, false)).await?;
+        assert_eq!(ids(&items), ["m2", "m1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_inbox_should_work() -> Result<()> {
+        store_should_work(&MemoryInbox::default()).await
+    }
+
+    #[tokio::test]
+    async fn pg_inbox_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        store_should_work(&PgInbox::new(pool)).await
+    }
+
+    #[tokio::test]
+    async fn inbox_should_stream_messages_of_device() -> Result<()> {
+        let config = AppConfig::load()?;
+        let svc = NotificationService::with_providers(config, Providers::default());
+        let provider = InboxProvider::new(svc.inbox.clone());
+        provider.send(&message("m1", "d1")).await?;
+        provider.send(&message("m2", "d1")).await?;
+        svc.mark_read(MarkReadRequest {
+            device_id: "d1".to_string(),
+            message_ids: vec!["m1".to_string()],
+        })
+        .await?;
+
+        let req = InboxRequest {
+            device_id: "d1".to_string(),
+            include_unread: true,
+        };
+        let mut stream = svc.inbox(req).await?.into_inner();
+        provider.send(&message("m3", "d2")).await?;
+        provider.send(&message("m2", "d1")).await?;
+        provider.send(&message("m4", "d1")).await?;
+
+        let wait = Duration::from_secs(5);
+        let mut received = vec![];
+        for _ in 0..2 {
+            let item = timeout(wait, stream.next()).await?.unwrap()?;
+            received.push(item.message_id);
+        }
+        assert_eq!(received, ["m2", "m4"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagged_inbox_should_end_with_data_loss() -> Result<()> {
+        let config = AppConfig::load()?;
+        let svc = NotificationService::with_providers(config, Providers::default());
+        let req = InboxRequest {
+            device_id: "d1".to_string(),
+            include_unread: false,
+        };
+        let stream = svc.inbox(req).await?.into_inner();
+        // the stream is not read, the subscription misses the oldest messages
+        for i in 0..INBOX_BUFFER + ADDED_BUFFER + 10 {
+            svc.inbox.add(&message(&format!("m{}", i), "d1")).await?;
+        }
+
+        let ret = timeout(Duration::from_secs(5), stream.collect::<Vec<_>>()).await?;
+        let (last, items) = ret.split_last().unwrap();
+        assert_eq!(last.as_ref().unwrap_err().code(), Code::DataLoss);
+        assert!(items.iter().all(|item| item.is_ok()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inbox_should_stream_unread_messages_of_every_page() -> Result<()> {
+        let config = AppConfig::load()?;
+        let svc = NotificationService::with_providers(config, Providers::default());
+        let count = MAX_PAGE_SIZE as usize + 10;
+        for i in 0..count {
+            svc.inbox.add(&message(&format!("m{}", i), "d1")).await?;
+        }
+
+        let req = InboxRequest {
+            device_id: "d1".to_string(),
+            include_unread: true,
+        };
+        let mut stream = svc.inbox(req).await?.into_inner();
+        let wait = Duration::from_secs(5);
+        let mut received = vec![];
+        for _ in 0..count {
+            let item = timeout(wait, stream.next()).await?.unwrap()?;
+            received.push(item.message_id);
+        }
+        let expected = (0..count).map(|i| format!("m{}", i)).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inbox_should_end_when_listener_reconnects() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let config = AppConfig::load()?;
+        let inbox = Arc::new(PgInbox::new(pool.clone()));
+        let svc = NotificationService::with_inbox(config, Providers::default(), inbox);
+        let req = InboxRequest {
+            device_id: "d1".to_string(),
+            include_unread: false,
+        };
+        let mut stream = svc.inbox(req.clone()).await?.into_inner();
+
+        let killed: Vec<bool> = sqlx::query_scalar(
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = current_database() AND query LIKE 'LISTEN%'",
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(killed, [true]);
+        let status = timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+
+        // a new subscription gets the messages after the listener reconnected
+        let listening = "SELECT EXISTS (SELECT 1 FROM pg_stat_activity \
+                         WHERE datname = current_database() AND query LIKE 'LISTEN%')";
+        timeout(Duration::from_secs(5), async {
+            while !sqlx::query_scalar(listening).fetch_one(&pool).await? {
+                sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, sqlx::Error>(())
+        })
+        .await??;
+        let mut stream = svc.inbox(req).await?.into_inner();
+        svc.inbox.add(&message("m1", "d1")).await?;
+        let item = timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .unwrap()?;
+        assert_eq!(item.message_id, "m1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_inbox_should_paginate() -> Result<()> {
+        let config = AppConfig::load()?;
+        let svc = NotificationService::with_providers(config, Providers::default());
+        for i in 0..5 {
+            svc.inbox.add(&message(&format!("m{}", i), "d1")).await?;
+        }
+
+        let mut req = ListInboxRequest {
+            device_id: "d1".to_string(),
+            page_size: 2,
+            ..Default::default()
+        };
+        let mut pages = vec![];
+        loop {
+            let ret = svc.list_inbox(req.clone()).await?.into_inner();
+            assert_eq!(ret.unread, 5);
+            pages.push(
+                ret.items
+                    .into_iter()
+                    .map(|i| i.message_id)
+                    .collect::<Vec<_>>(),
+            );
+            if ret.next_page_token.is_empty() {
+                break;
+            }
+            req.page_token = ret.next_page_token;
+        }
+        assert_eq!(pages, [vec!["m4", "m3"], vec!["m2", "m1"], vec!["m0"]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_inbox_request_should_be_rejected() -> Result<()> {
+        let config = AppConfig::load()?;
+        let svc = NotificationService::with_providers(config, Providers::default());
+        let status = svc
+            .list_inbox(ListInboxRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let req = ListInboxRequest {
+            device_id: "d1".to_string(),
+            page_token: "not a token!".to_string(),
+            ..Default::default()
+        };
+        let status = svc.list_inbox(req).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let req = DismissRequest {
+            device_id: "d1".to_string(),
+            message_ids: vec![],
+        };
+        let status = svc.dismiss(req).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = InboxProvider::new(svc.inbox.clone())
+            .send(&message("m1", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[test]
+    fn inbox_page_token_should_round_trip() -> Result<()> {
+        assert_eq!(decode_page_token(&encode_page_token(42))?, Some(42));
+        assert_eq!(decode_page_token("")?, None);
+        assert!(decode_page_token(&URL_SAFE_NO_PAD.encode("abc")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use chrono::Utc;
+use tokio::sync::broadcast;
+use tonic::Status;
+
+use super::{
+    inbox::{InboxPage, InboxStore, ADDED_BUFFER},
+    utc_to_ts,
+};
+use crate::pb::{InAppMessage, InboxItem};
+
+/// an in-memory inbox, for the servers without a database. The messages are lost on restart
+pub struct MemoryInbox {
+    data: RwLock<MemoryData>,
+    added: broadcast::Sender<Option<InboxItem>>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    /// the last seq assigned
+    seq: i64,
+    /// messages by seq, the dismissed ones are removed
+    items: BTreeMap<i64, InboxItem>,
+    /// seq of every message added, so a dismissed message is not added again
+    seqs: HashMap<String, i64>,
+}
+
+impl Default for MemoryInbox {
+    fn default() -> Self {
+        let (added, _) = broadcast::channel(ADDED_BUFFER);
+        Self {
+            data: Default::default(),
+            added,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl InboxStore for MemoryInbox {
+    async fn add(&self, msg: &InAppMessage) -> Result<Option<InboxItem>, Status> {
+        let item = {
+            let mut data = self.data.write().unwrap();
+            if data.seqs.contains_key(&msg.message_id) {
+                return Ok(None);
+            }
+            data.seq += 1;
+            let seq = data.seq;
+            let item = InboxItem {
+                message_id: msg.message_id.clone(),
+                device_id: msg.device_id.clone(),
+                title: msg.title.clone(),
+                body: msg.body.clone(),
+                created_at: Some(utc_to_ts(Utc::now())),
+                read_at: None,
+            };
+            data.seqs.insert(item.message_id.clone(), seq);
+            data.items.insert(seq, item.clone());
+            item
+        };
+        // no subscription is not an error
+        let _ = self.added.send(Some(item.clone()));
+        Ok(Some(item))
+    }
+
+    async fn list(&self, page: &InboxPage) -> Result<Vec<(i64, InboxItem)>, Status> {
+        let data = self.data.read().unwrap();
+        let before = page.before.unwrap_or(i64::MAX);
+        Ok(data
+            .items
+            .range(..before)
+            .rev()
+            .filter(|(_, i)| i.device_id == page.device_id)
+            .filter(|(_, i)| !page.unread_only || i.read_at.is_none())
+            .take(page.limit as usize)
+            .map(|(seq, i)| (*seq, i.clone()))
+            .collect())
+    }
+
+    async fn unread(&self, device_id: &str) -> Result<u64, Status> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .items
+            .values()
+            .filter(|i| i.device_id == device_id && i.read_at.is_none())
+            .count() as u64)
+    }
+
+    async fn mark_read(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status> {
+        let now = utc_to_ts(Utc::now());
+        let mut data = self.data.write().unwrap();
+        let mut updated = 0;
+        for item in data.items.values_mut() {
+            if item.device_id == device_id
+                && item.read_at.is_none()
+                && (message_ids.is_empty() || message_ids.contains(&item.message_id))
+            {
+                item.read_at = Some(now.clone());
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    async fn dismiss(&self, device_id: &str, message_ids: &[String]) -> Result<u64, Status> {
+        let mut data = self.data.write().unwrap();
+        let mut dismissed = 0;
+        for id in message_ids {
+            let Some(seq) = data.seqs.get(id).copied() else {
+                continue;
+            };
+            if data
+                .items
+                .get(&seq)
+                .is_some_and(|i| i.device_id == device_id)
+            {
+                data.items.remove(&seq);
+                dismissed += 1;
+            }
+        }
+        Ok(dismissed)
+    }
+
+    async fn subscribe(&self) -> Result<broadcast::Receiver<Option<InboxItem>>, Status> {
+        Ok(self.added.subscribe())
+    }
+}
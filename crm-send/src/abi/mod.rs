@@ -1,98 +1,204 @@
+mod auth;
 mod email;
 mod in_app;
+mod inbox;
+mod memory;
+mod provider;
 mod sms;
 
+pub(crate) use auth::require_owner;
+pub use auth::{DecodingKey, Device};
 // pub use email::*;
+pub use email::SmtpProvider;
+pub use inbox::{InboxPage, InboxProvider, InboxStore, PgInbox};
+pub use memory::MemoryInbox;
+pub use provider::{LogProvider, Provider, Providers};
+pub use sms::{HttpSmsProvider, SmsEncoding};
 
 use std::ops::Deref;
 use std::sync::Arc;
-use std::time::Duration;
 
-use chrono::Utc;
-use futures::Stream;
-use futures::StreamExt;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures::{future::ready, Stream, StreamExt};
+use itertools::Itertools;
 use prost_types::Timestamp;
+use sqlx::PgPool;
 use tokio::sync::mpsc;
-use tokio::time::sleep;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Response, Status};
-use tracing::{info, warn};
+use tonic::{service::interceptor::InterceptedService, Code, Response, Status};
+use tracing::warn;
 
 use crate::{
     pb::{
         notification_server::NotificationServer, send_request::Msg, EmailMessage, InAppMessage,
         SendRequest, SendResponse, SmsMessage,
     },
-    AppConfig, NotificationService, NotificationServiceInner, ResponseStream, ServiceResult,
+    AppConfig, InAppProviderConfig, NotificationService, NotificationServiceInner, ResponseStream,
+    ServiceResult,
 };
 
 pub trait Sender {
-    async fn send(self, svc: NotificationService) -> Result<SendResponse, Status>;
+    /// send the message, a failure is reported in the response
+    async fn send(self, svc: NotificationService) -> SendResponse;
 }
 
 const CHANNEL_SIZE: usize = 1024;
 
 impl NotificationService {
-    pub fn new(config: AppConfig) -> Self {
-        let sender = dummy_send();
-        let inner = NotificationServiceInner { config, sender };
+    /// the in-app messages are kept in the inbox database, which is migrated, if any. the inbox
+    /// provider requires it, the messages in memory would be lost on restart
+    pub async fn try_new(config: AppConfig) -> Result<Self> {
+        let inbox: Arc<dyn InboxStore> = match (&config.inbox.db_url, &config.providers.in_app) {
+            (Some(url), _) => {
+                let pool = PgPool::connect(url).await?;
+                sqlx::migrate!("./migrations").run(&pool).await?;
+                Arc::new(PgInbox::new(pool))
+            }
+            (None, InAppProviderConfig::Inbox) => {
+                bail!("inbox.db_url is required by the inbox provider of in-app messages")
+            }
+            (None, InAppProviderConfig::Log) => Arc::new(MemoryInbox::default()),
+        };
+        let providers = Providers::from_config(&config.providers, &inbox)?;
+        Ok(Self::with_inbox(config, providers, inbox))
+    }
+
+    /// the service delivering the messages with the given providers, the inbox is in memory
+    pub fn with_providers(config: AppConfig, providers: Providers) -> Self {
+        Self::with_inbox(config, providers, Arc::new(MemoryInbox::default()))
+    }
+
+    /// the service delivering the messages with the given providers, and serving the inbox of
+    /// the store
+    pub fn with_inbox(config: AppConfig, providers: Providers, inbox: Arc<dyn InboxStore>) -> Self {
+        let inner = NotificationServiceInner {
+            config,
+            providers,
+            inbox,
+        };
         Self {
             inner: Arc::new(inner),
         }
     }
 
-    pub fn into_server(self) -> NotificationServer<Self> {
-        NotificationServer::new(self)
+    pub fn into_server(self) -> Result<InterceptedService<NotificationServer<Self>, DecodingKey>> {
+        let dk = DecodingKey::load(&self.config.auth.pk)?;
+        Ok(NotificationServer::with_interceptor(self, dk))
     }
 
+    /// deliver the messages of the stream with up to `send_concurrency` at a time, the
+    /// responses are sent as the messages are delivered, not in the order of the requests
     pub async fn send(
         &self,
-        mut stream: impl Stream<Item = Result<SendRequest, Status>> + Send + 'static + Unpin,
+        stream: impl Stream<Item = Result<SendRequest, Status>> + Send + 'static + Unpin,
     ) -> ServiceResult<ResponseStream> {
         let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
         let notifi = self.clone();
+        let concurrency = self.config.server.send_concurrency.max(1);
         tokio::spawn(async move {
-            while let Some(Ok(req)) = stream.next().await {
-                let notifi_clone = notifi.clone();
-                let res = match req.msg {
-                    Some(Msg::Email(email)) => email.send(notifi_clone).await,
-                    Some(Msg::Sms(sms)) => sms.send(notifi_clone).await,
-                    Some(Msg::InApp(in_app)) => in_app.send(notifi_clone).await,
-                    None => {
-                        warn!("Invalid request");
-                        Err(Status::invalid_argument("msg is required"))
-                    }
-                };
-                let _ = tx.send(res).await;
+            let mut responses = stream
+                .take_while(|req| ready(req.is_ok()))
+                .filter_map(|req| ready(req.ok()))
+                .map(|req| notifi.clone().deliver(req))
+                .buffer_unordered(concurrency);
+            while let Some(res) = responses.next().await {
+                if tx.send(Ok(res)).await.is_err() {
+                    break;
+                }
             }
         });
 
         let stream = ReceiverStream::new(rx);
         Ok(Response::new(Box::pin(stream)))
     }
+
+    async fn deliver(self, req: SendRequest) -> SendResponse {
+        match req.msg {
+            Some(Msg::Email(email)) => email.send(self).await,
+            Some(Msg::Sms(sms)) => sms.send(self).await,
+            Some(Msg::InApp(in_app)) => in_app.send(self).await,
+            None => SendResponse::new(
+                String::new(),
+                Err(Status::invalid_argument("msg is required")),
+            ),
+        }
+    }
 }
 
 macro_rules! impl_sender {
-    ($name:ident, $msg_type:expr) => {
+    ($name:ident, $provider:ident) => {
         impl Sender for $name {
-            async fn send(self, svc: NotificationService) -> Result<SendResponse, Status> {
-                let message_id = self.message_id.clone();
-                svc.sender.send($msg_type(self)).await.map_err(|e| {
-                    warn!("Failed to send message: {:?}", e);
-                    Status::internal("Failed to send message")
-                })?;
-                Ok(SendResponse {
-                    message_id,
-                    timestamp: Some(to_timestamp()),
-                })
+            async fn send(self, svc: NotificationService) -> SendResponse {
+                let ret = svc.providers.$provider.send(&self).await;
+                SendResponse::new(self.message_id, ret.map(|_| 0))
             }
         }
     };
 }
 
-impl_sender!(EmailMessage, Msg::Email);
-impl_sender!(InAppMessage, Msg::InApp);
-impl_sender!(SmsMessage, Msg::Sms);
+impl_sender!(EmailMessage, email);
+impl_sender!(InAppMessage, in_app);
+
+impl Sender for SmsMessage {
+    /// the sms over the segments limit is rejected before reaching the provider
+    /// the sms is sent if any of its recipients received it, the failed ones are reported
+    async fn send(self, svc: NotificationService) -> SendResponse {
+        let segments = match self.check_segments(svc.config.sms.max_segments) {
+            Ok(segments) => segments,
+            Err(e) => return SendResponse::new(self.message_id, Err(e)),
+        };
+        let failed = match svc.providers.sms.send_each(&self).await {
+            Ok(failed) => failed,
+            Err(e) => return SendResponse::new(self.message_id, Err(e)),
+        };
+        let ret = match failed.first() {
+            Some(first) if failed.len() >= self.recipients.len() => Err(Status::new(
+                Code::from_i32(first.code),
+                failed.iter().map(|f| f.error.as_str()).join("; "),
+            )),
+            _ => Ok(segments),
+        };
+        SendResponse {
+            failed_recipients: failed,
+            ..SendResponse::new(self.message_id, ret)
+        }
+    }
+}
+
+impl SendResponse {
+    /// the response of the message with the segments sent, or the reason it failed
+    fn new(message_id: String, ret: Result<u32, Status>) -> Self {
+        match ret {
+            Ok(segments) => Self {
+                message_id,
+                timestamp: Some(to_timestamp()),
+                segments,
+                ..Default::default()
+            },
+            Err(e) => {
+                warn!("Failed to send message {}: {:?}", message_id, e);
+                Self {
+                    message_id,
+                    timestamp: Some(to_timestamp()),
+                    code: e.code() as i32,
+                    error: e.message().to_string(),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// true if the message is sent
+    pub fn is_sent(&self) -> bool {
+        self.code == Code::Ok as i32
+    }
+
+    /// the code of the delivery, Ok if the message is sent
+    pub fn code(&self) -> Code {
+        Code::from_i32(self.code)
+    }
+}
 
 macro_rules! impl_into_send_request {
     ($type:ty, $msg_type:expr) => {
@@ -123,36 +229,29 @@ impl Deref for NotificationService {
 }
 
 fn to_timestamp() -> Timestamp {
-    let now = Utc::now();
-    Timestamp {
-        seconds: now.timestamp(),
-        nanos: now.timestamp_subsec_nanos() as i32,
-    }
+    utc_to_ts(Utc::now())
 }
 
-fn dummy_send() -> mpsc::Sender<Msg> {
-    let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE * 100);
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            info!("Sending message: {:?}", msg);
-            sleep(Duration::from_millis(300)).await;
-        }
-    });
-    tx
+pub(crate) fn utc_to_ts(dt: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashMap, time::Duration};
+
     use anyhow::Result;
     use futures::StreamExt;
 
     use super::*;
-    use crate::AppConfig;
+    use crate::{test_utils::MockProvider, AppConfig};
 
     #[tokio::test]
     async fn send_should_work() -> Result<()> {
-        let config = AppConfig::load().expect("Failed to load config");
-        let svc = NotificationService::new(config);
+        let (_tdb, svc) = NotificationService::new_for_test().await?;
         let stream = tokio_stream::iter(vec![
             Ok(EmailMessage::fake().into()),
             Ok(SmsMessage::fake().into()),
@@ -167,4 +266,129 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn inbox_provider_should_require_db() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.inbox.db_url = None;
+        assert!(NotificationService::try_new(config).await.is_err());
+
+        let mut config = AppConfig::load()?;
+        config.inbox.db_url = None;
+        config.providers.in_app = InAppProviderConfig::Log;
+        NotificationService::try_new(config).await?;
+        Ok(())
+    }
+
+    /// the responses of the messages by message id, they are not in the order of the requests
+    async fn send_all(
+        svc: &NotificationService,
+        reqs: Vec<SendRequest>,
+    ) -> Result<HashMap<String, SendResponse>> {
+        let stream = tokio_stream::iter(reqs.into_iter().map(Ok));
+        let mut responses = svc.send(stream).await?.into_inner();
+        let mut ret = HashMap::new();
+        while let Some(res) = responses.next().await {
+            let res = res?;
+            ret.insert(res.message_id.clone(), res);
+        }
+        Ok(ret)
+    }
+
+    #[tokio::test]
+    async fn send_should_use_provider_of_channel() -> Result<()> {
+        let config = AppConfig::load()?;
+        let email = MockProvider::new();
+        let sms = MockProvider::failing(Status::unavailable("gateway is down"));
+        let providers = Providers::default()
+            .with_email(email.clone())
+            .with_sms(sms.clone());
+        let svc = NotificationService::with_providers(config, providers);
+
+        let msg = EmailMessage::fake();
+        let failed = SmsMessage::fake();
+        let in_app = InAppMessage::fake();
+        let ret = send_all(
+            &svc,
+            vec![
+                msg.clone().into(),
+                failed.clone().into(),
+                in_app.clone().into(),
+            ],
+        )
+        .await?;
+        assert!(ret[&msg.message_id].is_sent());
+        // the failed message doesn't end the stream
+        assert_eq!(ret[&failed.message_id].code(), Code::Unavailable);
+        assert_eq!(ret[&failed.message_id].error, "gateway is down");
+        assert!(ret[&in_app.message_id].is_sent());
+        assert_eq!(email.sent(), [msg]);
+        assert!(sms.sent().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_sms_should_return_segments_within_limit() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.sms.max_segments = 2;
+        let sms = MockProvider::new();
+        let providers = Providers::default().with_sms(sms.clone());
+        let svc = NotificationService::with_providers(config, providers);
+
+        let mut short = SmsMessage::fake();
+        short.body = "你好".repeat(40);
+        let mut long = SmsMessage::fake();
+        long.body = "你好".repeat(70);
+        let email = EmailMessage::fake();
+        let ret = send_all(
+            &svc,
+            vec![
+                short.clone().into(),
+                long.clone().into(),
+                email.clone().into(),
+            ],
+        )
+        .await?;
+        assert_eq!(ret[&short.message_id].segments, 2);
+        assert_eq!(ret[&long.message_id].code(), Code::InvalidArgument);
+        assert_eq!(ret[&email.message_id].segments, 0);
+        assert!(ret[&email.message_id].is_sent());
+        assert_eq!(sms.sent(), [short]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_sms_should_not_limit_segments_of_zero() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.sms.max_segments = 0;
+        let sms = MockProvider::new();
+        let providers = Providers::default().with_sms(sms.clone());
+        let svc = NotificationService::with_providers(config, providers);
+
+        let mut long = SmsMessage::fake();
+        long.body = "你好".repeat(200);
+        let ret = send_all(&svc, vec![long.clone().into()]).await?;
+        assert!(ret[&long.message_id].is_sent());
+        assert_eq!(ret[&long.message_id].segments, 6);
+        assert_eq!(sms.sent(), [long]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_should_deliver_messages_concurrently() -> Result<()> {
+        let mut config = AppConfig::load()?;
+        config.server.send_concurrency = 4;
+        let email = MockProvider::new().with_delay(Duration::from_millis(50));
+        let providers = Providers::default().with_email(email.clone());
+        let svc = NotificationService::with_providers(config, providers);
+
+        let msgs = (0..10).map(|_| EmailMessage::fake()).collect::<Vec<_>>();
+        let ret = send_all(&svc, msgs.iter().cloned().map(Into::into).collect()).await?;
+        assert_eq!(ret.len(), 10);
+        assert!(ret.values().all(SendResponse::is_sent));
+        assert_eq!(email.sent().len(), 10);
+        // bounded by the concurrency
+        assert_eq!(email.max_in_flight(), 4);
+        Ok(())
+    }
 }
@@ -0,0 +1,131 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    email::SmtpProvider,
+    inbox::{InboxProvider, InboxStore},
+    sms::HttpSmsProvider,
+};
+use crate::{
+    config::{EmailProviderConfig, InAppProviderConfig, ProvidersConfig, SmsProviderConfig},
+    pb::{EmailMessage, InAppMessage, RecipientError, SmsMessage},
+};
+
+/// delivers the messages of a channel
+#[tonic::async_trait]
+pub trait Provider<M: Sync>: Send + Sync + 'static {
+    async fn send(&self, msg: &M) -> Result<(), Status>;
+
+    /// send the message to each of its recipients, the ones it failed to reach are returned.
+    /// By default the message is sent to all of its recipients or fails as a whole
+    async fn send_each(&self, msg: &M) -> Result<Vec<RecipientError>, Status> {
+        self.send(msg).await.map(|_| vec![])
+    }
+}
+
+/// the provider of each channel
+#[derive(Clone)]
+pub struct Providers {
+    pub email: Arc<dyn Provider<EmailMessage>>,
+    pub sms: Arc<dyn Provider<SmsMessage>>,
+    pub in_app: Arc<dyn Provider<InAppMessage>>,
+}
+
+/// only log the messages, for the channels not set up yet
+pub struct LogProvider;
+
+impl Providers {
+    /// the providers of the config, the in-app messages are added to the inbox if configured
+    pub fn from_config(config: &ProvidersConfig, inbox: &Arc<dyn InboxStore>) -> Result<Self> {
+        let email: Arc<dyn Provider<EmailMessage>> = match &config.email {
+            EmailProviderConfig::Log => Arc::new(LogProvider),
+            EmailProviderConfig::Smtp(smtp) => Arc::new(SmtpProvider::new(smtp)?),
+        };
+        let sms: Arc<dyn Provider<SmsMessage>> = match &config.sms {
+            SmsProviderConfig::Log => Arc::new(LogProvider),
+            SmsProviderConfig::Http(http) => Arc::new(HttpSmsProvider::new(http)?),
+        };
+        let in_app: Arc<dyn Provider<InAppMessage>> = match &config.in_app {
+            InAppProviderConfig::Log => Arc::new(LogProvider),
+            InAppProviderConfig::Inbox => Arc::new(InboxProvider::new(inbox.clone())),
+        };
+        Ok(Self { email, sms, in_app })
+    }
+
+    pub fn with_email(mut self, provider: impl Provider<EmailMessage>) -> Self {
+        self.email = Arc::new(provider);
+        self
+    }
+
+    pub fn with_sms(mut self, provider: impl Provider<SmsMessage>) -> Self {
+        self.sms = Arc::new(provider);
+        self
+    }
+
+    pub fn with_in_app(mut self, provider: impl Provider<InAppMessage>) -> Self {
+        self.in_app = Arc::new(provider);
+        self
+    }
+}
+
+impl Default for Providers {
+    fn default() -> Self {
+        Self {
+            email: Arc::new(LogProvider),
+            sms: Arc::new(LogProvider),
+            in_app: Arc::new(LogProvider),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<M: Debug + Send + Sync + 'static> Provider<M> for LogProvider {
+    async fn send(&self, msg: &M) -> Result<(), Status> {
+        info!("Sending message: {:?}", msg);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        abi::MemoryInbox,
+        config::{AppConfig, SmsBodyFormat},
+    };
+
+    // the smtp connection pool runs on tokio
+    #[tokio::test]
+    async fn providers_config_should_be_parsed() -> Result<()> {
+        let config: ProvidersConfig = serde_yaml::from_str(
+            "email:\n  kind: smtp\n  host: smtp.example.com\n  tls: tls\n  port: 465\n\
+             sms:\n  kind: http\n  url: http://localhost/messages\n  format: json\n",
+        )?;
+        let EmailProviderConfig::Smtp(smtp) = &config.email else {
+            panic!("expect smtp provider, got {:?}", config.email);
+        };
+        assert_eq!(smtp.host, "smtp.example.com");
+        assert_eq!(smtp.port, 465);
+        let SmsProviderConfig::Http(http) = &config.sms else {
+            panic!("expect http provider, got {:?}", config.sms);
+        };
+        assert_eq!(http.format, SmsBodyFormat::Json);
+        assert_eq!(http.timeout_ms, 10_000);
+        assert!(matches!(config.in_app, InAppProviderConfig::Log));
+        let inbox: Arc<dyn InboxStore> = Arc::new(MemoryInbox::default());
+        Providers::from_config(&config, &inbox)?;
+
+        let config = AppConfig::load()?;
+        assert!(matches!(config.providers.email, EmailProviderConfig::Log));
+        assert!(matches!(
+            config.providers.in_app,
+            InAppProviderConfig::Inbox
+        ));
+        Ok(())
+    }
+}
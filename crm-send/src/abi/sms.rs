@@ -1,5 +1,232 @@
-#[allow(unused)]
-use crate::pb::SmsMessage;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{header::AUTHORIZATION, Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+use tracing::{info, warn};
+
+use super::Provider;
+use crate::{
+    config::{HttpSmsConfig, SmsBodyFormat},
+    pb::{RecipientError, SmsMessage},
+};
+
+/// the GSM 03.38 default alphabet, without the escape to the extension table
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+                          ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+/// the characters of the extension table, each takes the escape and itself
+const GSM7_EXTENSION: &str = "\u{c}^{}\\[~]|€";
+
+/// the encoding of a sms body, decides how many characters a segment holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmsEncoding {
+    /// 7-bit septets, 160 in a single segment
+    Gsm7,
+    /// 16-bit code units, 70 in a single segment
+    Ucs2,
+}
+
+impl SmsEncoding {
+    /// GSM-7 if every character is in the alphabet, otherwise UCS-2
+    pub fn detect(body: &str) -> Self {
+        if body
+            .chars()
+            .all(|c| GSM7_BASIC.contains(c) || GSM7_EXTENSION.contains(c))
+        {
+            Self::Gsm7
+        } else {
+            Self::Ucs2
+        }
+    }
+
+    /// the units of a single segment, and of each part of a concatenated sms, the rest of which
+    /// is taken by the user data header
+    fn capacity(&self) -> (usize, usize) {
+        match self {
+            Self::Gsm7 => (160, 153),
+            Self::Ucs2 => (70, 67),
+        }
+    }
+
+    fn units(&self, c: char) -> usize {
+        match self {
+            Self::Gsm7 if GSM7_EXTENSION.contains(c) => 2,
+            Self::Gsm7 => 1,
+            Self::Ucs2 => c.len_utf16(),
+        }
+    }
+}
+
+impl SmsMessage {
+    /// the encoding of the body, and the number of segments it is sent in
+    pub fn segments(&self) -> (SmsEncoding, u32) {
+        let encoding = SmsEncoding::detect(&self.body);
+        let (single, part) = encoding.capacity();
+        let units = self
+            .body
+            .chars()
+            .map(|c| encoding.units(c))
+            .collect::<Vec<_>>();
+        if units.iter().sum::<usize>() <= single {
+            return (encoding, 1);
+        }
+        // an escaped character or a surrogate pair is never split across the parts
+        let mut segments = 1;
+        let mut used = 0;
+        for n in units {
+            if used + n > part {
+                segments += 1;
+                used = 0;
+            }
+            used += n;
+        }
+        (encoding, segments)
+    }
+
+    /// the number of segments, if the body is within the limit. 0 means no limit
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check_segments(&self, max_segments: u32) -> Result<u32, Status> {
+        if self.body.is_empty() {
+            return Err(Status::invalid_argument("body is required"));
+        }
+        let (encoding, segments) = self.segments();
+        if max_segments > 0 && segments > max_segments {
+            return Err(Status::invalid_argument(format!(
+                "Sms of {} segments in {:?} exceeds the limit {}",
+                segments, encoding, max_segments
+            )));
+        }
+        Ok(segments)
+    }
+}
+
+/// post the sms to the http gateway, a request for each recipient
+pub struct HttpSmsProvider {
+    client: Client,
+    config: HttpSmsConfig,
+}
+
+/// the body of a message, with the field names of twilio for the form
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GatewayRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    body: &'a str,
+}
+
+/// the response of the gateway, `sid` for an accepted message, `code` and `message` for an error
+#[derive(Debug, Default, Deserialize)]
+struct GatewayResponse {
+    sid: Option<String>,
+    code: Option<i64>,
+    message: Option<String>,
+}
+
+impl HttpSmsProvider {
+    pub fn new(config: &HttpSmsConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+        })
+    }
+
+    async fn send_to(&self, sms: &SmsMessage, to: &str) -> Result<Option<String>, Status> {
+        let body = GatewayRequest {
+            from: &sms.sender,
+            to,
+            body: &sms.body,
+        };
+        let req = self.client.post(&self.config.url);
+        let req = match self.config.format {
+            SmsBodyFormat::Form => req.form(&body),
+            SmsBodyFormat::Json => req.json(&serde_json::json!({
+                "from": body.from,
+                "to": body.to,
+                "body": body.body,
+            })),
+        };
+        let res = self
+            .authorize(req)
+            .send()
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to reach sms gateway: {}", e)))?;
+
+        let status = res.status();
+        let text = res.text().await.unwrap_or_default();
+        let ret: GatewayResponse = serde_json::from_str(&text).unwrap_or_default();
+        if status.is_success() {
+            return Ok(ret.sid);
+        }
+        let reason = match (ret.code, ret.message) {
+            (Some(code), Some(message)) => format!("{} ({})", message, code),
+            (None, Some(message)) => message,
+            _ => text,
+        };
+        Err(Status::new(
+            to_code(status),
+            format!("Sms to {} is rejected with {}: {}", to, status, reason),
+        ))
+    }
+
+    fn authorize(&self, req: RequestBuilder) -> RequestBuilder {
+        match (&self.config.username, &self.config.auth_header) {
+            (Some(username), _) => req.basic_auth(username, self.config.password.as_ref()),
+            (None, Some(header)) => req.header(AUTHORIZATION, header),
+            (None, None) => req,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Provider<SmsMessage> for HttpSmsProvider {
+    /// fails with the first recipient failed, `send_each` reports all of them
+    async fn send(&self, sms: &SmsMessage) -> Result<(), Status> {
+        match self.send_each(sms).await?.into_iter().next() {
+            None => Ok(()),
+            Some(e) => Err(Status::new(Code::from_i32(e.code), e.error)),
+        }
+    }
+
+    /// the recipients are sent one by one, a failed one doesn't stop the others
+    async fn send_each(&self, sms: &SmsMessage) -> Result<Vec<RecipientError>, Status> {
+        if sms.recipients.is_empty() {
+            return Err(Status::invalid_argument("recipients are required"));
+        }
+        let mut failed = vec![];
+        for to in &sms.recipients {
+            match self.send_to(sms, to).await {
+                Ok(sid) => info!("Sms {} to {} is accepted: {:?}", sms.message_id, to, sid),
+                Err(e) => {
+                    warn!("Failed to send sms {} to {}: {:?}", sms.message_id, to, e);
+                    failed.push(RecipientError {
+                        recipient: to.clone(),
+                        code: e.code() as i32,
+                        error: e.message().to_string(),
+                    });
+                }
+            }
+        }
+        Ok(failed)
+    }
+}
+
+/// the invalid recipients and the misconfigured credentials are not retried, the others might be
+fn to_code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => {
+            Code::InvalidArgument
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        s if s.is_server_error() => Code::Unavailable,
+        _ => Code::Internal,
+    }
+}
 
 #[cfg(feature = "test_utils")]
 impl SmsMessage {
@@ -11,7 +238,175 @@ impl SmsMessage {
             message_id: Uuid::new_v4().to_string(),
             sender: PhoneNumber().fake(),
             recipients: vec![PhoneNumber().fake()],
-            subject: "Hello world".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hello world".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::test_utils::SmsGateway;
+
+    fn sms(body: &str) -> SmsMessage {
+        SmsMessage {
+            body: body.to_string(),
+            ..SmsMessage::fake()
+        }
+    }
+
+    #[test]
+    fn gsm7_body_should_be_counted_in_septets() {
+        assert_eq!(sms("Hello world").segments(), (SmsEncoding::Gsm7, 1));
+        assert_eq!(sms(&"a".repeat(160)).segments(), (SmsEncoding::Gsm7, 1));
+        assert_eq!(sms(&"a".repeat(161)).segments(), (SmsEncoding::Gsm7, 2));
+        assert_eq!(sms(&"a".repeat(306)).segments(), (SmsEncoding::Gsm7, 2));
+        assert_eq!(sms(&"a".repeat(307)).segments(), (SmsEncoding::Gsm7, 3));
+        // the characters of the extension table take two septets
+        assert_eq!(sms(&"€".repeat(80)).segments(), (SmsEncoding::Gsm7, 1));
+        assert_eq!(sms(&"€".repeat(81)).segments(), (SmsEncoding::Gsm7, 2));
+        // and they are not split across the parts
+        let body = format!("{}€", "a".repeat(152));
+        assert_eq!(sms(&format!("{}{}", body, "a".repeat(10))).segments().1, 2);
+        assert_eq!(sms(&format!("{}{}", body, "a".repeat(152))).segments().1, 3);
+    }
+
+    #[test]
+    fn non_gsm7_body_should_be_counted_in_ucs2() {
+        assert_eq!(sms("Café ñ à").segments(), (SmsEncoding::Gsm7, 1));
+        assert_eq!(sms("Hello 世界").segments(), (SmsEncoding::Ucs2, 1));
+        assert_eq!(sms(&"世".repeat(70)).segments(), (SmsEncoding::Ucs2, 1));
+        assert_eq!(sms(&"世".repeat(71)).segments(), (SmsEncoding::Ucs2, 2));
+        assert_eq!(sms(&"世".repeat(134)).segments(), (SmsEncoding::Ucs2, 2));
+        assert_eq!(sms(&"世".repeat(135)).segments(), (SmsEncoding::Ucs2, 3));
+        // an emoji takes a surrogate pair
+        assert_eq!(sms(&"👋".repeat(35)).segments(), (SmsEncoding::Ucs2, 1));
+        assert_eq!(sms(&"👋".repeat(36)).segments(), (SmsEncoding::Ucs2, 2));
+        let body = format!("{}👋", "世".repeat(66));
+        assert_eq!(sms(&body).segments(), (SmsEncoding::Ucs2, 1));
+        let body = format!("{}{}", body, "世".repeat(66));
+        assert_eq!(sms(&body).segments(), (SmsEncoding::Ucs2, 3));
+    }
+
+    #[test]
+    fn segments_over_limit_should_be_rejected() {
+        assert_eq!(sms(&"世".repeat(134)).check_segments(2).unwrap(), 2);
+        let e = sms(&"世".repeat(135)).check_segments(2).unwrap_err();
+        assert_eq!(e.code(), Code::InvalidArgument);
+        assert!(e.message().contains("3 segments in Ucs2"));
+        let e = sms("").check_segments(2).unwrap_err();
+        assert_eq!(e.code(), Code::InvalidArgument);
+        // no limit
+        assert_eq!(sms(&"世".repeat(700)).check_segments(0).unwrap(), 11);
+        assert!(sms("").check_segments(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn http_provider_should_post_form_with_basic_auth() -> Result<()> {
+        let gateway = SmsGateway::start().await?;
+        let provider = HttpSmsProvider::new(&gateway.config(SmsBodyFormat::Form))?;
+        let mut sms = SmsMessage::fake();
+        sms.recipients = vec!["+15550001".to_string(), "+15550002".to_string()];
+        provider.send(&sms).await?;
+
+        let received = gateway.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].from, sms.sender);
+        assert_eq!(received[0].to, "+15550001");
+        assert_eq!(received[1].to, "+15550002");
+        assert_eq!(received[0].body, sms.body);
+        assert_eq!(
+            received[0].content_type,
+            "application/x-www-form-urlencoded"
+        );
+        // base64 of crm:secret
+        assert_eq!(
+            received[0].authorization.as_deref(),
+            Some("Basic Y3JtOnNlY3JldA==")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_provider_should_post_json_with_auth_header() -> Result<()> {
+        let gateway = SmsGateway::start().await?;
+        let mut config = gateway.config(SmsBodyFormat::Json);
+        config.username = None;
+        config.auth_header = Some("Bearer token".to_string());
+        let provider = HttpSmsProvider::new(&config)?;
+        let sms = SmsMessage::fake();
+        provider.send(&sms).await?;
+
+        let received = gateway.received();
+        assert_eq!(received[0].to, sms.recipients[0]);
+        assert_eq!(received[0].body, sms.body);
+        assert_eq!(received[0].content_type, "application/json");
+        assert_eq!(received[0].authorization.as_deref(), Some("Bearer token"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_provider_should_map_gateway_errors() -> Result<()> {
+        let gateway = SmsGateway::start().await?;
+        let provider = HttpSmsProvider::new(&gateway.config(SmsBodyFormat::Form))?;
+        for (status, code) in [
+            (400, Code::InvalidArgument),
+            (401, Code::PermissionDenied),
+            (429, Code::ResourceExhausted),
+            (503, Code::Unavailable),
+        ] {
+            let mut sms = SmsMessage::fake();
+            sms.recipients = vec!["+15550001".to_string(), format!("+1555{}", status)];
+            gateway.reject(&sms.recipients[1], status);
+            let failed = provider.send_each(&sms).await?;
+            assert_eq!(failed.len(), 1);
+            assert_eq!(failed[0].recipient, sms.recipients[1]);
+            assert_eq!(failed[0].code, code as i32);
+            assert!(failed[0].error.contains("rejected by the gateway (21211)"));
+            let e = provider.send(&sms).await.unwrap_err();
+            assert_eq!(e.code(), code);
         }
+
+        // the accepted recipients are still sent, by both send_each and send
+        assert_eq!(gateway.received().len(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_provider_should_report_each_recipient() -> Result<()> {
+        let gateway = SmsGateway::start().await?;
+        let provider = HttpSmsProvider::new(&gateway.config(SmsBodyFormat::Json))?;
+        let mut sms = SmsMessage::fake();
+        sms.recipients = vec![
+            "+15550001".to_string(),
+            "+15550002".to_string(),
+            "+15550003".to_string(),
+        ];
+        gateway.reject("+15550002", 400);
+        let failed = provider.send_each(&sms).await?;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].recipient, "+15550002");
+        assert_eq!(failed[0].code, Code::InvalidArgument as i32);
+
+        // the recipients after the failed one are still sent
+        let received = gateway.received();
+        let to = received.iter().map(|r| r.to.as_str()).collect::<Vec<_>>();
+        assert_eq!(to, ["+15550001", "+15550003"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_provider_should_fail_if_gateway_is_down() -> Result<()> {
+        let gateway = SmsGateway::start().await?;
+        let mut config = gateway.config(SmsBodyFormat::Form);
+        // nothing listens on the port 1
+        config.url = "http://127.0.0.1:1/messages".to_string();
+        let provider = HttpSmsProvider::new(&config)?;
+        let e = provider.send(&SmsMessage::fake()).await.unwrap_err();
+        assert_eq!(e.code(), Code::Unavailable);
+        Ok(())
     }
 }
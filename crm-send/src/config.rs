@@ -6,6 +6,61 @@ use std::{env, fs::File};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub providers: ProvidersConfig,
+    #[serde(default)]
+    pub sms: SmsConfig,
+    #[serde(default)]
+    pub inbox: InboxConfig,
+}
+
+/// the store of the in-app messages, required by the inbox provider. it is migrated on startup
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InboxConfig {
+    pub db_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmsConfig {
+    /// the sms with more segments are rejected, each segment is charged. 0 means no limit
+    #[serde(default = "default_max_segments")]
+    pub max_segments: u32,
+}
+
+/// the provider of each channel, the messages are only logged by default
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProvidersConfig {
+    #[serde(default)]
+    pub email: EmailProviderConfig,
+    #[serde(default)]
+    pub sms: SmsProviderConfig,
+    #[serde(default)]
+    pub in_app: InAppProviderConfig,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EmailProviderConfig {
+    #[default]
+    Log,
+    Smtp(SmtpConfig),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SmsProviderConfig {
+    #[default]
+    Log,
+    Http(HttpSmsConfig),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum InAppProviderConfig {
+    #[default]
+    Log,
+    /// keep the messages in the inbox of the devices
+    Inbox,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,6 +71,92 @@ pub struct AuthConfig {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
+    /// number of messages of a send stream delivered at the same time, the responses are in
+    /// the order of delivery
+    #[serde(default = "default_send_concurrency")]
+    pub send_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// credentials of AUTH, no authentication if not set
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// timeout of each smtp command in milliseconds
+    #[serde(default = "default_smtp_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// plain text, only for a local relay
+    None,
+    /// upgrade the connection with STARTTLS, usually on port 587
+    #[default]
+    StartTls,
+    /// TLS from the start of the connection, usually on port 465
+    Tls,
+}
+
+/// a gateway api taking a message per recipient, like the messages api of twilio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpSmsConfig {
+    /// the endpoint the messages are posted to
+    pub url: String,
+    #[serde(default)]
+    pub format: SmsBodyFormat,
+    /// credentials of basic auth, e.g. the account sid and the auth token of twilio
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// value of the Authorization header, e.g. "Bearer <token>", used if no basic auth is set
+    pub auth_header: Option<String>,
+    /// timeout of each request in milliseconds
+    #[serde(default = "default_http_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsBodyFormat {
+    /// urlencoded form of From, To and Body, as twilio takes it
+    #[default]
+    Form,
+    /// json object of from, to and body
+    Json,
+}
+
+impl Default for SmsConfig {
+    fn default() -> Self {
+        Self {
+            max_segments: default_max_segments(),
+        }
+    }
+}
+
+fn default_send_concurrency() -> usize {
+    32
+}
+
+fn default_max_segments() -> u32 {
+    10
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_smtp_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_http_timeout_ms() -> u64 {
+    10_000
 }
 
 impl AppConfig {
@@ -13,7 +13,7 @@ async fn main() -> Result<()> {
     let addr = config.server.port;
     let addr = format!("[::1]:{}", addr).parse()?;
     info!("NotificationService listening on {}", &addr);
-    let svc = NotificationService::new(config).into_server();
+    let svc = NotificationService::try_new(config).await?.into_server()?;
     Server::builder().add_service(svc).serve(addr).await?;
     Ok(())
 }
@@ -31,6 +31,34 @@ pub struct SendResponse {
     /// timestamp of when the message was sent
     #[prost(message, optional, tag = "2")]
     pub timestamp: ::core::option::Option<::prost_types::Timestamp>,
+    /// number of segments of a sms to each recipient, 0 for the other channels
+    #[prost(uint32, tag = "3")]
+    pub segments: u32,
+    /// grpc status code of the delivery, 0 (OK) if the message is sent. A failed message is
+    /// reported in its response, the stream goes on with the other messages
+    #[prost(int32, tag = "4")]
+    pub code: i32,
+    /// the reason of a failed delivery, empty if the message is sent
+    #[prost(string, tag = "5")]
+    pub error: ::prost::alloc::string::String,
+    /// the recipients of a sms the message failed to reach, the message is sent if any other
+    /// recipient received it
+    #[prost(message, repeated, tag = "6")]
+    pub failed_recipients: ::prost::alloc::vec::Vec<RecipientError>,
+}
+/// a recipient a message failed to reach
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RecipientError {
+    /// the phone number of the recipient
+    #[prost(string, tag = "1")]
+    pub recipient: ::prost::alloc::string::String,
+    /// grpc status code of the delivery to the recipient
+    #[prost(int32, tag = "2")]
+    pub code: i32,
+    /// the reason of the failure
+    #[prost(string, tag = "3")]
+    pub error: ::prost::alloc::string::String,
 }
 /// email message to be sent
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -59,7 +87,7 @@ pub struct SmsMessage {
     /// unique identifier of the message
     #[prost(string, tag = "1")]
     pub message_id: ::prost::alloc::string::String,
-    /// subject of the sms
+    /// subject of the sms, only for tracking, the recipients get the body
     #[prost(string, tag = "2")]
     pub subject: ::prost::alloc::string::String,
     /// sender of the sms
@@ -68,6 +96,9 @@ pub struct SmsMessage {
     /// recipients of the sms
     #[prost(string, repeated, tag = "4")]
     pub recipients: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// body of the sms, sent in GSM-7 if possible, otherwise in UCS-2
+    #[prost(string, tag = "5")]
+    pub body: ::prost::alloc::string::String,
 }
 /// in-app message to sent
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -86,6 +117,98 @@ pub struct InAppMessage {
     #[prost(string, tag = "4")]
     pub body: ::prost::alloc::string::String,
 }
+/// an in-app message kept in the inbox of its device
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InboxItem {
+    /// unique identifier of the message
+    #[prost(string, tag = "1")]
+    pub message_id: ::prost::alloc::string::String,
+    /// device id of the inbox
+    #[prost(string, tag = "2")]
+    pub device_id: ::prost::alloc::string::String,
+    /// title of the in-app message
+    #[prost(string, tag = "3")]
+    pub title: ::prost::alloc::string::String,
+    /// body of the in-app message
+    #[prost(string, tag = "4")]
+    pub body: ::prost::alloc::string::String,
+    /// timestamp of when the message was added to the inbox
+    #[prost(message, optional, tag = "5")]
+    pub created_at: ::core::option::Option<::prost_types::Timestamp>,
+    /// timestamp of when the message was read, unset if it is unread
+    #[prost(message, optional, tag = "6")]
+    pub read_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// subscribe to the inbox of a device
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InboxRequest {
+    #[prost(string, tag = "1")]
+    pub device_id: ::prost::alloc::string::String,
+    /// send the unread messages before the new ones
+    #[prost(bool, tag = "2")]
+    pub include_unread: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListInboxRequest {
+    #[prost(string, tag = "1")]
+    pub device_id: ::prost::alloc::string::String,
+    /// max number of messages to return, 0 means the default page size
+    #[prost(uint32, tag = "2")]
+    pub page_size: u32,
+    /// next_page_token returned by the previous page, messages are returned from the newest
+    #[prost(string, tag = "3")]
+    pub page_token: ::prost::alloc::string::String,
+    /// only return the unread messages
+    #[prost(bool, tag = "4")]
+    pub unread_only: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListInboxResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub items: ::prost::alloc::vec::Vec<InboxItem>,
+    /// token to get the next page, empty if there are no more messages
+    #[prost(string, tag = "2")]
+    pub next_page_token: ::prost::alloc::string::String,
+    /// number of unread messages in the inbox
+    #[prost(uint64, tag = "3")]
+    pub unread: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MarkReadRequest {
+    #[prost(string, tag = "1")]
+    pub device_id: ::prost::alloc::string::String,
+    /// all the unread messages of the inbox if empty
+    #[prost(string, repeated, tag = "2")]
+    pub message_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MarkReadResponse {
+    /// number of messages marked as read
+    #[prost(uint64, tag = "1")]
+    pub updated: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DismissRequest {
+    #[prost(string, tag = "1")]
+    pub device_id: ::prost::alloc::string::String,
+    /// the messages to remove from the inbox, required
+    #[prost(string, repeated, tag = "2")]
+    pub message_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DismissResponse {
+    /// number of messages removed from the inbox
+    #[prost(uint64, tag = "1")]
+    pub dismissed: u64,
+}
 /// Generated client implementations.
 pub mod notification_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -192,6 +315,80 @@ pub mod notification_client {
                 .insert(GrpcMethod::new("notification.Notification", "Send"));
             self.inner.streaming(req, path, codec).await
         }
+        /// stream the in-app messages of a device as they are delivered
+        pub async fn inbox(
+            &mut self,
+            request: impl tonic::IntoRequest<super::InboxRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::InboxItem>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/notification.Notification/Inbox");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("notification.Notification", "Inbox"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// one page of the in-app messages of a device, from the newest
+        pub async fn list_inbox(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListInboxRequest>,
+        ) -> std::result::Result<tonic::Response<super::ListInboxResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/notification.Notification/ListInbox");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("notification.Notification", "ListInbox"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn mark_read(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MarkReadRequest>,
+        ) -> std::result::Result<tonic::Response<super::MarkReadResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/notification.Notification/MarkRead");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("notification.Notification", "MarkRead"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// remove the in-app messages from the inbox of a device
+        pub async fn dismiss(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DismissRequest>,
+        ) -> std::result::Result<tonic::Response<super::DismissResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/notification.Notification/Dismiss");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("notification.Notification", "Dismiss"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -211,6 +408,30 @@ pub mod notification_server {
             &self,
             request: tonic::Request<tonic::Streaming<super::SendRequest>>,
         ) -> std::result::Result<tonic::Response<Self::SendStream>, tonic::Status>;
+        /// Server streaming response type for the Inbox method.
+        type InboxStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::InboxItem, tonic::Status>,
+            > + Send
+            + 'static;
+        /// stream the in-app messages of a device as they are delivered
+        async fn inbox(
+            &self,
+            request: tonic::Request<super::InboxRequest>,
+        ) -> std::result::Result<tonic::Response<Self::InboxStream>, tonic::Status>;
+        /// one page of the in-app messages of a device, from the newest
+        async fn list_inbox(
+            &self,
+            request: tonic::Request<super::ListInboxRequest>,
+        ) -> std::result::Result<tonic::Response<super::ListInboxResponse>, tonic::Status>;
+        async fn mark_read(
+            &self,
+            request: tonic::Request<super::MarkReadRequest>,
+        ) -> std::result::Result<tonic::Response<super::MarkReadResponse>, tonic::Status>;
+        /// remove the in-app messages from the inbox of a device
+        async fn dismiss(
+            &self,
+            request: tonic::Request<super::DismissRequest>,
+        ) -> std::result::Result<tonic::Response<super::DismissResponse>, tonic::Status>;
     }
     /// The Notification Service providers a way to send notification to users.
     #[derive(Debug)]
@@ -330,6 +551,166 @@ pub mod notification_server {
                     };
                     Box::pin(fut)
                 }
+                "/notification.Notification/Inbox" => {
+                    #[allow(non_camel_case_types)]
+                    struct InboxSvc<T: Notification>(pub Arc<T>);
+                    impl<T: Notification> tonic::server::ServerStreamingService<super::InboxRequest> for InboxSvc<T> {
+                        type Response = super::InboxItem;
+                        type ResponseStream = T::InboxStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::InboxRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as Notification>::inbox(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = InboxSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/notification.Notification/ListInbox" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListInboxSvc<T: Notification>(pub Arc<T>);
+                    impl<T: Notification> tonic::server::UnaryService<super::ListInboxRequest> for ListInboxSvc<T> {
+                        type Response = super::ListInboxResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListInboxRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Notification>::list_inbox(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListInboxSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/notification.Notification/MarkRead" => {
+                    #[allow(non_camel_case_types)]
+                    struct MarkReadSvc<T: Notification>(pub Arc<T>);
+                    impl<T: Notification> tonic::server::UnaryService<super::MarkReadRequest> for MarkReadSvc<T> {
+                        type Response = super::MarkReadResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MarkReadRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Notification>::mark_read(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = MarkReadSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/notification.Notification/Dismiss" => {
+                    #[allow(non_camel_case_types)]
+                    struct DismissSvc<T: Notification>(pub Arc<T>);
+                    impl<T: Notification> tonic::server::UnaryService<super::DismissRequest> for DismissSvc<T> {
+                        type Response = super::DismissResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DismissRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as Notification>::dismiss(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = DismissSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => Box::pin(async move {
                     Ok(http::Response::builder()
                         .status(200)
@@ -1,16 +1,22 @@
 use std::{net::SocketAddr, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crm_send::{
     pb::{
-        notification_client::NotificationClient, EmailMessage, InAppMessage, SendRequest,
-        SmsMessage,
+        notification_client::NotificationClient, DismissRequest, EmailMessage, InAppMessage,
+        InboxRequest, ListInboxRequest, MarkReadRequest, SendRequest, SmsMessage,
     },
-    AppConfig, NotificationService,
+    test_utils::{get_test_pool, SmsGateway, SmtpSink, TestKey},
+    AppConfig, EmailProviderConfig, NotificationService, SmsBodyFormat, SmsProviderConfig,
 };
 use futures::StreamExt;
-use tokio::time::sleep;
-use tonic::transport::Server;
+use tokio::time::{sleep, timeout};
+use tonic::{
+    metadata::MetadataValue,
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{server::TcpIncoming, Channel, Server},
+    Code, Request,
+};
 
 #[tokio::test]
 async fn send_should_work() -> Result<()> {
@@ -37,16 +43,301 @@ async fn send_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn send_email_should_deliver_with_smtp() -> Result<()> {
+    let sink = SmtpSink::start().await?;
+    let mut config = AppConfig::load()?;
+    config.providers.email = EmailProviderConfig::Smtp(sink.config());
+    let addr = start_server_with(config, 20).await?;
+
+    let email = EmailMessage::fake();
+    let req = tokio_stream::iter(vec![SendRequest {
+        msg: Some(email.clone().into()),
+    }]);
+    let mut client = NotificationClient::connect(format!("http://{addr}")).await?;
+    let ret = client
+        .send(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(ret.len(), 1);
+    assert_eq!(ret[0].as_ref().unwrap().message_id, email.message_id);
+
+    let mails = sink.mails();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0].recipients, email.recipients);
+    Ok(())
+}
+
+#[tokio::test]
+async fn send_sms_should_post_to_http_gateway() -> Result<()> {
+    let gateway = SmsGateway::start().await?;
+    let mut config = AppConfig::load()?;
+    config.providers.sms = SmsProviderConfig::Http(gateway.config(SmsBodyFormat::Form));
+    let addr = start_server_with(config, 30).await?;
+
+    let sms = SmsMessage::fake();
+    let mut rejected = SmsMessage::fake();
+    rejected.recipients = vec!["+15550000".to_string()];
+    gateway.reject("+15550000", 400);
+    let req = tokio_stream::iter(vec![
+        SendRequest {
+            msg: Some(sms.clone().into()),
+        },
+        SendRequest {
+            msg: Some(rejected.into()),
+        },
+    ]);
+    let mut client = NotificationClient::connect(format!("http://{addr}")).await?;
+    let ret = client
+        .send(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    let mut ret = ret.into_iter().collect::<Result<Vec<_>, _>>()?;
+    // the responses are in the order of delivery
+    ret.sort_by_key(|r| r.message_id != sms.message_id);
+    assert_eq!(ret.len(), 2);
+    assert_eq!(ret[0].message_id, sms.message_id);
+    assert_eq!(ret[0].segments, 1);
+    assert_eq!(ret[1].code(), Code::InvalidArgument);
+
+    let received = gateway.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].to, sms.recipients[0]);
+    assert_eq!(received[0].body, sms.body);
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_message_should_not_end_send_stream() -> Result<()> {
+    let gateway = SmsGateway::start().await?;
+    let mut config = AppConfig::load()?;
+    config.providers.sms = SmsProviderConfig::Http(gateway.config(SmsBodyFormat::Json));
+    let addr = start_server_with(config, 50).await?;
+
+    let mut rejected = SmsMessage::fake();
+    rejected.recipients = vec!["+15550000".to_string()];
+    gateway.reject("+15550000", 400);
+    let msgs = vec![
+        SmsMessage::fake(),
+        rejected.clone(),
+        SmsMessage::fake(),
+        SmsMessage::fake(),
+    ];
+    let req = tokio_stream::iter(msgs.clone().into_iter().map(SendRequest::from));
+    let mut client = NotificationClient::connect(format!("http://{addr}")).await?;
+    let ret = client
+        .send(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    let ret = ret.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(ret.len(), 4);
+    let failed = ret.iter().filter(|r| !r.is_sent()).collect::<Vec<_>>();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].message_id, rejected.message_id);
+    assert!(!failed[0].error.is_empty());
+    assert_eq!(gateway.received().len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn send_sms_should_report_failed_recipients() -> Result<()> {
+    let gateway = SmsGateway::start().await?;
+    let mut config = AppConfig::load()?;
+    config.providers.sms = SmsProviderConfig::Http(gateway.config(SmsBodyFormat::Form));
+    let addr = start_server_with(config, 60).await?;
+
+    let mut sms = SmsMessage::fake();
+    sms.recipients = vec![
+        "+15550001".to_string(),
+        "+15550002".to_string(),
+        "+15550003".to_string(),
+    ];
+    gateway.reject("+15550002", 400);
+    let mut all_rejected = SmsMessage::fake();
+    all_rejected.recipients = vec!["+15550002".to_string()];
+    let req = tokio_stream::iter(vec![
+        SendRequest::from(sms.clone()),
+        SendRequest::from(all_rejected.clone()),
+    ]);
+    let mut client = NotificationClient::connect(format!("http://{addr}")).await?;
+    let ret = client
+        .send(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    let ret = ret.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let partial = ret.iter().find(|r| r.message_id == sms.message_id).unwrap();
+    // sent to the other recipients
+    assert!(partial.is_sent());
+    assert_eq!(partial.failed_recipients.len(), 1);
+    assert_eq!(partial.failed_recipients[0].recipient, "+15550002");
+    assert_eq!(
+        partial.failed_recipients[0].code,
+        Code::InvalidArgument as i32
+    );
+    let failed = ret
+        .iter()
+        .find(|r| r.message_id == all_rejected.message_id)
+        .unwrap();
+    assert_eq!(failed.code(), Code::InvalidArgument);
+    assert_eq!(failed.failed_recipients.len(), 1);
+    assert_eq!(gateway.received().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_app_should_be_delivered_to_inbox() -> Result<()> {
+    let key = TestKey::new();
+    let mut config = AppConfig::load()?;
+    config.auth.pk = key.pk();
+    let addr = start_server_with(config, 40).await?;
+    let msg = InAppMessage::fake();
+    let mut client = inbox_client(addr, &key.token(&msg.device_id)).await?;
+    let req = InboxRequest {
+        device_id: msg.device_id.clone(),
+        include_unread: false,
+    };
+    let mut inbox = client.inbox(req).await?.into_inner();
+
+    let req = tokio_stream::iter(vec![SendRequest {
+        msg: Some(msg.clone().into()),
+    }]);
+    let ret = client
+        .send(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert!(ret[0].is_ok());
+    let item = timeout(Duration::from_secs(5), inbox.next())
+        .await?
+        .unwrap()?;
+    assert_eq!(item.message_id, msg.message_id);
+    assert_eq!(item.body, msg.body);
+
+    let req = ListInboxRequest {
+        device_id: msg.device_id.clone(),
+        ..Default::default()
+    };
+    let ret = client.list_inbox(req.clone()).await?.into_inner();
+    assert_eq!(ret.items, [item]);
+    assert_eq!(ret.unread, 1);
+
+    let ret = client
+        .mark_read(MarkReadRequest {
+            device_id: msg.device_id.clone(),
+            message_ids: vec![],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(ret.updated, 1);
+    let ret = client
+        .dismiss(DismissRequest {
+            device_id: msg.device_id.clone(),
+            message_ids: vec![msg.message_id.clone()],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(ret.dismissed, 1);
+    let ret = client.list_inbox(req).await?.into_inner();
+    assert!(ret.items.is_empty());
+    assert_eq!(ret.unread, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn inbox_should_only_be_accessed_by_its_device() -> Result<()> {
+    let key = TestKey::new();
+    let mut config = AppConfig::load()?;
+    config.auth.pk = key.pk();
+    let addr = start_server_with(config, 70).await?;
+    let req = ListInboxRequest {
+        device_id: "d1".to_string(),
+        ..Default::default()
+    };
+
+    let mut client = NotificationClient::connect(format!("http://{addr}")).await?;
+    let status = client.list_inbox(req.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let mut client = inbox_client(addr, &TestKey::new().token("d1")).await?;
+    let status = client.list_inbox(req.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut client = inbox_client(addr, &key.token("d2")).await?;
+    let status = client.list_inbox(req.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let inbox = InboxRequest {
+        device_id: "d1".to_string(),
+        include_unread: true,
+    };
+    let status = client.inbox(inbox).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = client
+        .mark_read(MarkReadRequest {
+            device_id: "d1".to_string(),
+            message_ids: vec![],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = client
+        .dismiss(DismissRequest {
+            device_id: "d1".to_string(),
+            message_ids: vec!["m1".to_string()],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    let mut client = inbox_client(addr, &key.token("d1")).await?;
+    let ret = client.list_inbox(req).await?.into_inner();
+    assert!(ret.items.is_empty());
+    Ok(())
+}
+
+/// the client of the device with its token
+#[allow(clippy::result_large_err)]
+async fn inbox_client(
+    addr: SocketAddr,
+    token: &str,
+) -> Result<NotificationClient<InterceptedService<Channel, impl Interceptor>>> {
+    let channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    let token: MetadataValue<_> = format!("Bearer {token}").parse()?;
+    let client = NotificationClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut().insert("authorization", token.clone());
+        Ok(req)
+    });
+    Ok(client)
+}
+
 async fn start_server() -> Result<SocketAddr> {
-    let config = AppConfig::load()?;
-    let addr = config.server.port + 10; // 避免测试端口冲突
+    start_server_with(AppConfig::load()?, 10).await
+}
+
+async fn start_server_with(mut config: AppConfig, port: u16) -> Result<SocketAddr> {
+    let addr = config.server.port + port; // 避免测试端口冲突
     let addr = format!("[::1]:{}", addr).parse()?;
+    // bind before connecting to the inbox database, whose connections take ephemeral ports
+    let incoming = TcpIncoming::new(addr, true, None).map_err(|e| anyhow!(e))?;
 
-    let svc = NotificationService::new(config).into_server();
+    let (tdb, _) = get_test_pool(None).await;
+    config.inbox.db_url = Some(tdb.url());
+    let svc = NotificationService::try_new(config).await?.into_server()?;
     tokio::spawn(async move {
+        // the inbox database is kept as long as the server
+        let _tdb = tdb;
         Server::builder()
             .add_service(svc)
-            .serve(addr)
+            .serve_with_incoming(incoming)
             .await
             .unwrap();
     });
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use futures::future::Either;
+use tokio::sync::mpsc::{self, error::SendError};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Status, Streaming};
+use tracing::{info, warn};
+use user_stat::pb::{FrequencyCap, NotificationChannel, QueryRequest, ReserveRequest, User};
+
+use super::delivery::Deliveries;
+use crate::{
+    config::{CapChannel, FrequencyCapConfig},
+    CrmService,
+};
+
+/// number of reserved users buffered for the campaign
+const RESERVED_BUFFER: usize = 1024;
+
+/// the users queried directly, or reserved with the caps
+pub(crate) type UserStream = Either<Streaming<User>, ReceiverStream<Result<User, Status>>>;
+
+impl CrmService {
+    /// query the users with the frequency caps of the channel. the capped users are reserved
+    /// atomically by user-stat, so the concurrent campaigns never exceed the caps. return the
+    /// users, the number of users suppressed by the caps, and the deliveries to record the
+    /// notifications with. the reserved users not taken by the campaign are released
+    pub(crate) async fn query_capped(
+        &self,
+        mut query: QueryRequest,
+        channel: CapChannel,
+    ) -> Result<(UserStream, u64, Deliveries), Status> {
+        let caps = to_caps(&self.config.server.frequency_caps, channel);
+        let mut user_stats = self.user_stats.clone();
+        if caps.is_empty() {
+            let users = user_stats.query(query).await?.into_inner();
+            return Ok((Either::Left(users), 0, Deliveries::default()));
+        }
+
+        query.caps.extend(caps);
+        let req = ReserveRequest {
+            query: Some(query),
+            channel: NotificationChannel::from(channel) as i32,
+        };
+        let mut responses = user_stats.reserve(req).await?.into_inner();
+        let first = responses.message().await?.unwrap_or_default();
+        info!("{} users are suppressed by the caps", first.suppressed);
+
+        let deliveries = Deliveries::reserved(channel.into(), first.reserved_at);
+        let suppressed = first.suppressed;
+        let tracker = deliveries.clone();
+        let (tx, rx) = mpsc::channel(RESERVED_BUFFER);
+        tokio::spawn(async move {
+            let mut users = first.users;
+            let mut unsent = vec![];
+            loop {
+                for user in users {
+                    if !unsent.is_empty() {
+                        unsent.push(user.email);
+                    } else if let Err(SendError(user)) = tx.send(Ok(user)).await {
+                        warn!("The campaign stopped before all reserved users are notified");
+                        unsent.extend(user.map(|u| u.email));
+                    }
+                }
+                users = match responses.message().await {
+                    Ok(Some(res)) => res.users,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                };
+            }
+            tracker.release(unsent, &mut user_stats).await;
+        });
+        Ok((
+            Either::Right(ReceiverStream::new(rx)),
+            suppressed,
+            deliveries,
+        ))
+    }
+}
+
+fn to_caps(caps: &[FrequencyCapConfig], channel: CapChannel) -> Vec<FrequencyCap> {
+    caps.iter()
+        .filter(|c| c.channel == channel)
+        .map(|c| {
+            let window = Duration::from_secs(c.window_hours as u64 * 3600);
+            FrequencyCap::new(channel.into(), c.max_count, window)
+        })
+        .collect()
+}
+
+impl From<CapChannel> for NotificationChannel {
+    fn from(channel: CapChannel) -> Self {
+        match channel {
+            CapChannel::Email => NotificationChannel::Email,
+            CapChannel::InApp => NotificationChannel::InApp,
+            CapChannel::Sms => NotificationChannel::Sms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_caps_should_filter_by_channel() {
+        let config = |channel, max_count, window_hours| FrequencyCapConfig {
+            channel,
+            max_count,
+            window_hours,
+        };
+        let caps = [
+            config(CapChannel::Email, 1, 48),
+            config(CapChannel::Sms, 1, 24),
+            config(CapChannel::Email, 3, 168),
+        ];
+        let ret = to_caps(&caps, CapChannel::Email);
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0].channel, NotificationChannel::Email as i32);
+        assert_eq!(ret[0].max_count, 1);
+        assert_eq!(ret[0].window.as_ref().unwrap().seconds, 48 * 3600);
+        assert_eq!(ret[1].max_count, 3);
+        assert!(to_caps(&caps, CapChannel::InApp).is_empty());
+    }
+}
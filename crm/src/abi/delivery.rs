@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use crm_send::pb::{send_request::Msg, SendRequest, SendResponse};
+use futures::{Stream, StreamExt};
+use prost_types::Timestamp;
+use tonic::{transport::Channel, Status};
+use tracing::{info, warn};
+use user_stat::pb::{
+    user_stats_client::UserStatsClient, MarkNotifiedRequest, NotificationChannel, Notified,
+    ReleaseRequest,
+};
+
+/// number of notified or released users reported to user-stat at a time
+const BATCH_SIZE: usize = 100;
+
+/// the users the messages are sent to, so the send responses could be recorded back to
+/// user_stats
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Deliveries {
+    // message_id -> (channel, email)
+    pending: Arc<Mutex<HashMap<String, (NotificationChannel, String)>>>,
+    /// the channel of the reservation, the users not notified are released on it
+    channel: NotificationChannel,
+    /// the time the notifications are reserved at, it replaces the time of the send responses
+    /// so the reserved notifications are not recorded twice
+    reserved_at: Option<Timestamp>,
+}
+
+impl Deliveries {
+    /// the deliveries of the users reserved on the channel at the time
+    pub fn reserved(channel: NotificationChannel, reserved_at: Option<Timestamp>) -> Self {
+        Self {
+            channel,
+            reserved_at,
+            ..Default::default()
+        }
+    }
+
+    /// remember the user the message is sent to
+    pub fn track(&self, req: &SendRequest, email: &str) {
+        let (message_id, channel) = match &req.msg {
+            Some(Msg::Email(msg)) => (&msg.message_id, NotificationChannel::Email),
+            Some(Msg::Sms(msg)) => (&msg.message_id, NotificationChannel::Sms),
+            Some(Msg::InApp(msg)) => (&msg.message_id, NotificationChannel::InApp),
+            None => return,
+        };
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(message_id.clone(), (channel, email.to_string()));
+    }
+
+    /// the user notified by the message of the response, the email of the user if the message
+    /// failed, None if the message is unknown
+    fn resolve(&self, res: SendResponse) -> Option<Result<Notified, String>> {
+        let (channel, email) = self.pending.lock().unwrap().remove(&res.message_id)?;
+        if !res.is_sent() {
+            warn!(
+                "Failed to send message {} to {}: {:?} {}",
+                res.message_id,
+                email,
+                res.code(),
+                res.error
+            );
+            return Some(Err(email));
+        }
+        Some(Ok(Notified {
+            email,
+            channel: channel as i32,
+            notified_at: self.reserved_at.clone().or(res.timestamp),
+        }))
+    }
+
+    /// release the reservation of the users who are not notified, so they are not counted by
+    /// the caps. nothing to release if the users are not reserved
+    pub async fn release(&self, emails: Vec<String>, user_stats: &mut UserStatsClient<Channel>) {
+        if self.reserved_at.is_none() || emails.is_empty() {
+            return;
+        }
+        let req = ReleaseRequest {
+            channel: self.channel as i32,
+            reserved_at: self.reserved_at.clone(),
+            emails,
+        };
+        match user_stats.release(req).await {
+            Ok(ret) => info!("Released {} users", ret.into_inner().released),
+            Err(e) => warn!("Failed to release users: {:?}", e),
+        }
+    }
+
+    /// release the users of the messages without a response, they are never sent
+    pub async fn release_pending(&self, user_stats: &mut UserStatsClient<Channel>) {
+        let emails = self
+            .pending
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, (_, email))| email)
+            .collect();
+        self.release(emails, user_stats).await;
+    }
+
+    /// consume the send responses in the background, and mark the users as notified in
+    /// batches. the users of the failed messages are released, and so are the users without a
+    /// response once the stream is over
+    pub fn mark_notified<S>(self, mut responses: S, mut user_stats: UserStatsClient<Channel>)
+    where
+        S: Stream<Item = Result<SendResponse, Status>> + Send + Unpin + 'static,
+    {
+        tokio::spawn(async move {
+            let mut notifications = Vec::with_capacity(BATCH_SIZE);
+            let mut failed = Vec::new();
+            // the messages without a response might be sent if the stream fails
+            let mut complete = true;
+            loop {
+                let res = responses.next().await;
+                let done = res.is_none();
+                match res {
+                    Some(Ok(res)) => match self.resolve(res) {
+                        Some(Ok(notified)) => notifications.push(notified),
+                        Some(Err(email)) => failed.push(email),
+                        None => {}
+                    },
+                    Some(Err(e)) => {
+                        warn!("Send stream failed: {:?}", e);
+                        complete = false;
+                    }
+                    None => {}
+                }
+
+                if failed.len() >= BATCH_SIZE || done {
+                    self.release(mem::take(&mut failed), &mut user_stats).await;
+                }
+
+                if notifications.len() >= BATCH_SIZE || (done && !notifications.is_empty()) {
+                    let req = MarkNotifiedRequest {
+                        notifications: mem::take(&mut notifications),
+                    };
+                    match user_stats.mark_notified(req).await {
+                        Ok(ret) => info!("Marked {} users notified", ret.into_inner().updated),
+                        Err(e) => warn!("Failed to mark users notified: {:?}", e),
+                    }
+                }
+                if done {
+                    break;
+                }
+            }
+            if complete {
+                self.release_pending(&mut user_stats).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crm_send::pb::{EmailMessage, SmsMessage};
+
+    use super::*;
+
+    #[test]
+    fn deliveries_should_resolve_tracked_messages() {
+        let deliveries = Deliveries::default();
+        let email: SendRequest = EmailMessage {
+            message_id: "m1".to_string(),
+            ..Default::default()
+        }
+        .into();
+        let sms: SendRequest = SmsMessage {
+            message_id: "m2".to_string(),
+            ..Default::default()
+        }
+        .into();
+        deliveries.track(&email, "tyr@acme.org");
+        deliveries.track(&sms, "tyr@acme.org");
+
+        let res = |id: &str| SendResponse {
+            message_id: id.to_string(),
+            ..Default::default()
+        };
+        let notified = deliveries.resolve(res("m2")).unwrap().unwrap();
+        assert_eq!(notified.email, "tyr@acme.org");
+        assert_eq!(notified.channel, NotificationChannel::Sms as i32);
+        assert!(deliveries.resolve(res("m2")).is_none());
+        assert!(deliveries.resolve(res("unknown")).is_none());
+        assert_eq!(
+            deliveries.resolve(res("m1")).unwrap().unwrap().channel,
+            NotificationChannel::Email as i32
+        );
+    }
+
+    #[test]
+    fn deliveries_should_return_users_of_failed_messages() {
+        let deliveries = Deliveries::default();
+        let sms: SendRequest = SmsMessage {
+            message_id: "m1".to_string(),
+            ..Default::default()
+        }
+        .into();
+        deliveries.track(&sms, "tyr@acme.org");
+        let failed = SendResponse {
+            message_id: "m1".to_string(),
+            code: tonic::Code::Unavailable as i32,
+            error: "gateway is down".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            deliveries.resolve(failed),
+            Some(Err("tyr@acme.org".to_string()))
+        );
+        assert!(deliveries.pending.lock().unwrap().is_empty());
+    }
+}
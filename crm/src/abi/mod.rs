@@ -1,20 +1,24 @@
 mod auth;
+mod cap;
+mod delivery;
 
 pub use auth::DecodingKey;
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use chrono::{DateTime, Duration, Utc};
 use crm_metadata::pb::{Content, MaterializeRequest};
 use crm_send::pb::SendRequest;
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Response, Status};
 use tracing::warn;
-use user_stat::pb::QueryRequest;
+use user_stat::pb::{CompareOp, Filter, QueryRequest, User};
 
+use self::delivery::Deliveries;
 use crate::{
+    config::CapChannel,
     pb::{
         RecallRequest, RecallResponse, RemindRequest, RemindResponse, WelcomeRequest,
         WelcomeResponse,
@@ -29,7 +33,8 @@ impl CrmService {
         let dt1 = Utc::now() - Duration::days(req.interval as _);
         let dt2 = dt1 + Duration::days(1);
         let query = QueryRequest::new_with_date("created_at", dt1, dt2);
-        let mut users = self.user_stats.clone().query(query).await?.into_inner();
+        // the contents are materialized before the users are reserved, so a failure reserves
+        // nobody
         let contents = self
             .metadata
             .clone()
@@ -41,10 +46,15 @@ impl CrmService {
             .collect()
             .await;
         let contents = Arc::new(contents);
+        let (mut users, suppressed, deliveries) =
+            self.query_capped(query, CapChannel::Email).await?;
         let sender = self.config.server.sender_email.clone();
+        let tracker = deliveries.clone();
+        let mut user_stats = self.user_stats.clone();
         // 下面两种写法都可以(用来作为学习)
         let (tx, rx) = mpsc::channel(1024);
         tokio::spawn(async move {
+            let mut unsent = vec![];
             while let Some(Ok(user)) = users.next().await {
                 let contents = Arc::clone(&contents);
                 let tx = tx.clone();
@@ -52,13 +62,16 @@ impl CrmService {
                 let req = SendRequest::new_email_msg(
                     "Welcome".to_string(),
                     sender,
-                    &[user.email],
+                    std::slice::from_ref(&user.email),
                     &contents,
                 );
+                tracker.track(&req, &user.email);
                 if let Err(e) = tx.send(req).await {
-                    warn!("Failed to send message: {:?}", e)
+                    warn!("Failed to send message: {:?}", e);
+                    unsent.push(user.email);
                 }
             }
+            tracker.release(unsent, &mut user_stats).await;
         });
         let reqs = ReceiverStream::new(rx);
 
@@ -72,8 +85,11 @@ impl CrmService {
         //     }
         // });
 
-        self.notification.clone().send(reqs).await?;
-        let ret = WelcomeResponse { id: request_id };
+        self.send(reqs, deliveries).await?;
+        let ret = WelcomeResponse {
+            id: request_id,
+            suppressed,
+        };
         Ok(Response::new(ret))
     }
 
@@ -93,7 +109,6 @@ impl CrmService {
                 last_watched_interval.1,
             ),
         ]);
-        let users = self.user_stats.clone().query(query).await?.into_inner();
         let contents = self
             .metadata
             .clone()
@@ -105,24 +120,31 @@ impl CrmService {
             .collect()
             .await;
         let contents = Arc::new(contents);
+        let (users, suppressed, deliveries) = self.query_capped(query, CapChannel::Email).await?;
         let sender = self.config.server.sender_email.clone();
+        let tracker = deliveries.clone();
         let reqs = users.filter_map(move |user| {
             let sender = sender.clone();
             let contents = Arc::clone(&contents);
+            let tracker = tracker.clone();
             async move {
                 let user = user.ok()?;
                 let req = SendRequest::new_email_msg(
                     "Recall".to_string(),
                     sender,
-                    &[user.email],
+                    std::slice::from_ref(&user.email),
                     &contents,
                 );
+                tracker.track(&req, &user.email);
                 Some(req)
             }
         });
 
-        self.notification.clone().send(reqs).await?;
-        let ret = RecallResponse { id: request_id };
+        self.send(reqs, deliveries).await?;
+        let ret = RecallResponse {
+            id: request_id,
+            suppressed,
+        };
         Ok(Response::new(ret))
     }
 
@@ -131,20 +153,27 @@ impl CrmService {
     pub async fn remind(&self, req: RemindRequest) -> Result<Response<RemindResponse>, Status> {
         let request_id = req.id;
         let last_visit_interval = make_lower_upper(req.last_visit_interval, 1);
-        let query = QueryRequest::new_with_timestamps(&[(
+        let mut query = QueryRequest::new_with_timestamps(&[(
             "last_visited_at",
             last_visit_interval.0,
             last_visit_interval.1,
         )]);
-        let mut users = self.user_stats.clone().query(query).await?.into_inner();
+        // only the users with unfinished contents are reserved
+        query.filter = Some(Filter::array_length(
+            "started_but_not_finished",
+            CompareOp::Gt,
+            0,
+        ));
+        let (mut users, suppressed, deliveries) =
+            self.query_capped(query, CapChannel::Email).await?;
         let sender = self.config.server.sender_email.clone();
+        let tracker = deliveries.clone();
+        let mut user_stats = self.user_stats.clone();
         let (tx, rx) = mpsc::channel(1024);
         let metadata_service = self.metadata.clone();
         tokio::spawn(async move {
+            let mut unsent = vec![];
             while let Some(Ok(user)) = users.next().await {
-                if user.started_but_not_finished.is_empty() {
-                    continue;
-                }
                 // TODO: 此处设计/写法不好，读放大，每一个用户都得调用一次微服务查一下他未看完内容的信息
                 let content_ids = user
                     .started_but_not_finished
@@ -159,6 +188,7 @@ impl CrmService {
                     Ok(contents) => contents.into_inner(),
                     Err(e) => {
                         warn!("Failed to query content materialize: {:?}", e);
+                        unsent.push(user.email);
                         continue;
                     }
                 };
@@ -169,27 +199,97 @@ impl CrmService {
                     .await;
                 let tx = tx.clone();
                 let sender = sender.clone();
-                let req = SendRequest::new_email_msg(
+                let req = SendRequest::new_email_body(
                     "Remind".to_string(),
                     sender,
-                    &[user.email],
-                    &contents,
+                    std::slice::from_ref(&user.email),
+                    remind_body(&user, &contents),
                 );
+                tracker.track(&req, &user.email);
                 if let Err(e) = tx.send(req).await {
-                    warn!("Failed to send message: {:?}", e)
+                    warn!("Failed to send message: {:?}", e);
+                    unsent.push(user.email);
                 }
             }
+            tracker.release(unsent, &mut user_stats).await;
         });
         let reqs = ReceiverStream::new(rx);
 
-        self.notification.clone().send(reqs).await?;
-        let ret = RemindResponse { id: request_id };
+        self.send(reqs, deliveries).await?;
+        let ret = RemindResponse {
+            id: request_id,
+            suppressed,
+        };
         Ok(Response::new(ret))
     }
 }
 
+impl CrmService {
+    /// send the messages, the responses are recorded to user_stats in the background. the
+    /// users reserved for the messages are released if they could not be sent
+    async fn send<S>(&self, reqs: S, deliveries: Deliveries) -> Result<(), Status>
+    where
+        S: Stream<Item = SendRequest> + Send + 'static,
+    {
+        let mut user_stats = self.user_stats.clone();
+        match self.notification.clone().send(reqs).await {
+            Ok(responses) => {
+                deliveries.mark_notified(responses.into_inner(), user_stats);
+                Ok(())
+            }
+            Err(e) => {
+                deliveries.release_pending(&mut user_stats).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// one line for each unfinished content, with the watch progress if it is known
+fn remind_body(user: &User, contents: &[Content]) -> String {
+    let progress = user
+        .started_but_not_finished
+        .iter()
+        .zip(&user.started_progress)
+        .map(|(&id, &p)| (id as u32, p))
+        .collect::<HashMap<_, _>>();
+    contents
+        .iter()
+        .map(|c| match progress.get(&c.id) {
+            Some(&p) if p > 0 => format!("You're {}% through {}", p, c.name),
+            _ => format!("You haven't finished {}", c.name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 fn make_lower_upper(interval: u32, days: i64) -> (DateTime<Utc>, DateTime<Utc>) {
     let dt1 = Utc::now() - Duration::days(interval as _);
     let dt2 = dt1 + Duration::days(days);
     (dt1, dt2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remind_body_should_show_progress() {
+        let user = User {
+            email: "tyr@acme.org".to_string(),
+            name: "Tyr".to_string(),
+            started_but_not_finished: vec![1, 2],
+            started_progress: vec![70, 0],
+        };
+        let content = |id: u32, name: &str| Content {
+            id,
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let contents = [content(2, "Dune"), content(1, "Arrival")];
+        assert_eq!(
+            remind_body(&user, &contents),
+            "You haven't finished Dune\nYou're 70% through Arrival"
+        );
+    }
+}
@@ -21,6 +21,24 @@ pub struct ServerConfig {
     pub metadata: String,
     pub notification: String,
     pub tls: Option<TlsConfig>,
+    /// max number of notifications a user could receive in a time window, per channel
+    #[serde(default)]
+    pub frequency_caps: Vec<FrequencyCapConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapChannel {
+    Email,
+    InApp,
+    Sms,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrequencyCapConfig {
+    pub channel: CapChannel,
+    pub max_count: u32,
+    pub window_hours: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +17,9 @@ pub struct WelcomeRequest {
 pub struct WelcomeResponse {
     #[prost(string, tag = "1")]
     pub id: ::prost::alloc::string::String,
+    /// number of users skipped due to the frequency caps
+    #[prost(uint64, tag = "2")]
+    pub suppressed: u64,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -37,6 +40,9 @@ pub struct RecallRequest {
 pub struct RecallResponse {
     #[prost(string, tag = "1")]
     pub id: ::prost::alloc::string::String,
+    /// number of users skipped due to the frequency caps
+    #[prost(uint64, tag = "2")]
+    pub suppressed: u64,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -53,6 +59,9 @@ pub struct RemindRequest {
 pub struct RemindResponse {
     #[prost(string, tag = "1")]
     pub id: ::prost::alloc::string::String,
+    /// number of users skipped due to the frequency caps
+    #[prost(uint64, tag = "2")]
+    pub suppressed: u64,
 }
 /// Generated client implementations.
 pub mod crm_client {
@@ -19,7 +19,6 @@ fn main() -> Result<()> {
             true,
             Some(&[r#"#[serde(rename_all = "camelCase")]"#]),
         )
-        .with_sqlx_from_row(&["User"], None)
         .with_derive_builder(
             &[
                 "User",
@@ -38,6 +37,10 @@ fn main() -> Result<()> {
             &["TimeQuery.before", "TimeQuery.after"],
             &[r#"#[builder(setter(into, strip_option))]"#],
         )
+        .with_field_attributes(
+            &["QueryRequest.filter", "QueryRequest.field_mask"],
+            &[r#"#[builder(setter(into, strip_option))]"#],
+        )
         .with_field_attributes(
             &["QueryRequest.timestamps"],
             &[r#"#[builder(setter(each(name="timestamp", into)))]"#],
@@ -46,6 +49,10 @@ fn main() -> Result<()> {
             &["QueryRequest.ids"],
             &[r#"#[builder(setter(each(name="id", into)))]"#],
         )
+        .with_field_attributes(
+            &["QueryRequest.caps"],
+            &[r#"#[builder(setter(each(name="cap", into)))]"#],
+        )
         .compile(
             &[
                 "../protos/user-stats/messages.proto",
@@ -99,3 +99,16 @@ INSERT INTO user_stats (email, name, gender, created_at, last_visited_at, last_w
 INSERT INTO user_stats (email, name, gender, created_at, last_visited_at, last_watched_at, recent_watched, viewed_but_not_started, started_but_not_finished, finished, last_email_notification, last_in_app_notification, last_sms_notification) VALUES ('moriah.pimur8d9@example.org', '闫明志', 'male', '2024-03-21 18:22:16.525422+08', '2024-06-15 22:48:16.525423+08', '2024-04-23 02:14:16.525423+08', '{142713,190182,129497,163971,138876,175866,124926}', '{298539}', '{310429,327162,395937,374736}', '{449011,482071,464462,404431,490662,440215,417267,456418,455437,439444,421447,474837,481229,438933,402655,476439,463763,428503,486899,416269,429192}', '2024-06-27 09:18:16.525425+08', '2024-06-23 07:07:16.525425+08', '2024-04-20 18:26:16.525425+08');
 INSERT INTO user_stats (email, name, gender, created_at, last_visited_at, last_watched_at, recent_watched, viewed_but_not_started, started_but_not_finished, finished, last_email_notification, last_in_app_notification, last_sms_notification) VALUES ('dolly.mnzkoivn@example.net', '孟海桃', 'female', '2024-03-24 19:56:16.523977+08', '2024-06-18 10:53:16.523978+08', '2024-04-01 22:53:16.523978+08', '{174362,139620,161813,128352,171022,157621,168588,123240,156729,165841,164923,190622,170907,120129,108624,166006,156039,128702}', '{258837,296886,239995,205585,277362,204810,286344,233830,269069,254877,297670,234048,208245,221373,281530,296730,200523,247101,218196,233601,246587,298421,271771,279992}', '{359195,355648,388180,331269,344496,372402,300670,356249,351868,354086,395532,356510,307773}', '{427595,493522,497221,415083,438280,414275,435108,454618,428804,442246,439171,446746}', '2024-06-11 04:00:16.523981+08', '2024-06-24 02:48:16.523982+08', '2024-06-11 17:54:16.523983+08');
 INSERT INTO user_stats (email, name, gender, created_at, last_visited_at, last_watched_at, recent_watched, viewed_but_not_started, started_but_not_finished, finished, last_email_notification, last_in_app_notification, last_sms_notification) VALUES ('ignacio.g5cooc2m@example.org', '谢丰瞻', 'unknown', '2024-01-08 08:47:16.519689+08', '2024-06-12 11:07:16.519689+08', '2024-03-31 22:43:16.519689+08', '{168140,191716,182755,148079,185937,160055,111516,165821,114431,135531,124378,177000,166115,184701,135121,178219,114686,113321,131278,123912,148574,181353,131608,113178,195177,155406,188687,190409,169889,153478,140167,157079,189284,158291,154368,162433,129354}', '{233508,208031,211573,201614,288718,242008,213974,267534,295432,235131,272871,254037,205597,298535,223199,218440,263195,208636,299225,272012,222354,235147,204639,256050,269499,283132,214463,225851,295948,293110,211497}', '{343573,321999,334419,341202,377728,342892,350479,335451,310947,334319,359199,342394}', '{492914,418940,459520,441427,476738,487549,490828,412164,429755,451844,495081,459512,478427,472429,428642,477928,471209,457300,466545,401892,479036,432653,489931,450539,444335,485158,447018,403046,484542,458128,453756}', '2024-06-05 23:44:16.519691+08', '2024-06-17 12:03:16.519691+08', '2024-05-29 03:38:16.519692+08');
+
+-- the progress of the contents of the users above, the arrays are derived from it. The order of
+-- the arrays is kept by the update time
+INSERT INTO user_content_progress (email, content_id, state, progress, updated_at)
+SELECT DISTINCT ON (u.email, c.content_id)
+    u.email, c.content_id, c.state::content_state, c.progress,
+    COALESCE(u.last_watched_at, u.last_visited_at, u.created_at) + c.pos * INTERVAL '1 microsecond'
+FROM user_stats u, LATERAL (
+    SELECT *, 'finished', 100, 0 FROM unnest(u.finished) WITH ORDINALITY
+    UNION ALL SELECT *, 'started', 0, 1 FROM unnest(u.started_but_not_finished) WITH ORDINALITY
+    UNION ALL SELECT *, 'viewed', 0, 2 FROM unnest(u.viewed_but_not_started) WITH ORDINALITY
+) AS c(content_id, pos, state, progress, rank)
+ORDER BY u.email, c.content_id, c.rank
@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TYPE event_type AS ENUM(
+    'visit',
+    'view',
+    'watch_start',
+    'watch_finish'
+);
+
+-- every event applied to user_stats, event_id makes the ingestion idempotent
+CREATE TABLE IF NOT EXISTS user_events (
+    event_id VARCHAR(64) NOT NULL PRIMARY KEY,
+    email VARCHAR(128) NOT NULL,
+    event_type event_type NOT NULL,
+    content_id INT,
+    occurred_at TIMESTAMPTZ NOT NULL,
+    recorded_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX user_events_email_idx ON user_events(email);
@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TYPE notification_channel AS ENUM(
+    'email',
+    'in_app',
+    'sms'
+);
+
+-- every notification sent to the users, used to enforce the frequency caps
+CREATE TABLE IF NOT EXISTS notification_history (
+    email VARCHAR(128) NOT NULL,
+    channel notification_channel NOT NULL,
+    notified_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (email, channel, notified_at)
+);
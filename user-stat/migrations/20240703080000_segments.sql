@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- a named segment of users, the query of each version is kept in segment_versions
+CREATE TABLE IF NOT EXISTS segments (
+    name VARCHAR(64) NOT NULL PRIMARY KEY,
+    description TEXT NOT NULL DEFAULT '',
+    version INT NOT NULL DEFAULT 1,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- query is the protobuf encoded QueryRequest
+CREATE TABLE IF NOT EXISTS segment_versions (
+    name VARCHAR(64) NOT NULL REFERENCES segments(name) ON DELETE CASCADE,
+    version INT NOT NULL,
+    query BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (name, version)
+);
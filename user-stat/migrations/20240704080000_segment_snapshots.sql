@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- every snapshot of a segment is a run, the members are kept in segment_snapshot_members
+CREATE TABLE IF NOT EXISTS segment_snapshots (
+    run_id BIGSERIAL PRIMARY KEY,
+    segment VARCHAR(64) NOT NULL REFERENCES segments(name) ON DELETE CASCADE,
+    version INT NOT NULL,
+    member_count BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX segment_snapshots_segment_idx ON segment_snapshots(segment, run_id);
+
+CREATE TABLE IF NOT EXISTS segment_snapshot_members (
+    run_id BIGINT NOT NULL REFERENCES segment_snapshots(run_id) ON DELETE CASCADE,
+    email VARCHAR(128) NOT NULL,
+    PRIMARY KEY (run_id, email)
+);
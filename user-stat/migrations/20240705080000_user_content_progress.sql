@@ -0,0 +1,45 @@
+CREATE TYPE content_state AS ENUM(
+    'viewed',
+    'started',
+    'finished'
+);
+
+-- the state and watch progress of each content of a user
+CREATE TABLE IF NOT EXISTS user_content_progress (
+    email VARCHAR(128) NOT NULL,
+    content_id INT NOT NULL,
+    state content_state NOT NULL,
+    -- watch progress in percent
+    progress SMALLINT NOT NULL DEFAULT 0 CHECK (progress BETWEEN 0 AND 100),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (email, content_id)
+);
+
+CREATE INDEX user_content_progress_content_idx ON user_content_progress(content_id, state);
+
+-- backfill from the arrays, the progress of a started content is unknown. A content in more
+-- than one array keeps the most advanced state
+INSERT INTO user_content_progress (email, content_id, state, progress, updated_at)
+SELECT DISTINCT ON (u.email, c.content_id)
+    u.email, c.content_id, c.state::content_state, c.progress,
+    COALESCE(u.last_watched_at, u.last_visited_at, u.created_at, CURRENT_TIMESTAMP)
+FROM user_stats u, LATERAL (
+    SELECT unnest(u.finished), 'finished', 100, 0
+    UNION ALL SELECT unnest(u.started_but_not_finished), 'started', 0, 1
+    UNION ALL SELECT unnest(u.viewed_but_not_started), 'viewed', 0, 2
+) AS c(content_id, state, progress, rank)
+ORDER BY u.email, c.content_id, c.rank
+ON CONFLICT (email, content_id) DO NOTHING;
+
+-- the content arrays of user_stats derived from the progress, for the readers of the arrays
+CREATE VIEW user_content_arrays AS
+SELECT
+    email,
+    COALESCE(array_agg(content_id ORDER BY updated_at, content_id)
+        FILTER (WHERE state = 'viewed'), '{}') AS viewed_but_not_started,
+    COALESCE(array_agg(content_id ORDER BY updated_at, content_id)
+        FILTER (WHERE state = 'started'), '{}') AS started_but_not_finished,
+    COALESCE(array_agg(content_id ORDER BY updated_at, content_id)
+        FILTER (WHERE state = 'finished'), '{}') AS finished
+FROM user_content_progress
+GROUP BY email;
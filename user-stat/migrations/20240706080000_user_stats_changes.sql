@@ -0,0 +1,15 @@
+-- notify the email of the inserted or updated user, WatchUsers listens on the channel
+CREATE OR REPLACE FUNCTION notify_user_stats_changes() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('user_stats_changes', NEW.email);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_stats_inserted AFTER INSERT ON user_stats
+FOR EACH ROW EXECUTE FUNCTION notify_user_stats_changes();
+
+-- updates changing nothing, e.g. an older notification time, are not notified
+CREATE TRIGGER user_stats_updated AFTER UPDATE ON user_stats
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+EXECUTE FUNCTION notify_user_stats_changes();
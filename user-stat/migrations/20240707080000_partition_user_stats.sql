@@ -0,0 +1,122 @@
+-- range partition user_stats by the month (UTC) of created_at, so that a query of a created_at
+-- range only scans the partitions of the range. The primary key of a partitioned table has to
+-- contain the partition key, so the unique emails are kept in user_stats_emails
+ALTER TABLE user_stats RENAME TO user_stats_unpartitioned;
+-- the users without a sign up date are kept at the epoch in the default partition, they must
+-- not look like new users
+UPDATE user_stats_unpartitioned SET created_at = 'epoch' WHERE created_at IS NULL;
+
+CREATE TABLE user_stats (LIKE user_stats_unpartitioned INCLUDING DEFAULTS)
+PARTITION BY RANGE (created_at);
+ALTER TABLE user_stats ALTER COLUMN created_at SET NOT NULL;
+ALTER TABLE user_stats ADD PRIMARY KEY (email, created_at);
+
+-- rows out of the range of the monthly partitions
+CREATE TABLE user_stats_default PARTITION OF user_stats DEFAULT;
+
+CREATE TABLE user_stats_emails (
+    email VARCHAR(128) NOT NULL PRIMARY KEY
+);
+
+-- create the partition user_stats_YYYY_MM of the month, the rows of the month in the default
+-- partition are moved to it. Return the name of the partition, NULL if it exists
+CREATE FUNCTION create_user_stats_partition(month DATE) RETURNS TEXT AS $$
+DECLARE
+    lower TIMESTAMPTZ := date_trunc('month', month::timestamp) AT TIME ZONE 'UTC';
+    upper TIMESTAMPTZ := (date_trunc('month', month::timestamp) + interval '1 month') AT TIME ZONE 'UTC';
+    name TEXT := 'user_stats_' || to_char(month, 'YYYY_MM');
+BEGIN
+    IF to_regclass(name) IS NOT NULL THEN
+        RETURN NULL;
+    END IF;
+
+    IF NOT EXISTS (
+        SELECT 1 FROM user_stats_default WHERE created_at >= lower AND created_at < upper
+    ) THEN
+        EXECUTE format('CREATE TABLE %I PARTITION OF user_stats FOR VALUES FROM (%L) TO (%L)',
+            name, lower, upper);
+        RETURN name;
+    END IF;
+
+    -- a new partition can't overlap the rows in the default partition
+    EXECUTE format('CREATE TABLE %I (LIKE user_stats INCLUDING DEFAULTS)', name);
+    EXECUTE format('WITH moved AS (DELETE FROM user_stats_default WHERE created_at >= %L '
+        'AND created_at < %L RETURNING *) INSERT INTO %I SELECT * FROM moved', lower, upper, name);
+    -- the emails are released by the deletion
+    EXECUTE format('INSERT INTO user_stats_emails SELECT email FROM %I', name);
+    EXECUTE format('ALTER TABLE user_stats ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+        name, lower, upper);
+    RETURN name;
+END;
+$$ LANGUAGE plpgsql;
+
+SELECT create_user_stats_partition(month::date)
+FROM generate_series(
+    date_trunc('month', COALESCE(
+        (SELECT MIN(created_at) FROM user_stats_unpartitioned WHERE created_at > 'epoch'),
+        CURRENT_TIMESTAMP
+    ) AT TIME ZONE 'UTC'),
+    date_trunc('month', CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    interval '1 month'
+) AS month;
+
+INSERT INTO user_stats SELECT * FROM user_stats_unpartitioned;
+INSERT INTO user_stats_emails SELECT email FROM user_stats_unpartitioned;
+DROP TABLE user_stats_unpartitioned;
+
+CREATE INDEX user_stats_email_idx ON user_stats(email);
+CREATE INDEX user_stats_created_at_idx ON user_stats(created_at);
+CREATE INDEX user_stats_last_visited_at_idx ON user_stats(last_visited_at);
+CREATE INDEX user_stats_last_watched_at_idx ON user_stats(last_watched_at);
+
+CREATE INDEX user_stats_recent_watched_idx ON user_stats USING GIN(recent_watched);
+CREATE INDEX user_stats_viewed_but_not_started_idx ON user_stats USING GIN(viewed_but_not_started);
+CREATE INDEX user_stats_started_but_not_finished_idx ON user_stats USING GIN(started_but_not_finished);
+
+CREATE INDEX user_stats_last_email_notification_idx ON user_stats(last_email_notification);
+CREATE INDEX user_stats_last_in_app_notification_idx ON user_stats(last_in_app_notification);
+CREATE INDEX user_stats_last_sms_notification_idx ON user_stats(last_sms_notification);
+
+-- a row of an existing email is skipped like ON CONFLICT DO NOTHING, a concurrent insert of the
+-- same email waits for the other transaction
+CREATE FUNCTION claim_user_stats_email() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO user_stats_emails (email) VALUES (NEW.email) ON CONFLICT DO NOTHING;
+    IF FOUND THEN
+        RETURN NEW;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- also called when an update moves the row to another partition, before it is inserted again
+CREATE FUNCTION release_user_stats_email() RETURNS TRIGGER AS $$
+BEGIN
+    DELETE FROM user_stats_emails WHERE email = OLD.email;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION reject_user_stats_email_change() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'email of user_stats can not be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_stats_claim_email BEFORE INSERT ON user_stats
+FOR EACH ROW EXECUTE FUNCTION claim_user_stats_email();
+
+CREATE TRIGGER user_stats_release_email BEFORE DELETE ON user_stats
+FOR EACH ROW EXECUTE FUNCTION release_user_stats_email();
+
+CREATE TRIGGER user_stats_email_changed BEFORE UPDATE ON user_stats
+FOR EACH ROW WHEN (OLD.email IS DISTINCT FROM NEW.email)
+EXECUTE FUNCTION reject_user_stats_email_change();
+
+-- the triggers of WatchUsers are dropped with the old table
+CREATE TRIGGER user_stats_inserted AFTER INSERT ON user_stats
+FOR EACH ROW EXECUTE FUNCTION notify_user_stats_changes();
+
+CREATE TRIGGER user_stats_updated AFTER UPDATE ON user_stats
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+EXECUTE FUNCTION notify_user_stats_changes();
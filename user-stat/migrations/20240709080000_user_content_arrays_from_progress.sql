@@ -0,0 +1,36 @@
+-- the content arrays of user_stats are derived from user_content_progress, the only source of
+-- the states of the contents. They are refreshed from user_content_arrays of the changed emails
+CREATE OR REPLACE FUNCTION refresh_user_content_arrays() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE user_stats u SET
+        viewed_but_not_started = COALESCE(a.viewed_but_not_started, '{}'),
+        started_but_not_finished = COALESCE(a.started_but_not_finished, '{}'),
+        finished = COALESCE(a.finished, '{}')
+    FROM (SELECT DISTINCT email FROM changed) c
+    LEFT JOIN user_content_arrays a ON a.email = c.email
+    WHERE u.email = c.email;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- a trigger with transition tables only has one event
+CREATE TRIGGER user_content_progress_inserted AFTER INSERT ON user_content_progress
+REFERENCING NEW TABLE AS changed
+FOR EACH STATEMENT EXECUTE FUNCTION refresh_user_content_arrays();
+
+CREATE TRIGGER user_content_progress_updated AFTER UPDATE ON user_content_progress
+REFERENCING NEW TABLE AS changed
+FOR EACH STATEMENT EXECUTE FUNCTION refresh_user_content_arrays();
+
+CREATE TRIGGER user_content_progress_deleted AFTER DELETE ON user_content_progress
+REFERENCING OLD TABLE AS changed
+FOR EACH STATEMENT EXECUTE FUNCTION refresh_user_content_arrays();
+
+-- the arrays maintained before might be ordered differently
+UPDATE user_stats u SET
+    viewed_but_not_started = COALESCE(
+        (SELECT a.viewed_but_not_started FROM user_content_arrays a WHERE a.email = u.email), '{}'),
+    started_but_not_finished = COALESCE(
+        (SELECT a.started_but_not_finished FROM user_content_arrays a WHERE a.email = u.email), '{}'),
+    finished = COALESCE(
+        (SELECT a.finished FROM user_content_arrays a WHERE a.email = u.email), '{}');
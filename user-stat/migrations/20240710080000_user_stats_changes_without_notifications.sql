@@ -0,0 +1,15 @@
+-- the notification times are only the bookkeeping of crm, updating them is not a change of the
+-- user for WatchUsers, otherwise every notification sent to a watched segment is watched again
+DROP TRIGGER user_stats_updated ON user_stats;
+
+CREATE TRIGGER user_stats_updated AFTER UPDATE ON user_stats
+FOR EACH ROW WHEN (
+    (OLD.name, OLD.gender, OLD.created_at, OLD.last_visited_at, OLD.last_watched_at,
+        OLD.recent_watched, OLD.viewed_but_not_started, OLD.started_but_not_finished,
+        OLD.finished)
+    IS DISTINCT FROM
+    (NEW.name, NEW.gender, NEW.created_at, NEW.last_visited_at, NEW.last_watched_at,
+        NEW.recent_watched, NEW.viewed_but_not_started, NEW.started_but_not_finished,
+        NEW.finished)
+)
+EXECUTE FUNCTION notify_user_stats_changes();
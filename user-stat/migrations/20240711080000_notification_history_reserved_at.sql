@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- the users of a reservation are the notifications recorded at its reserved_at
+CREATE INDEX notification_history_channel_notified_at_idx ON notification_history(channel, notified_at);
@@ -0,0 +1,593 @@
+use std::time::Duration;
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    cursor,
+    guard::{set_statement_timeout, timeout_status},
+    query::{ts_to_utc, user_columns, utc_to_ts, USER_STATS_TABLE},
+};
+use crate::{
+    pb::{
+        FrequencyCap, NotificationChannel, QueryRequest, ReleaseRequest, ReleaseResponse,
+        ReserveRequest, ReserveResponse,
+    },
+    ReserveStream, ResponseStream,
+};
+
+/// the first key of the advisory lock serializing the reservations, the second is the channel
+const RESERVE_LOCK: i32 = 0x6361_7073;
+
+/// number of reserved users in each response
+pub(crate) const RESERVE_BATCH_SIZE: usize = 1000;
+
+#[allow(clippy::result_large_err)]
+impl FrequencyCap {
+    pub fn new(channel: NotificationChannel, max_count: u32, window: Duration) -> Self {
+        Self {
+            channel: channel as i32,
+            max_count,
+            window: Some(prost_types::Duration {
+                seconds: window.as_secs() as i64,
+                nanos: window.subsec_nanos() as i32,
+            }),
+        }
+    }
+
+    /// push the condition that the user is notified less than max_count times through the
+    /// channel in the window
+    pub(crate) fn push(&self, builder: &mut QueryBuilder<'static, Postgres>) -> Result<(), Status> {
+        let (channel, window) = self.validate()?;
+        builder
+            .push(
+                "(SELECT COUNT(*) FROM notification_history h \
+                 WHERE h.email = user_stats.email AND h.channel = ",
+            )
+            .push_bind(channel.to_db().expect("channel is validated"))
+            .push("::notification_channel AND h.notified_at > now() - make_interval(secs => ")
+            .push_bind(window)
+            .push(")) < ")
+            .push_bind(self.max_count as i64);
+        Ok(())
+    }
+
+    /// the channel and the window in seconds of the cap
+    pub(crate) fn validate(&self) -> Result<(NotificationChannel, f64), Status> {
+        let channel = NotificationChannel::try_from(self.channel)
+            .ok()
+            .filter(|c| c.to_db().is_some())
+            .ok_or_else(|| {
+                Status::invalid_argument(format!("invalid cap channel {}", self.channel))
+            })?;
+        if self.max_count == 0 {
+            return Err(Status::invalid_argument("cap max_count must be positive"));
+        }
+        let window = match &self.window {
+            Some(w) if w.seconds > 0 || (w.seconds == 0 && w.nanos > 0) => {
+                w.seconds as f64 + w.nanos as f64 / 1e9
+            }
+            _ => return Err(Status::invalid_argument("cap window must be positive")),
+        };
+        Ok((channel, window))
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl ReserveRequest {
+    /// the query and the channel of the reservation, all the users without a query
+    pub(crate) fn validate(&self) -> Result<(QueryRequest, NotificationChannel), Status> {
+        let channel = NotificationChannel::try_from(self.channel)
+            .ok()
+            .filter(|c| c.to_db().is_some())
+            .ok_or_else(|| {
+                Status::invalid_argument(format!("invalid reserve channel {}", self.channel))
+            })?;
+        Ok((self.query.clone().unwrap_or_default(), channel))
+    }
+
+    /// check the caps and record the notification of the users allowed by them in one
+    /// statement. the reservations of a channel are serialized by an advisory lock, so each of
+    /// them sees the notifications recorded by the previous ones. the reserved users are
+    /// streamed with a cursor once the reservation is committed
+    pub(crate) async fn reserve(
+        &self,
+        pool: &PgPool,
+        fetch_size: u32,
+        timeout: Option<Duration>,
+    ) -> Result<ReserveStream, Status> {
+        let (query, channel) = self.validate()?;
+        // postgres keeps microseconds, so MarkNotified with reserved_at hits the same history
+        let reserved_at = Utc::now()
+            .duration_trunc(TimeDelta::microseconds(1))
+            .expect("microseconds never overflow");
+        let mut builder = QueryBuilder::new("WITH matched AS (SELECT email, ");
+        if query.caps.is_empty() {
+            builder.push("TRUE");
+        }
+        for (i, cap) in query.caps.iter().enumerate() {
+            if i > 0 {
+                builder.push(" AND ");
+            }
+            cap.push(&mut builder)?;
+        }
+        builder.push(format!(" AS allowed FROM {}", USER_STATS_TABLE));
+        query.push_matches(&mut builder)?;
+        let column = channel.column().expect("channel is validated");
+        builder
+            .push(format!(
+                "), reserved AS (UPDATE {0} SET {1} = GREATEST({1}, ",
+                USER_STATS_TABLE, column
+            ))
+            .push_bind(reserved_at)
+            .push(format!(
+                ") FROM matched WHERE {0}.email = matched.email AND matched.allowed \
+                 RETURNING {0}.email), \
+                 recorded AS (INSERT INTO notification_history (email, channel, notified_at) \
+                 SELECT email, ",
+                USER_STATS_TABLE
+            ))
+            .push_bind(channel.to_db())
+            .push("::notification_channel, ")
+            .push_bind(reserved_at)
+            .push(
+                " FROM reserved ON CONFLICT DO NOTHING) \
+                 SELECT (SELECT COUNT(*) FROM matched WHERE NOT allowed) AS suppressed, \
+                 (SELECT COUNT(*) FROM reserved) AS reserved",
+            );
+        info!("Reserve users: {}", builder.sql());
+
+        let to_status = |e: sqlx::Error| {
+            timeout_status(&e)
+                .unwrap_or_else(|| Status::internal(format!("Failed to reserve users: {}", e)))
+        };
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        set_statement_timeout(&mut ts, timeout)
+            .await
+            .map_err(to_status)?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+            .bind(RESERVE_LOCK)
+            .bind(channel as i32)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        let row = builder
+            .build()
+            .fetch_one(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+
+        let ret = ReserveResponse {
+            users: vec![],
+            suppressed: row.try_get::<i64, _>("suppressed").map_err(to_status)? as u64,
+            reserved_at: Some(utc_to_ts(reserved_at)),
+        };
+        if row.try_get::<i64, _>("reserved").map_err(to_status)? == 0 {
+            return Ok(Box::pin(stream::iter([Ok(ret)])));
+        }
+        let users = cursor::stream_rows(pool, fetch_size, timeout, |builder| {
+            builder
+                .push(format!(
+                    "SELECT {} FROM {} WHERE email IN (SELECT email FROM notification_history \
+                     WHERE channel = ",
+                    user_columns(),
+                    USER_STATS_TABLE
+                ))
+                .push_bind(channel.to_db())
+                .push("::notification_channel AND notified_at = ")
+                .push_bind(reserved_at)
+                .push(") ORDER BY email");
+            Ok(())
+        })
+        .await?;
+        Ok(ret.batches(users, RESERVE_BATCH_SIZE))
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl ReleaseRequest {
+    /// the channel and the reserved_at of the reservation
+    pub(crate) fn validate(&self) -> Result<(NotificationChannel, DateTime<Utc>), Status> {
+        let channel = NotificationChannel::try_from(self.channel)
+            .ok()
+            .filter(|c| c.to_db().is_some())
+            .ok_or_else(|| {
+                Status::invalid_argument(format!("invalid release channel {}", self.channel))
+            })?;
+        let reserved_at = self
+            .reserved_at
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("reserved_at is required"))?;
+        Ok((channel, ts_to_utc(reserved_at)?))
+    }
+
+    /// delete the notifications recorded at reserved_at, and restore the last notification time
+    /// of the users to the latest notification left in the history, unless they are notified
+    /// again since
+    pub(crate) async fn release(&self, pool: &PgPool) -> Result<ReleaseResponse, Status> {
+        let (channel, reserved_at) = self.validate()?;
+        let to_status =
+            |e: sqlx::Error| Status::internal(format!("Failed to release users: {}", e));
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        let emails: Vec<String> = sqlx::query_scalar(
+            "DELETE FROM notification_history \
+             WHERE channel = $1::notification_channel AND notified_at = $2 AND email = ANY($3) \
+             RETURNING email",
+        )
+        .bind(channel.to_db())
+        .bind(reserved_at)
+        .bind(&self.emails)
+        .fetch_all(&mut *ts)
+        .await
+        .map_err(to_status)?;
+
+        // a separate statement, so the deleted notifications are not seen
+        let column = channel.column().expect("channel is validated");
+        let sql = format!(
+            "UPDATE {0} u SET {1} = (SELECT MAX(h.notified_at) FROM notification_history h \
+             WHERE h.email = u.email AND h.channel = $1::notification_channel) \
+             WHERE u.email = ANY($3) AND u.{1} = $2",
+            USER_STATS_TABLE, column
+        );
+        sqlx::query(&sql)
+            .bind(channel.to_db())
+            .bind(reserved_at)
+            .bind(&emails)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+        info!(
+            "Released {} users reserved at {}",
+            emails.len(),
+            reserved_at
+        );
+        Ok(ReleaseResponse {
+            released: emails.len() as u64,
+        })
+    }
+}
+
+impl ReserveResponse {
+    /// split the users into batches of the size, the other fields are kept in every batch
+    pub(crate) fn into_batches(self, size: usize) -> Vec<Self> {
+        if self.users.is_empty() {
+            return vec![self];
+        }
+        self.users
+            .chunks(size)
+            .map(|users| Self {
+                users: users.to_vec(),
+                suppressed: self.suppressed,
+                reserved_at: self.reserved_at.clone(),
+            })
+            .collect()
+    }
+
+    /// stream the users in batches of the size, the other fields are kept in every batch
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn batches(self, users: ResponseStream, size: usize) -> ReserveStream {
+        Box::pin(users.chunks(size).map(move |users| {
+            let users = users.into_iter().collect::<Result<Vec<_>, _>>()?;
+            Ok(Self {
+                users,
+                ..self.clone()
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use tonic::Code;
+
+    use super::*;
+    use crate::abi::query::STARTED_PROGRESS;
+    use crate::{
+        pb::{MarkNotifiedRequest, Notified, QueryRequestBuilder, User},
+        test_utils::{collect_reserved, get_test_pool},
+    };
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    async fn last_email_notification(pool: &PgPool, email: &str) -> Result<Option<DateTime<Utc>>> {
+        let ret =
+            sqlx::query_scalar("SELECT last_email_notification FROM user_stats WHERE email = $1")
+                .bind(email)
+                .fetch_one(pool)
+                .await?;
+        Ok(ret)
+    }
+
+    #[test]
+    fn cap_to_sql_should_work() -> Result<()> {
+        let query = QueryRequestBuilder::default()
+            .cap(FrequencyCap::new(NotificationChannel::Email, 1, DAY * 2))
+            .build()?;
+        let builder = query.to_query_builder()?;
+        assert_eq!(builder.sql(), format!("SELECT email, name, started_but_not_finished, {} FROM user_stats WHERE (SELECT COUNT(*) FROM notification_history h WHERE h.email = user_stats.email AND h.channel = $1::notification_channel AND h.notified_at > now() - make_interval(secs => $2)) < $3", STARTED_PROGRESS));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_cap_should_fail() {
+        let caps = [
+            (
+                FrequencyCap::new(NotificationChannel::Unspecified, 1, DAY),
+                "invalid cap channel 0",
+            ),
+            (
+                FrequencyCap::new(NotificationChannel::Sms, 0, DAY),
+                "cap max_count must be positive",
+            ),
+            (
+                FrequencyCap::new(NotificationChannel::Sms, 1, Duration::ZERO),
+                "cap window must be positive",
+            ),
+        ];
+        for (cap, msg) in caps {
+            let mut builder = QueryBuilder::new("");
+            let status = cap.push(&mut builder).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_should_suppress_notified_users() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let now = Utc::now();
+        let email = "frederik.2r2jvb8l@example.org";
+        let other = "clifford.smjmlbu8@example.net";
+        let req = MarkNotifiedRequest {
+            notifications: vec![
+                Notified::new(
+                    email,
+                    NotificationChannel::Email,
+                    now - ChronoDuration::hours(1),
+                ),
+                Notified::new(
+                    other,
+                    NotificationChannel::Email,
+                    now - ChronoDuration::days(3),
+                ),
+                Notified::new(
+                    other,
+                    NotificationChannel::Email,
+                    now - ChronoDuration::days(4),
+                ),
+                Notified::new(
+                    other,
+                    NotificationChannel::Sms,
+                    now - ChronoDuration::hours(1),
+                ),
+            ],
+        };
+        req.mark(&pool).await?;
+
+        let count = |caps: Vec<FrequencyCap>| {
+            let pool = pool.clone();
+            async move {
+                let query = QueryRequestBuilder::default().caps(caps).build()?;
+                anyhow::Ok(query.count(&pool, None).await?.count)
+            }
+        };
+        assert_eq!(count(vec![]).await?, 100);
+        // 1 email in 48 hours
+        let cap1 = FrequencyCap::new(NotificationChannel::Email, 1, DAY * 2);
+        assert_eq!(count(vec![cap1.clone()]).await?, 99);
+        // 2 emails in a week
+        let cap2 = FrequencyCap::new(NotificationChannel::Email, 2, DAY * 7);
+        assert_eq!(count(vec![cap2.clone()]).await?, 99);
+        assert_eq!(count(vec![cap1, cap2]).await?, 98);
+        // sms is capped separately
+        let cap3 = FrequencyCap::new(NotificationChannel::Sms, 1, DAY);
+        assert_eq!(count(vec![cap3]).await?, 99);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reserve_should_record_notifications_atomically() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let req = MarkNotifiedRequest {
+            notifications: vec![Notified::new(
+                email,
+                NotificationChannel::Email,
+                Utc::now() - ChronoDuration::hours(1),
+            )],
+        };
+        req.mark(&pool).await?;
+
+        let query = QueryRequestBuilder::default()
+            .cap(FrequencyCap::new(NotificationChannel::Email, 1, DAY * 2))
+            .build()?;
+        let req = ReserveRequest {
+            query: Some(query),
+            channel: NotificationChannel::Email as i32,
+        };
+        // the concurrent reservations never notify a user twice
+        let (ret1, ret2) = tokio::join!(
+            async { collect_reserved(req.reserve(&pool, 7, None).await?).await },
+            async { collect_reserved(req.reserve(&pool, 7, None).await?).await },
+        );
+        let (ret1, ret2) = (ret1?, ret2?);
+        assert!(ret1.users.len() == 99 || ret2.users.len() == 99);
+        assert!(ret1
+            .users
+            .iter()
+            .chain(&ret2.users)
+            .all(|u| !u.name.is_empty()));
+        assert_eq!(ret1.users.len() + ret2.users.len(), 99);
+        assert_eq!(ret1.suppressed + ret2.suppressed, 100 + 1);
+        assert!(ret1
+            .users
+            .iter()
+            .chain(&ret2.users)
+            .all(|u| u.email != email));
+
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM notification_history WHERE channel = 'email'")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(count, 100);
+
+        // marking the reserved users notified at reserved_at records nothing new
+        let reserved = if ret1.users.is_empty() { &ret2 } else { &ret1 };
+        let reserved_at = ts_to_utc(reserved.reserved_at.as_ref().unwrap())?;
+        let req = MarkNotifiedRequest {
+            notifications: reserved
+                .users
+                .iter()
+                .map(|u| Notified::new(&u.email, NotificationChannel::Email, reserved_at))
+                .collect(),
+        };
+        req.mark(&pool).await?;
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM notification_history WHERE channel = 'email'")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(count, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn release_should_restore_last_notification() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let other = "clifford.smjmlbu8@example.net";
+        let notified_at =
+            (Utc::now() - ChronoDuration::days(3)).duration_trunc(TimeDelta::microseconds(1))?;
+        let req = MarkNotifiedRequest {
+            notifications: vec![Notified::new(
+                email,
+                NotificationChannel::Email,
+                notified_at,
+            )],
+        };
+        req.mark(&pool).await?;
+
+        let query = QueryRequestBuilder::default()
+            .cap(FrequencyCap::new(NotificationChannel::Email, 1, DAY))
+            .build()?;
+        let req = ReserveRequest {
+            query: Some(query),
+            channel: NotificationChannel::Email as i32,
+        };
+        let reserved = collect_reserved(req.reserve(&pool, 100, None).await?).await?;
+        assert_eq!(reserved.users.len(), 100);
+        let reserved_at = ts_to_utc(reserved.reserved_at.as_ref().unwrap())?;
+        assert_eq!(
+            last_email_notification(&pool, email).await?,
+            Some(reserved_at)
+        );
+
+        let release = ReleaseRequest {
+            channel: NotificationChannel::Email as i32,
+            reserved_at: reserved.reserved_at.clone(),
+            emails: vec![email.to_string(), other.to_string(), "unknown".to_string()],
+        };
+        assert_eq!(release.release(&pool).await?.released, 2);
+        assert_eq!(release.release(&pool).await?.released, 0);
+        assert_eq!(
+            last_email_notification(&pool, email).await?,
+            Some(notified_at)
+        );
+        assert_eq!(last_email_notification(&pool, other).await?, None);
+
+        // the released users are not capped, the others are
+        let reserved = collect_reserved(req.reserve(&pool, 100, None).await?).await?;
+        let mut emails = reserved
+            .users
+            .iter()
+            .map(|u| u.email.as_str())
+            .collect::<Vec<_>>();
+        emails.sort();
+        assert_eq!(emails, [other, email]);
+        assert_eq!(reserved.suppressed, 98);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn release_should_keep_users_notified_again() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let req = ReserveRequest {
+            query: None,
+            channel: NotificationChannel::Email as i32,
+        };
+        let reserved = collect_reserved(req.reserve(&pool, 100, None).await?).await?;
+        let later = Utc::now() + ChronoDuration::hours(1);
+        let notified = MarkNotifiedRequest {
+            notifications: vec![Notified::new(email, NotificationChannel::Email, later)],
+        };
+        notified.mark(&pool).await?;
+
+        let release = ReleaseRequest {
+            channel: NotificationChannel::Email as i32,
+            reserved_at: reserved.reserved_at,
+            emails: vec![email.to_string()],
+        };
+        assert_eq!(release.release(&pool).await?.released, 1);
+        let last = last_email_notification(&pool, email).await?.unwrap();
+        assert_eq!(last.timestamp_micros(), later.timestamp_micros());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_release_should_fail() {
+        let release = ReleaseRequest {
+            channel: NotificationChannel::Unspecified as i32,
+            reserved_at: Some(utc_to_ts(Utc::now())),
+            emails: vec![],
+        };
+        assert_eq!(
+            release.validate().unwrap_err().message(),
+            "invalid release channel 0"
+        );
+        let release = ReleaseRequest {
+            channel: NotificationChannel::Sms as i32,
+            ..release
+        };
+        assert!(release.validate().is_ok());
+        let release = ReleaseRequest {
+            reserved_at: None,
+            ..release
+        };
+        assert_eq!(
+            release.validate().unwrap_err().message(),
+            "reserved_at is required"
+        );
+    }
+
+    #[test]
+    fn reserve_should_batch_users() {
+        let user = |email: &str| User {
+            email: email.to_string(),
+            ..Default::default()
+        };
+        let ret = ReserveResponse {
+            users: vec![user("a"), user("b"), user("c")],
+            suppressed: 2,
+            reserved_at: Some(utc_to_ts(Utc::now())),
+        };
+        let batches = ret.clone().into_batches(2);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1].users, [user("c")]);
+        assert!(batches
+            .iter()
+            .all(|b| b.suppressed == 2 && b.reserved_at == ret.reserved_at));
+
+        let empty = ReserveResponse {
+            users: vec![],
+            ..ret
+        };
+        assert_eq!(empty.clone().into_batches(2), [empty]);
+    }
+}
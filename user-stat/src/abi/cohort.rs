@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use itertools::Itertools;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    guard::{set_statement_timeout, timeout_status},
+    query::{push_time_range, utc_to_ts, Column, ColumnKind, USER_STATS_TABLE},
+};
+use crate::pb::{Cohort, CohortPeriod, CohortsRequest, CohortsResponse};
+
+const DEFAULT_PERIODS: u32 = 12;
+const MAX_PERIODS: u32 = 120;
+
+/// (start of the cohort, size of the cohort, period since the sign up, active users of the period)
+type CohortRow = (NaiveDateTime, i64, Option<i32>, Option<i64>);
+
+#[allow(clippy::result_large_err)]
+impl CohortsRequest {
+    /// the retention of the cohorts, the statement is canceled after the timeout
+    pub(crate) async fn cohorts(
+        &self,
+        pool: &PgPool,
+        timeout: Option<Duration>,
+    ) -> Result<CohortsResponse, Status> {
+        let mut builder = QueryBuilder::new("");
+        let (period, periods) = self.push_cohorts(&mut builder)?;
+        info!("Cohorts: {}", builder.sql());
+
+        let to_status = |e: sqlx::Error| {
+            timeout_status(&e)
+                .unwrap_or_else(|| Status::internal(format!("Failed to query cohorts: {}", e)))
+        };
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        set_statement_timeout(&mut ts, timeout)
+            .await
+            .map_err(to_status)?;
+        let rows: Vec<CohortRow> = builder
+            .build_query_as()
+            .fetch_all(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+        Ok(CohortsResponse {
+            cohorts: to_cohorts(rows, period, periods, Utc::now()),
+        })
+    }
+
+    /// the query of the cohorts with the period and the number of periods of the request
+    pub(crate) fn push_cohorts(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+    ) -> Result<(CohortPeriod, u32), Status> {
+        let period = self.cohort_period()?;
+        let periods = self.periods()?;
+        self.push_select(builder, period, periods)?;
+        Ok((period, periods))
+    }
+
+    fn cohort_period(&self) -> Result<CohortPeriod, Status> {
+        match CohortPeriod::try_from(self.period) {
+            Ok(CohortPeriod::Unspecified) => {
+                Err(Status::invalid_argument("cohort period is required"))
+            }
+            Ok(period) => Ok(period),
+            Err(_) => Err(Status::invalid_argument(format!(
+                "invalid cohort period {}",
+                self.period
+            ))),
+        }
+    }
+
+    fn periods(&self) -> Result<u32, Status> {
+        match self.periods {
+            0 => Ok(DEFAULT_PERIODS),
+            n if n <= MAX_PERIODS => Ok(n),
+            n => Err(Status::invalid_argument(format!(
+                "periods {} exceeds the limit {}",
+                n, MAX_PERIODS
+            ))),
+        }
+    }
+
+    /// the users of the cohorts, and the distinct periods each user is active in. A user is
+    /// active in the periods of last_visited_at, last_watched_at and the recorded events
+    fn push_select(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        period: CohortPeriod,
+        periods: u32,
+    ) -> Result<(), Status> {
+        let unit = period.unit();
+        builder.push(format!(
+            "WITH users AS (SELECT email, date_trunc('{}', created_at AT TIME ZONE 'UTC') AS cohort, \
+             last_visited_at, last_watched_at FROM {}",
+            unit, USER_STATS_TABLE
+        ));
+        let query = self.query.clone().unwrap_or_default();
+        let mut conditions = query.push_conditions(builder)?;
+        if let Some(tq) = &self.created_at {
+            let column = Column::find_with_kind("created_at", ColumnKind::Timestamp)?;
+            push_time_range(conditions.next(), column, tq)?;
+        }
+        conditions.finish().push(format!(
+            "), active AS (\
+                SELECT DISTINCT u.cohort, u.email, {} AS period FROM users u, LATERAL (\
+                    SELECT date_trunc('{}', a.at AT TIME ZONE 'UTC') FROM (\
+                        SELECT u.last_visited_at UNION ALL SELECT u.last_watched_at \
+                        UNION ALL SELECT e.occurred_at FROM user_events e WHERE e.email = u.email\
+                    ) a(at) WHERE a.at IS NOT NULL\
+                ) p(at)\
+             ) \
+             SELECT c.cohort, c.size, a.period, a.active \
+             FROM (SELECT cohort, COUNT(*) AS size FROM users GROUP BY cohort) c \
+             LEFT JOIN (\
+                SELECT cohort, period, COUNT(*) AS active FROM active WHERE period BETWEEN 0 AND ",
+            period.index_sql(),
+            unit
+        ));
+        builder.push_bind(periods as i32).push(
+            " GROUP BY cohort, period\
+             ) a ON a.cohort = c.cohort \
+             ORDER BY c.cohort, a.period",
+        );
+        Ok(())
+    }
+}
+
+impl CohortPeriod {
+    /// the field of date_trunc
+    fn unit(&self) -> &'static str {
+        match self {
+            CohortPeriod::Week => "week",
+            _ => "month",
+        }
+    }
+
+    /// the number of periods from the cohort of the user `u.cohort` to the active period `p.at`
+    fn index_sql(&self) -> &'static str {
+        match self {
+            CohortPeriod::Week => "(p.at::date - u.cohort::date) / 7",
+            _ => {
+                "((EXTRACT(YEAR FROM p.at) - EXTRACT(YEAR FROM u.cohort)) * 12 \
+                 + EXTRACT(MONTH FROM p.at) - EXTRACT(MONTH FROM u.cohort))::int"
+            }
+        }
+    }
+
+    /// the number of periods from the start of the cohort to now
+    fn elapsed(&self, start: NaiveDateTime, now: NaiveDateTime) -> i64 {
+        match self {
+            CohortPeriod::Week => (now - start).num_weeks(),
+            _ => {
+                (now.year() as i64 - start.year() as i64) * 12 + now.month() as i64
+                    - start.month() as i64
+            }
+        }
+    }
+}
+
+fn to_cohorts(
+    rows: Vec<CohortRow>,
+    period: CohortPeriod,
+    periods: u32,
+    now: DateTime<Utc>,
+) -> Vec<Cohort> {
+    rows.into_iter()
+        .chunk_by(|(start, size, _, _)| (*start, *size))
+        .into_iter()
+        .map(|((start, size), rows)| {
+            let reached = period
+                .elapsed(start, now.naive_utc())
+                .clamp(0, periods as i64);
+            let mut active = vec![0; reached as usize + 1];
+            for (_, _, i, n) in rows {
+                if let (Some(i), Some(n)) = (i, n) {
+                    if let Some(v) = active.get_mut(i as usize) {
+                        *v = n as u64;
+                    }
+                }
+            }
+            let retention = active.iter().map(|&n| n as f64 / size as f64).collect();
+            Cohort {
+                start: Some(utc_to_ts(start.and_utc())),
+                size: size as u64,
+                active,
+                retention,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::TimeZone;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{Filter, Gender, QueryRequest, TimeQuery},
+        test_utils::get_test_pool,
+        AppConfig, PgStore, UserStatsService,
+    };
+
+    fn request(period: CohortPeriod, periods: u32) -> CohortsRequest {
+        let lower = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let upper = Utc.with_ymd_and_hms(2020, 12, 31, 0, 0, 0).unwrap();
+        CohortsRequest {
+            period: period as i32,
+            created_at: Some(TimeQuery {
+                lower: Some(utc_to_ts(lower)),
+                upper: Some(utc_to_ts(upper)),
+                ..Default::default()
+            }),
+            periods,
+            query: None,
+        }
+    }
+
+    async fn insert_users(pool: &PgPool) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO user_stats (email, name, gender, created_at, last_visited_at) VALUES \
+             ('a@acme.org', 'a', 'female', '2020-01-05T00:00:00Z', '2020-01-10T00:00:00Z'), \
+             ('b@acme.org', 'b', 'male', '2020-01-20T00:00:00Z', '2020-02-15T00:00:00Z'), \
+             ('c@acme.org', 'c', 'female', '2020-02-03T00:00:00Z', NULL)",
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO user_events (event_id, email, event_type, content_id, occurred_at) \
+             VALUES ('e1', 'a@acme.org', 'watch_start', 1, '2020-03-02T00:00:00Z')",
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cohorts_should_count_active_users_by_month() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        insert_users(&pool).await?;
+
+        let ret = request(CohortPeriod::Month, 3).cohorts(&pool, None).await?;
+        assert_eq!(ret.cohorts.len(), 2);
+        let jan = &ret.cohorts[0];
+        assert_eq!(jan.start.as_ref().unwrap().seconds, 1577836800);
+        assert_eq!(jan.size, 2);
+        assert_eq!(jan.active, [1, 1, 1, 0]);
+        assert_eq!(jan.retention, [0.5, 0.5, 0.5, 0.0]);
+        let feb = &ret.cohorts[1];
+        assert_eq!(feb.size, 1);
+        assert_eq!(feb.active, [0, 0, 0, 0]);
+
+        let mut req = request(CohortPeriod::Month, 3);
+        req.query = Some(QueryRequest {
+            filter: Some(Filter::gender(Gender::Female)),
+            ..Default::default()
+        });
+        let ret = req.cohorts(&pool, None).await?;
+        assert_eq!(ret.cohorts[0].size, 1);
+        assert_eq!(ret.cohorts[0].active, [1, 0, 1, 0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cohorts_should_count_active_users_by_week() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        insert_users(&pool).await?;
+
+        let ret = request(CohortPeriod::Week, 8).cohorts(&pool, None).await?;
+        let starts = ret
+            .cohorts
+            .iter()
+            .map(|c| c.start.as_ref().unwrap().seconds)
+            .collect::<Vec<_>>();
+        // mondays of 2019-12-30, 2020-01-20, 2020-02-03
+        assert_eq!(starts, [1577664000, 1579478400, 1580688000]);
+        assert_eq!(ret.cohorts[0].active, [0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        // the visit on 2020-02-15 is 3 weeks later
+        assert_eq!(ret.cohorts[1].active, [0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cohorts_should_time_out() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // the cohorts wait for the lock until they are canceled
+        let mut lock = pool.begin().await?;
+        sqlx::query("LOCK TABLE user_events IN ACCESS EXCLUSIVE MODE")
+            .execute(&mut *lock)
+            .await?;
+        let status = request(CohortPeriod::Month, 3)
+            .cohorts(&pool, Some(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        lock.rollback().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expensive_cohorts_should_be_rejected() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut config = AppConfig::load()?;
+        config.server.max_raw_query_cost = 0.1;
+        let svc = UserStatsService::with_store(config, PgStore::new(pool, 500));
+        let status = svc
+            .cohorts(request(CohortPeriod::Month, 3))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        Ok(())
+    }
+
+    #[test]
+    fn cohorts_should_omit_periods_not_reached() {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 7, 10, 0, 0, 0).unwrap();
+        let rows = vec![
+            (start.naive_utc(), 4, Some(0), Some(4)),
+            (start.naive_utc(), 4, Some(2), Some(1)),
+        ];
+        let cohorts = to_cohorts(rows, CohortPeriod::Month, 12, now);
+        assert_eq!(cohorts[0].active, [4, 0, 1]);
+        assert_eq!(cohorts[0].retention, [1.0, 0.0, 0.25]);
+    }
+
+    #[tokio::test]
+    async fn cohorts_should_reject_invalid_request() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let status = request(CohortPeriod::Unspecified, 3)
+            .cohorts(&pool, None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = request(CohortPeriod::Week, 1000)
+            .cohorts(&pool, None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+}
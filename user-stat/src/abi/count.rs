@@ -0,0 +1,272 @@
+use std::time::Duration;
+
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    guard::{set_statement_timeout, timeout_status},
+    query::USER_STATS_TABLE,
+};
+use crate::pb::{CountResponse, EstimateMethod, EstimateRequest, EstimateResponse, QueryRequest};
+
+const DEFAULT_SAMPLE_PERCENT: f64 = 1.0;
+
+impl QueryRequest {
+    /// count the users matching the query, pagination is ignored. the statement is canceled
+    /// after the timeout
+    pub(crate) async fn count(
+        &self,
+        pool: &PgPool,
+        timeout: Option<Duration>,
+    ) -> Result<CountResponse, Status> {
+        let mut builder = QueryBuilder::new(format!("SELECT COUNT(*) FROM {}", USER_STATS_TABLE));
+        self.push_conditions(&mut builder)?;
+        let count = fetch_count(builder, pool, timeout).await?;
+        Ok(CountResponse {
+            count: count as u64,
+        })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl EstimateRequest {
+    pub(crate) async fn estimate(
+        &self,
+        pool: &PgPool,
+        timeout: Option<Duration>,
+    ) -> Result<EstimateResponse, Status> {
+        let query = self.query.clone().unwrap_or_default();
+        let method = EstimateMethod::try_from(self.method).map_err(|_| {
+            Status::invalid_argument(format!("invalid estimate method {}", self.method))
+        })?;
+        let count = match method {
+            EstimateMethod::Planner => estimate_by_planner(&query, pool, timeout).await?,
+            EstimateMethod::Sample => {
+                estimate_by_sample(&query, self.sample_percent()?, pool, timeout).await?
+            }
+        };
+        Ok(EstimateResponse {
+            count,
+            method: method as i32,
+        })
+    }
+
+    fn sample_percent(&self) -> Result<f64, Status> {
+        match self.sample_percent {
+            0.0 => Ok(DEFAULT_SAMPLE_PERCENT),
+            p if p > 0.0 && p <= 100.0 => Ok(p),
+            p => Err(Status::invalid_argument(format!(
+                "sample percent {} is out of range (0, 100]",
+                p
+            ))),
+        }
+    }
+}
+
+/// the planner estimates the rows of the scan with the table statistics, it costs nothing but
+/// may be inaccurate if the table is not analyzed recently or the conditions are correlated
+async fn estimate_by_planner(
+    query: &QueryRequest,
+    pool: &PgPool,
+    timeout: Option<Duration>,
+) -> Result<u64, Status> {
+    let mut builder = QueryBuilder::new(format!("EXPLAIN SELECT 1 FROM {}", USER_STATS_TABLE));
+    query.push_conditions(&mut builder)?;
+    info!("Estimate users: {}", builder.sql());
+
+    // the first line is the top plan node, e.g.
+    // Seq Scan on user_stats  (cost=0.00..4.25 rows=100 width=4)
+    let plan: String = fetch_one(builder, pool, timeout).await?;
+    parse_plan_rows(&plan)
+        .ok_or_else(|| Status::internal(format!("Failed to parse query plan: {}", plan)))
+}
+
+/// count a random sample of the table pages, and scale it up to the whole table
+async fn estimate_by_sample(
+    query: &QueryRequest,
+    percent: f64,
+    pool: &PgPool,
+    timeout: Option<Duration>,
+) -> Result<u64, Status> {
+    let mut builder = QueryBuilder::new(format!(
+        "SELECT COUNT(*) FROM {} TABLESAMPLE SYSTEM (",
+        USER_STATS_TABLE
+    ));
+    builder.push_bind(percent).push(")");
+    query.push_conditions(&mut builder)?;
+    let count = fetch_count(builder, pool, timeout).await?;
+    Ok((count as f64 * 100.0 / percent).round() as u64)
+}
+
+async fn fetch_count(
+    builder: QueryBuilder<'_, Postgres>,
+    pool: &PgPool,
+    timeout: Option<Duration>,
+) -> Result<i64, Status> {
+    info!("Count users: {}", builder.sql());
+    fetch_one(builder, pool, timeout).await
+}
+
+/// fetch the single value of the statement in a transaction with the statement timeout
+async fn fetch_one<T>(
+    mut builder: QueryBuilder<'_, Postgres>,
+    pool: &PgPool,
+    timeout: Option<Duration>,
+) -> Result<T, Status>
+where
+    T: for<'r> sqlx::Decode<'r, Postgres> + sqlx::Type<Postgres> + Send + Unpin,
+{
+    let mut ts = pool.begin().await.map_err(to_status)?;
+    set_statement_timeout(&mut ts, timeout)
+        .await
+        .map_err(to_status)?;
+    let value = builder
+        .build_query_scalar()
+        .fetch_one(&mut *ts)
+        .await
+        .map_err(to_status)?;
+    ts.commit().await.map_err(to_status)?;
+    Ok(value)
+}
+
+pub(super) fn parse_plan_rows(plan: &str) -> Option<u64> {
+    let (_, rest) = plan.split_once("rows=")?;
+    let rows = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+    rows.parse().ok()
+}
+
+/// the total cost of the plan node, e.g. 4.25 of "cost=0.00..4.25"
+pub(super) fn parse_plan_cost(plan: &str) -> Option<f64> {
+    let (_, rest) = plan.split_once("cost=")?;
+    let (_, total) = rest.split_once("..")?;
+    let total = total
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?;
+    total.parse().ok()
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    if let Some(status) = timeout_status(&e) {
+        return status;
+    }
+    match e {
+        sqlx::Error::Database(e) => Status::invalid_argument(format!("Invalid query: {}", e)),
+        e => Status::internal(format!("Failed to count users: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{Filter, Gender, QueryRequestBuilder},
+        test_utils::{get_test_pool, to_timequery},
+    };
+
+    #[test]
+    fn parse_plan_rows_should_work() {
+        let plan = "Seq Scan on user_stats  (cost=0.00..4.25 rows=42 width=4)";
+        assert_eq!(parse_plan_rows(plan), Some(42));
+        assert_eq!(parse_plan_rows("Result  (cost=0.00..0.01)"), None);
+        assert_eq!(parse_plan_cost(plan), Some(4.25));
+        assert_eq!(parse_plan_cost("Result"), None);
+    }
+
+    #[tokio::test]
+    async fn count_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let query = QueryRequestBuilder::default()
+            .filter(Filter::gender(Gender::Male))
+            .timestamp(("created_at".to_string(), to_timequery(Some(120), None)))
+            .page_size(10u32)
+            .build()?;
+        let count = query.count(&pool, None).await?.count;
+        let expected: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM user_stats WHERE gender = 'male' AND created_at >= '2024-07-06 00:00:00+00'::timestamptz - interval '120 days'",
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(count, expected as u64);
+        assert!(count > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn estimate_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("ANALYZE user_stats").execute(&pool).await?;
+
+        let req = EstimateRequest::default();
+        let ret = req.estimate(&pool, None).await?;
+        // an empty partition is still estimated as 1 row
+        assert!((100..105).contains(&ret.count), "{}", ret.count);
+        assert_eq!(ret.method, EstimateMethod::Planner as i32);
+
+        // sampling the whole table is an exact count
+        let req = EstimateRequest {
+            query: Some(QueryRequest {
+                filter: Some(Filter::gender(Gender::Female)),
+                ..Default::default()
+            }),
+            method: EstimateMethod::Sample as i32,
+            sample_percent: 100.0,
+        };
+        let ret = req.estimate(&pool, None).await?;
+        let expected = req.query.as_ref().unwrap().count(&pool, None).await?.count;
+        assert_eq!(ret.count, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_should_time_out() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // the count waits for the lock until it is canceled
+        let mut lock = pool.begin().await?;
+        sqlx::query("LOCK TABLE user_stats IN ACCESS EXCLUSIVE MODE")
+            .execute(&mut *lock)
+            .await?;
+
+        let timeout = Some(Duration::from_millis(100));
+        let status = QueryRequest::default()
+            .count(&pool, timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        let req = EstimateRequest {
+            method: EstimateMethod::Sample as i32,
+            ..Default::default()
+        };
+        let status = req.estimate(&pool, timeout).await.unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        lock.rollback().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn estimate_with_invalid_request_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let req = EstimateRequest {
+            method: EstimateMethod::Sample as i32,
+            sample_percent: 120.0,
+            ..Default::default()
+        };
+        let status = req.estimate(&pool, None).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "sample percent 120 is out of range (0, 100]"
+        );
+
+        let req = EstimateRequest {
+            method: 7,
+            ..Default::default()
+        };
+        let status = req.estimate(&pool, None).await.unwrap_err();
+        assert_eq!(status.message(), "invalid estimate method 7");
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use sqlx::{postgres::PgRow, FromRow, PgPool, Postgres, QueryBuilder, Transaction};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+use tracing::{info, warn};
+
+use super::guard::{set_statement_timeout, timeout_status};
+use crate::RowStream;
+
+const CURSOR_NAME: &str = "user_stats_cursor";
+
+/// stream rows (users or profiles) with a server side cursor.
+///
+/// The cursor is declared inside a transaction before the stream is returned, so an invalid
+/// query fails the request directly. Rows are then fetched `fetch_size` at a time, and the next
+/// batch is only fetched when the client has pulled the previous one, so at most `fetch_size`
+/// rows are buffered. Once the client drops the stream, the transaction is rolled back and the
+/// cursor is closed. The `timeout` applies to each statement of the transaction.
+pub(crate) async fn stream_rows<T, F>(
+    pool: &PgPool,
+    fetch_size: u32,
+    timeout: Option<Duration>,
+    push_select: F,
+) -> Result<RowStream<T>, Status>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin + 'static,
+    F: FnOnce(&mut QueryBuilder<'static, Postgres>) -> Result<(), Status>,
+{
+    let fetch_size = fetch_size.max(1);
+    let mut builder = QueryBuilder::new(format!("DECLARE {} NO SCROLL CURSOR FOR ", CURSOR_NAME));
+    push_select(&mut builder)?;
+    info!("Declare cursor: {}", builder.sql());
+
+    let mut ts = pool.begin().await.map_err(to_status)?;
+    set_statement_timeout(&mut ts, timeout)
+        .await
+        .map_err(to_status)?;
+    builder
+        .build()
+        .execute(&mut *ts)
+        .await
+        .map_err(|e| match e {
+            e if timeout_status(&e).is_some() => to_status(e),
+            sqlx::Error::Database(e) => Status::invalid_argument(format!("Invalid query: {}", e)),
+            e => to_status(e),
+        })?;
+
+    let (tx, rx) = mpsc::channel(fetch_size as usize);
+    tokio::spawn(async move {
+        let closed = tx.closed();
+        let fetch = fetch_all(ts, fetch_size, &tx);
+        tokio::select! {
+            ret = fetch => {
+                if let Err(e) = ret {
+                    warn!("Failed to fetch rows: {:?}", e);
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+            _ = closed => info!("Stream is dropped by client, close the cursor"),
+        }
+    });
+
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+async fn fetch_all<T>(
+    mut ts: Transaction<'static, Postgres>,
+    fetch_size: u32,
+    tx: &mpsc::Sender<Result<T, Status>>,
+) -> Result<(), Status>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+{
+    let sql = format!("FETCH FORWARD {} FROM {}", fetch_size, CURSOR_NAME);
+    loop {
+        // the columns of FETCH depend on the cursor, don't cache the statement for the connection
+        let rows = sqlx::query_as::<_, T>(&sql)
+            .persistent(false)
+            .fetch_all(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        let done = rows.len() < fetch_size as usize;
+        for row in rows {
+            // wait for the client to pull, this is where the backpressure comes from
+            if tx.send(Ok(row)).await.is_err() {
+                return Ok(());
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    ts.commit().await.map_err(to_status)
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    if let Some(status) = timeout_status(&e) {
+        return status;
+    }
+    Status::internal(format!("Failed to fetch data: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::{pb::User, test_utils::get_test_pool};
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn stream_rows_should_fetch_in_batches() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let stream = stream_rows::<User, _>(&pool, 7, None, |builder| {
+            builder.push("SELECT * FROM user_stats ORDER BY email");
+            Ok(())
+        })
+        .await?;
+        let users = stream.collect::<Vec<_>>().await;
+        assert_eq!(users.len(), 100);
+
+        let emails = users
+            .into_iter()
+            .map(|u| u.map(|u| u.email))
+            .collect::<Result<Vec<_>, _>>()?;
+        let expected: Vec<String> =
+            sqlx::query_scalar("SELECT email FROM user_stats ORDER BY email")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(emails, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn stream_rows_should_stop_when_dropped() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut stream = stream_rows::<User, _>(&pool, 1, None, |builder| {
+            builder.push("SELECT * FROM user_stats");
+            Ok(())
+        })
+        .await?;
+        assert!(stream.next().await.is_some());
+        assert_eq!(pool.num_idle(), pool.size() as usize - 1);
+        drop(stream);
+
+        // the connection holding the cursor should be released back to the pool
+        for _ in 0..100 {
+            if pool.num_idle() == pool.size() as usize {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("connection is not released after the stream is dropped");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn stream_rows_with_invalid_sql_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ret = stream_rows::<User, _>(&pool, 10, None, |builder| {
+            builder.push("SELECT not_exist FROM user_stats");
+            Ok(())
+        })
+        .await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use sqlx::{PgConnection, PgPool};
+use tonic::Status;
+use tracing::info;
+
+use super::query::{ts_to_utc, utc_to_ts};
+use crate::pb::{EventType, RecordEventsResponse, UserEvent};
+
+/// max number of content ids kept in recent_watched, the latest one comes first
+const RECENT_WATCHED_LIMIT: i32 = 50;
+
+const VISIT_SQL: &str = r#"
+UPDATE user_stats SET
+    last_visited_at = GREATEST(last_visited_at, $2)
+WHERE email = $1"#;
+
+// the content arrays of user_stats are derived from user_content_progress by its triggers
+const WATCH_SQL: &str = r#"
+UPDATE user_stats SET
+    last_visited_at = GREATEST(last_visited_at, $2),
+    last_watched_at = GREATEST(last_watched_at, $2),
+    recent_watched = (array_prepend($3, array_remove(COALESCE(recent_watched, '{}'), $3)))[1:$4]
+WHERE email = $1"#;
+
+// a content is only viewed if it is not started or finished yet
+const VIEW_PROGRESS_SQL: &str = r#"
+INSERT INTO user_content_progress (email, content_id, state, progress, updated_at)
+VALUES ($1, $3, 'viewed', 0, $2)
+ON CONFLICT (email, content_id) DO NOTHING"#;
+
+// a finished content is not moved back to started, and a late event doesn't move the progress
+// back
+const WATCH_START_PROGRESS_SQL: &str = r#"
+INSERT INTO user_content_progress AS p (email, content_id, state, progress, updated_at)
+VALUES ($1, $3, 'started', $4, $2)
+ON CONFLICT (email, content_id) DO UPDATE SET
+    state = 'started',
+    progress = CASE
+        WHEN p.state = 'viewed' OR p.updated_at <= EXCLUDED.updated_at THEN EXCLUDED.progress
+        ELSE p.progress
+    END,
+    updated_at = GREATEST(p.updated_at, EXCLUDED.updated_at)
+WHERE p.state <> 'finished'"#;
+
+const WATCH_FINISH_PROGRESS_SQL: &str = r#"
+INSERT INTO user_content_progress AS p (email, content_id, state, progress, updated_at)
+VALUES ($1, $3, 'finished', 100, $2)
+ON CONFLICT (email, content_id) DO UPDATE SET
+    state = 'finished',
+    progress = 100,
+    updated_at = GREATEST(p.updated_at, EXCLUDED.updated_at)
+WHERE p.state <> 'finished'"#;
+
+#[allow(clippy::result_large_err)]
+impl UserEvent {
+    pub fn new(
+        event_id: impl Into<String>,
+        email: impl Into<String>,
+        event_type: EventType,
+        content_id: u32,
+        occurred_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            event_id: event_id.into(),
+            email: email.into(),
+            event_type: event_type as i32,
+            content_id,
+            occurred_at: Some(utc_to_ts(occurred_at)),
+            ..Default::default()
+        }
+    }
+
+    /// the watch progress in percent of a watch start event
+    pub fn with_progress(mut self, progress: u32) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    fn validate(&self) -> Result<EventType, Status> {
+        let invalid = |msg: &str| {
+            Status::invalid_argument(format!("invalid event {:?}: {}", self.event_id, msg))
+        };
+        if self.event_id.is_empty() {
+            return Err(invalid("event_id is empty"));
+        }
+        if self.email.is_empty() {
+            return Err(invalid("email is empty"));
+        }
+        if self.progress > 100 {
+            return Err(invalid("progress is out of range"));
+        }
+        match EventType::try_from(self.event_type) {
+            Ok(EventType::Unspecified) | Err(_) => Err(invalid("event_type is not specified")),
+            Ok(EventType::Visit) => Ok(EventType::Visit),
+            Ok(_) if self.content_id == 0 => Err(invalid("content_id is required")),
+            Ok(_) if i32::try_from(self.content_id).is_err() => {
+                Err(invalid("content_id is out of range"))
+            }
+            Ok(event_type) => Ok(event_type),
+        }
+    }
+}
+
+/// apply the events one by one, the stream stops at the first invalid event. Events before it
+/// are kept, so the client could fix it and send them all again.
+pub(crate) async fn record_events<S>(
+    pool: &PgPool,
+    mut events: S,
+) -> Result<RecordEventsResponse, Status>
+where
+    S: Stream<Item = Result<UserEvent, Status>> + Unpin,
+{
+    let mut ret = RecordEventsResponse::default();
+    while let Some(event) = events.next().await {
+        let event = event?;
+        let event_type = event.validate()?;
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        if apply_event(&mut ts, &event, event_type).await? {
+            ts.commit().await.map_err(to_status)?;
+            ret.applied += 1;
+        } else {
+            info!("Event {} is already applied", event.event_id);
+            ret.duplicated += 1;
+        }
+    }
+    Ok(ret)
+}
+
+/// apply the event in the transaction, returns false if it is already applied
+async fn apply_event(
+    conn: &mut PgConnection,
+    event: &UserEvent,
+    event_type: EventType,
+) -> Result<bool, Status> {
+    let occurred_at = match &event.occurred_at {
+        Some(ts) => ts_to_utc(ts)?,
+        None => Utc::now(),
+    };
+    let content_id = (event_type != EventType::Visit).then_some(event.content_id as i32);
+
+    let ret = sqlx::query(
+        "INSERT INTO user_events (event_id, email, event_type, content_id, occurred_at) \
+         VALUES ($1, $2, $3::event_type, $4, $5) ON CONFLICT (event_id) DO NOTHING",
+    )
+    .bind(&event.event_id)
+    .bind(&event.email)
+    .bind(event_type.to_db())
+    .bind(content_id)
+    .bind(occurred_at)
+    .execute(&mut *conn)
+    .await
+    .map_err(to_status)?;
+    if ret.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    // an existing user is skipped by the trigger of user_stats. A new user signs up with its
+    // first event, so a backfilled event keeps its sign up date. Its name is empty until an
+    // event or an import brings it
+    sqlx::query("INSERT INTO user_stats (email, name, created_at) VALUES ($1, $2, $3)")
+        .bind(&event.email)
+        .bind(&event.name)
+        .bind(occurred_at)
+        .execute(&mut *conn)
+        .await
+        .map_err(to_status)?;
+    if !event.name.is_empty() {
+        sqlx::query("UPDATE user_stats SET name = $2 WHERE email = $1 AND name = ''")
+            .bind(&event.email)
+            .bind(&event.name)
+            .execute(&mut *conn)
+            .await
+            .map_err(to_status)?;
+    }
+
+    let query = match event_type {
+        EventType::Visit | EventType::View => sqlx::query(VISIT_SQL),
+        EventType::WatchStart | EventType::WatchFinish => sqlx::query(WATCH_SQL),
+        EventType::Unspecified => unreachable!("event is validated"),
+    };
+    let mut query = query.bind(&event.email).bind(occurred_at);
+    if matches!(event_type, EventType::WatchStart | EventType::WatchFinish) {
+        query = query.bind(content_id).bind(RECENT_WATCHED_LIMIT);
+    }
+    query.execute(&mut *conn).await.map_err(to_status)?;
+
+    let query = match event_type {
+        EventType::View => sqlx::query(VIEW_PROGRESS_SQL),
+        EventType::WatchStart => sqlx::query(WATCH_START_PROGRESS_SQL),
+        EventType::WatchFinish => sqlx::query(WATCH_FINISH_PROGRESS_SQL),
+        _ => return Ok(true),
+    };
+    let mut query = query.bind(&event.email).bind(occurred_at).bind(content_id);
+    if event_type == EventType::WatchStart {
+        query = query.bind(event.progress as i16);
+    }
+    query.execute(&mut *conn).await.map_err(to_status)?;
+    Ok(true)
+}
+
+impl EventType {
+    /// the value of the event_type enum in postgres
+    fn to_db(self) -> &'static str {
+        match self {
+            EventType::Visit => "visit",
+            EventType::View => "view",
+            EventType::WatchStart => "watch_start",
+            EventType::WatchFinish => "watch_finish",
+            EventType::Unspecified => "unspecified",
+        }
+    }
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to record event: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::{Duration, TimeZone};
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{QueryRequest, User},
+        test_utils::get_test_pool,
+    };
+
+    #[derive(Debug, sqlx::FromRow)]
+    struct Stats {
+        last_visited_at: Option<DateTime<Utc>>,
+        last_watched_at: Option<DateTime<Utc>>,
+        recent_watched: Option<Vec<i32>>,
+        viewed_but_not_started: Option<Vec<i32>>,
+        started_but_not_finished: Option<Vec<i32>>,
+        finished: Option<Vec<i32>>,
+    }
+
+    async fn get_stats(pool: &PgPool, email: &str) -> Result<Stats> {
+        let stats = sqlx::query_as(
+            "SELECT last_visited_at, last_watched_at, recent_watched, viewed_but_not_started, \
+             started_but_not_finished, finished FROM user_stats WHERE email = $1",
+        )
+        .bind(email)
+        .fetch_one(pool)
+        .await?;
+        Ok(stats)
+    }
+
+    #[tokio::test]
+    async fn record_events_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "new.user@acme.org";
+        let t0 = Utc.with_ymd_and_hms(2024, 7, 1, 0, 0, 0).unwrap();
+        let t = |mins| t0 + Duration::minutes(mins);
+        let events = vec![
+            UserEvent::new("e1", email, EventType::Visit, 0, t(0)),
+            UserEvent::new("e2", email, EventType::View, 1, t(1)),
+            UserEvent::new("e3", email, EventType::View, 2, t(2)),
+            UserEvent::new("e4", email, EventType::WatchStart, 1, t(3)),
+            UserEvent::new("e5", email, EventType::WatchStart, 3, t(4)),
+            UserEvent::new("e6", email, EventType::WatchFinish, 1, t(5)),
+            // duplicated event is ignored
+            UserEvent::new("e6", email, EventType::WatchFinish, 1, t(5)),
+            // late event doesn't move the timestamp back, nor the finished content back
+            UserEvent::new("e7", email, EventType::WatchStart, 1, t(-10)),
+            UserEvent::new("e8", email, EventType::View, 3, t(6)),
+        ];
+        let stream = futures::stream::iter(events.into_iter().map(Ok));
+        let ret = record_events(&pool, stream).await?;
+        assert_eq!(ret.applied, 8);
+        assert_eq!(ret.duplicated, 1);
+
+        let stats = get_stats(&pool, email).await?;
+        assert_eq!(stats.last_visited_at, Some(t(6)));
+        assert_eq!(stats.last_watched_at, Some(t(5)));
+        assert_eq!(stats.viewed_but_not_started, Some(vec![2]));
+        assert_eq!(stats.started_but_not_finished, Some(vec![3]));
+        assert_eq!(stats.finished, Some(vec![1]));
+        assert_eq!(stats.recent_watched, Some(vec![1, 3]));
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_events WHERE email = $1")
+            .bind(email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(count, 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_events_should_track_progress() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "new.user@acme.org";
+        let t0 = Utc.with_ymd_and_hms(2024, 7, 1, 0, 0, 0).unwrap();
+        let t = |mins| t0 + Duration::minutes(mins);
+        let events = vec![
+            UserEvent::new("e1", email, EventType::WatchStart, 1, t(0)).with_progress(30),
+            UserEvent::new("e2", email, EventType::WatchStart, 1, t(2)).with_progress(70),
+            // late event doesn't move the progress back
+            UserEvent::new("e3", email, EventType::WatchStart, 1, t(1)).with_progress(10),
+            UserEvent::new("e4", email, EventType::View, 2, t(3)),
+            UserEvent::new("e5", email, EventType::WatchStart, 3, t(4)).with_progress(50),
+            UserEvent::new("e6", email, EventType::WatchFinish, 3, t(5)),
+            UserEvent::new("e7", email, EventType::WatchStart, 3, t(6)).with_progress(5),
+        ];
+        let stream = futures::stream::iter(events.into_iter().map(Ok));
+        record_events(&pool, stream).await?;
+
+        let progress: Vec<(i32, String, i16)> = sqlx::query_as(
+            "SELECT content_id, state::text, progress FROM user_content_progress \
+             WHERE email = $1 ORDER BY content_id",
+        )
+        .bind(email)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(
+            progress,
+            [
+                (1, "started".to_string(), 70),
+                (2, "viewed".to_string(), 0),
+                (3, "finished".to_string(), 100),
+            ]
+        );
+
+        // the arrays derived from the progress are the same as the arrays of user_stats
+        let (viewed, started, finished): (Vec<i32>, Vec<i32>, Vec<i32>) = sqlx::query_as(
+            "SELECT viewed_but_not_started, started_but_not_finished, finished \
+             FROM user_content_arrays WHERE email = $1",
+        )
+        .bind(email)
+        .fetch_one(&pool)
+        .await?;
+        let stats = get_stats(&pool, email).await?;
+        assert_eq!(Some(viewed), stats.viewed_but_not_started);
+        assert_eq!(Some(started), stats.started_but_not_finished);
+        assert_eq!(Some(finished), stats.finished);
+
+        let users = QueryRequest::default()
+            .to_query_builder()?
+            .build_query_as::<User>()
+            .fetch_all(&pool)
+            .await?;
+        let user = users.into_iter().find(|u| u.email == email).unwrap();
+        assert_eq!(user.started_but_not_finished, [1]);
+        assert_eq!(user.started_progress, [70]);
+
+        let event = UserEvent::new("e8", email, EventType::WatchStart, 1, t(7)).with_progress(101);
+        let stream = futures::stream::iter([Ok(event)]);
+        let status = record_events(&pool, stream).await.unwrap_err();
+        assert_eq!(
+            status.message(),
+            "invalid event \"e8\": progress is out of range"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_user_should_sign_up_with_first_event() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "new.user@acme.org";
+        let t0 = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+        let events = vec![
+            UserEvent::new("e1", email, EventType::Visit, 0, t0),
+            UserEvent::new("e2", email, EventType::Visit, 0, t0 + Duration::days(1)),
+        ];
+        let stream = futures::stream::iter(events.into_iter().map(Ok));
+        record_events(&pool, stream).await?;
+
+        let (created_at, name): (DateTime<Utc>, String) =
+            sqlx::query_as("SELECT created_at, name FROM user_stats WHERE email = $1")
+                .bind(email)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(created_at, t0);
+        assert_eq!(name, "");
+        let users = QueryRequest::default()
+            .to_query_builder()?
+            .build_query_as::<User>()
+            .fetch_all(&pool)
+            .await?;
+        let user = users.into_iter().find(|u| u.email == email).unwrap();
+        assert_eq!(user.name, "");
+
+        // the name is filled by a later event
+        let mut event = UserEvent::new("e3", email, EventType::Visit, 0, Utc::now());
+        event.name = "Tyr".to_string();
+        record_events(&pool, futures::stream::iter([Ok(event)])).await?;
+        let name: String = sqlx::query_scalar("SELECT name FROM user_stats WHERE email = $1")
+            .bind(email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(name, "Tyr");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_invalid_event_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let now = Utc::now();
+        let events = vec![
+            UserEvent::new("e1", "tyr@acme.org", EventType::Visit, 0, now),
+            UserEvent::new("e2", "tyr@acme.org", EventType::View, 0, now),
+        ];
+        let stream = futures::stream::iter(events.into_iter().map(Ok));
+        let status = record_events(&pool, stream).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "invalid event \"e2\": content_id is required"
+        );
+
+        // events before the invalid one are applied
+        let stats = get_stats(&pool, "tyr@acme.org").await?;
+        assert!(stats.last_visited_at.is_some());
+        Ok(())
+    }
+}
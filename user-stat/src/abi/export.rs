@@ -0,0 +1,489 @@
+use std::{mem, sync::Arc};
+
+use chrono::SecondsFormat;
+use futures::StreamExt;
+use itertools::Itertools;
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+use tracing::{info, warn};
+
+use super::query::{Column, ColumnKind};
+use crate::{
+    pb::{ExportChunk, ExportFormat, Gender, UserProfile},
+    ExportStream, ProfileStream,
+};
+
+/// max size of an exported chunk, the encoded rows are sent once they exceed it
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// number of users in a row group of parquet
+const ROW_GROUP_SIZE: usize = 10_000;
+
+/// encode the columns of the users into the format, every format has the same columns in the
+/// same order. the encoded bytes are taken out chunk by chunk
+struct Encoder {
+    columns: Vec<&'static Column>,
+    sink: Sink,
+}
+
+enum Sink {
+    Csv(csv::Writer<Vec<u8>>),
+    Jsonl(Vec<u8>),
+    Parquet {
+        writer: SerializedFileWriter<Vec<u8>>,
+        users: Vec<UserProfile>,
+    },
+}
+
+/// encode the columns of the users of the stream in the background, at most one chunk is
+/// buffered
+#[allow(clippy::result_large_err)]
+pub(crate) fn export(
+    mut users: ProfileStream,
+    columns: Vec<&'static Column>,
+    format: ExportFormat,
+) -> Result<ExportStream, Status> {
+    let mut encoder = Encoder::new(columns, format)?;
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut rows = 0;
+        while let Some(user) = users.next().await {
+            let ret = user.and_then(|user| encoder.write(user));
+            if let Err(e) = ret {
+                warn!("Failed to export users: {:?}", e);
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+            rows += 1;
+
+            if encoder.buffered() >= CHUNK_SIZE {
+                for chunk in to_chunks(encoder.take()) {
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        info!("Export is cancelled by client");
+                        return;
+                    }
+                }
+            }
+        }
+
+        let ret = encoder.finish();
+        let chunks = match ret {
+            Ok(data) => to_chunks(data),
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        for chunk in chunks {
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+        info!("Exported {} users as {:?}", rows, format);
+    });
+
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+fn to_chunks(data: Vec<u8>) -> Vec<ExportChunk> {
+    data.chunks(CHUNK_SIZE)
+        .map(|data| ExportChunk {
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+#[allow(clippy::result_large_err)]
+impl Encoder {
+    fn new(columns: Vec<&'static Column>, format: ExportFormat) -> Result<Self, Status> {
+        let sink = match format {
+            ExportFormat::Unspecified => {
+                return Err(Status::invalid_argument("export format is required"))
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer
+                    .write_record(columns.iter().map(|c| c.name))
+                    .map_err(to_status)?;
+                Sink::Csv(writer)
+            }
+            ExportFormat::Jsonl => Sink::Jsonl(vec![]),
+            ExportFormat::Parquet => {
+                let schema = parse_message_type(&parquet_schema(&columns)).map_err(to_status)?;
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer = SerializedFileWriter::new(vec![], Arc::new(schema), Arc::new(props))
+                    .map_err(to_status)?;
+                Sink::Parquet {
+                    writer,
+                    users: Vec::with_capacity(ROW_GROUP_SIZE),
+                }
+            }
+        };
+        Ok(Self { columns, sink })
+    }
+
+    fn write(&mut self, user: UserProfile) -> Result<(), Status> {
+        match &mut self.sink {
+            Sink::Csv(writer) => {
+                let record = self.columns.iter().map(|c| match c.kind {
+                    ColumnKind::Text | ColumnKind::Gender => text(&user, c.name).to_string(),
+                    ColumnKind::Timestamp => user
+                        .timestamp(c.name)
+                        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Micros, true))
+                        .unwrap_or_default(),
+                    ColumnKind::IdArray => {
+                        format!("{{{}}}", user.id_array(c.name).iter().join(","))
+                    }
+                });
+                writer.write_record(record).map_err(to_status)?;
+            }
+            Sink::Jsonl(buf) => {
+                let object = self
+                    .columns
+                    .iter()
+                    .map(|c| {
+                        let value = match c.kind {
+                            ColumnKind::Text | ColumnKind::Gender => json!(text(&user, c.name)),
+                            ColumnKind::Timestamp => json!(user
+                                .timestamp(c.name)
+                                .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Micros, true))),
+                            ColumnKind::IdArray => json!(user.id_array(c.name)),
+                        };
+                        (c.name.to_string(), value)
+                    })
+                    .collect::<Map<_, _>>();
+                serde_json::to_writer(&mut *buf, &Value::Object(object)).map_err(to_status)?;
+                buf.push(b'\n');
+            }
+            Sink::Parquet { writer, users } => {
+                users.push(user);
+                if users.len() >= ROW_GROUP_SIZE {
+                    write_row_group(writer, &self.columns, mem::take(users))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// number of the encoded bytes not taken yet
+    fn buffered(&self) -> usize {
+        match &self.sink {
+            Sink::Csv(writer) => writer.get_ref().len(),
+            Sink::Jsonl(buf) => buf.len(),
+            Sink::Parquet { writer, .. } => writer.inner().len(),
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        match &mut self.sink {
+            Sink::Csv(writer) => {
+                let writer = mem::replace(writer, csv::Writer::from_writer(vec![]));
+                // the writer is flushed into the vec, it never fails
+                writer.into_inner().unwrap_or_default()
+            }
+            Sink::Jsonl(buf) => mem::take(buf),
+            // the offsets in the footer are counted by the writer, not by the buffer
+            Sink::Parquet { writer, .. } => mem::take(writer.inner_mut()),
+        }
+    }
+
+    /// the rest of the file
+    fn finish(mut self) -> Result<Vec<u8>, Status> {
+        match self.sink {
+            Sink::Parquet {
+                mut writer, users, ..
+            } => {
+                if !users.is_empty() {
+                    write_row_group(&mut writer, &self.columns, users)?;
+                }
+                writer.into_inner().map_err(to_status)
+            }
+            _ => Ok(self.take()),
+        }
+    }
+}
+
+/// the value of a text or gender column, an unspecified gender is empty
+fn text<'a>(user: &'a UserProfile, column: &str) -> &'a str {
+    match column {
+        "email" => &user.email,
+        "name" => &user.name,
+        "gender" => Gender::try_from(user.gender)
+            .ok()
+            .and_then(|g| g.to_db())
+            .unwrap_or_default(),
+        _ => "",
+    }
+}
+
+/// a missing timestamp is null, the id arrays are lists that are never null
+fn parquet_schema(columns: &[&Column]) -> String {
+    let fields = columns
+        .iter()
+        .map(|c| match c.kind {
+            ColumnKind::Text | ColumnKind::Gender => {
+                format!("REQUIRED BYTE_ARRAY {} (UTF8);", c.name)
+            }
+            ColumnKind::Timestamp => {
+                format!("OPTIONAL INT64 {} (TIMESTAMP(MICROS, true));", c.name)
+            }
+            ColumnKind::IdArray => format!(
+                "REQUIRED GROUP {} (LIST) {{ REPEATED GROUP list {{ REQUIRED INT32 element; }} }}",
+                c.name
+            ),
+        })
+        .join("\n");
+    format!("message user {{\n{}\n}}", fields)
+}
+
+/// a column of the row group is written per column of the export. an empty list only has a
+/// definition level 0, the first element of each list has a repetition level 0
+#[allow(clippy::result_large_err)]
+fn write_row_group(
+    writer: &mut SerializedFileWriter<Vec<u8>>,
+    columns: &[&Column],
+    users: Vec<UserProfile>,
+) -> Result<(), Status> {
+    let mut row_group = writer.next_row_group().map_err(to_status)?;
+    for c in columns {
+        let mut column = row_group
+            .next_column()
+            .map_err(to_status)?
+            .ok_or_else(|| Status::internal("parquet column is missing"))?;
+        match c.kind {
+            ColumnKind::Text | ColumnKind::Gender => {
+                let values = users
+                    .iter()
+                    .map(|u| ByteArray::from(text(u, c.name)))
+                    .collect::<Vec<_>>();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)
+                    .map_err(to_status)?;
+            }
+            ColumnKind::Timestamp => {
+                let timestamps = users.iter().map(|u| u.timestamp(c.name));
+                let def_levels = timestamps
+                    .clone()
+                    .map(|dt| dt.is_some() as i16)
+                    .collect::<Vec<_>>();
+                let values = timestamps
+                    .flatten()
+                    .map(|dt| dt.timestamp_micros())
+                    .collect::<Vec<_>>();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, Some(&def_levels), None)
+                    .map_err(to_status)?;
+            }
+            ColumnKind::IdArray => {
+                let mut ids = vec![];
+                let mut def_levels = vec![];
+                let mut rep_levels = vec![];
+                for user in &users {
+                    let values = user.id_array(c.name);
+                    if values.is_empty() {
+                        def_levels.push(0);
+                        rep_levels.push(0);
+                        continue;
+                    }
+                    for (i, id) in values.iter().enumerate() {
+                        ids.push(*id);
+                        def_levels.push(1);
+                        rep_levels.push(if i == 0 { 0 } else { 1 });
+                    }
+                }
+                column
+                    .typed::<Int32Type>()
+                    .write_batch(&ids, Some(&def_levels), Some(&rep_levels))
+                    .map_err(to_status)?;
+            }
+        }
+        column.close().map_err(to_status)?;
+    }
+    row_group.close().map_err(to_status)?;
+    Ok(())
+}
+
+fn to_status(e: impl std::fmt::Display) -> Status {
+    Status::internal(format!("Failed to export users: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bytes::Bytes;
+    use futures::stream;
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+    use prost_types::{FieldMask, Timestamp};
+
+    use super::*;
+    use crate::pb::QueryRequest;
+
+    fn columns(paths: &[&str]) -> Vec<&'static Column> {
+        let query = QueryRequest {
+            field_mask: Some(FieldMask {
+                paths: paths.iter().map(|p| p.to_string()).collect(),
+            }),
+            ..Default::default()
+        };
+        query.profile_fields().unwrap()
+    }
+
+    fn users() -> Vec<UserProfile> {
+        vec![
+            UserProfile {
+                email: "tyr@acme.org".to_string(),
+                name: "Tyr, Chen".to_string(),
+                gender: Gender::Male as i32,
+                created_at: Some(Timestamp {
+                    seconds: 1_717_200_000,
+                    nanos: 123_456_000,
+                }),
+                started_but_not_finished: vec![1, 2],
+                finished: vec![3],
+                ..Default::default()
+            },
+            UserProfile {
+                email: "alice@acme.org".to_string(),
+                name: "Alice".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    async fn run(
+        users: Vec<UserProfile>,
+        columns: Vec<&'static Column>,
+        format: ExportFormat,
+    ) -> Result<Vec<u8>> {
+        let users: ProfileStream = Box::pin(stream::iter(users.into_iter().map(Ok)));
+        let mut chunks = export(users, columns, format)?;
+        let mut data = vec![];
+        while let Some(chunk) = chunks.next().await {
+            data.extend(chunk?.data);
+        }
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn export_csv_and_jsonl_should_work() -> Result<()> {
+        let fields = ["name", "started_but_not_finished"];
+        let data = run(users(), columns(&fields), ExportFormat::Csv).await?;
+        assert_eq!(
+            String::from_utf8(data)?,
+            "email,name,started_but_not_finished\n\
+             tyr@acme.org,\"Tyr, Chen\",\"{1,2}\"\n\
+             alice@acme.org,Alice,{}\n"
+        );
+
+        let data = run(users(), columns(&fields), ExportFormat::Jsonl).await?;
+        let lines = String::from_utf8(data)?;
+        let lines = lines
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?;
+        assert_eq!(
+            lines,
+            [
+                json!({"email": "tyr@acme.org", "name": "Tyr, Chen", "started_but_not_finished": [1, 2]}),
+                json!({"email": "alice@acme.org", "name": "Alice", "started_but_not_finished": []}),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_formats_should_have_same_columns() -> Result<()> {
+        let data = run(users(), columns(&[]), ExportFormat::Csv).await?;
+        let mut reader = csv::Reader::from_reader(data.as_slice());
+        let headers = reader
+            .headers()?
+            .iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(headers.len(), 13);
+        let record = reader.records().next().unwrap()?;
+        let csv = headers.iter().zip(record.iter()).collect::<Vec<_>>();
+        assert!(csv.contains(&(&"gender".to_string(), "male")));
+        assert!(csv.contains(&(&"created_at".to_string(), "2024-06-01T00:00:00.123456Z")));
+        assert!(csv.contains(&(&"last_visited_at".to_string(), "")));
+        assert!(csv.contains(&(&"finished".to_string(), "{3}")));
+
+        let data = run(users(), columns(&[]), ExportFormat::Jsonl).await?;
+        let line = String::from_utf8(data)?;
+        let user: Map<String, Value> = serde_json::from_str(line.lines().next().unwrap())?;
+        let mut keys = user.keys().cloned().collect::<Vec<_>>();
+        let mut expected = headers.clone();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(user["gender"], "male");
+        assert_eq!(user["created_at"], "2024-06-01T00:00:00.123456Z");
+        assert_eq!(user["last_visited_at"], Value::Null);
+        assert_eq!(user["finished"], json!([3]));
+
+        let data = run(users(), columns(&[]), ExportFormat::Parquet).await?;
+        let reader = SerializedFileReader::new(Bytes::from(data))?;
+        let row = reader.get_row_iter(None)?.next().unwrap()?;
+        let parquet = row.get_column_iter().collect::<Vec<_>>();
+        let names = parquet
+            .iter()
+            .map(|(name, _)| (*name).clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, headers);
+        assert_eq!(parquet[2].1, &Field::Str("male".to_string()));
+        assert_eq!(parquet[3].1, &Field::TimestampMicros(1_717_200_000_123_456));
+        assert_eq!(parquet[4].1, &Field::Null);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_parquet_should_work() -> Result<()> {
+        // more than one row group
+        let users = (0..ROW_GROUP_SIZE + 10)
+            .map(|i| UserProfile {
+                email: format!("user{}@acme.org", i),
+                name: format!("user {}", i),
+                started_but_not_finished: (0..(i % 3) as i32).collect(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let fields = ["name", "started_but_not_finished"];
+        let data = run(users.clone(), columns(&fields), ExportFormat::Parquet).await?;
+
+        let reader = SerializedFileReader::new(Bytes::from(data))?;
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows = reader.get_row_iter(None)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows.len(), users.len());
+        let columns = rows[2].get_column_iter().collect::<Vec<_>>();
+        assert_eq!(columns[0].1, &Field::Str("user2@acme.org".to_string()));
+        match columns[2].1 {
+            Field::ListInternal(list) => assert_eq!(list.len(), 2),
+            f => panic!("expect list, got {:?}", f),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unspecified_format_should_fail() {
+        let users: ProfileStream = Box::pin(stream::empty());
+        let status = export(users, columns(&[]), ExportFormat::Unspecified)
+            .err()
+            .unwrap();
+        assert_eq!(status.message(), "export format is required");
+    }
+}
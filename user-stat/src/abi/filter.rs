@@ -0,0 +1,386 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, QueryBuilder};
+use tonic::Status;
+
+use super::query::{push_time_range, to_content_ids, Column, ColumnKind};
+use crate::pb::{
+    filter::Expr, ArrayLengthFilter, CompareOp, Filter, FilterGroup, Gender, GenderFilter,
+    IdFilter, IdOp, NullFilter, TimeFilter, TimeQuery,
+};
+
+/// filters nested deeper than this are rejected, to keep the generated sql reasonable
+pub(super) const MAX_FILTER_DEPTH: usize = 16;
+
+#[allow(clippy::result_large_err)]
+impl Filter {
+    pub fn and(filters: impl IntoIterator<Item = Filter>) -> Self {
+        Self::new(Expr::And(FilterGroup {
+            filters: filters.into_iter().collect(),
+        }))
+    }
+
+    pub fn or(filters: impl IntoIterator<Item = Filter>) -> Self {
+        Self::new(Expr::Or(FilterGroup {
+            filters: filters.into_iter().collect(),
+        }))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(filter: Filter) -> Self {
+        Self::new(Expr::Not(Box::new(filter)))
+    }
+
+    pub fn time(column: impl Into<String>, range: TimeQuery) -> Self {
+        Self::new(Expr::Time(TimeFilter {
+            column: column.into(),
+            range: Some(range),
+        }))
+    }
+
+    pub fn ids(column: impl Into<String>, ids: &[u32], op: IdOp) -> Self {
+        Self::new(Expr::Ids(IdFilter {
+            column: column.into(),
+            ids: ids.to_vec(),
+            op: op as i32,
+        }))
+    }
+
+    pub fn gender(gender: Gender) -> Self {
+        Self::new(Expr::Gender(GenderFilter {
+            gender: gender as i32,
+        }))
+    }
+
+    pub fn is_null(column: impl Into<String>, is_null: bool) -> Self {
+        Self::new(Expr::Null(NullFilter {
+            column: column.into(),
+            is_null,
+        }))
+    }
+
+    pub fn array_length(column: impl Into<String>, op: CompareOp, length: u32) -> Self {
+        Self::new(Expr::ArrayLength(ArrayLengthFilter {
+            column: column.into(),
+            op: op as i32,
+            length,
+        }))
+    }
+
+    fn new(expr: Expr) -> Self {
+        Self { expr: Some(expr) }
+    }
+
+    /// resolve the relative time ranges of the filter against now
+    pub(crate) fn resolve(&mut self, now: DateTime<Utc>) -> Result<(), Status> {
+        match &mut self.expr {
+            Some(Expr::And(group)) | Some(Expr::Or(group)) => {
+                for filter in &mut group.filters {
+                    filter.resolve(now)?;
+                }
+            }
+            Some(Expr::Not(filter)) => filter.resolve(now)?,
+            Some(Expr::Time(f)) => {
+                if let Some(range) = &mut f.range {
+                    *range = range.resolve(now)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// push the filter as a single boolean expression, all the values are bound as parameters
+    pub(crate) fn push(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        depth: usize,
+    ) -> Result<(), Status> {
+        if depth >= MAX_FILTER_DEPTH {
+            return Err(Status::invalid_argument(format!(
+                "filter is nested too deep, max depth is {}",
+                MAX_FILTER_DEPTH
+            )));
+        }
+
+        let Some(expr) = &self.expr else {
+            return Err(Status::invalid_argument("filter expression is empty"));
+        };
+
+        match expr {
+            // empty AND matches everyone, empty OR matches no one
+            Expr::And(group) => push_group(builder, group, " AND ", "TRUE", depth)?,
+            Expr::Or(group) => push_group(builder, group, " OR ", "FALSE", depth)?,
+            Expr::Not(filter) => {
+                builder.push("NOT (");
+                filter.push(builder, depth + 1)?;
+                builder.push(")");
+            }
+            Expr::Time(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::Timestamp)?;
+                let range = f.range.clone().unwrap_or_default();
+                push_time_range(builder, column, &range)?;
+            }
+            Expr::Ids(f) => f.push(builder)?,
+            Expr::Gender(f) => {
+                let gender = Gender::try_from(f.gender)
+                    .ok()
+                    .and_then(Gender::to_db)
+                    .ok_or_else(|| {
+                        Status::invalid_argument(format!("invalid gender {}", f.gender))
+                    })?;
+                builder.push("gender = ").push_bind(gender).push("::gender");
+            }
+            Expr::Null(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::Timestamp)?;
+                let op = if f.is_null { "IS NULL" } else { "IS NOT NULL" };
+                builder.push(format!("{} {}", column.name, op));
+            }
+            Expr::ArrayLength(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::IdArray)?;
+                let op = match CompareOp::try_from(f.op) {
+                    Ok(CompareOp::Eq) => "=",
+                    Ok(CompareOp::Ne) => "<>",
+                    Ok(CompareOp::Lt) => "<",
+                    Ok(CompareOp::Le) => "<=",
+                    Ok(CompareOp::Gt) => ">",
+                    Ok(CompareOp::Ge) => ">=",
+                    Err(_) => {
+                        return Err(Status::invalid_argument(format!(
+                            "invalid compare op {}",
+                            f.op
+                        )))
+                    }
+                };
+                builder
+                    .push(format!("cardinality({}) {} ", id_array(column), op))
+                    .push_bind(f.length as i32);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl IdFilter {
+    fn push(&self, builder: &mut QueryBuilder<'static, Postgres>) -> Result<(), Status> {
+        let column = Column::find_with_kind(&self.column, ColumnKind::IdArray)?;
+        let op = IdOp::try_from(self.op)
+            .map_err(|_| Status::invalid_argument(format!("invalid id op {}", self.op)))?;
+        let ids = to_content_ids(&self.ids)?;
+        match op {
+            // every array contains the empty set, but none overlaps with it
+            IdOp::Contains if ids.is_empty() => builder.push("TRUE"),
+            IdOp::Overlaps if ids.is_empty() => builder.push("FALSE"),
+            IdOp::Contains => builder
+                .push_bind(ids)
+                .push(format!(" <@ {}", id_array(column))),
+            IdOp::Overlaps => builder
+                .push(format!("{} && ", id_array(column)))
+                .push_bind(ids),
+        };
+        Ok(())
+    }
+}
+
+/// the id arrays of the users created by events are NULL, treat them as empty arrays, so that
+/// a negated filter on them is TRUE instead of NULL
+fn id_array(column: &Column) -> String {
+    format!("COALESCE({}, '{{}}')", column.name)
+}
+
+#[allow(clippy::result_large_err)]
+fn push_group(
+    builder: &mut QueryBuilder<'static, Postgres>,
+    group: &FilterGroup,
+    sep: &str,
+    empty: &str,
+    depth: usize,
+) -> Result<(), Status> {
+    if group.filters.is_empty() {
+        builder.push(empty);
+        return Ok(());
+    }
+
+    builder.push("(");
+    for (i, filter) in group.filters.iter().enumerate() {
+        if i > 0 {
+            builder.push(sep);
+        }
+        builder.push("(");
+        filter.push(builder, depth + 1)?;
+        builder.push(")");
+    }
+    builder.push(")");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tonic::Code;
+
+    use super::*;
+    use crate::abi::query::STARTED_PROGRESS;
+    use crate::{
+        pb::QueryRequestBuilder,
+        test_utils::{get_test_pool, to_timequery},
+    };
+
+    #[allow(clippy::result_large_err)]
+    fn to_sql(filter: &Filter) -> Result<String, Status> {
+        let mut builder = QueryBuilder::new("");
+        filter.push(&mut builder, 0)?;
+        Ok(builder.sql().to_string())
+    }
+
+    #[test]
+    fn filter_to_sql_should_work() -> Result<()> {
+        // watched 1 or 2 but not 3, not emailed in 7 days
+        let filter = Filter::and([
+            Filter::or([
+                Filter::ids("recent_watched", &[1], IdOp::Contains),
+                Filter::ids("recent_watched", &[2], IdOp::Contains),
+            ]),
+            Filter::not(Filter::ids("finished", &[3], IdOp::Overlaps)),
+            Filter::or([
+                Filter::is_null("last_email_notification", true),
+                Filter::time("last_email_notification", to_timequery(None, Some(7))),
+            ]),
+            Filter::gender(Gender::Female),
+            Filter::array_length("finished", CompareOp::Ge, 10),
+        ]);
+        assert_eq!(
+            to_sql(&filter)?,
+            "(((($1 <@ COALESCE(recent_watched, '{}')) OR ($2 <@ COALESCE(recent_watched, '{}')))) \
+             AND (NOT (COALESCE(finished, '{}') && $3)) \
+             AND (((last_email_notification IS NULL) OR (last_email_notification <= $4))) \
+             AND (gender = $5::gender) AND (cardinality(COALESCE(finished, '{}')) >= $6))"
+        );
+
+        assert_eq!(to_sql(&Filter::and([]))?, "TRUE");
+        assert_eq!(to_sql(&Filter::or([]))?, "FALSE");
+        assert_eq!(
+            to_sql(&Filter::ids("finished", &[], IdOp::Contains))?,
+            "TRUE"
+        );
+        assert_eq!(
+            to_sql(&Filter::ids("finished", &[], IdOp::Overlaps))?,
+            "FALSE"
+        );
+        assert_eq!(
+            to_sql(&Filter::time("created_at", to_timequery(None, None)))?,
+            "TRUE"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_request_with_filter_should_work() -> Result<()> {
+        let query = QueryRequestBuilder::default()
+            .timestamp(("created_at".to_string(), to_timequery(Some(220), None)))
+            .filter(Filter::is_null("last_sms_notification", false))
+            .build()?;
+        let builder = query.to_query_builder()?;
+        assert_eq!(builder.sql(), format!("SELECT email, name, started_but_not_finished, {} FROM user_stats WHERE created_at >= $1 AND last_sms_notification IS NOT NULL", STARTED_PROGRESS));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_filter_should_fail() {
+        let mut deep = Filter::gender(Gender::Male);
+        for _ in 0..MAX_FILTER_DEPTH {
+            deep = Filter::not(deep);
+        }
+
+        let filters = [
+            (Filter::default(), "filter expression is empty"),
+            (Filter::gender(Gender::Unspecified), "invalid gender 0"),
+            (
+                Filter::is_null("finished", true),
+                "column finished is a IdArray column, expect Timestamp",
+            ),
+            (
+                Filter::array_length("created_at", CompareOp::Eq, 1),
+                "column created_at is a Timestamp column, expect IdArray",
+            ),
+            (
+                Filter::ids("email; --", &[1], IdOp::Overlaps),
+                "unknown column email; -- of user_stats",
+            ),
+            (
+                Filter::new(Expr::Ids(IdFilter {
+                    column: "finished".to_string(),
+                    ids: vec![1],
+                    op: 42,
+                })),
+                "invalid id op 42",
+            ),
+            (deep, "filter is nested too deep, max depth is 16"),
+        ];
+        for (filter, msg) in filters {
+            let status = to_sql(&filter).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_should_match_raw_sql() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let cases = [
+            (
+                Filter::or([
+                    Filter::gender(Gender::Unknown),
+                    Filter::array_length("finished", CompareOp::Lt, 10),
+                ]),
+                "gender = 'unknown' OR cardinality(finished) < 10",
+            ),
+            (
+                Filter::and([
+                    Filter::not(Filter::gender(Gender::Male)),
+                    Filter::ids("recent_watched", &[134782, 142093], IdOp::Overlaps),
+                ]),
+                "gender <> 'male' AND recent_watched && '{134782,142093}'",
+            ),
+            (
+                Filter::is_null("last_email_notification", false),
+                "last_email_notification IS NOT NULL",
+            ),
+        ];
+        for (filter, condition) in cases {
+            let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM user_stats WHERE ");
+            filter.push(&mut builder, 0)?;
+            let count: i64 = builder.build_query_scalar().fetch_one(&pool).await?;
+
+            let sql = format!("SELECT COUNT(*) FROM user_stats WHERE {}", condition);
+            let expected: i64 = sqlx::query_scalar(&sql).fetch_one(&pool).await?;
+            assert_eq!(count, expected, "{}", condition);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn null_arrays_should_be_empty() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // a user created by a visit event has no content arrays
+        sqlx::query("INSERT INTO user_stats (email, name, created_at) VALUES ($1, '', now())")
+            .bind("visitor@example.com")
+            .execute(&pool)
+            .await?;
+        let filters = [
+            Filter::not(Filter::ids("finished", &[3], IdOp::Overlaps)),
+            Filter::not(Filter::ids("recent_watched", &[1, 2], IdOp::Contains)),
+            Filter::array_length("started_but_not_finished", CompareOp::Eq, 0),
+            Filter::not(Filter::array_length("finished", CompareOp::Gt, 0)),
+        ];
+        for filter in filters {
+            let mut builder = QueryBuilder::new(
+                "SELECT COUNT(*) FROM user_stats WHERE email = 'visitor@example.com' AND ",
+            );
+            filter.push(&mut builder, 0)?;
+            let count: i64 = builder.build_query_scalar().fetch_one(&pool).await?;
+            assert_eq!(count, 1, "{}", builder.sql());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder};
+use tonic::{metadata::MetadataMap, Request, Status};
+use tracing::info;
+
+use super::count::{parse_plan_cost, parse_plan_rows};
+use crate::UserStatsService;
+
+/// sqlstate of a statement canceled by statement_timeout
+const QUERY_CANCELED: &str = "57014";
+
+impl UserStatsService {
+    /// the service to handle the request, the queries are canceled once the grpc deadline of the
+    /// request is exceeded
+    pub fn with_deadline<T>(&self, request: &Request<T>) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            deadline: grpc_timeout(request.metadata()),
+        }
+    }
+
+    /// the shorter one of the grpc deadline and the default query timeout
+    pub(crate) fn statement_timeout(&self) -> Option<Duration> {
+        let default = match self.config.server.query_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        match (self.deadline, default) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// reject the raw query or cohorts if the plan is too expensive
+    pub(crate) async fn check_plan<F>(&self, push_query: F) -> Result<(), Status>
+    where
+        F: FnOnce(&mut QueryBuilder<'static, Postgres>) -> Result<(), Status>,
+    {
+        let server = &self.config.server;
+        check_plan(
+            self.pool()?,
+            push_query,
+            server.max_raw_query_cost,
+            server.max_raw_query_rows,
+        )
+        .await
+    }
+}
+
+/// set the statement_timeout of the current transaction
+pub(crate) async fn set_statement_timeout(
+    conn: &mut PgConnection,
+    timeout: Option<Duration>,
+) -> Result<(), sqlx::Error> {
+    let Some(timeout) = timeout else {
+        return Ok(());
+    };
+    // 0 disables the timeout, so at least 1ms
+    let ms = timeout.as_millis().max(1);
+    sqlx::query(&format!("SET LOCAL statement_timeout = {}", ms))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// deadline_exceeded if the statement is canceled by statement_timeout
+pub(crate) fn timeout_status(e: &sqlx::Error) -> Option<Status> {
+    match e {
+        sqlx::Error::Database(e) if e.code().as_deref() == Some(QUERY_CANCELED) => {
+            Some(Status::deadline_exceeded(format!("Query timeout: {}", e)))
+        }
+        _ => None,
+    }
+}
+
+/// EXPLAIN the query, and reject it with resource_exhausted if the estimated cost or rows of the
+/// top plan node exceeds the limits. 0 means no limit
+async fn check_plan<F>(
+    pool: &PgPool,
+    push_query: F,
+    max_cost: f64,
+    max_rows: u64,
+) -> Result<(), Status>
+where
+    F: FnOnce(&mut QueryBuilder<'static, Postgres>) -> Result<(), Status>,
+{
+    if max_cost <= 0.0 && max_rows == 0 {
+        return Ok(());
+    }
+
+    let mut builder = QueryBuilder::new("EXPLAIN ");
+    push_query(&mut builder)?;
+    let plan: String = builder
+        .build_query_scalar()
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) => Status::invalid_argument(format!("Invalid query: {}", e)),
+            e => Status::internal(format!("Failed to explain query: {}", e)),
+        })?;
+    let (cost, rows) = parse_plan_cost(&plan)
+        .zip(parse_plan_rows(&plan))
+        .ok_or_else(|| Status::internal(format!("Failed to parse query plan: {}", plan)))?;
+    info!("Plan of query: cost={} rows={}", cost, rows);
+
+    if max_cost > 0.0 && cost > max_cost {
+        return Err(Status::resource_exhausted(format!(
+            "estimated cost {} of the query exceeds the limit {}",
+            cost, max_cost
+        )));
+    }
+    if max_rows > 0 && rows > max_rows {
+        return Err(Status::resource_exhausted(format!(
+            "estimated rows {} of the query exceeds the limit {}",
+            rows, max_rows
+        )));
+    }
+    Ok(())
+}
+
+/// the most digits of the grpc-timeout header allowed by the grpc spec
+const MAX_TIMEOUT_DIGITS: usize = 8;
+
+/// parse the grpc-timeout header, e.g. "100m" is 100 milliseconds
+fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > MAX_TIMEOUT_DIGITS + 1 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tonic::Code;
+
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[test]
+    fn grpc_timeout_should_be_parsed() {
+        let timeout = |value: &str| {
+            let mut metadata = MetadataMap::new();
+            metadata.insert("grpc-timeout", value.parse().unwrap());
+            grpc_timeout(&metadata)
+        };
+        assert_eq!(timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(timeout("10x"), None);
+        assert_eq!(timeout("m"), None);
+        assert_eq!(
+            timeout("99999999H"),
+            Some(Duration::from_secs(99999999 * 3600))
+        );
+        assert_eq!(timeout("99999999999999999H"), None);
+        assert_eq!(timeout("123456789m"), None);
+        assert_eq!(grpc_timeout(&MetadataMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn statement_timeout_should_use_shorter_deadline() -> Result<()> {
+        let (_tdb, svc) = UserStatsService::new_for_test().await?;
+        let default = Duration::from_millis(svc.config.server.query_timeout_ms);
+        assert_eq!(svc.statement_timeout(), Some(default));
+
+        let mut request = Request::new(());
+        request.set_timeout(Duration::from_millis(100));
+        let svc = svc.with_deadline(&request);
+        assert_eq!(svc.statement_timeout(), Some(Duration::from_millis(100)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn expensive_plan_should_be_rejected() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let query = || {
+            |builder: &mut QueryBuilder<'static, Postgres>| {
+                builder
+                    .push("SELECT * FROM user_stats WHERE email > ")
+                    .push_bind("a");
+                Ok(())
+            }
+        };
+        check_plan(&pool, query(), 0.0, 0).await?;
+        check_plan(&pool, query(), 1e9, 1_000_000).await?;
+
+        let status = check_plan(&pool, query(), 0.1, 0).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.message().starts_with("estimated cost"));
+        let status = check_plan(&pool, query(), 0.0, 1).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.message().starts_with("estimated rows"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn statement_timeout_should_cancel_query() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut ts = pool.begin().await?;
+        set_statement_timeout(&mut ts, Some(Duration::from_millis(50))).await?;
+        let e = sqlx::query("SELECT pg_sleep(1)")
+            .execute(&mut *ts)
+            .await
+            .unwrap_err();
+        let status = timeout_status(&e).unwrap();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        Ok(())
+    }
+}
@@ -0,0 +1,748 @@
+use std::mem;
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use itertools::Itertools;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tonic::Status;
+use tracing::info;
+
+use super::query::{to_content_ids, Column, ColumnKind, USER_STATS_COLUMNS, USER_STATS_TABLE};
+use crate::pb::{ImportFormat, ImportProgress, ImportRequest, ImportRowError};
+
+/// number of valid rows upserted in one transaction
+const BATCH_SIZE: usize = 1000;
+
+/// number of times the records are merged, if new users of them are inserted concurrently
+const MERGE_ATTEMPTS: usize = 3;
+
+const MAX_EMAIL_LEN: usize = 128;
+const MAX_NAME_LEN: usize = 64;
+
+/// a row of the file, absent or null columns keep the current value of the user
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImportRow {
+    email: String,
+    name: String,
+    gender: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    last_visited_at: Option<DateTime<Utc>>,
+    last_watched_at: Option<DateTime<Utc>>,
+    recent_watched: Option<Ids>,
+    viewed_but_not_started: Option<Ids>,
+    started_but_not_finished: Option<Ids>,
+    finished: Option<Ids>,
+    last_email_notification: Option<DateTime<Utc>>,
+    last_in_app_notification: Option<DateTime<Utc>>,
+    last_sms_notification: Option<DateTime<Utc>>,
+}
+
+/// content ids are a json array, or a string like "{1,2,3}" or "1,2,3" in csv
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Ids {
+    List(Vec<u32>),
+    One(u32),
+    Text(String),
+}
+
+/// the parsed rows with their line numbers
+type Rows = Vec<(u64, Result<ImportRow, String>)>;
+
+/// split the chunks of the file into rows, a row is only parsed when it is complete
+#[derive(Debug)]
+struct Parser {
+    format: ImportFormat,
+    buf: Vec<u8>,
+    // number of lines parsed
+    lines: u64,
+    headers: Option<csv::StringRecord>,
+}
+
+/// import the rows of the stream into user_stats, the progress is sent to tx
+pub(crate) async fn import<S>(
+    pool: &PgPool,
+    mut stream: S,
+    tx: &mpsc::Sender<Result<ImportProgress, Status>>,
+) -> Result<(), Status>
+where
+    S: Stream<Item = Result<ImportRequest, Status>> + Unpin,
+{
+    let mut parser: Option<Parser> = None;
+    let mut progress = ImportProgress::default();
+    let mut records = Vec::with_capacity(BATCH_SIZE);
+
+    loop {
+        let (rows, done) = match stream.next().await {
+            Some(req) => {
+                let req = req?;
+                let parser = match &mut parser {
+                    Some(parser) => parser,
+                    None => parser.insert(Parser::new(req.format())?),
+                };
+                (parser.feed(&req.data)?, false)
+            }
+            None => match &mut parser {
+                Some(parser) => (parser.finish()?, true),
+                None => (vec![], true),
+            },
+        };
+
+        for (line, row) in rows {
+            progress.rows_read += 1;
+            match row.and_then(|r| r.into_record()) {
+                Ok(record) => records.push((line, record)),
+                Err(message) => progress.errors.push(ImportRowError { line, message }),
+            }
+            if records.len() >= BATCH_SIZE || progress.errors.len() >= BATCH_SIZE {
+                progress.rows_imported += upsert(pool, mem::take(&mut records)).await?;
+                if tx.send(Ok(take_progress(&mut progress))).await.is_err() {
+                    info!("Import is cancelled by client");
+                    return Ok(());
+                }
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    progress.rows_imported += upsert(pool, records).await?;
+    progress.done = true;
+    info!(
+        "Imported {} of {} rows",
+        progress.rows_imported, progress.rows_read
+    );
+    let _ = tx.send(Ok(progress)).await;
+    Ok(())
+}
+
+/// the progress to send, the errors are only reported once
+fn take_progress(progress: &mut ImportProgress) -> ImportProgress {
+    let errors = mem::take(&mut progress.errors);
+    ImportProgress {
+        errors,
+        ..progress.clone()
+    }
+}
+
+// the contents of an imported array replace the contents of the state, the ones moved to
+// another state are updated, the progress of a started content is unknown
+const IMPORT_PROGRESS_SQL: &str = r#"
+WITH latest AS (
+    SELECT DISTINCT ON (email) * FROM import_staging ORDER BY email, line DESC
+), removed AS (
+    DELETE FROM user_content_progress p USING latest s
+    WHERE p.email = s.email
+        AND ((p.state = 'finished' AND s.finished IS NOT NULL)
+            OR (p.state = 'started' AND s.started_but_not_finished IS NOT NULL)
+            OR (p.state = 'viewed' AND s.viewed_but_not_started IS NOT NULL))
+        AND p.content_id <> ALL(COALESCE(s.finished, '{}')
+            || COALESCE(s.started_but_not_finished, '{}')
+            || COALESCE(s.viewed_but_not_started, '{}'))
+)
+INSERT INTO user_content_progress AS p (email, content_id, state, progress)
+SELECT DISTINCT ON (s.email, c.content_id) s.email, c.content_id, c.state::content_state, c.progress
+FROM latest s, LATERAL (
+    SELECT unnest(s.finished), 'finished', 100, 0
+    UNION ALL SELECT unnest(s.started_but_not_finished), 'started', 0, 1
+    UNION ALL SELECT unnest(s.viewed_but_not_started), 'viewed', 0, 2
+) AS c(content_id, state, progress, rank)
+ORDER BY s.email, c.content_id, c.rank
+ON CONFLICT (email, content_id) DO UPDATE SET
+    state = EXCLUDED.state,
+    progress = EXCLUDED.progress,
+    updated_at = EXCLUDED.updated_at
+WHERE p.state <> EXCLUDED.state"#;
+
+/// COPY the records into a staging table, then merge them into user_stats. The rows of the
+/// same email are merged into the last one of the batch
+async fn upsert(pool: &PgPool, records: Vec<(u64, Vec<String>)>) -> Result<u64, Status> {
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for (line, record) in &records {
+        writer
+            .write_field(line.to_string())
+            .and_then(|_| writer.write_record(record))
+            .map_err(|e| Status::internal(format!("Failed to write record: {}", e)))?;
+    }
+    let data = writer
+        .into_inner()
+        .map_err(|e| Status::internal(format!("Failed to write records: {}", e)))?;
+
+    let columns = USER_STATS_COLUMNS.iter().map(|c| c.name).join(", ");
+    let mut ts = pool.begin().await.map_err(to_status)?;
+    sqlx::query(&format!(
+        "CREATE TEMP TABLE import_staging (line BIGINT NOT NULL, LIKE {}) ON COMMIT DROP",
+        USER_STATS_TABLE
+    ))
+    .execute(&mut *ts)
+    .await
+    .map_err(to_status)?;
+    // created_at is only NOT NULL as the partition key, it defaults to the existing one
+    sqlx::query("ALTER TABLE import_staging ALTER COLUMN created_at DROP NOT NULL")
+        .execute(&mut *ts)
+        .await
+        .map_err(to_status)?;
+
+    let mut copy = ts
+        .copy_in_raw(&format!(
+            "COPY import_staging (line, {}) FROM STDIN WITH (FORMAT csv)",
+            columns
+        ))
+        .await
+        .map_err(to_status)?;
+    copy.send(data).await.map_err(to_status)?;
+    copy.finish().await.map_err(to_status)?;
+
+    // the partitioned user_stats has no unique index of email to upsert ON CONFLICT, the
+    // existing users are updated first, then the new ones are inserted
+    let merged = format!(
+        "SELECT {merged} FROM (\
+             SELECT DISTINCT ON (email) * FROM import_staging ORDER BY email, line DESC\
+         ) s LEFT JOIN {table} u ON u.email = s.email",
+        table = USER_STATS_TABLE,
+        merged = USER_STATS_COLUMNS
+            .iter()
+            .map(|c| format!("{} AS {}", merge_column(c), c.name))
+            .join(", "),
+    );
+    let update = format!(
+        "UPDATE {table} u SET {updates} FROM ({merged}) m WHERE u.email = m.email",
+        table = USER_STATS_TABLE,
+        merged = merged,
+        updates = USER_STATS_COLUMNS
+            .iter()
+            .filter(|c| c.name != "email")
+            .map(|c| format!("{0} = m.{0}", c.name))
+            .join(", "),
+    );
+    let insert = format!(
+        "INSERT INTO {table} ({columns}) {merged} WHERE u.email IS NULL",
+        table = USER_STATS_TABLE,
+        columns = columns,
+        merged = merged,
+    );
+    let staged: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT email) FROM import_staging")
+        .fetch_one(&mut *ts)
+        .await
+        .map_err(to_status)?;
+    // a new email inserted by another transaction meanwhile is skipped by the trigger of
+    // user_stats once the other one commits, it is updated by the next attempt. The update is
+    // the same for the users merged already
+    let mut upserted = 0;
+    for _ in 0..MERGE_ATTEMPTS {
+        let updated = sqlx::query(&update)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        let inserted = sqlx::query(&insert)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        upserted = updated.rows_affected() + inserted.rows_affected();
+        if upserted == staged as u64 {
+            break;
+        }
+        info!(
+            "{} of {} users are inserted meanwhile, merge again",
+            staged as u64 - upserted,
+            staged
+        );
+    }
+    if upserted != staged as u64 {
+        return Err(Status::aborted(format!(
+            "{} of {} users are changed concurrently, import them again",
+            staged as u64 - upserted,
+            staged
+        )));
+    }
+    sqlx::query(IMPORT_PROGRESS_SQL)
+        .execute(&mut *ts)
+        .await
+        .map_err(to_status)?;
+    ts.commit().await.map_err(to_status)?;
+    Ok(upserted)
+}
+
+/// the value of the column to upsert, new users get the defaults of the table, and empty
+/// arrays so that they could be decoded as `User`
+fn merge_column(column: &Column) -> String {
+    match (column.name, column.kind) {
+        ("email" | "name", _) => format!("s.{}", column.name),
+        ("gender", _) => "COALESCE(s.gender, u.gender, 'unknown')".to_string(),
+        ("created_at", _) => "COALESCE(s.created_at, u.created_at, CURRENT_TIMESTAMP)".to_string(),
+        // derived from user_content_progress by its triggers
+        ("viewed_but_not_started" | "started_but_not_finished" | "finished", _) => {
+            format!("COALESCE(u.{}, '{{}}')", column.name)
+        }
+        (name, ColumnKind::IdArray) => format!("COALESCE(s.{0}, u.{0}, '{{}}')", name),
+        (name, _) => format!("COALESCE(s.{0}, u.{0})", name),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl Parser {
+    fn new(format: ImportFormat) -> Result<Self, Status> {
+        if format == ImportFormat::Unspecified {
+            return Err(Status::invalid_argument("import format is required"));
+        }
+        Ok(Self {
+            format,
+            buf: vec![],
+            lines: 0,
+            headers: None,
+        })
+    }
+
+    /// parse the complete rows in the buffer, the rest is kept for the next chunk
+    fn feed(&mut self, data: &[u8]) -> Result<Rows, Status> {
+        self.buf.extend_from_slice(data);
+        match self.split_at() {
+            Some(pos) => {
+                let rest = self.buf.split_off(pos + 1);
+                let chunk = mem::replace(&mut self.buf, rest);
+                self.parse(&chunk)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// parse the last row, which might not end with a newline
+    fn finish(&mut self) -> Result<Rows, Status> {
+        let chunk = mem::take(&mut self.buf);
+        self.parse(&chunk)
+    }
+
+    /// position of the last newline that ends a row. A newline inside a quoted csv field is not
+    /// the end of a row, the field is quoted if there are odd number of quotes before it
+    fn split_at(&self) -> Option<usize> {
+        match self.format {
+            ImportFormat::Csv => {
+                let mut quoted = false;
+                let mut pos = None;
+                for (i, &b) in self.buf.iter().enumerate() {
+                    match b {
+                        b'"' => quoted = !quoted,
+                        b'\n' if !quoted => pos = Some(i),
+                        _ => {}
+                    }
+                }
+                pos
+            }
+            _ => self.buf.iter().rposition(|&b| b == b'\n'),
+        }
+    }
+
+    fn parse(&mut self, chunk: &[u8]) -> Result<Rows, Status> {
+        let ret = match self.format {
+            ImportFormat::Csv => self.parse_csv(chunk)?,
+            _ => self.parse_jsonl(chunk),
+        };
+        self.lines += chunk.iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(ret)
+    }
+
+    fn parse_jsonl(&self, chunk: &[u8]) -> Rows {
+        chunk
+            .split(|&b| b == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.trim_ascii().is_empty())
+            .map(|(i, line)| {
+                let row = serde_json::from_slice(line).map_err(|e| e.to_string());
+                (self.lines + i as u64 + 1, row)
+            })
+            .collect()
+    }
+
+    fn parse_csv(&mut self, chunk: &[u8]) -> Result<Rows, Status> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(chunk);
+        let mut rows = vec![];
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    let line = e.position().map_or(0, |p| p.line());
+                    rows.push((self.lines + line, Err(e.to_string())));
+                    continue;
+                }
+            };
+            let line = self.lines + record.position().map_or(0, |p| p.line());
+            let Some(headers) = &self.headers else {
+                self.headers = Some(validate_headers(record)?);
+                continue;
+            };
+            let row = if record.len() != headers.len() {
+                Err(format!(
+                    "expect {} fields, got {}",
+                    headers.len(),
+                    record.len()
+                ))
+            } else {
+                record.deserialize(Some(headers)).map_err(|e| e.to_string())
+            };
+            rows.push((line, row));
+        }
+        Ok(rows)
+    }
+}
+
+/// the header of csv must be the columns of user_stats, with email and name
+#[allow(clippy::result_large_err)]
+fn validate_headers(headers: csv::StringRecord) -> Result<csv::StringRecord, Status> {
+    if let Some(name) = headers.iter().find(|h| Column::find(h).is_none()) {
+        return Err(Status::invalid_argument(format!(
+            "unknown column {} of {}",
+            name, USER_STATS_TABLE
+        )));
+    }
+    for name in ["email", "name"] {
+        if !headers.iter().any(|h| h == name) {
+            return Err(Status::invalid_argument(format!(
+                "column {} is required",
+                name
+            )));
+        }
+    }
+    Ok(headers)
+}
+
+impl ImportRow {
+    /// validate the row, and convert it to the fields of COPY in the order of the columns of
+    /// user_stats. NULL is an empty field
+    fn into_record(self) -> Result<Vec<String>, String> {
+        if self.email.is_empty() || self.email.len() > MAX_EMAIL_LEN || !self.email.contains('@') {
+            return Err(format!("invalid email {:?}", self.email));
+        }
+        if self.name.is_empty() || self.name.chars().count() > MAX_NAME_LEN {
+            return Err(format!("invalid name {:?}", self.name));
+        }
+        if let Some(gender) = &self.gender {
+            if !["female", "male", "unknown"].contains(&gender.as_str()) {
+                return Err(format!("invalid gender {:?}", gender));
+            }
+        }
+
+        let ts = |dt: Option<DateTime<Utc>>| dt.map(|dt| dt.to_rfc3339()).unwrap_or_default();
+        let ids = |ids: Option<Ids>| -> Result<String, String> {
+            let Some(ids) = ids else {
+                return Ok(String::new());
+            };
+            let ids = ids.into_content_ids()?;
+            Ok(format!("{{{}}}", ids.iter().join(",")))
+        };
+        Ok(vec![
+            self.email,
+            self.name,
+            self.gender.unwrap_or_default(),
+            ts(self.created_at),
+            ts(self.last_visited_at),
+            ts(self.last_watched_at),
+            ids(self.recent_watched)?,
+            ids(self.viewed_but_not_started)?,
+            ids(self.started_but_not_finished)?,
+            ids(self.finished)?,
+            ts(self.last_email_notification),
+            ts(self.last_in_app_notification),
+            ts(self.last_sms_notification),
+        ])
+    }
+}
+
+impl Ids {
+    fn into_content_ids(self) -> Result<Vec<i32>, String> {
+        let ids = match self {
+            Ids::List(ids) => ids,
+            Ids::One(id) => vec![id],
+            Ids::Text(s) => s
+                .trim()
+                .trim_start_matches(['{', '['])
+                .trim_end_matches(['}', ']'])
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse()
+                        .map_err(|_| format!("invalid content id {:?}", id))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        to_content_ids(&ids).map_err(|e| e.message().to_string())
+    }
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to import user stats: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::stream;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{Gender, UserProfile},
+        test_utils::get_test_pool,
+    };
+
+    fn lines(rows: &[(u64, Result<ImportRow, String>)]) -> Vec<u64> {
+        rows.iter().map(|(line, _)| *line).collect()
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn run(
+        pool: &PgPool,
+        format: ImportFormat,
+        chunks: &[&str],
+    ) -> Result<Vec<ImportProgress>, Status> {
+        let reqs = chunks
+            .iter()
+            .map(|c| {
+                Ok(ImportRequest {
+                    format: format as i32,
+                    data: c.as_bytes().to_vec(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let (tx, mut rx) = mpsc::channel(16);
+        import(pool, stream::iter(reqs), &tx).await?;
+        drop(tx);
+        let mut ret = vec![];
+        while let Some(progress) = rx.recv().await {
+            ret.push(progress?);
+        }
+        Ok(ret)
+    }
+
+    #[test]
+    fn csv_row_split_across_chunks_should_work() -> Result<()> {
+        let mut parser = Parser::new(ImportFormat::Csv)?;
+        assert!(parser.feed(b"email,name,recent_watched\n")?.is_empty());
+        assert!(parser.headers.is_some());
+        // the newline in the quoted name is not the end of the row
+        let rows = parser.feed(b"tyr@acme.org,\"Tyr\nChen\",\"{1,")?;
+        assert!(rows.is_empty());
+        let rows = parser.feed(b"2}\"\nalice@acme.org,Alice,3\nbob")?;
+        assert_eq!(lines(&rows), [2, 4]);
+        let row = rows.into_iter().next().unwrap().1.unwrap();
+        assert_eq!(row.name, "Tyr\nChen");
+        assert_eq!(row.into_record().unwrap()[6], "{1,2}");
+
+        let rows = parser.finish()?;
+        assert_eq!(lines(&rows), [5]);
+        assert_eq!(rows[0].1.as_ref().unwrap_err(), "expect 3 fields, got 1");
+        Ok(())
+    }
+
+    #[test]
+    fn jsonl_rows_should_be_validated() -> Result<()> {
+        let mut parser = Parser::new(ImportFormat::Jsonl)?;
+        let data = r#"{"email":"tyr@acme.org","name":"Tyr","finished":[1,2]}
+
+{"email":"tyr","name":"Tyr"}
+{"email":"tyr@acme.org","name":"Tyr","gender":"other"}
+{"email":"tyr@acme.org","name":"Tyr","finished":"{1,x}"}
+{"email":"tyr@acme.org","name":"Tyr","finished":[4294967295]}
+{"email":"tyr@acme.org"}"#;
+        let rows = parser.feed(data.as_bytes())?;
+        let rows = rows.into_iter().chain(parser.finish()?).collect::<Vec<_>>();
+        assert_eq!(lines(&rows), [1, 3, 4, 5, 6, 7]);
+
+        let ret = rows
+            .into_iter()
+            .map(|(_, row)| row.and_then(|r| r.into_record()))
+            .collect::<Vec<_>>();
+        assert_eq!(ret[0].as_ref().unwrap()[9], "{1,2}");
+        assert_eq!(ret[1].as_ref().unwrap_err(), "invalid email \"tyr\"");
+        assert_eq!(ret[2].as_ref().unwrap_err(), "invalid gender \"other\"");
+        assert_eq!(ret[3].as_ref().unwrap_err(), "invalid content id \"x\"");
+        assert_eq!(
+            ret[4].as_ref().unwrap_err(),
+            "invalid content id 4294967295"
+        );
+        assert!(ret[5]
+            .as_ref()
+            .unwrap_err()
+            .starts_with("missing field `name`"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_csv_headers_should_fail() -> Result<()> {
+        let mut parser = Parser::new(ImportFormat::Csv)?;
+        let status = parser.feed(b"email,name,password\n").unwrap_err();
+        assert_eq!(status.message(), "unknown column password of user_stats");
+
+        let mut parser = Parser::new(ImportFormat::Csv)?;
+        let status = parser.feed(b"email,gender\n").unwrap_err();
+        assert_eq!(status.message(), "column name is required");
+
+        let status = Parser::new(ImportFormat::Unspecified).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_should_upsert_user_stats() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let existing = "frederik.2r2jvb8l@example.org";
+        let csv = format!(
+            "email,name,gender,finished\n\
+             {existing},Frederik,,\"{{1,2,3}}\"\n\
+             new@acme.org,New,male,\n\
+             bad,Bad,,\n\
+             new@acme.org,Newer,,\n"
+        );
+        let progress = run(&pool, ImportFormat::Csv, &[&csv[..30], &csv[30..]]).await?;
+        assert_eq!(progress.len(), 1);
+        let progress = &progress[0];
+        assert!(progress.done);
+        assert_eq!(progress.rows_read, 4);
+        assert_eq!(progress.rows_imported, 2);
+        assert_eq!(progress.errors.len(), 1);
+        assert_eq!(progress.errors[0].line, 4);
+
+        let get = |email: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let sql =
+                    "SELECT email, name, gender::text AS gender, created_at, last_visited_at, \
+                    last_watched_at, recent_watched, viewed_but_not_started, \
+                    started_but_not_finished, finished, last_email_notification, \
+                    last_in_app_notification, last_sms_notification \
+                    FROM user_stats WHERE email = $1";
+                sqlx::query_as::<_, UserProfile>(sql)
+                    .bind(email)
+                    .fetch_one(&pool)
+                    .await
+            }
+        };
+        // the columns not in the file are kept
+        let user = get(existing).await?;
+        assert_eq!(user.name, "Frederik");
+        assert_eq!(user.finished, [1, 2, 3]);
+        assert_eq!(user.started_but_not_finished, [306577, 368582, 315580]);
+        assert_eq!(user.gender, Gender::Female as i32);
+        // the imported contents replace the finished ones in the progress
+        let states: Vec<(i32, String)> = sqlx::query_as(
+            "SELECT content_id, state::text FROM user_content_progress \
+             WHERE email = $1 AND state = 'finished' ORDER BY content_id",
+        )
+        .bind(existing)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(
+            states,
+            [
+                (1, "finished".to_string()),
+                (2, "finished".to_string()),
+                (3, "finished".to_string()),
+            ]
+        );
+        // the last row of the same email wins, defaults are used for the new user
+        let user = get("new@acme.org").await?;
+        assert_eq!(user.name, "Newer");
+        assert_eq!(user.gender, Gender::Unknown as i32);
+        assert!(user.created_at.is_some());
+        assert!(user.finished.is_empty());
+
+        let jsonl =
+            r#"{"email":"new@acme.org","name":"New","gender":"female","recent_watched":[9]}"#;
+        let progress = run(&pool, ImportFormat::Jsonl, &[jsonl]).await?;
+        assert_eq!(progress[0].rows_imported, 1);
+        let user = get("new@acme.org").await?;
+        assert_eq!(user.gender, Gender::Female as i32);
+        assert_eq!(user.recent_watched, [9]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reimport_should_update_progress() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let started =
+            format!("{{\"email\":\"{email}\",\"name\":\"F\",\"started_but_not_finished\":[7]}}");
+        run(&pool, ImportFormat::Jsonl, &[&started]).await?;
+        let finished = format!("{{\"email\":\"{email}\",\"name\":\"F\",\"finished\":[7,8]}}");
+        run(&pool, ImportFormat::Jsonl, &[&finished]).await?;
+
+        let state: String = sqlx::query_scalar(
+            "SELECT state::text FROM user_content_progress WHERE email = $1 AND content_id = 7",
+        )
+        .bind(email)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(state, "finished");
+
+        // the arrays of user_stats are the ones derived from the progress
+        let arrays = "SELECT viewed_but_not_started, started_but_not_finished, finished FROM";
+        let derived: (Vec<i32>, Vec<i32>, Vec<i32>) =
+            sqlx::query_as(&format!("{arrays} user_content_arrays WHERE email = $1"))
+                .bind(email)
+                .fetch_one(&pool)
+                .await?;
+        let stored: (Vec<i32>, Vec<i32>, Vec<i32>) =
+            sqlx::query_as(&format!("{arrays} user_stats WHERE email = $1"))
+                .bind(email)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(derived, stored);
+        assert!(stored.1.is_empty());
+        assert_eq!(stored.2, [7, 8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_should_merge_users_inserted_concurrently() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "race@acme.org";
+        let mut other = pool.begin().await?;
+        sqlx::query("INSERT INTO user_stats (email, name) VALUES ($1, 'Other')")
+            .bind(email)
+            .execute(&mut *other)
+            .await?;
+
+        // the import waits for the other transaction to claim the email
+        let csv = format!("email,name\n{email},Imported\n");
+        let import = tokio::spawn({
+            let pool = pool.clone();
+            async move { run(&pool, ImportFormat::Csv, &[&csv]).await }
+        });
+        wait_for_lock(&pool).await?;
+        other.commit().await?;
+
+        let progress = import.await??;
+        assert_eq!(progress[0].rows_imported, 1);
+        let name: String = sqlx::query_scalar("SELECT name FROM user_stats WHERE email = $1")
+            .bind(email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(name, "Imported");
+        Ok(())
+    }
+
+    /// wait until a session of the database is blocked by a lock
+    async fn wait_for_lock(pool: &PgPool) -> Result<()> {
+        let sql = "SELECT EXISTS (SELECT 1 FROM pg_stat_activity \
+                   WHERE datname = current_database() AND wait_event_type = 'Lock')";
+        for _ in 0..500 {
+            if sqlx::query_scalar(sql).fetch_one(pool).await? {
+                return Ok(());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        anyhow::bail!("no session is waiting for a lock")
+    }
+}
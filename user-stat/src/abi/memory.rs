@@ -0,0 +1,787 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use futures::stream;
+use itertools::Itertools;
+use prost_types::Timestamp;
+use tonic::Status;
+
+use super::{
+    cap::RESERVE_BATCH_SIZE,
+    filter::MAX_FILTER_DEPTH,
+    page::Pagination,
+    query::{to_content_ids, ts_to_utc, utc_to_ts, Column, ColumnKind},
+    store::UserStore,
+};
+use crate::{
+    pb::{
+        filter::Expr, CompareOp, CountResponse, Filter, Gender, IdOp, MarkNotifiedRequest,
+        MarkNotifiedResponse, NotificationChannel, QueryRequest, ReleaseRequest, ReleaseResponse,
+        ReserveRequest, ReserveResponse, TimeQuery, User, UserPage, UserProfile,
+    },
+    ProfileStream, ReserveStream, ResponseStream,
+};
+
+/// an in-memory store, the queries are evaluated natively with the same semantics as the sql,
+/// so that the service could be tested without a postgres server.
+///
+/// The id arrays are never NULL in memory, a missing array is an empty array, as the filters
+/// treat a NULL array in postgres.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<MemoryData>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    /// users sorted by email, the key of pagination
+    users: BTreeMap<String, UserProfile>,
+    /// (email, channel, notified_at) of each notification
+    history: BTreeSet<(String, NotificationChannel, DateTime<Utc>)>,
+}
+
+/// the compiled QueryRequest, evaluated with the three-valued logic of sql: None is NULL
+#[derive(Debug)]
+enum Predicate {
+    All(Vec<Predicate>),
+    Any(Vec<Predicate>),
+    Not(Box<Predicate>),
+    Time {
+        column: &'static str,
+        lower: Option<DateTime<Utc>>,
+        upper: Option<DateTime<Utc>>,
+    },
+    Ids {
+        column: &'static str,
+        ids: Vec<i32>,
+        op: IdOp,
+    },
+    Gender(Gender),
+    Null {
+        column: &'static str,
+        is_null: bool,
+    },
+    ArrayLength {
+        column: &'static str,
+        op: CompareOp,
+        length: usize,
+    },
+    Cap {
+        channel: NotificationChannel,
+        window: Duration,
+        max_count: u32,
+    },
+}
+
+#[allow(clippy::result_large_err)]
+impl MemoryStore {
+    pub fn new(users: impl IntoIterator<Item = UserProfile>) -> Self {
+        let store = Self::default();
+        for user in users {
+            store.insert(user);
+        }
+        store
+    }
+
+    /// insert the user, or replace the user with the same email
+    pub fn insert(&self, user: UserProfile) {
+        self.write().users.insert(user.email.clone(), user);
+    }
+
+    pub fn get(&self, email: &str) -> Option<UserProfile> {
+        self.read().users.get(email).cloned()
+    }
+
+    /// the profiles matching the query in the order of email
+    fn select(&self, query: &QueryRequest, page: &Pagination) -> Result<Vec<UserProfile>, Status> {
+        let predicate = Predicate::new(query)?;
+        let now = Utc::now();
+        let data = self.read();
+        let users = data
+            .users
+            .values()
+            .filter(|u| predicate.eval(u, &data, now) == Some(true));
+        Ok(page
+            .paginate(users, |u| u.email.as_str())
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, MemoryData> {
+        self.data.read().expect("memory store is poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, MemoryData> {
+        self.data.write().expect("memory store is poisoned")
+    }
+}
+
+#[tonic::async_trait]
+impl UserStore for MemoryStore {
+    async fn query(
+        &self,
+        query: &QueryRequest,
+        _timeout: Option<Duration>,
+    ) -> Result<ResponseStream, Status> {
+        let page = Pagination::for_stream(&query.page_token, query.page_size)?;
+        let users = self.select(query, &page)?.into_iter().map(to_user);
+        Ok(Box::pin(stream::iter(users.map(Ok))))
+    }
+
+    async fn query_profiles(
+        &self,
+        query: &QueryRequest,
+        _timeout: Option<Duration>,
+    ) -> Result<ProfileStream, Status> {
+        // validate the field mask
+        query.profile_columns()?;
+        let paths = query
+            .field_mask
+            .as_ref()
+            .map(|m| m.paths.clone())
+            .unwrap_or_default();
+        let page = Pagination::for_stream(&query.page_token, query.page_size)?;
+        let profiles = self
+            .select(query, &page)?
+            .into_iter()
+            .map(move |u| mask(u, &paths));
+        Ok(Box::pin(stream::iter(profiles.map(Ok))))
+    }
+
+    async fn query_page(
+        &self,
+        query: &QueryRequest,
+        _timeout: Option<Duration>,
+    ) -> Result<UserPage, Status> {
+        let page = Pagination::for_page(&query.page_token, query.page_size)?;
+        let users = self
+            .select(query, &page)?
+            .into_iter()
+            .map(to_user)
+            .collect();
+        Ok(UserPage::new(users, query.page_size))
+    }
+
+    async fn count(
+        &self,
+        query: &QueryRequest,
+        _timeout: Option<Duration>,
+    ) -> Result<CountResponse, Status> {
+        let predicate = Predicate::new(query)?;
+        let now = Utc::now();
+        let data = self.read();
+        let count = data
+            .users
+            .values()
+            .filter(|u| predicate.eval(u, &data, now) == Some(true))
+            .count();
+        Ok(CountResponse {
+            count: count as u64,
+        })
+    }
+
+    async fn mark_notified(
+        &self,
+        req: &MarkNotifiedRequest,
+    ) -> Result<MarkNotifiedResponse, Status> {
+        let notifications = req.validate()?;
+        let mut data = self.write();
+        let mut updated = HashSet::new();
+        for (channel, email, at) in notifications {
+            data.history.insert((email.clone(), channel, at));
+
+            let column = channel.column().expect("channel is validated");
+            let Some(user) = data.users.get_mut(&email) else {
+                continue;
+            };
+            // an older notification never overrides a newer one
+            let last = timestamp_mut(user, column).expect("column is a timestamp");
+            if last.as_ref().and_then(|t| ts_to_utc(t).ok()) < Some(at) {
+                *last = Some(utc_to_ts(at));
+            }
+            updated.insert((channel, email));
+        }
+        Ok(MarkNotifiedResponse {
+            updated: updated.len() as u64,
+        })
+    }
+
+    async fn reserve(
+        &self,
+        req: &ReserveRequest,
+        _timeout: Option<Duration>,
+    ) -> Result<ReserveStream, Status> {
+        let (query, channel) = req.validate()?;
+        let allowed = Predicate::new(&query)?;
+        let matches = Predicate::new(&QueryRequest {
+            caps: vec![],
+            ..query
+        })?;
+        let now = Utc::now();
+        let reserved_at = now
+            .duration_trunc(TimeDelta::microseconds(1))
+            .expect("microseconds never overflow");
+        let column = channel.column().expect("channel is validated");
+
+        // the write lock makes the check and the record atomic
+        let mut data = self.write();
+        let mut emails = vec![];
+        let mut suppressed = 0;
+        for user in data.users.values() {
+            if matches.eval(user, &data, now) != Some(true) {
+                continue;
+            }
+            match allowed.eval(user, &data, now) {
+                Some(true) => emails.push(user.email.clone()),
+                _ => suppressed += 1,
+            }
+        }
+
+        let mut users = Vec::with_capacity(emails.len());
+        for email in emails {
+            data.history.insert((email.clone(), channel, reserved_at));
+            let user = data.users.get_mut(&email).expect("user is matched");
+            let last = timestamp_mut(user, column).expect("column is a timestamp");
+            if last.as_ref().and_then(|t| ts_to_utc(t).ok()) < Some(reserved_at) {
+                *last = Some(utc_to_ts(reserved_at));
+            }
+            users.push(to_user(user.clone()));
+        }
+        let ret = ReserveResponse {
+            users,
+            suppressed,
+            reserved_at: Some(utc_to_ts(reserved_at)),
+        };
+        let batches = ret.into_batches(RESERVE_BATCH_SIZE).into_iter().map(Ok);
+        Ok(Box::pin(stream::iter(batches)))
+    }
+
+    async fn release(&self, req: &ReleaseRequest) -> Result<ReleaseResponse, Status> {
+        let (channel, reserved_at) = req.validate()?;
+        let column = channel.column().expect("channel is validated");
+        let mut data = self.write();
+        let mut released = 0;
+        for email in &req.emails {
+            if !data.history.remove(&(email.clone(), channel, reserved_at)) {
+                continue;
+            }
+            released += 1;
+
+            let last = data
+                .history
+                .iter()
+                .filter(|(e, c, _)| e == email && *c == channel)
+                .map(|(_, _, at)| *at)
+                .max();
+            let Some(user) = data.users.get_mut(email) else {
+                continue;
+            };
+            // the users notified again since are kept
+            let current = timestamp_mut(user, column).expect("column is a timestamp");
+            if current.as_ref().and_then(|t| ts_to_utc(t).ok()) == Some(reserved_at) {
+                *current = last.map(utc_to_ts);
+            }
+        }
+        Ok(ReleaseResponse { released })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl Predicate {
+    /// compile the query, the errors are the same as building the sql
+    fn new(query: &QueryRequest) -> Result<Self, Status> {
+        let mut all = vec![];
+        for (name, tq) in query.timestamps.iter().sorted_by_key(|(k, _)| *k) {
+            let column = Column::find_with_kind(name, ColumnKind::Timestamp)?;
+            all.push(Self::time(column, tq)?);
+        }
+
+        for (name, iq) in query.ids.iter().sorted_by_key(|(k, _)| *k) {
+            let column = Column::find_with_kind(name, ColumnKind::IdArray)?;
+            all.push(Self::Ids {
+                column: column.name,
+                ids: to_content_ids(&iq.ids)?,
+                op: IdOp::Contains,
+            });
+        }
+
+        if let Some(filter) = &query.filter {
+            all.push(Self::from_filter(filter, 0)?);
+        }
+
+        for cap in &query.caps {
+            let (channel, window) = cap.validate()?;
+            all.push(Self::Cap {
+                channel,
+                window: Duration::from_secs_f64(window),
+                max_count: cap.max_count,
+            });
+        }
+        Ok(Self::All(all))
+    }
+
+    fn from_filter(filter: &Filter, depth: usize) -> Result<Self, Status> {
+        if depth >= MAX_FILTER_DEPTH {
+            return Err(Status::invalid_argument(format!(
+                "filter is nested too deep, max depth is {}",
+                MAX_FILTER_DEPTH
+            )));
+        }
+
+        let Some(expr) = &filter.expr else {
+            return Err(Status::invalid_argument("filter expression is empty"));
+        };
+
+        let predicate = match expr {
+            Expr::And(group) => Self::All(
+                group
+                    .filters
+                    .iter()
+                    .map(|f| Self::from_filter(f, depth + 1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Or(group) => Self::Any(
+                group
+                    .filters
+                    .iter()
+                    .map(|f| Self::from_filter(f, depth + 1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Not(filter) => Self::Not(Box::new(Self::from_filter(filter, depth + 1)?)),
+            Expr::Time(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::Timestamp)?;
+                Self::time(column, &f.range.clone().unwrap_or_default())?
+            }
+            Expr::Ids(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::IdArray)?;
+                let op = IdOp::try_from(f.op)
+                    .map_err(|_| Status::invalid_argument(format!("invalid id op {}", f.op)))?;
+                Self::Ids {
+                    column: column.name,
+                    ids: to_content_ids(&f.ids)?,
+                    op,
+                }
+            }
+            Expr::Gender(f) => {
+                let gender = Gender::try_from(f.gender)
+                    .ok()
+                    .filter(|g| g.to_db().is_some())
+                    .ok_or_else(|| {
+                        Status::invalid_argument(format!("invalid gender {}", f.gender))
+                    })?;
+                Self::Gender(gender)
+            }
+            Expr::Null(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::Timestamp)?;
+                Self::Null {
+                    column: column.name,
+                    is_null: f.is_null,
+                }
+            }
+            Expr::ArrayLength(f) => {
+                let column = Column::find_with_kind(&f.column, ColumnKind::IdArray)?;
+                let op = CompareOp::try_from(f.op).map_err(|_| {
+                    Status::invalid_argument(format!("invalid compare op {}", f.op))
+                })?;
+                Self::ArrayLength {
+                    column: column.name,
+                    op,
+                    length: f.length as usize,
+                }
+            }
+        };
+        Ok(predicate)
+    }
+
+    fn time(column: &Column, tq: &TimeQuery) -> Result<Self, Status> {
+        let (lower, upper) = tq.bounds(Utc::now())?;
+        Ok(Self::Time {
+            column: column.name,
+            lower,
+            upper,
+        })
+    }
+
+    /// FALSE wins over NULL in AND, TRUE wins over NULL in OR, like sql
+    fn eval(&self, user: &UserProfile, data: &MemoryData, now: DateTime<Utc>) -> Option<bool> {
+        match self {
+            Self::All(predicates) => {
+                let values = predicates
+                    .iter()
+                    .map(|p| p.eval(user, data, now))
+                    .collect::<Vec<_>>();
+                if values.contains(&Some(false)) {
+                    Some(false)
+                } else if values.contains(&None) {
+                    None
+                } else {
+                    Some(true)
+                }
+            }
+            Self::Any(predicates) => {
+                let values = predicates
+                    .iter()
+                    .map(|p| p.eval(user, data, now))
+                    .collect::<Vec<_>>();
+                if values.contains(&Some(true)) {
+                    Some(true)
+                } else if values.contains(&None) {
+                    None
+                } else {
+                    Some(false)
+                }
+            }
+            Self::Not(p) => p.eval(user, data, now).map(|v| !v),
+            Self::Time {
+                column,
+                lower,
+                upper,
+            } => {
+                if lower.is_none() && upper.is_none() {
+                    return Some(true);
+                }
+                let value = user.timestamp(column)?;
+                Some(lower.is_none_or(|l| value >= l) && upper.is_none_or(|u| value <= u))
+            }
+            Self::Ids { column, ids, op } => {
+                let values = user.id_array(column);
+                Some(match op {
+                    IdOp::Contains => ids.iter().all(|id| values.contains(id)),
+                    IdOp::Overlaps => ids.iter().any(|id| values.contains(id)),
+                })
+            }
+            Self::Gender(gender) => Some(user.gender == *gender as i32),
+            Self::Null { column, is_null } => Some(user.timestamp(column).is_none() == *is_null),
+            Self::ArrayLength { column, op, length } => {
+                let len = user.id_array(column).len();
+                Some(match op {
+                    CompareOp::Eq => len == *length,
+                    CompareOp::Ne => len != *length,
+                    CompareOp::Lt => len < *length,
+                    CompareOp::Le => len <= *length,
+                    CompareOp::Gt => len > *length,
+                    CompareOp::Ge => len >= *length,
+                })
+            }
+            Self::Cap {
+                channel,
+                window,
+                max_count,
+            } => {
+                // notified after now - window
+                let within =
+                    |at: &DateTime<Utc>| (now - *at).to_std().map_or(true, |d| d < *window);
+                let count = data
+                    .history
+                    .iter()
+                    .filter(|(email, c, at)| *email == user.email && c == channel && within(at))
+                    .count();
+                Some(count < *max_count as usize)
+            }
+        }
+    }
+}
+
+/// the watch progress is not tracked in memory, it is always 0
+fn to_user(user: UserProfile) -> User {
+    User {
+        email: user.email,
+        name: user.name,
+        started_progress: vec![0; user.started_but_not_finished.len()],
+        started_but_not_finished: user.started_but_not_finished,
+    }
+}
+
+/// keep the fields in the field mask, email is always kept
+fn mask(user: UserProfile, paths: &[String]) -> UserProfile {
+    let keep = |name: &str| paths.is_empty() || paths.iter().any(|p| p == name);
+    fn pick<T: Default>(keep: bool, value: T) -> T {
+        if keep {
+            value
+        } else {
+            T::default()
+        }
+    }
+    UserProfile {
+        email: user.email,
+        name: pick(keep("name"), user.name),
+        gender: pick(keep("gender"), user.gender),
+        created_at: pick(keep("created_at"), user.created_at),
+        last_visited_at: pick(keep("last_visited_at"), user.last_visited_at),
+        last_watched_at: pick(keep("last_watched_at"), user.last_watched_at),
+        recent_watched: pick(keep("recent_watched"), user.recent_watched),
+        viewed_but_not_started: pick(keep("viewed_but_not_started"), user.viewed_but_not_started),
+        started_but_not_finished: pick(
+            keep("started_but_not_finished"),
+            user.started_but_not_finished,
+        ),
+        finished: pick(keep("finished"), user.finished),
+        last_email_notification: pick(
+            keep("last_email_notification"),
+            user.last_email_notification,
+        ),
+        last_in_app_notification: pick(
+            keep("last_in_app_notification"),
+            user.last_in_app_notification,
+        ),
+        last_sms_notification: pick(keep("last_sms_notification"), user.last_sms_notification),
+    }
+}
+
+/// the last notification time of a channel
+fn timestamp_mut<'a>(user: &'a mut UserProfile, column: &str) -> Option<&'a mut Option<Timestamp>> {
+    match column {
+        "last_email_notification" => Some(&mut user.last_email_notification),
+        "last_in_app_notification" => Some(&mut user.last_in_app_notification),
+        "last_sms_notification" => Some(&mut user.last_sms_notification),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use anyhow::Result;
+    use futures::{StreamExt, TryStreamExt};
+    use prost_types::FieldMask;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        abi::store::PgStore,
+        pb::{FrequencyCap, Notified, QueryRequestBuilder},
+        test_utils::{collect_reserved, get_test_pool, to_idquery, to_timequery},
+    };
+
+    const DAY: StdDuration = StdDuration::from_secs(24 * 3600);
+
+    async fn emails(store: &dyn UserStore, query: &QueryRequest) -> Result<Vec<String>> {
+        let users = store.query(query, None).await?;
+        let mut emails = users.map_ok(|u| u.email).try_collect::<Vec<_>>().await?;
+        emails.sort();
+        Ok(emails)
+    }
+
+    fn profile(email: &str, gender: Gender, finished: &[i32]) -> UserProfile {
+        UserProfile {
+            email: email.to_string(),
+            name: email.to_uppercase(),
+            gender: gender as i32,
+            finished: finished.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_store_should_match_postgres() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // a user created by a visit event has no content arrays
+        sqlx::query("INSERT INTO user_stats (email, name, created_at) VALUES ($1, '', now())")
+            .bind("visitor@example.com")
+            .execute(&pool)
+            .await?;
+        let pg = PgStore::new(pool, 100);
+        let profiles = pg
+            .query_profiles(&QueryRequest::default(), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        let memory = MemoryStore::new(profiles);
+
+        // notify some users, so the caps and the notification columns have something to match
+        let now = Utc::now();
+        let req = MarkNotifiedRequest {
+            notifications: vec![
+                Notified::new(
+                    "frederik.2r2jvb8l@example.org",
+                    NotificationChannel::Email,
+                    now,
+                ),
+                Notified::new(
+                    "clifford.smjmlbu8@example.net",
+                    NotificationChannel::Sms,
+                    now,
+                ),
+                Notified::new("nobody@acme.org", NotificationChannel::Email, now),
+            ],
+        };
+        assert_eq!(
+            pg.mark_notified(&req).await?,
+            memory.mark_notified(&req).await?
+        );
+
+        let queries = [
+            QueryRequestBuilder::default()
+                .timestamp(("created_at".to_string(), to_timequery(Some(220), None)))
+                .timestamp(("last_visited_at".to_string(), to_timequery(Some(50), None)))
+                .build()?,
+            QueryRequestBuilder::default()
+                .id((
+                    "started_but_not_finished".to_string(),
+                    to_idquery(&[306577]),
+                ))
+                .build()?,
+            QueryRequestBuilder::default()
+                .filter(Filter::or([
+                    Filter::gender(Gender::Unknown),
+                    Filter::array_length("finished", CompareOp::Lt, 10),
+                ]))
+                .build()?,
+            QueryRequestBuilder::default()
+                .filter(Filter::and([
+                    Filter::not(Filter::gender(Gender::Male)),
+                    Filter::ids("recent_watched", &[134782, 142093], IdOp::Overlaps),
+                ]))
+                .build()?,
+            // a NULL array is an empty array
+            QueryRequestBuilder::default()
+                .filter(Filter::not(Filter::ids(
+                    "finished",
+                    &[454057],
+                    IdOp::Overlaps,
+                )))
+                .build()?,
+            QueryRequestBuilder::default()
+                .filter(Filter::array_length(
+                    "started_but_not_finished",
+                    CompareOp::Eq,
+                    0,
+                ))
+                .build()?,
+            // NOT of a NULL timestamp is still NULL
+            QueryRequestBuilder::default()
+                .filter(Filter::not(Filter::time(
+                    "last_email_notification",
+                    to_timequery(Some(30), None),
+                )))
+                .build()?,
+            QueryRequestBuilder::default()
+                .filter(Filter::is_null("last_sms_notification", false))
+                .build()?,
+            QueryRequestBuilder::default()
+                .cap(FrequencyCap::new(NotificationChannel::Email, 1, DAY))
+                .build()?,
+            QueryRequestBuilder::default()
+                .filter(Filter::gender(Gender::Female))
+                .page_size(10u32)
+                .build()?,
+        ];
+        for query in &queries {
+            assert_eq!(
+                emails(&memory, query).await?,
+                emails(&pg, query).await?,
+                "{:?}",
+                query
+            );
+            assert_eq!(
+                memory.count(query, None).await?,
+                pg.count(query, None).await?
+            );
+            assert_eq!(
+                memory.query_page(query, None).await?,
+                pg.query_page(query, None).await?
+            );
+        }
+
+        // the users reserved are capped by the next reservation
+        let req = ReserveRequest {
+            query: Some(queries[8].clone()),
+            channel: NotificationChannel::Email as i32,
+        };
+        // and the users released are reserved again
+        let release = |ret: &ReserveResponse| ReleaseRequest {
+            channel: NotificationChannel::Email as i32,
+            reserved_at: ret.reserved_at.clone(),
+            emails: ret.users.iter().take(3).map(|u| u.email.clone()).collect(),
+        };
+        for _ in 0..2 {
+            let (mut m, mut p) = (
+                collect_reserved(memory.reserve(&req, None).await?).await?,
+                collect_reserved(pg.reserve(&req, None).await?).await?,
+            );
+            m.users.sort_by(|a, b| a.email.cmp(&b.email));
+            p.users.sort_by(|a, b| a.email.cmp(&b.email));
+            assert_eq!(
+                memory.release(&release(&m)).await?,
+                pg.release(&release(&p)).await?
+            );
+            assert_eq!((m.users, m.suppressed), (p.users, p.suppressed));
+        }
+        let m = collect_reserved(memory.reserve(&req, None).await?).await?;
+        assert_eq!(m.users.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_store_should_paginate_and_mask() -> Result<()> {
+        let memory = MemoryStore::new([
+            profile("c@acme.org", Gender::Male, &[]),
+            profile("a@acme.org", Gender::Female, &[1, 2]),
+            profile("b@acme.org", Gender::Female, &[3]),
+        ]);
+
+        let query = QueryRequestBuilder::default().page_size(2u32).build()?;
+        let page = memory.query_page(&query, None).await?;
+        let emails = page
+            .users
+            .iter()
+            .map(|u| u.email.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(emails, ["a@acme.org", "b@acme.org"]);
+
+        let query = QueryRequestBuilder::default()
+            .page_size(2u32)
+            .page_token(page.next_page_token)
+            .build()?;
+        let page = memory.query_page(&query, None).await?;
+        assert_eq!(page.users.len(), 1);
+        assert_eq!(page.users[0].email, "c@acme.org");
+        assert!(page.next_page_token.is_empty());
+
+        let query = QueryRequestBuilder::default()
+            .filter(Filter::array_length("finished", CompareOp::Ge, 1))
+            .field_mask(FieldMask {
+                paths: vec!["gender".to_string()],
+            })
+            .build()?;
+        let profiles = memory
+            .query_profiles(&query, None)
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(profiles.len(), 2);
+        let profile = profiles[0].as_ref().unwrap();
+        assert_eq!(profile.email, "a@acme.org");
+        assert_eq!(profile.gender, Gender::Female as i32);
+        assert!(profile.name.is_empty());
+        assert!(profile.finished.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_query_should_fail_in_memory() -> Result<()> {
+        let memory = MemoryStore::default();
+        let query = QueryRequestBuilder::default()
+            .id(("created_at".to_string(), to_idquery(&[1])))
+            .build()?;
+        let status = memory.count(&query, None).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "column created_at is a Timestamp column, expect IdArray"
+        );
+
+        let query = QueryRequestBuilder::default()
+            .filter(Filter::gender(Gender::Unspecified))
+            .build()?;
+        let status = memory.query(&query, None).await.err().unwrap();
+        assert_eq!(status.message(), "invalid gender 0");
+        Ok(())
+    }
+}
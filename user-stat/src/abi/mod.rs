@@ -1,31 +1,82 @@
-use core::fmt;
+mod cap;
+mod cohort;
+mod count;
+mod cursor;
+mod event;
+mod export;
+mod filter;
+mod guard;
+mod import;
+mod memory;
+mod notified;
+mod page;
+mod partition;
+mod profile;
+mod query;
+mod segment;
+mod snapshot;
+mod store;
+mod validator;
+mod watch;
+
 use std::ops::Deref;
 use std::sync::Arc;
 
-use chrono::{DateTime, TimeZone, Utc};
-use futures::stream;
-use itertools::Itertools;
+use chrono::{DateTime, Utc};
+use futures::Stream;
 use prost_types::Timestamp;
-use sqlx::PgPool;
+use sqlx::{PgPool, QueryBuilder};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Response, Status};
-use tracing::info;
-
+use tracing::warn;
+
+use self::page::Pagination;
+pub(crate) use self::watch::Changes;
+pub use self::{
+    memory::MemoryStore,
+    partition::PartitionReport,
+    store::{PgStore, UserStore},
+};
 use crate::{
     pb::{
-        user_stats_server::UserStatsServer, IdQuery, QueryRequest, QueryRequestBuilder,
-        RawQueryRequest, TimeQuery, User,
+        user_stats_server::UserStatsServer, CohortsRequest, CohortsResponse, CountResponse,
+        CreateSegmentRequest, DeleteSegmentRequest, DeleteSegmentResponse, DiffSnapshotsRequest,
+        EstimateRequest, EstimateResponse, ExportFormat, ExportRequest, GetSegmentRequest, IdQuery,
+        ImportRequest, ListSegmentsResponse, MarkNotifiedRequest, MarkNotifiedResponse,
+        QueryRequest, QueryRequestBuilder, QuerySegmentRequest, RawQueryRequest,
+        RecordEventsResponse, ReleaseRequest, ReleaseResponse, ReserveRequest, Segment,
+        SegmentSnapshot, SnapshotSegmentRequest, TimeQuery, UpdateSegmentRequest, UserEvent,
+        UserPage,
     },
-    AppConfig, ResponseStream, ServiceResult, UserStatsService, UserStatsServiceInner,
+    AppConfig, DiffStream, ExportStream, ImportStream, ProfileStream, ReserveStream,
+    ResponseStream, ServiceResult, UserStatsService, UserStatsServiceInner,
 };
 
+/// number of import progress buffered for the client
+const IMPORT_PROGRESS_BUFFER: usize = 16;
+
+#[allow(clippy::result_large_err)]
 impl UserStatsService {
     pub async fn new(config: AppConfig) -> Self {
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .expect("Failed to connect to db");
-        let inner = UserStatsServiceInner { config, pool };
+        let store = PgStore::new(pool, config.server.fetch_size);
+        Self::with_store(config, store)
+    }
+
+    /// the service backed by the store, the features only implemented in sql are unavailable
+    /// without a postgres store
+    pub fn with_store(config: AppConfig, store: impl UserStore) -> Self {
+        let inner = UserStatsServiceInner {
+            config,
+            store: Box::new(store),
+            changes: Default::default(),
+        };
         Self {
             inner: Arc::new(inner),
+            deadline: None,
         }
     }
 
@@ -34,26 +85,193 @@ impl UserStatsService {
     }
 
     pub async fn query(&self, query: QueryRequest) -> ServiceResult<ResponseStream> {
-        // generate sql base on query
-        let sql = query.to_string();
-        self.raw_query(RawQueryRequest { query: sql }).await
+        let stream = self.store.query(&query, self.statement_timeout()).await?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn query_profiles(&self, query: QueryRequest) -> ServiceResult<ProfileStream> {
+        let stream = self
+            .store
+            .query_profiles(&query, self.statement_timeout())
+            .await?;
+        Ok(Response::new(stream))
     }
 
     pub async fn raw_query(&self, req: RawQueryRequest) -> ServiceResult<ResponseStream> {
-        // TODO: query must only email and name, so we should use sqlparser to parse the query
-        let Ok(ret) = sqlx::query_as::<_, User>(&req.query)
-            .fetch_all(&self.pool)
-            .await
-        else {
-            return Err(Status::internal(format!(
-                "Failed to fetch data with query {}",
-                req.query
-            )));
-        };
-        println!("query users: {:?}", &ret);
-        Ok(Response::new(Box::pin(stream::iter(
-            ret.into_iter().map(Ok),
-        ))))
+        req.validate()?;
+        let page = Pagination::for_stream(&req.page_token, req.page_size)?;
+        self.check_plan(|builder| {
+            page.push_raw(builder, req.statement());
+            Ok(())
+        })
+        .await?;
+        let stream = cursor::stream_rows(
+            self.pool()?,
+            self.config.server.fetch_size,
+            self.statement_timeout(),
+            |builder| {
+                page.push_raw(builder, req.statement());
+                Ok(())
+            },
+        )
+        .await?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn query_page(&self, query: QueryRequest) -> ServiceResult<UserPage> {
+        let ret = self
+            .store
+            .query_page(&query, self.statement_timeout())
+            .await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn raw_query_page(&self, req: RawQueryRequest) -> ServiceResult<UserPage> {
+        req.validate()?;
+        let page = Pagination::for_page(&req.page_token, req.page_size)?;
+        self.check_plan(|builder| {
+            page.push_raw(builder, req.statement());
+            Ok(())
+        })
+        .await?;
+        let mut builder = QueryBuilder::new("");
+        page.push_raw(&mut builder, req.statement());
+        let users = store::fetch_users(self.pool()?, builder, self.statement_timeout()).await?;
+        Ok(Response::new(UserPage::new(users, req.page_size)))
+    }
+
+    pub async fn record_events<S>(&self, events: S) -> ServiceResult<RecordEventsResponse>
+    where
+        S: Stream<Item = Result<UserEvent, Status>> + Unpin,
+    {
+        let ret = event::record_events(self.pool()?, events).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn export(&self, req: ExportRequest) -> ServiceResult<ExportStream> {
+        let format = ExportFormat::try_from(req.format).map_err(|_| {
+            Status::invalid_argument(format!("invalid export format {}", req.format))
+        })?;
+        let query = req.query.unwrap_or_default();
+        let columns = query.profile_fields()?;
+        let users = self.query_profiles(query).await?.into_inner();
+        let stream = export::export(users, columns, format)?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn import<S>(&self, stream: S) -> ServiceResult<ImportStream>
+    where
+        S: Stream<Item = Result<ImportRequest, Status>> + Send + Unpin + 'static,
+    {
+        let (tx, rx) = mpsc::channel(IMPORT_PROGRESS_BUFFER);
+        let pool = self.pool()?.clone();
+        tokio::spawn(async move {
+            if let Err(e) = import::import(&pool, stream, &tx).await {
+                warn!("Failed to import user stats: {:?}", e);
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    pub async fn mark_notified(
+        &self,
+        req: MarkNotifiedRequest,
+    ) -> ServiceResult<MarkNotifiedResponse> {
+        let ret = self.store.mark_notified(&req).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn reserve(&self, req: ReserveRequest) -> ServiceResult<ReserveStream> {
+        let stream = self.store.reserve(&req, self.statement_timeout()).await?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn release(&self, req: ReleaseRequest) -> ServiceResult<ReleaseResponse> {
+        let ret = self.store.release(&req).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn create_segment(&self, req: CreateSegmentRequest) -> ServiceResult<Segment> {
+        let ret = req.create(self.pool()?).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn update_segment(&self, req: UpdateSegmentRequest) -> ServiceResult<Segment> {
+        let ret = req.update(self.pool()?).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn get_segment(&self, req: GetSegmentRequest) -> ServiceResult<Segment> {
+        let ret = req.get(self.pool()?).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn list_segments(&self) -> ServiceResult<ListSegmentsResponse> {
+        let ret = segment::list_segments(self.pool()?).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn delete_segment(
+        &self,
+        req: DeleteSegmentRequest,
+    ) -> ServiceResult<DeleteSegmentResponse> {
+        req.delete(self.pool()?).await?;
+        Ok(Response::new(DeleteSegmentResponse {}))
+    }
+
+    pub async fn query_segment(&self, req: QuerySegmentRequest) -> ServiceResult<ResponseStream> {
+        let query = req.to_query(self.pool()?, Utc::now()).await?;
+        self.query(query).await
+    }
+
+    pub async fn snapshot_segment(
+        &self,
+        req: SnapshotSegmentRequest,
+    ) -> ServiceResult<SegmentSnapshot> {
+        let ret = req.snapshot(self.pool()?, Utc::now()).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn diff_snapshots(&self, req: DiffSnapshotsRequest) -> ServiceResult<DiffStream> {
+        let (from, to) = req.runs(self.pool()?).await?;
+        let stream = cursor::stream_rows(
+            self.pool()?,
+            self.config.server.fetch_size,
+            self.statement_timeout(),
+            |builder| req.push_select(builder, from, to),
+        )
+        .await?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn watch_users(&self, query: QueryRequest) -> ServiceResult<ResponseStream> {
+        let stream = watch::watch_users(self.pool()?, &self.changes, query).await?;
+        Ok(Response::new(stream))
+    }
+
+    pub async fn count(&self, query: QueryRequest) -> ServiceResult<CountResponse> {
+        let ret = self.store.count(&query, self.statement_timeout()).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn cohorts(&self, req: CohortsRequest) -> ServiceResult<CohortsResponse> {
+        self.check_plan(|builder| req.push_cohorts(builder).map(drop))
+            .await?;
+        let ret = req.cohorts(self.pool()?, self.statement_timeout()).await?;
+        Ok(Response::new(ret))
+    }
+
+    pub async fn estimate(&self, req: EstimateRequest) -> ServiceResult<EstimateResponse> {
+        let ret = req.estimate(self.pool()?, self.statement_timeout()).await?;
+        Ok(Response::new(ret))
+    }
+
+    /// the postgres pool of the store
+    fn pool(&self) -> Result<&PgPool, Status> {
+        self.store
+            .pool()
+            .ok_or_else(|| Status::unimplemented("Only supported by the postgres store"))
     }
 }
 
@@ -86,6 +304,7 @@ impl QueryRequest {
             let tq = TimeQuery {
                 lower: Some(lower_ts),
                 upper: Some(upper_ts),
+                ..Default::default()
             };
             query.timestamp((t.0.to_string(), tq));
         }
@@ -102,73 +321,6 @@ impl Deref for UserStatsService {
     }
 }
 
-impl fmt::Display for QueryRequest {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut sql =
-            "SELECT name, email, started_but_not_finished FROM user_stats WHERE ".to_string();
-        let timestamp_conditions = self
-            .timestamps
-            .iter()
-            .map(|(k, v)| timestamp_query(k, v.lower.as_ref(), v.upper.as_ref()))
-            .join(" AND ");
-
-        sql.push_str(&timestamp_conditions);
-
-        let id_conditions = self
-            .ids
-            .iter()
-            .map(|(k, v)| ids_query(k, &v.ids))
-            .join(" AND ");
-
-        if !id_conditions.is_empty() {
-            sql.push_str(" AND ");
-            sql.push_str(&id_conditions);
-        };
-
-        info!("Generate SQL: {}", sql);
-
-        write!(f, "{}", sql)
-    }
-}
-
-fn ids_query(name: &str, ids: &[u32]) -> String {
-    if ids.is_empty() {
-        return "TRUE".to_string();
-    }
-
-    // ARRAY{:?} 会被格式化成 ARRAY[1,2,x,..] 这种形势
-    // <@ 表示 name 是否包含了 ids
-    format!("array{:?} <@ {}", ids, name)
-}
-
-fn timestamp_query(name: &str, lower: Option<&Timestamp>, upper: Option<&Timestamp>) -> String {
-    match (lower, upper) {
-        (None, None) => "".to_string(),
-        (Some(lower), None) => {
-            let lower = ts_to_utc(lower);
-            format!("{} >= '{}'", name, lower.to_rfc3339())
-        }
-        (None, Some(upper)) => {
-            let upper = ts_to_utc(upper);
-            format!("{} <= '{}'", name, upper.to_rfc3339())
-        }
-        (Some(lower), Some(upper)) => {
-            let lower = ts_to_utc(lower);
-            let upper = ts_to_utc(upper);
-            format!(
-                "{} BETWEEN '{}' AND '{}'",
-                name,
-                lower.to_rfc3339(),
-                upper.to_rfc3339()
-            )
-        }
-    }
-}
-
-fn ts_to_utc(ts: &Timestamp) -> DateTime<Utc> {
-    Utc.timestamp_opt(ts.seconds, ts.nanos as _).unwrap()
-}
-
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
@@ -176,7 +328,7 @@ mod tests {
 
     use super::*;
     use crate::pb::QueryRequestBuilder;
-    use crate::test_utils::{days_to_timestamp, to_idquery, to_timequery};
+    use crate::test_utils::{to_idquery, to_timequery};
 
     #[tokio::test]
     async fn raw_query_should_work() -> Result<()> {
@@ -185,6 +337,7 @@ mod tests {
             .raw_query(RawQueryRequest {
                 query: "SELECT * FROM user_stats WHERE created_at > '2024-01-01' LIMIT 5"
                     .to_string(),
+                ..Default::default()
             })
             .await?
             .into_inner();
@@ -210,38 +363,4 @@ mod tests {
         }
         Ok(())
     }
-
-    #[test]
-    fn timestamp_query_should_work() -> Result<()> {
-        let query = timestamp_query("created_at", None, None);
-        assert!(query.is_empty());
-        let ts = days_to_timestamp(15);
-
-        let utc_time = ts_to_utc(&ts).to_rfc3339();
-        let query = timestamp_query("created_at", Some(&ts), None);
-        assert_eq!(query, format!("created_at >= '{}'", utc_time));
-
-        let query = timestamp_query("created_at", None, Some(&ts));
-        assert_eq!(query, format!("created_at <= '{}'", utc_time));
-
-        let end = days_to_timestamp(5);
-        let utc_time_end = ts_to_utc(&end).to_rfc3339();
-        let query = timestamp_query("created_at", Some(&ts), Some(&end));
-        assert_eq!(
-            query,
-            format!("created_at BETWEEN '{}' AND '{}'", utc_time, utc_time_end)
-        );
-
-        Ok(())
-    }
-
-    #[test]
-    fn query_request_to_string_should_work() -> Result<()> {
-        let dt1 = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
-        let dt2 = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
-        let query = QueryRequest::new_with_date("created_at", dt1, dt2);
-        let sql = query.to_string();
-        assert_eq!(sql, "SELECT name, email, started_but_not_finished FROM user_stats WHERE created_at BETWEEN '2024-01-01T00:00:00+00:00' AND '2024-01-02T00:00:00+00:00'");
-        Ok(())
-    }
 }
@@ -0,0 +1,178 @@
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use sqlx::PgPool;
+use tonic::Status;
+
+use super::query::{ts_to_utc, utc_to_ts};
+use crate::pb::{MarkNotifiedRequest, MarkNotifiedResponse, NotificationChannel, Notified};
+
+impl Notified {
+    pub fn new(email: impl Into<String>, channel: NotificationChannel, at: DateTime<Utc>) -> Self {
+        Self {
+            email: email.into(),
+            channel: channel as i32,
+            notified_at: Some(utc_to_ts(at)),
+        }
+    }
+}
+
+impl NotificationChannel {
+    /// the column of user_stats to record the last notification time
+    pub(crate) fn column(self) -> Option<&'static str> {
+        match self {
+            NotificationChannel::Email => Some("last_email_notification"),
+            NotificationChannel::InApp => Some("last_in_app_notification"),
+            NotificationChannel::Sms => Some("last_sms_notification"),
+            NotificationChannel::Unspecified => None,
+        }
+    }
+
+    /// the value of the notification_channel enum in postgres
+    pub(crate) fn to_db(self) -> Option<&'static str> {
+        match self {
+            NotificationChannel::Email => Some("email"),
+            NotificationChannel::InApp => Some("in_app"),
+            NotificationChannel::Sms => Some("sms"),
+            NotificationChannel::Unspecified => None,
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl MarkNotifiedRequest {
+    /// record the notifications in the history, and update the last notification time of each
+    /// channel in one statement, an older notification never overrides a newer one
+    pub(crate) async fn mark(&self, pool: &PgPool) -> Result<MarkNotifiedResponse, Status> {
+        let notifications = self.validate()?;
+        let groups = notifications
+            .into_iter()
+            .into_group_map_by(|(c, _, _)| *c)
+            .into_iter()
+            .sorted_by_key(|(c, _)| *c);
+
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        let mut ret = MarkNotifiedResponse::default();
+        for (channel, group) in groups {
+            let (emails, times): (Vec<_>, Vec<_>) =
+                group.into_iter().map(|(_, e, t)| (e, t)).unzip();
+            sqlx::query(
+                "INSERT INTO notification_history (email, channel, notified_at) \
+                 SELECT email, $3::notification_channel, notified_at \
+                 FROM UNNEST($1::text[], $2::timestamptz[]) AS t(email, notified_at) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(&emails)
+            .bind(&times)
+            .bind(channel.to_db())
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+
+            let column = channel.column().expect("channel is validated");
+            let sql = format!(
+                "UPDATE user_stats u SET {0} = GREATEST(u.{0}, n.notified_at) \
+                 FROM (SELECT email, MAX(notified_at) AS notified_at \
+                 FROM UNNEST($1::text[], $2::timestamptz[]) AS t(email, notified_at) \
+                 GROUP BY email) n WHERE u.email = n.email",
+                column
+            );
+            let updated = sqlx::query(&sql)
+                .bind(emails)
+                .bind(times)
+                .execute(&mut *ts)
+                .await
+                .map_err(to_status)?;
+            ret.updated += updated.rows_affected();
+        }
+        ts.commit().await.map_err(to_status)?;
+        Ok(ret)
+    }
+
+    /// the channel, email and time of each notification, the time defaults to now
+    pub(crate) fn validate(
+        &self,
+    ) -> Result<Vec<(NotificationChannel, String, DateTime<Utc>)>, Status> {
+        let now = Utc::now();
+        let mut notifications = Vec::with_capacity(self.notifications.len());
+        for n in &self.notifications {
+            let channel = NotificationChannel::try_from(n.channel)
+                .ok()
+                .filter(|c| *c != NotificationChannel::Unspecified)
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "invalid notification channel {} of {}",
+                        n.channel, n.email
+                    ))
+                })?;
+            let at = n.notified_at.as_ref().map(ts_to_utc).transpose()?;
+            notifications.push((channel, n.email.clone(), at.unwrap_or(now)));
+        }
+        Ok(notifications)
+    }
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to mark notified: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::TimeZone;
+    use tonic::Code;
+
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[tokio::test]
+    async fn mark_notified_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let dt1 = Utc.with_ymd_and_hms(2024, 7, 1, 0, 0, 0).unwrap();
+        let dt2 = Utc.with_ymd_and_hms(2024, 7, 2, 0, 0, 0).unwrap();
+        let old = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let req = MarkNotifiedRequest {
+            notifications: vec![
+                Notified::new(email, NotificationChannel::Email, dt1),
+                Notified::new(email, NotificationChannel::Email, dt2),
+                Notified::new(email, NotificationChannel::Sms, dt1),
+                // older notification doesn't move the time back
+                Notified::new(email, NotificationChannel::InApp, old),
+                Notified::new("nobody@acme.org", NotificationChannel::Sms, dt1),
+            ],
+        };
+        let ret = req.mark(&pool).await?;
+        assert_eq!(ret.updated, 3);
+
+        let (email_at, in_app_at, sms_at): (DateTime<Utc>, DateTime<Utc>, DateTime<Utc>) =
+            sqlx::query_as(
+                "SELECT last_email_notification, last_in_app_notification, last_sms_notification \
+                 FROM user_stats WHERE email = $1",
+            )
+            .bind(email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(email_at, dt2);
+        assert_eq!(sms_at, dt1);
+        assert!(in_app_at > old);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mark_notified_with_invalid_channel_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let req = MarkNotifiedRequest {
+            notifications: vec![Notified {
+                email: "tyr@acme.org".to_string(),
+                ..Default::default()
+            }],
+        };
+        let status = req.mark(&pool).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "invalid notification channel 0 of tyr@acme.org"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sqlx::{Postgres, QueryBuilder};
+use tonic::Status;
+
+use super::query::Conditions;
+use crate::pb::{User, UserPage};
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// keyset pagination on the email primary key
+#[derive(Debug, Default)]
+pub(crate) struct Pagination {
+    /// only return users whose email is greater than it
+    after: Option<String>,
+    limit: Option<u32>,
+}
+
+#[allow(clippy::result_large_err)]
+impl Pagination {
+    pub fn new(page_token: &str, limit: Option<u32>) -> Result<Self, Status> {
+        let after = decode_page_token(page_token)?;
+        Ok(Self { after, limit })
+    }
+
+    /// pagination for a streaming query, page_size is optional
+    pub fn for_stream(page_token: &str, page_size: u32) -> Result<Self, Status> {
+        Self::new(page_token, (page_size > 0).then_some(page_size))
+    }
+
+    /// pagination for a unary paginated query, one more user is fetched to know if there is a
+    /// next page
+    pub fn for_page(page_token: &str, page_size: u32) -> Result<Self, Status> {
+        Self::new(page_token, Some(page_size_or_default(page_size) + 1))
+    }
+
+    /// push the keyset condition, ORDER BY and LIMIT, the conditions must be the last part of
+    /// the WHERE clause
+    pub fn push(&self, mut conditions: Conditions) {
+        if let Some(after) = &self.after {
+            conditions.next().push("email > ").push_bind(after.clone());
+        }
+
+        let builder = conditions.finish();
+        if self.after.is_some() || self.limit.is_some() {
+            builder.push(" ORDER BY email");
+        }
+        if let Some(limit) = self.limit {
+            builder.push(" LIMIT ").push_bind(limit as i64);
+        }
+    }
+
+    /// apply the pagination to the users sorted by email
+    pub fn paginate<T>(
+        &self,
+        users: impl Iterator<Item = T>,
+        email: impl Fn(&T) -> &str,
+    ) -> Vec<T> {
+        let users = users.filter(|u| match &self.after {
+            Some(after) => email(u) > after.as_str(),
+            None => true,
+        });
+        match self.limit {
+            Some(limit) => users.take(limit as usize).collect(),
+            None => users.collect(),
+        }
+    }
+
+    /// wrap a validated raw query as a sub query, so that it could be paginated as well
+    pub fn push_raw(&self, builder: &mut QueryBuilder<'static, Postgres>, query: &str) {
+        if self.after.is_none() && self.limit.is_none() {
+            builder.push(query);
+            return;
+        }
+
+        builder.push(format!("SELECT * FROM ({}) AS raw_query", query));
+        self.push(Conditions::new(builder));
+    }
+}
+
+impl UserPage {
+    /// build the page from the users fetched with `Pagination::for_page`
+    pub fn new(mut users: Vec<User>, page_size: u32) -> Self {
+        let page_size = page_size_or_default(page_size) as usize;
+        let next_page_token = if users.len() > page_size {
+            users.truncate(page_size);
+            users
+                .last()
+                .map(|u| encode_page_token(&u.email))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        Self {
+            users,
+            next_page_token,
+        }
+    }
+}
+
+fn page_size_or_default(page_size: u32) -> u32 {
+    match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    }
+}
+
+fn encode_page_token(email: &str) -> String {
+    URL_SAFE_NO_PAD.encode(email)
+}
+
+#[allow(clippy::result_large_err)]
+fn decode_page_token(token: &str) -> Result<Option<String>, Status> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+
+    URL_SAFE_NO_PAD
+        .decode(token)
+        .ok()
+        .and_then(|v| String::from_utf8(v).ok())
+        .map(Some)
+        .ok_or_else(|| Status::invalid_argument(format!("invalid page token {}", token)))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    fn to_sql(page: &Pagination) -> String {
+        let mut builder = QueryBuilder::new("SELECT * FROM user_stats");
+        page.push(Conditions::new(&mut builder));
+        builder.sql().to_string()
+    }
+
+    #[test]
+    fn page_token_should_round_trip() -> Result<()> {
+        let token = encode_page_token("tyr@acme.org");
+        assert_eq!(decode_page_token(&token)?, Some("tyr@acme.org".to_string()));
+        assert_eq!(decode_page_token("")?, None);
+        assert!(decode_page_token("not a token!").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pagination_should_generate_sql() -> Result<()> {
+        let page = Pagination::for_stream("", 0)?;
+        assert_eq!(to_sql(&page), "SELECT * FROM user_stats");
+
+        let page = Pagination::for_stream("", 10)?;
+        assert_eq!(
+            to_sql(&page),
+            "SELECT * FROM user_stats ORDER BY email LIMIT $1"
+        );
+
+        let token = encode_page_token("tyr@acme.org");
+        let page = Pagination::for_page(&token, 10)?;
+        assert_eq!(page.limit, Some(11));
+        assert_eq!(
+            to_sql(&page),
+            "SELECT * FROM user_stats WHERE email > $1 ORDER BY email LIMIT $2"
+        );
+
+        let mut builder = QueryBuilder::new("");
+        page.push_raw(
+            &mut builder,
+            "SELECT * FROM user_stats WHERE gender = 'male'",
+        );
+        assert_eq!(builder.sql(), "SELECT * FROM (SELECT * FROM user_stats WHERE gender = 'male') AS raw_query WHERE email > $1 ORDER BY email LIMIT $2");
+        Ok(())
+    }
+
+    #[test]
+    fn user_page_should_work() {
+        let users = (0..4)
+            .map(|i| User {
+                email: format!("user{}@acme.org", i),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let page = UserPage::new(users.clone(), 3);
+        assert_eq!(page.users.len(), 3);
+        assert_eq!(page.next_page_token, encode_page_token("user2@acme.org"));
+
+        let page = UserPage::new(users, 4);
+        assert_eq!(page.users.len(), 4);
+        assert!(page.next_page_token.is_empty());
+    }
+}
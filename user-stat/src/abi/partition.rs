@@ -0,0 +1,350 @@
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use sqlx::{PgExecutor, PgPool};
+use tonic::Status;
+use tracing::info;
+
+use super::query::USER_STATS_TABLE;
+use crate::{config::PartitionConfig, UserStatsService};
+
+/// the monthly partitions are named user_stats_YYYY_MM
+const PARTITION_PREFIX: &str = "user_stats_";
+
+/// the partitions changed by the maintenance
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PartitionReport {
+    pub created: Vec<String>,
+    pub detached: Vec<String>,
+    /// the expired partitions kept for their users active within the retention
+    pub kept: Vec<String>,
+}
+
+impl UserStatsService {
+    /// create the partitions of user_stats ahead, and detach the expired ones
+    pub async fn maintain_partitions(&self) -> Result<PartitionReport, Status> {
+        maintain(self.pool()?, &self.config.partition, Utc::now()).await
+    }
+}
+
+/// create the partitions from the month of `now` to `months_ahead` months later, and detach the
+/// partitions older than `retention_months`, unless any of their users visited or watched since
+/// then. The detached tables are kept as archives, and the emails of their users are released,
+/// so a returning user is inserted as a new one
+async fn maintain(
+    pool: &PgPool,
+    config: &PartitionConfig,
+    now: DateTime<Utc>,
+) -> Result<PartitionReport, Status> {
+    let current = month_of(now);
+    let mut report = PartitionReport::default();
+    for i in 0..=config.months_ahead {
+        let month = current + Months::new(i);
+        let name: Option<String> = sqlx::query_scalar("SELECT create_user_stats_partition($1)")
+            .bind(month)
+            .fetch_one(pool)
+            .await
+            .map_err(to_status)?;
+        report.created.extend(name);
+    }
+
+    if config.retention_months > 0 {
+        let cutoff = current - Months::new(config.retention_months);
+        for (name, month) in list_partitions(pool).await? {
+            if month >= cutoff {
+                continue;
+            }
+            if detach(pool, &name, cutoff).await? {
+                report.detached.push(name);
+            } else {
+                report.kept.push(name);
+            }
+        }
+    }
+
+    info!("Maintained partitions of user_stats: {:?}", report);
+    Ok(report)
+}
+
+/// the monthly partitions attached to user_stats, with their months
+async fn list_partitions(pool: &PgPool) -> Result<Vec<(String, NaiveDate)>, Status> {
+    let names: Vec<String> = sqlx::query_scalar(
+        "SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+         WHERE i.inhparent = $1::regclass ORDER BY c.relname",
+    )
+    .bind(USER_STATS_TABLE)
+    .fetch_all(pool)
+    .await
+    .map_err(to_status)?;
+    Ok(names
+        .into_iter()
+        .filter_map(|name| {
+            let month = parse_month(&name)?;
+            Some((name, month))
+        })
+        .collect())
+}
+
+/// detach the partition if none of its users is active since the cutoff, false if it is kept.
+/// The users are scanned before detaching, without blocking user_stats. They are checked again
+/// after detaching with the indexes of the activity, so that the lock of user_stats is short and
+/// no event could make them active in between
+async fn detach(pool: &PgPool, name: &str, cutoff: NaiveDate) -> Result<bool, Status> {
+    let since = cutoff.and_hms_opt(0, 0, 0).expect("midnight").and_utc();
+    if has_active_users(pool, name, since).await? {
+        info!("Partition {} is kept for its active users", name);
+        return Ok(false);
+    }
+
+    let mut ts = pool.begin().await.map_err(to_status)?;
+    // a concurrent insert of these emails waits for the transaction
+    sqlx::query(&format!(
+        "DELETE FROM user_stats_emails e USING {} d WHERE e.email = d.email",
+        name
+    ))
+    .execute(&mut *ts)
+    .await
+    .map_err(to_status)?;
+    sqlx::query(&format!(
+        "ALTER TABLE {} DETACH PARTITION {}",
+        USER_STATS_TABLE, name
+    ))
+    .execute(&mut *ts)
+    .await
+    .map_err(to_status)?;
+    if has_active_users(&mut *ts, name, since).await? {
+        info!("Partition {} is kept for its users active just now", name);
+        ts.rollback().await.map_err(to_status)?;
+        return Ok(false);
+    }
+    ts.commit().await.map_err(to_status)?;
+    Ok(true)
+}
+
+/// if any user of the partition visited or watched since then, each check could use its index
+async fn has_active_users(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    since: DateTime<Utc>,
+) -> Result<bool, Status> {
+    sqlx::query_scalar(&format!(
+        "SELECT EXISTS (SELECT 1 FROM {0} WHERE last_visited_at >= $1) \
+         OR EXISTS (SELECT 1 FROM {0} WHERE last_watched_at >= $1)",
+        name
+    ))
+    .bind(since)
+    .fetch_one(executor)
+    .await
+    .map_err(to_status)
+}
+
+fn month_of(dt: DateTime<Utc>) -> NaiveDate {
+    NaiveDate::from_ymd_opt(dt.year(), dt.month(), 1).expect("first day of month")
+}
+
+/// the month of user_stats_YYYY_MM, None for the default partition
+fn parse_month(name: &str) -> Option<NaiveDate> {
+    let suffix = name.strip_prefix(PARTITION_PREFIX)?;
+    NaiveDate::parse_from_str(&format!("{}_01", suffix), "%Y_%m_%d").ok()
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to maintain partitions: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::TimeZone;
+    use std::{path::Path, str::FromStr};
+
+    use sqlx::{
+        migrate::Migrator,
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Postgres, QueryBuilder,
+    };
+
+    use super::*;
+    use crate::{pb::QueryRequest, test_utils::get_test_pool};
+
+    fn config(months_ahead: u32, retention_months: u32) -> PartitionConfig {
+        PartitionConfig {
+            months_ahead,
+            retention_months,
+            ..Default::default()
+        }
+    }
+
+    async fn count(pool: &PgPool, table: &str) -> Result<i64> {
+        let sql = format!("SELECT COUNT(*) FROM {}", table);
+        Ok(sqlx::query_scalar(&sql).fetch_one(pool).await?)
+    }
+
+    #[test]
+    fn partition_name_should_be_parsed() {
+        let month = NaiveDate::from_ymd_opt(2024, 3, 1);
+        assert_eq!(parse_month("user_stats_2024_03"), month);
+        assert_eq!(parse_month("user_stats_default"), None);
+        assert_eq!(parse_month("user_stats_emails"), None);
+    }
+
+    #[tokio::test]
+    async fn maintain_should_create_and_detach_partitions() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // the fixtures are in the default partition, they are moved to the new partitions
+        let now = Utc.with_ymd_and_hms(2024, 2, 15, 0, 0, 0).unwrap();
+        let report = maintain(&pool, &config(1, 0), now).await?;
+        assert_eq!(report.created, ["user_stats_2024_02", "user_stats_2024_03"]);
+        assert!(report.detached.is_empty());
+        let march = count(&pool, "user_stats_2024_03").await?;
+        assert!(march > 0);
+        assert_eq!(count(&pool, "user_stats").await?, 100);
+        assert_eq!(count(&pool, "user_stats_emails").await?, 100);
+
+        // created only once
+        let report = maintain(&pool, &config(1, 0), now).await?;
+        assert!(report.created.is_empty());
+
+        // the users are active until 2024-06
+        let february = count(&pool, "user_stats_2024_02").await?;
+        let now = Utc.with_ymd_and_hms(2024, 9, 1, 0, 0, 0).unwrap();
+        let report = maintain(&pool, &config(0, 2), now).await?;
+        assert_eq!(report.created, ["user_stats_2024_09"]);
+        assert_eq!(
+            report.detached,
+            ["user_stats_2024_02", "user_stats_2024_03"]
+        );
+        assert!(report.kept.is_empty());
+        let left = count(&pool, "user_stats").await?;
+        assert_eq!(left, 100 - february - march);
+        assert_eq!(count(&pool, "user_stats_emails").await?, left);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn maintain_should_keep_partitions_of_active_users() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let now = Utc.with_ymd_and_hms(2024, 2, 15, 0, 0, 0).unwrap();
+        maintain(&pool, &config(1, 0), now).await?;
+        // an old user of february is still watching
+        let email: String = sqlx::query_scalar("SELECT email FROM user_stats_2024_02 LIMIT 1")
+            .fetch_one(&pool)
+            .await?;
+        sqlx::query(
+            "UPDATE user_stats SET last_watched_at = '2024-08-20T00:00:00Z' WHERE email = $1",
+        )
+        .bind(&email)
+        .execute(&pool)
+        .await?;
+
+        let now = Utc.with_ymd_and_hms(2024, 9, 1, 0, 0, 0).unwrap();
+        let report = maintain(&pool, &config(0, 1), now).await?;
+        assert_eq!(report.detached, ["user_stats_2024_03"]);
+        assert_eq!(report.kept, ["user_stats_2024_02"]);
+        let user: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_stats WHERE email = $1")
+            .bind(&email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(user, 1);
+        assert_eq!(
+            count(&pool, "user_stats_emails").await?,
+            count(&pool, "user_stats").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_without_sign_up_date_should_be_migrated_to_epoch() -> Result<()> {
+        let (tdb, pool) = get_test_pool(None).await;
+        // migrate a schema of its own up to the partitioning
+        sqlx::query("CREATE SCHEMA legacy").execute(&pool).await?;
+        let options = PgConnectOptions::from_str(&tdb.url())?.options([("search_path", "legacy")]);
+        let legacy = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let migrator = Migrator::new(path.clone()).await?;
+        let mut before = Migrator::new(path).await?;
+        before.migrations = migrator
+            .migrations
+            .iter()
+            .filter(|m| m.version < 20240707080000)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into();
+        before.run(&legacy).await?;
+        sqlx::query(
+            "INSERT INTO user_stats (email, name, created_at) VALUES \
+             ('old@acme.org', 'Old', NULL), ('new@acme.org', 'New', '2024-06-15T00:00:00Z')",
+        )
+        .execute(&legacy)
+        .await?;
+
+        migrator.run(&legacy).await?;
+        let table: String = sqlx::query_scalar(
+            "SELECT tableoid::regclass::text FROM user_stats \
+             WHERE email = 'old@acme.org' AND created_at = 'epoch'",
+        )
+        .fetch_one(&legacy)
+        .await?;
+        assert_eq!(table, "user_stats_default");
+        let first: String = sqlx::query_scalar(
+            "SELECT MIN(c.relname::text) FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+             WHERE i.inhparent = 'user_stats'::regclass AND c.relname <> 'user_stats_default'",
+        )
+        .fetch_one(&legacy)
+        .await?;
+        assert_eq!(first, "user_stats_2024_06");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_should_move_rows_across_partitions() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        maintain(&pool, &config(2, 0), now).await?;
+
+        let email = "frederik.2r2jvb8l@example.org";
+        sqlx::query("UPDATE user_stats SET created_at = '2024-01-15T00:00:00Z' WHERE email = $1")
+            .bind(email)
+            .execute(&pool)
+            .await?;
+        let table: String =
+            sqlx::query_scalar("SELECT tableoid::regclass::text FROM user_stats WHERE email = $1")
+                .bind(email)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(table, "user_stats_2024_01");
+        assert_eq!(count(&pool, "user_stats_emails").await?, 100);
+
+        // an existing email is skipped, even in another partition
+        let ret = sqlx::query(
+            "INSERT INTO user_stats (email, name, created_at) VALUES ($1, 'dup', '2024-03-01')",
+        )
+        .bind(email)
+        .execute(&pool)
+        .await?;
+        assert_eq!(ret.rows_affected(), 0);
+        assert_eq!(count(&pool, "user_stats").await?, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn created_at_range_should_prune_partitions() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        maintain(&pool, &config(2, 0), now).await?;
+
+        let lower = Utc.with_ymd_and_hms(2024, 2, 10, 0, 0, 0).unwrap();
+        let upper = Utc.with_ymd_and_hms(2024, 2, 11, 0, 0, 0).unwrap();
+        let query = QueryRequest::new_with_date("created_at", lower, upper);
+        let mut builder = QueryBuilder::<Postgres>::new("EXPLAIN ");
+        query.push_select(&mut builder, &Default::default())?;
+        let plan: Vec<String> = builder.build_query_scalar().fetch_all(&pool).await?;
+        let plan = plan.join("\n");
+        assert!(plan.contains("user_stats_2024_02"), "{}", plan);
+        assert!(!plan.contains("user_stats_2024_01"), "{}", plan);
+        assert!(!plan.contains("user_stats_2024_03"), "{}", plan);
+        assert!(!plan.contains("user_stats_default"), "{}", plan);
+        Ok(())
+    }
+}
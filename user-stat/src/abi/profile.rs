@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use prost_types::Timestamp;
+use sqlx::{postgres::PgRow, Decode, FromRow, Postgres, Row, Type};
+use tonic::Status;
+
+use super::query::{ts_to_utc, utc_to_ts, Column, ColumnKind, USER_STATS_COLUMNS};
+use crate::pb::{Gender, QueryRequest, User, UserProfile};
+
+#[allow(clippy::result_large_err)]
+impl QueryRequest {
+    /// the select list of the columns in the field mask, email is always selected as it is the
+    /// key of pagination
+    pub(crate) fn profile_columns(&self) -> Result<String, Status> {
+        let columns = self
+            .profile_fields()?
+            .into_iter()
+            .map(|c| match c.kind {
+                // gender is a postgres enum, decode it as text
+                ColumnKind::Gender => format!("{0}::text AS {0}", c.name),
+                _ => c.name.to_string(),
+            })
+            .collect::<Vec<_>>();
+        Ok(columns.join(", "))
+    }
+
+    /// the columns in the field mask, in the order of the catalogue, so the same mask always
+    /// generates the same sql
+    pub(crate) fn profile_fields(&self) -> Result<Vec<&'static Column>, Status> {
+        let paths = self
+            .field_mask
+            .as_ref()
+            .map(|m| m.paths.as_slice())
+            .unwrap_or_default();
+        if let Some(path) = paths.iter().find(|p| Column::find(p).is_none()) {
+            return Err(Status::invalid_argument(format!(
+                "unknown field {} of UserProfile",
+                path
+            )));
+        }
+
+        Ok(USER_STATS_COLUMNS
+            .iter()
+            .filter(|c| paths.is_empty() || c.name == "email" || paths.iter().any(|p| p == c.name))
+            .collect())
+    }
+}
+
+impl UserProfile {
+    /// the value of a timestamp column, None for the other columns
+    pub(crate) fn timestamp(&self, column: &str) -> Option<DateTime<Utc>> {
+        let ts = match column {
+            "created_at" => &self.created_at,
+            "last_visited_at" => &self.last_visited_at,
+            "last_watched_at" => &self.last_watched_at,
+            "last_email_notification" => &self.last_email_notification,
+            "last_in_app_notification" => &self.last_in_app_notification,
+            "last_sms_notification" => &self.last_sms_notification,
+            _ => return None,
+        };
+        ts.as_ref().and_then(|ts| ts_to_utc(ts).ok())
+    }
+
+    /// the value of an id array column, empty for the other columns
+    pub(crate) fn id_array(&self, column: &str) -> &[i32] {
+        match column {
+            "recent_watched" => &self.recent_watched,
+            "viewed_but_not_started" => &self.viewed_but_not_started,
+            "started_but_not_finished" => &self.started_but_not_finished,
+            "finished" => &self.finished,
+            _ => &[],
+        }
+    }
+}
+
+impl Gender {
+    /// the value of the gender enum in postgres
+    pub(crate) fn to_db(self) -> Option<&'static str> {
+        match self {
+            Gender::Female => Some("female"),
+            Gender::Male => Some("male"),
+            Gender::Unknown => Some("unknown"),
+            Gender::Unspecified => None,
+        }
+    }
+
+    fn from_db(s: &str) -> Self {
+        match s {
+            "female" => Gender::Female,
+            "male" => Gender::Male,
+            "unknown" => Gender::Unknown,
+            _ => Gender::Unspecified,
+        }
+    }
+}
+
+/// only the columns in the field mask are selected, the others are left as default
+impl FromRow<'_, PgRow> for UserProfile {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let gender = get::<String>(row, "gender")?
+            .map(|g| Gender::from_db(&g))
+            .unwrap_or_default();
+        Ok(Self {
+            email: get(row, "email")?.unwrap_or_default(),
+            name: get(row, "name")?.unwrap_or_default(),
+            gender: gender as i32,
+            created_at: get_timestamp(row, "created_at")?,
+            last_visited_at: get_timestamp(row, "last_visited_at")?,
+            last_watched_at: get_timestamp(row, "last_watched_at")?,
+            recent_watched: get(row, "recent_watched")?.unwrap_or_default(),
+            viewed_but_not_started: get(row, "viewed_but_not_started")?.unwrap_or_default(),
+            started_but_not_finished: get(row, "started_but_not_finished")?.unwrap_or_default(),
+            finished: get(row, "finished")?.unwrap_or_default(),
+            last_email_notification: get_timestamp(row, "last_email_notification")?,
+            last_in_app_notification: get_timestamp(row, "last_in_app_notification")?,
+            last_sms_notification: get_timestamp(row, "last_sms_notification")?,
+        })
+    }
+}
+
+/// a NULL array is decoded as empty, as the users created by events have none.
+/// started_progress is only selected by the queries
+impl FromRow<'_, PgRow> for User {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            email: row.try_get("email")?,
+            name: row.try_get("name")?,
+            started_but_not_finished: get(row, "started_but_not_finished")?.unwrap_or_default(),
+            started_progress: get(row, "started_progress")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// get the value of the column, None if the column is not selected or is NULL
+fn get<'r, T>(row: &'r PgRow, column: &str) -> Result<Option<T>, sqlx::Error>
+where
+    T: Decode<'r, Postgres> + Type<Postgres>,
+{
+    match row.try_get::<Option<T>, _>(column) {
+        Ok(v) => Ok(v),
+        Err(sqlx::Error::ColumnNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_timestamp(row: &PgRow, column: &str) -> Result<Option<Timestamp>, sqlx::Error> {
+    let dt = get::<DateTime<Utc>>(row, column)?;
+    Ok(dt.map(utc_to_ts))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use prost_types::FieldMask;
+    use sqlx::QueryBuilder;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{pb::QueryRequestBuilder, test_utils::get_test_pool};
+
+    fn mask(paths: &[&str]) -> FieldMask {
+        FieldMask {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn profile_columns_should_work() -> Result<()> {
+        let query = QueryRequest::default();
+        assert_eq!(query.profile_columns()?, "email, name, gender::text AS gender, created_at, last_visited_at, last_watched_at, recent_watched, viewed_but_not_started, started_but_not_finished, finished, last_email_notification, last_in_app_notification, last_sms_notification");
+
+        let query = QueryRequestBuilder::default()
+            .field_mask(mask(&["last_email_notification", "gender"]))
+            .build()?;
+        assert_eq!(
+            query.profile_columns()?,
+            "email, gender::text AS gender, last_email_notification"
+        );
+
+        let query = QueryRequestBuilder::default()
+            .field_mask(mask(&["password"]))
+            .build()?;
+        let status = query.profile_columns().unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "unknown field password of UserProfile");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_profile_from_row_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let email = "frederik.2r2jvb8l@example.org";
+        let query = |paths: &[&str]| {
+            let query = QueryRequestBuilder::default()
+                .field_mask(mask(paths))
+                .build()
+                .unwrap();
+            let mut builder = QueryBuilder::new(format!(
+                "SELECT {} FROM user_stats WHERE email = ",
+                query.profile_columns().unwrap()
+            ));
+            builder.push_bind(email);
+            builder
+        };
+
+        let profile: UserProfile = query(&[]).build_query_as().fetch_one(&pool).await?;
+        assert_eq!(profile.email, email);
+        assert_eq!(profile.name, "魏明轩");
+        assert_eq!(profile.gender, Gender::Female as i32);
+        assert_eq!(profile.started_but_not_finished, [306577, 368582, 315580]);
+        assert!(profile.created_at.is_some());
+        assert!(profile.last_sms_notification.is_some());
+
+        let profile: UserProfile = query(&["gender", "finished"])
+            .build_query_as()
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(profile.email, email);
+        assert_eq!(profile.gender, Gender::Female as i32);
+        assert!(profile.name.is_empty());
+        assert!(profile.created_at.is_none());
+        assert_eq!(profile.finished.len(), 47);
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use itertools::Itertools;
+use prost_types::Timestamp;
+use sqlx::{Postgres, QueryBuilder};
+use tonic::Status;
+
+use super::page::Pagination;
+use crate::pb::{IdQuery, QueryRequest, TimeQuery};
+
+pub(crate) const USER_STATS_TABLE: &str = "user_stats";
+
+/// columns required to decode a `User`
+pub(crate) const USER_COLUMNS: &[&str] = &["email", "name", "started_but_not_finished"];
+
+/// watch progress of the contents in started_but_not_finished, in the same order
+pub(crate) const STARTED_PROGRESS: &str = "ARRAY(SELECT COALESCE(p.progress, 0)::int \
+     FROM unnest(started_but_not_finished) WITH ORDINALITY AS s(content_id, i) \
+     LEFT JOIN user_content_progress p \
+     ON p.email = user_stats.email AND p.content_id = s.content_id \
+     ORDER BY s.i) AS started_progress";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColumnKind {
+    Text,
+    Gender,
+    Timestamp,
+    IdArray,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Column {
+    pub name: &'static str,
+    pub kind: ColumnKind,
+}
+
+/// the column catalogue of user_stats table, only these columns could be used to build a query
+pub(crate) const USER_STATS_COLUMNS: &[Column] = &[
+    Column::new("email", ColumnKind::Text),
+    Column::new("name", ColumnKind::Text),
+    Column::new("gender", ColumnKind::Gender),
+    Column::new("created_at", ColumnKind::Timestamp),
+    Column::new("last_visited_at", ColumnKind::Timestamp),
+    Column::new("last_watched_at", ColumnKind::Timestamp),
+    Column::new("recent_watched", ColumnKind::IdArray),
+    Column::new("viewed_but_not_started", ColumnKind::IdArray),
+    Column::new("started_but_not_finished", ColumnKind::IdArray),
+    Column::new("finished", ColumnKind::IdArray),
+    Column::new("last_email_notification", ColumnKind::Timestamp),
+    Column::new("last_in_app_notification", ColumnKind::Timestamp),
+    Column::new("last_sms_notification", ColumnKind::Timestamp),
+];
+
+#[allow(clippy::result_large_err)]
+impl Column {
+    const fn new(name: &'static str, kind: ColumnKind) -> Self {
+        Self { name, kind }
+    }
+
+    pub fn find(name: &str) -> Option<&'static Column> {
+        USER_STATS_COLUMNS.iter().find(|c| c.name == name)
+    }
+
+    /// find the column by name and make sure it is of the expected kind
+    pub fn find_with_kind(name: &str, kind: ColumnKind) -> Result<&'static Column, Status> {
+        match Self::find(name) {
+            Some(column) if column.kind == kind => Ok(column),
+            Some(column) => Err(Status::invalid_argument(format!(
+                "column {} is a {:?} column, expect {:?}",
+                column.name, column.kind, kind
+            ))),
+            None => Err(Status::invalid_argument(format!(
+                "unknown column {} of {}",
+                name, USER_STATS_TABLE
+            ))),
+        }
+    }
+}
+
+/// the select list to decode a `User`
+pub(crate) fn user_columns() -> String {
+    format!("{}, {}", USER_COLUMNS.join(", "), STARTED_PROGRESS)
+}
+
+#[allow(clippy::result_large_err)]
+impl QueryRequest {
+    /// build the sql to query users, all the values are bound as parameters.
+    /// use `QueryBuilder::sql` to render the sql for logging
+    pub fn to_query_builder(&self) -> Result<QueryBuilder<'static, Postgres>, Status> {
+        let mut builder = QueryBuilder::new("");
+        let page = Pagination::for_stream(&self.page_token, self.page_size)?;
+        self.push_select(&mut builder, &page)?;
+        Ok(builder)
+    }
+
+    /// push the SELECT statement of the query to the builder
+    pub(crate) fn push_select(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        page: &Pagination,
+    ) -> Result<(), Status> {
+        self.push_select_columns(builder, &user_columns(), page)
+    }
+
+    /// push the SELECT statement of the columns in the field mask to the builder
+    pub(crate) fn push_select_profiles(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        page: &Pagination,
+    ) -> Result<(), Status> {
+        let columns = self.profile_columns()?;
+        self.push_select_columns(builder, &columns, page)
+    }
+
+    fn push_select_columns(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        columns: &str,
+        page: &Pagination,
+    ) -> Result<(), Status> {
+        builder.push(format!("SELECT {} FROM {}", columns, USER_STATS_TABLE));
+        let conditions = self.push_conditions(builder)?;
+        page.push(conditions);
+        Ok(())
+    }
+
+    /// push the WHERE clause of the query to the builder, more conditions could be added with
+    /// the returned `Conditions`
+    pub(crate) fn push_conditions<'a>(
+        &self,
+        builder: &'a mut QueryBuilder<'static, Postgres>,
+    ) -> Result<Conditions<'a>, Status> {
+        let mut conditions = self.push_matches(builder)?;
+        for cap in &self.caps {
+            cap.push(conditions.next())?;
+        }
+        Ok(conditions)
+    }
+
+    /// push the WHERE clause of the query without the caps
+    pub(crate) fn push_matches<'a>(
+        &self,
+        builder: &'a mut QueryBuilder<'static, Postgres>,
+    ) -> Result<Conditions<'a>, Status> {
+        let mut conditions = Conditions::new(builder);
+
+        // sort by column name, so the same query always generates the same sql
+        for (name, tq) in self.timestamps.iter().sorted_by_key(|(k, _)| *k) {
+            let column = Column::find_with_kind(name, ColumnKind::Timestamp)?;
+            conditions.push_timestamp(column, tq)?;
+        }
+
+        for (name, iq) in self.ids.iter().sorted_by_key(|(k, _)| *k) {
+            let column = Column::find_with_kind(name, ColumnKind::IdArray)?;
+            conditions.push_ids(column, iq)?;
+        }
+
+        if let Some(filter) = &self.filter {
+            filter.push(conditions.next(), 0)?;
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// helper to join conditions with AND, the first condition starts the WHERE clause
+pub(crate) struct Conditions<'a> {
+    builder: &'a mut QueryBuilder<'static, Postgres>,
+    empty: bool,
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a> Conditions<'a> {
+    pub fn new(builder: &'a mut QueryBuilder<'static, Postgres>) -> Self {
+        Self {
+            builder,
+            empty: true,
+        }
+    }
+
+    /// start a new condition
+    pub fn next(&mut self) -> &mut QueryBuilder<'static, Postgres> {
+        if self.empty {
+            self.builder.push(" WHERE ");
+            self.empty = false;
+        } else {
+            self.builder.push(" AND ");
+        }
+        self.builder
+    }
+
+    /// finish the WHERE clause, and get back the builder
+    pub fn finish(self) -> &'a mut QueryBuilder<'static, Postgres> {
+        self.builder
+    }
+
+    fn push_timestamp(&mut self, column: &Column, tq: &TimeQuery) -> Result<(), Status> {
+        if tq.is_unbounded() {
+            return Ok(());
+        }
+        push_time_range(self.next(), column, tq)
+    }
+
+    fn push_ids(&mut self, column: &Column, iq: &IdQuery) -> Result<(), Status> {
+        if iq.ids.is_empty() {
+            return Ok(());
+        }
+
+        let ids = to_content_ids(&iq.ids)?;
+        // <@ 表示 ids 被 column 包含
+        self.next()
+            .push_bind(ids)
+            .push(format!(" <@ {}", column.name));
+        Ok(())
+    }
+}
+
+/// push the condition of a timestamp column in the range, TRUE if there is no bound
+#[allow(clippy::result_large_err)]
+pub(crate) fn push_time_range(
+    builder: &mut QueryBuilder<'static, Postgres>,
+    column: &Column,
+    tq: &TimeQuery,
+) -> Result<(), Status> {
+    match tq.bounds(Utc::now())? {
+        (None, None) => {
+            builder.push("TRUE");
+        }
+        (Some(lower), None) => {
+            builder
+                .push(format!("{} >= ", column.name))
+                .push_bind(lower);
+        }
+        (None, Some(upper)) => {
+            builder
+                .push(format!("{} <= ", column.name))
+                .push_bind(upper);
+        }
+        (Some(lower), Some(upper)) => {
+            builder
+                .push(format!("{} BETWEEN ", column.name))
+                .push_bind(lower)
+                .push(" AND ")
+                .push_bind(upper);
+        }
+    }
+    Ok(())
+}
+
+/// the lower and upper bounds of a time range, None is unbounded
+pub(crate) type TimeBounds = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+#[allow(clippy::result_large_err)]
+impl TimeQuery {
+    /// the range relative to the time the query runs, e.g. `ago(Some(30 days), None)` is the
+    /// last 30 days
+    pub fn ago(lower: Option<Duration>, upper: Option<Duration>) -> Self {
+        let to_pb = |d: Duration| prost_types::Duration {
+            seconds: d.as_secs() as i64,
+            nanos: d.subsec_nanos() as i32,
+        };
+        Self {
+            lower_ago: lower.map(to_pb),
+            upper_ago: upper.map(to_pb),
+            ..Default::default()
+        }
+    }
+
+    /// the absolute bounds of the range, the relative bounds are resolved against now
+    pub(crate) fn bounds(&self, now: DateTime<Utc>) -> Result<TimeBounds, Status> {
+        let bound = |at: &Option<Timestamp>, ago: &Option<prost_types::Duration>| match (at, ago) {
+            (Some(_), Some(_)) => Err(Status::invalid_argument(
+                "a time bound could not be both absolute and relative",
+            )),
+            (Some(at), None) => ts_to_utc(at).map(Some),
+            (None, Some(ago)) => {
+                let ago = TimeDelta::new(ago.seconds, ago.nanos.try_into().unwrap_or(u32::MAX))
+                    .filter(|d| *d >= TimeDelta::zero())
+                    .ok_or_else(|| {
+                        Status::invalid_argument(format!("invalid relative time {:?}", ago))
+                    })?;
+                Ok(Some(now - ago))
+            }
+            (None, None) => Ok(None),
+        };
+        Ok((
+            bound(&self.lower, &self.lower_ago)?,
+            bound(&self.upper, &self.upper_ago)?,
+        ))
+    }
+
+    /// resolve the relative bounds against now, so the range no longer moves with time
+    pub(crate) fn resolve(&self, now: DateTime<Utc>) -> Result<Self, Status> {
+        let (lower, upper) = self.bounds(now)?;
+        Ok(Self {
+            lower: lower.map(utc_to_ts),
+            upper: upper.map(utc_to_ts),
+            ..Default::default()
+        })
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.lower.is_none()
+            && self.upper.is_none()
+            && self.lower_ago.is_none()
+            && self.upper_ago.is_none()
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl QueryRequest {
+    /// the query with all the relative time ranges resolved against now, it matches the users
+    /// the query matches at the time
+    pub(crate) fn resolve(&self, now: DateTime<Utc>) -> Result<Self, Status> {
+        let mut query = self.clone();
+        for tq in query.timestamps.values_mut() {
+            *tq = tq.resolve(now)?;
+        }
+        if let Some(filter) = &mut query.filter {
+            filter.resolve(now)?;
+        }
+        Ok(query)
+    }
+}
+
+/// content ids are stored as INT[], make sure they fit in i32
+#[allow(clippy::result_large_err)]
+pub(crate) fn to_content_ids(ids: &[u32]) -> Result<Vec<i32>, Status> {
+    ids.iter()
+        .map(|&id| {
+            i32::try_from(id)
+                .map_err(|_| Status::invalid_argument(format!("invalid content id {}", id)))
+        })
+        .collect()
+}
+
+pub(crate) fn utc_to_ts(dt: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
+
+#[allow(clippy::result_large_err)]
+pub(crate) fn ts_to_utc(ts: &Timestamp) -> Result<DateTime<Utc>, Status> {
+    u32::try_from(ts.nanos)
+        .ok()
+        .and_then(|nanos| Utc.timestamp_opt(ts.seconds, nanos).single())
+        .ok_or_else(|| Status::invalid_argument(format!("invalid timestamp {:?}", ts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tonic::Code;
+
+    use super::*;
+    use crate::pb::QueryRequestBuilder;
+    use crate::test_utils::{days_to_timestamp, to_idquery, to_timequery};
+
+    #[test]
+    fn timestamp_query_should_work() -> Result<()> {
+        let query = |tq: TimeQuery| -> Result<String> {
+            let query = QueryRequestBuilder::default()
+                .timestamp(("created_at".to_string(), tq))
+                .build()?;
+            Ok(query.to_query_builder()?.sql().to_string())
+        };
+        let prefix = format!(
+            "SELECT email, name, started_but_not_finished, {} FROM user_stats",
+            STARTED_PROGRESS
+        );
+
+        assert_eq!(query(to_timequery(None, None))?, prefix);
+        assert_eq!(
+            query(to_timequery(Some(15), None))?,
+            format!("{} WHERE created_at >= $1", prefix)
+        );
+        assert_eq!(
+            query(to_timequery(None, Some(15)))?,
+            format!("{} WHERE created_at <= $1", prefix)
+        );
+        assert_eq!(
+            query(to_timequery(Some(15), Some(5)))?,
+            format!("{} WHERE created_at BETWEEN $1 AND $2", prefix)
+        );
+        let day = Duration::from_secs(24 * 3600);
+        assert_eq!(
+            query(TimeQuery::ago(Some(day * 7), None))?,
+            format!("{} WHERE created_at >= $1", prefix)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn relative_time_query_should_resolve_against_now() -> Result<()> {
+        let now = Utc.with_ymd_and_hms(2024, 7, 6, 0, 0, 0).unwrap();
+        let day = Duration::from_secs(24 * 3600);
+        let mut tq = TimeQuery::ago(Some(day * 15), Some(day * 5));
+        assert_eq!(tq.resolve(now)?, to_timequery(Some(15), Some(5)));
+
+        tq.upper = tq.upper_ago.take().map(|_| utc_to_ts(now));
+        assert_eq!(tq.resolve(now)?, to_timequery(Some(15), Some(0)));
+
+        tq.lower = Some(utc_to_ts(now));
+        let status = tq.resolve(now).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[test]
+    fn query_request_to_sql_should_work() -> Result<()> {
+        let dt1 = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let dt2 = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+        let query = QueryRequest::new_with_date("created_at", dt1, dt2);
+        let builder = query.to_query_builder()?;
+        assert_eq!(builder.sql(), format!("SELECT email, name, started_but_not_finished, {} FROM user_stats WHERE created_at BETWEEN $1 AND $2", STARTED_PROGRESS));
+
+        let query = QueryRequestBuilder::default()
+            .timestamp(("last_visited_at".to_string(), to_timequery(Some(50), None)))
+            .timestamp(("created_at".to_string(), to_timequery(Some(220), None)))
+            .id(("viewed_but_not_started".to_string(), to_idquery(&[269904])))
+            .id(("finished".to_string(), to_idquery(&[])))
+            .build()?;
+        let builder = query.to_query_builder()?;
+        assert_eq!(builder.sql(), format!("SELECT email, name, started_but_not_finished, {} FROM user_stats WHERE created_at >= $1 AND last_visited_at >= $2 AND $3 <@ viewed_but_not_started", STARTED_PROGRESS));
+        Ok(())
+    }
+
+    #[test]
+    fn query_request_with_unknown_column_should_fail() -> Result<()> {
+        let query = QueryRequestBuilder::default()
+            .timestamp((
+                "created_at; DROP TABLE user_stats".to_string(),
+                to_timequery(Some(10), None),
+            ))
+            .build()?;
+        let status = to_sql_err(&query);
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "unknown column created_at; DROP TABLE user_stats of user_stats"
+        );
+
+        let query = QueryRequestBuilder::default()
+            .id(("created_at".to_string(), to_idquery(&[1])))
+            .build()?;
+        let status = to_sql_err(&query);
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "column created_at is a Timestamp column, expect IdArray"
+        );
+        Ok(())
+    }
+
+    fn to_sql_err(query: &QueryRequest) -> Status {
+        match query.to_query_builder() {
+            Ok(builder) => panic!("expect error, but got sql: {}", builder.sql()),
+            Err(status) => status,
+        }
+    }
+
+    #[test]
+    fn ts_to_utc_should_work() -> Result<()> {
+        let ts = days_to_timestamp(15);
+        let dt = ts_to_utc(&ts)?;
+        assert_eq!(dt.timestamp(), ts.seconds);
+
+        let ts = Timestamp {
+            seconds: 0,
+            nanos: -1,
+        };
+        assert!(ts_to_utc(&ts).is_err());
+        Ok(())
+    }
+}
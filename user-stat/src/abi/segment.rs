@@ -0,0 +1,419 @@
+use chrono::{DateTime, Utc};
+use prost::Message;
+use sqlx::{FromRow, PgPool};
+use tonic::Status;
+
+use super::query::utc_to_ts;
+use crate::pb::{
+    CreateSegmentRequest, DeleteSegmentRequest, GetSegmentRequest, ListSegmentsResponse,
+    QueryRequest, QuerySegmentRequest, Segment, UpdateSegmentRequest,
+};
+
+const MAX_NAME_LEN: usize = 64;
+
+const SELECT_SEGMENT: &str = "SELECT s.name, s.description, v.version, v.query, s.created_at, \
+    s.updated_at FROM segments s JOIN segment_versions v ON s.name = v.name";
+
+#[derive(Debug, FromRow)]
+struct SegmentRow {
+    name: String,
+    description: String,
+    version: i32,
+    query: Vec<u8>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl CreateSegmentRequest {
+    pub(crate) async fn create(&self, pool: &PgPool) -> Result<Segment, Status> {
+        validate_name(&self.name)?;
+        let query = encode_query(self.query.as_ref())?;
+
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        sqlx::query("INSERT INTO segments (name, description) VALUES ($1, $2)")
+            .bind(&self.name)
+            .bind(&self.description)
+            .execute(&mut *ts)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(e) if e.is_unique_violation() => {
+                    Status::already_exists(format!("segment {} already exists", self.name))
+                }
+                e => to_status(e),
+            })?;
+        sqlx::query("INSERT INTO segment_versions (name, version, query) VALUES ($1, 1, $2)")
+            .bind(&self.name)
+            .bind(query)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+
+        get_segment(pool, &self.name, 0).await
+    }
+}
+
+impl UpdateSegmentRequest {
+    /// replace the description and the query of the segment with a new version
+    pub(crate) async fn update(&self, pool: &PgPool) -> Result<Segment, Status> {
+        let query = encode_query(self.query.as_ref())?;
+
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        let version: i32 =
+            sqlx::query_scalar("SELECT version FROM segments WHERE name = $1 FOR UPDATE")
+                .bind(&self.name)
+                .fetch_optional(&mut *ts)
+                .await
+                .map_err(to_status)?
+                .ok_or_else(|| not_found(&self.name))?;
+        if self.expected_version != 0 && self.expected_version != version as u32 {
+            return Err(Status::failed_precondition(format!(
+                "segment {} is at version {}, expect {}",
+                self.name, version, self.expected_version
+            )));
+        }
+
+        let version = version + 1;
+        sqlx::query(
+            "UPDATE segments SET description = $2, version = $3, updated_at = now() \
+             WHERE name = $1",
+        )
+        .bind(&self.name)
+        .bind(&self.description)
+        .bind(version)
+        .execute(&mut *ts)
+        .await
+        .map_err(to_status)?;
+        sqlx::query("INSERT INTO segment_versions (name, version, query) VALUES ($1, $2, $3)")
+            .bind(&self.name)
+            .bind(version)
+            .bind(query)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+
+        get_segment(pool, &self.name, 0).await
+    }
+}
+
+impl GetSegmentRequest {
+    pub(crate) async fn get(&self, pool: &PgPool) -> Result<Segment, Status> {
+        get_segment(pool, &self.name, self.version).await
+    }
+}
+
+impl DeleteSegmentRequest {
+    /// delete the segment with all its versions
+    pub(crate) async fn delete(&self, pool: &PgPool) -> Result<(), Status> {
+        let ret = sqlx::query("DELETE FROM segments WHERE name = $1")
+            .bind(&self.name)
+            .execute(pool)
+            .await
+            .map_err(to_status)?;
+        if ret.rows_affected() == 0 {
+            return Err(not_found(&self.name));
+        }
+        Ok(())
+    }
+}
+
+impl QuerySegmentRequest {
+    /// the stored query of the segment evaluated at the time, with the pagination of the
+    /// request
+    pub(crate) async fn to_query(
+        &self,
+        pool: &PgPool,
+        now: DateTime<Utc>,
+    ) -> Result<QueryRequest, Status> {
+        let segment = get_segment(pool, &self.name, self.version).await?;
+        let mut query = segment.query.unwrap_or_default().resolve(now)?;
+        query.page_size = self.page_size;
+        query.page_token.clone_from(&self.page_token);
+        Ok(query)
+    }
+}
+
+pub(crate) async fn list_segments(pool: &PgPool) -> Result<ListSegmentsResponse, Status> {
+    let sql = format!(
+        "{} AND s.version = v.version ORDER BY s.name",
+        SELECT_SEGMENT
+    );
+    let rows: Vec<SegmentRow> = sqlx::query_as(&sql)
+        .fetch_all(pool)
+        .await
+        .map_err(to_status)?;
+    let segments = rows
+        .into_iter()
+        .map(Segment::try_from)
+        .collect::<Result<_, _>>()?;
+    Ok(ListSegmentsResponse { segments })
+}
+
+/// get the version of the segment, 0 means the latest version
+pub(super) async fn get_segment(
+    pool: &PgPool,
+    name: &str,
+    version: u32,
+) -> Result<Segment, Status> {
+    let row: Option<SegmentRow> = if version == 0 {
+        let sql = format!(
+            "{} AND s.version = v.version WHERE s.name = $1",
+            SELECT_SEGMENT
+        );
+        sqlx::query_as(&sql).bind(name).fetch_optional(pool).await
+    } else {
+        let sql = format!("{} WHERE s.name = $1 AND v.version = $2", SELECT_SEGMENT);
+        sqlx::query_as(&sql)
+            .bind(name)
+            .bind(version as i32)
+            .fetch_optional(pool)
+            .await
+    }
+    .map_err(to_status)?;
+
+    match row {
+        Some(row) => row.try_into(),
+        None if version == 0 => Err(not_found(name)),
+        None => Err(Status::not_found(format!(
+            "segment {} version {} is not found",
+            name, version
+        ))),
+    }
+}
+
+impl TryFrom<SegmentRow> for Segment {
+    type Error = Status;
+
+    fn try_from(row: SegmentRow) -> Result<Self, Self::Error> {
+        let query = QueryRequest::decode(row.query.as_slice()).map_err(|e| {
+            Status::internal(format!(
+                "Failed to decode query of segment {}: {}",
+                row.name, e
+            ))
+        })?;
+        Ok(Self {
+            name: row.name,
+            description: row.description,
+            version: row.version as u32,
+            query: Some(query),
+            created_at: Some(utc_to_ts(row.created_at)),
+            updated_at: Some(utc_to_ts(row.updated_at)),
+        })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn validate_name(name: &str) -> Result<(), Status> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        return Err(Status::invalid_argument(format!(
+            "invalid segment name {:?}, only lower case letters, digits, '_' and '-' are allowed, at most {} chars",
+            name, MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// make sure the query could be compiled before it is stored, the pagination is not stored
+#[allow(clippy::result_large_err)]
+fn encode_query(query: Option<&QueryRequest>) -> Result<Vec<u8>, Status> {
+    let mut query = query.cloned().unwrap_or_default();
+    query.page_size = 0;
+    query.page_token.clear();
+    query.to_query_builder()?;
+    Ok(query.encode_to_vec())
+}
+
+fn not_found(name: &str) -> Status {
+    Status::not_found(format!("segment {} is not found", name))
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to access segments: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use chrono::TimeZone;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{Filter, Gender, QueryRequestBuilder, TimeQuery},
+        test_utils::get_test_pool,
+        test_utils::to_timequery,
+    };
+
+    fn dormant(days: i64) -> QueryRequest {
+        QueryRequestBuilder::default()
+            .timestamp((
+                "last_visited_at".to_string(),
+                to_timequery(None, Some(days)),
+            ))
+            .page_size(10u32)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn segment_crud_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let req = CreateSegmentRequest {
+            name: "dormant".to_string(),
+            description: "not visited in 30 days".to_string(),
+            query: Some(dormant(30)),
+        };
+        let segment = req.create(&pool).await?;
+        assert_eq!(segment.version, 1);
+        // pagination is not stored
+        let mut expected = dormant(30);
+        expected.page_size = 0;
+        assert_eq!(segment.query, Some(expected.clone()));
+
+        let status = req.create(&pool).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let req = UpdateSegmentRequest {
+            name: "dormant".to_string(),
+            description: "not visited in 14 days".to_string(),
+            query: Some(dormant(14)),
+            expected_version: 1,
+        };
+        let segment = req.update(&pool).await?;
+        assert_eq!(segment.version, 2);
+        assert_eq!(segment.description, "not visited in 14 days");
+        // the update is based on an outdated version
+        let status = req.update(&pool).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        // older versions are kept
+        let req = GetSegmentRequest {
+            name: "dormant".to_string(),
+            version: 1,
+        };
+        assert_eq!(req.get(&pool).await?.query, Some(expected));
+
+        CreateSegmentRequest {
+            name: "female".to_string(),
+            query: Some(QueryRequest {
+                filter: Some(Filter::gender(Gender::Female)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await?;
+        let segments = list_segments(&pool).await?.segments;
+        let names = segments.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["dormant", "female"]);
+        assert_eq!(segments[0].version, 2);
+
+        let req = DeleteSegmentRequest {
+            name: "dormant".to_string(),
+        };
+        req.delete(&pool).await?;
+        assert_eq!(req.delete(&pool).await.unwrap_err().code(), Code::NotFound);
+        let req = GetSegmentRequest {
+            name: "dormant".to_string(),
+            version: 1,
+        };
+        assert_eq!(req.get(&pool).await.unwrap_err().code(), Code::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn relative_segment_should_slide_with_time() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let query = QueryRequestBuilder::default()
+            .timestamp((
+                "last_visited_at".to_string(),
+                TimeQuery::ago(None, Some(Duration::from_secs(30 * 24 * 3600))),
+            ))
+            .build()?;
+        let segment = CreateSegmentRequest {
+            name: "dormant".to_string(),
+            description: "not visited in 30 days".to_string(),
+            query: Some(query.clone()),
+        }
+        .create(&pool)
+        .await?;
+        // the range is stored relative
+        assert_eq!(segment.query, Some(query));
+
+        let count = |now| {
+            let req = QuerySegmentRequest {
+                name: "dormant".to_string(),
+                ..Default::default()
+            };
+            let pool = pool.clone();
+            async move {
+                let query = req.to_query(&pool, now).await?;
+                anyhow::Ok(query.count(&pool, None).await?.count)
+            }
+        };
+        let june = count(Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap()).await?;
+        let august = count(Utc.with_ymd_and_hms(2024, 8, 1, 0, 0, 0).unwrap()).await?;
+        assert!(june < august, "{} < {}", june, august);
+        assert_eq!(august, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_segment_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let req = CreateSegmentRequest {
+            name: "Dormant Users".to_string(),
+            ..Default::default()
+        };
+        let status = req.create(&pool).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let req = CreateSegmentRequest {
+            name: "bad-query".to_string(),
+            query: Some(
+                QueryRequestBuilder::default()
+                    .id(("email".to_string(), Default::default()))
+                    .build()?,
+            ),
+            ..Default::default()
+        };
+        let status = req.create(&pool).await.unwrap_err();
+        assert_eq!(
+            status.message(),
+            "column email is a Text column, expect IdArray"
+        );
+
+        let mut range = TimeQuery::ago(Some(Duration::from_secs(3600)), None);
+        range.lower = Some(utc_to_ts(Utc::now()));
+        let req = CreateSegmentRequest {
+            name: "bad-range".to_string(),
+            query: Some(
+                QueryRequestBuilder::default()
+                    .timestamp(("created_at".to_string(), range))
+                    .build()?,
+            ),
+            ..Default::default()
+        };
+        let status = req.create(&pool).await.unwrap_err();
+        assert_eq!(
+            status.message(),
+            "a time bound could not be both absolute and relative"
+        );
+
+        let req = UpdateSegmentRequest {
+            name: "not-exist".to_string(),
+            ..Default::default()
+        };
+        let status = req.update(&pool).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        Ok(())
+    }
+}
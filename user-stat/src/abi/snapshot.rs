@@ -0,0 +1,387 @@
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, FromRow, PgPool, Postgres, QueryBuilder, Row};
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    query::{utc_to_ts, USER_STATS_TABLE},
+    segment::get_segment,
+};
+use crate::pb::{
+    DiffSnapshotsRequest, MembershipChange, MembershipDiff, SegmentSnapshot,
+    SnapshotSegmentRequest, User,
+};
+
+impl SnapshotSegmentRequest {
+    /// save the users matching the query of the segment at the time as a new run, the
+    /// relative time ranges are resolved against the time so the membership slides with it
+    pub(crate) async fn snapshot(
+        &self,
+        pool: &PgPool,
+        now: DateTime<Utc>,
+    ) -> Result<SegmentSnapshot, Status> {
+        let segment = get_segment(pool, &self.name, self.version).await?;
+        let query = segment.query.unwrap_or_default().resolve(now)?;
+
+        let mut ts = pool.begin().await.map_err(to_status)?;
+        let (run_id, created_at): (i64, DateTime<Utc>) = sqlx::query_as(
+            "INSERT INTO segment_snapshots (segment, version, created_at) VALUES ($1, $2, $3) \
+             RETURNING run_id, created_at",
+        )
+        .bind(&segment.name)
+        .bind(segment.version as i32)
+        .bind(now)
+        .fetch_one(&mut *ts)
+        .await
+        .map_err(|e| match e {
+            // the segment is deleted in the meantime
+            sqlx::Error::Database(e) if e.is_foreign_key_violation() => {
+                Status::not_found(format!("segment {} is not found", segment.name))
+            }
+            e => to_status(e),
+        })?;
+
+        let mut builder =
+            QueryBuilder::new("INSERT INTO segment_snapshot_members (run_id, email) SELECT ");
+        builder
+            .push_bind(run_id)
+            .push(format!(", email FROM {}", USER_STATS_TABLE));
+        query.push_conditions(&mut builder)?;
+        info!("Snapshot segment {}: {}", segment.name, builder.sql());
+        let member_count = builder
+            .build()
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?
+            .rows_affected();
+
+        sqlx::query("UPDATE segment_snapshots SET member_count = $2 WHERE run_id = $1")
+            .bind(run_id)
+            .bind(member_count as i64)
+            .execute(&mut *ts)
+            .await
+            .map_err(to_status)?;
+        ts.commit().await.map_err(to_status)?;
+
+        Ok(SegmentSnapshot {
+            run_id: run_id as u64,
+            segment: segment.name,
+            version: segment.version,
+            member_count,
+            created_at: Some(utc_to_ts(created_at)),
+        })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl DiffSnapshotsRequest {
+    /// resolve the (from, to) run ids of the diff, both must be snapshots of the segment
+    pub(crate) async fn runs(&self, pool: &PgPool) -> Result<(i64, i64), Status> {
+        let to = if self.to_run_id == 0 {
+            sqlx::query_scalar(
+                "SELECT run_id FROM segment_snapshots WHERE segment = $1 \
+                 ORDER BY run_id DESC LIMIT 1",
+            )
+            .bind(&self.segment)
+            .fetch_optional(pool)
+            .await
+            .map_err(to_status)?
+            .ok_or_else(|| Status::not_found(format!("segment {} has no snapshot", self.segment)))?
+        } else {
+            self.find_run(self.to_run_id, pool).await?
+        };
+
+        let from = if self.from_run_id == 0 {
+            sqlx::query_scalar(
+                "SELECT run_id FROM segment_snapshots WHERE segment = $1 AND run_id < $2 \
+                 ORDER BY run_id DESC LIMIT 1",
+            )
+            .bind(&self.segment)
+            .bind(to)
+            .fetch_optional(pool)
+            .await
+            .map_err(to_status)?
+            .ok_or_else(|| {
+                Status::failed_precondition(format!(
+                    "segment {} has no snapshot before {}",
+                    self.segment, to
+                ))
+            })?
+        } else {
+            self.find_run(self.from_run_id, pool).await?
+        };
+
+        Ok((from, to))
+    }
+
+    /// push the SELECT statement of the diff to the builder, the users removed from user_stats
+    /// only have the email
+    pub(crate) fn push_select(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        from: i64,
+        to: i64,
+    ) -> Result<(), Status> {
+        let change = MembershipChange::try_from(self.change).map_err(|_| {
+            Status::invalid_argument(format!("invalid membership change {}", self.change))
+        })?;
+        let changes = match change {
+            MembershipChange::Unspecified => {
+                vec![MembershipChange::Entered, MembershipChange::Left]
+            }
+            change => vec![change],
+        };
+
+        builder.push(
+            "SELECT d.email, COALESCE(u.name, '') AS name, \
+             COALESCE(u.started_but_not_finished, '{}') AS started_but_not_finished, d.change \
+             FROM (",
+        );
+        for (i, change) in changes.into_iter().enumerate() {
+            if i > 0 {
+                builder.push(" UNION ALL ");
+            }
+            // entered: in to but not in from, left: in from but not in to
+            let (a, b) = match change {
+                MembershipChange::Left => (from, to),
+                _ => (to, from),
+            };
+            builder
+                .push(format!(
+                    "SELECT email, {} AS change FROM segment_snapshot_members WHERE run_id = ",
+                    change as i32
+                ))
+                .push_bind(a)
+                .push(" EXCEPT SELECT email, ")
+                .push(change as i32)
+                .push(" FROM segment_snapshot_members WHERE run_id = ")
+                .push_bind(b);
+        }
+        builder.push(format!(
+            ") d LEFT JOIN {} u ON u.email = d.email ORDER BY d.email",
+            USER_STATS_TABLE
+        ));
+        Ok(())
+    }
+
+    async fn find_run(&self, run_id: u64, pool: &PgPool) -> Result<i64, Status> {
+        sqlx::query_scalar(
+            "SELECT run_id FROM segment_snapshots WHERE segment = $1 AND run_id = $2",
+        )
+        .bind(&self.segment)
+        .bind(run_id as i64)
+        .fetch_optional(pool)
+        .await
+        .map_err(to_status)?
+        .ok_or_else(|| {
+            Status::not_found(format!(
+                "snapshot {} of segment {} is not found",
+                run_id, self.segment
+            ))
+        })
+    }
+}
+
+impl FromRow<'_, PgRow> for MembershipDiff {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            user: Some(User::from_row(row)?),
+            change: row.try_get("change")?,
+        })
+    }
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to access segment snapshots: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use anyhow::Result;
+    use chrono::{TimeDelta, TimeZone};
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        pb::{CreateSegmentRequest, Filter, Gender, QueryRequestBuilder, TimeQuery},
+        test_utils::get_test_pool,
+    };
+
+    fn diff_request(
+        from_run_id: u64,
+        to_run_id: u64,
+        change: MembershipChange,
+    ) -> DiffSnapshotsRequest {
+        DiffSnapshotsRequest {
+            segment: "female".to_string(),
+            from_run_id,
+            to_run_id,
+            change: change as i32,
+        }
+    }
+
+    async fn diff(
+        pool: &PgPool,
+        req: &DiffSnapshotsRequest,
+    ) -> Result<Vec<(String, MembershipChange)>> {
+        let (from, to) = req.runs(pool).await?;
+        let mut builder = QueryBuilder::new("");
+        req.push_select(&mut builder, from, to)?;
+        let ret: Vec<MembershipDiff> = builder.build_query_as().fetch_all(pool).await?;
+        Ok(ret
+            .into_iter()
+            .map(|d| {
+                let change = d.change();
+                (d.user.unwrap_or_default().email, change)
+            })
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn snapshot_diff_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        CreateSegmentRequest {
+            name: "female".to_string(),
+            query: Some(
+                QueryRequestBuilder::default()
+                    .filter(Filter::gender(Gender::Female))
+                    .build()?,
+            ),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await?;
+
+        let req = SnapshotSegmentRequest {
+            name: "female".to_string(),
+            version: 0,
+        };
+        let first = req.snapshot(&pool, Utc::now()).await?;
+        assert_eq!(first.version, 1);
+        assert!(first.member_count > 0);
+        // no snapshot to compare with
+        let status = diff(&pool, &diff_request(0, 0, MembershipChange::Unspecified))
+            .await
+            .unwrap_err()
+            .downcast::<Status>()?;
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let entered = "clifford.smjmlbu8@example.net";
+        let left = "frederik.2r2jvb8l@example.org";
+        sqlx::query("UPDATE user_stats SET gender = 'female' WHERE email = $1")
+            .bind(entered)
+            .execute(&pool)
+            .await?;
+        sqlx::query("DELETE FROM user_stats WHERE email = $1")
+            .bind(left)
+            .execute(&pool)
+            .await?;
+        let second = req.snapshot(&pool, Utc::now()).await?;
+        assert_eq!(second.member_count, first.member_count);
+
+        let ret = diff(&pool, &diff_request(0, 0, MembershipChange::Unspecified)).await?;
+        assert_eq!(
+            ret,
+            [
+                (entered.to_string(), MembershipChange::Entered),
+                (left.to_string(), MembershipChange::Left)
+            ]
+        );
+        let ret = diff(
+            &pool,
+            &diff_request(second.run_id, first.run_id, MembershipChange::Entered),
+        )
+        .await?;
+        assert_eq!(ret, [(left.to_string(), MembershipChange::Entered)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_diff_should_follow_time() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        // visited in the last 7 days
+        let week = Duration::from_secs(7 * 24 * 3600);
+        CreateSegmentRequest {
+            name: "recent".to_string(),
+            query: Some(
+                QueryRequestBuilder::default()
+                    .timestamp((
+                        "last_visited_at".to_string(),
+                        TimeQuery::ago(Some(week), None),
+                    ))
+                    .build()?,
+            ),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await?;
+
+        let req = SnapshotSegmentRequest {
+            name: "recent".to_string(),
+            version: 0,
+        };
+        // nothing changes in user_stats between the snapshots, only the time moves
+        let day = |d| Utc.with_ymd_and_hms(2024, 6, d, 0, 0, 0).unwrap();
+        let first = req.snapshot(&pool, day(10)).await?;
+        let second = req.snapshot(&pool, day(20)).await?;
+        assert_eq!(first.created_at, Some(utc_to_ts(day(10))));
+
+        let visited: Vec<(String, DateTime<Utc>)> =
+            sqlx::query_as("SELECT email, last_visited_at FROM user_stats")
+                .fetch_all(&pool)
+                .await?;
+        let members = |now: DateTime<Utc>| {
+            let lower = now - TimeDelta::days(7);
+            visited
+                .iter()
+                .filter(|(_, at)| *at >= lower)
+                .map(|(email, _)| email.clone())
+                .collect::<HashSet<_>>()
+        };
+        let (before, after) = (members(day(10)), members(day(20)));
+        assert_eq!(first.member_count, before.len() as u64);
+        assert_eq!(second.member_count, after.len() as u64);
+
+        let req = DiffSnapshotsRequest {
+            segment: "recent".to_string(),
+            ..Default::default()
+        };
+        let ret = diff(&pool, &req).await?;
+        let mut expected = after
+            .difference(&before)
+            .map(|e| (e.clone(), MembershipChange::Entered))
+            .chain(
+                before
+                    .difference(&after)
+                    .map(|e| (e.clone(), MembershipChange::Left)),
+            )
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert!(!expected.is_empty());
+        assert_eq!(ret, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_unknown_snapshot_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let req = diff_request(0, 0, MembershipChange::Unspecified);
+        assert_eq!(req.runs(&pool).await.unwrap_err().code(), Code::NotFound);
+        let req = diff_request(1, 2, MembershipChange::Unspecified);
+        let status = req.runs(&pool).await.unwrap_err();
+        assert_eq!(
+            status.message(),
+            "snapshot 2 of segment female is not found"
+        );
+
+        let mut req = diff_request(1, 2, MembershipChange::Unspecified);
+        req.change = 10;
+        let status = req
+            .push_select(&mut QueryBuilder::new(""), 1, 2)
+            .unwrap_err();
+        assert_eq!(status.message(), "invalid membership change 10");
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tonic::Status;
+use tracing::info;
+
+use super::{
+    cursor,
+    guard::{set_statement_timeout, timeout_status},
+    page::Pagination,
+};
+use crate::{
+    pb::{
+        CountResponse, MarkNotifiedRequest, MarkNotifiedResponse, QueryRequest, ReleaseRequest,
+        ReleaseResponse, ReserveRequest, User, UserPage,
+    },
+    ProfileStream, ReserveStream, ResponseStream,
+};
+
+/// the storage of user stats behind the service
+#[tonic::async_trait]
+pub trait UserStore: Send + Sync + 'static {
+    /// stream the users matching the query, the timeout applies to each statement
+    async fn query(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ResponseStream, Status>;
+
+    /// stream the profiles matching the query, only the fields in the field mask are set
+    async fn query_profiles(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ProfileStream, Status>;
+
+    /// one page of the users matching the query
+    async fn query_page(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<UserPage, Status>;
+
+    /// count the users matching the query, pagination is ignored
+    async fn count(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<CountResponse, Status>;
+
+    async fn mark_notified(
+        &self,
+        req: &MarkNotifiedRequest,
+    ) -> Result<MarkNotifiedResponse, Status>;
+
+    /// record the notification for the users of the query allowed by its caps, the check and
+    /// the record are atomic. the reserved users are streamed in batches afterwards
+    async fn reserve(
+        &self,
+        req: &ReserveRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ReserveStream, Status>;
+
+    /// delete the notifications recorded by a reservation for the users not notified
+    async fn release(&self, req: &ReleaseRequest) -> Result<ReleaseResponse, Status>;
+
+    /// the postgres pool, the raw queries, segments, events, import and estimation are only
+    /// supported with it
+    fn pool(&self) -> Option<&PgPool> {
+        None
+    }
+}
+
+/// the store of user_stats table in postgres
+pub struct PgStore {
+    pool: PgPool,
+    /// number of rows fetched from the cursor at a time
+    fetch_size: u32,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool, fetch_size: u32) -> Self {
+        Self { pool, fetch_size }
+    }
+}
+
+#[tonic::async_trait]
+#[allow(clippy::result_large_err)]
+impl UserStore for PgStore {
+    async fn query(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ResponseStream, Status> {
+        // generate sql base on query, and stream the users with a cursor
+        let page = Pagination::for_stream(&query.page_token, query.page_size)?;
+        cursor::stream_rows(&self.pool, self.fetch_size, timeout, |builder| {
+            query.push_select(builder, &page)
+        })
+        .await
+    }
+
+    async fn query_profiles(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ProfileStream, Status> {
+        let page = Pagination::for_stream(&query.page_token, query.page_size)?;
+        cursor::stream_rows(&self.pool, self.fetch_size, timeout, |builder| {
+            query.push_select_profiles(builder, &page)
+        })
+        .await
+    }
+
+    async fn query_page(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<UserPage, Status> {
+        let page = Pagination::for_page(&query.page_token, query.page_size)?;
+        let mut builder = QueryBuilder::new("");
+        query.push_select(&mut builder, &page)?;
+        let users = fetch_users(&self.pool, builder, timeout).await?;
+        Ok(UserPage::new(users, query.page_size))
+    }
+
+    async fn count(
+        &self,
+        query: &QueryRequest,
+        timeout: Option<Duration>,
+    ) -> Result<CountResponse, Status> {
+        query.count(&self.pool, timeout).await
+    }
+
+    async fn mark_notified(
+        &self,
+        req: &MarkNotifiedRequest,
+    ) -> Result<MarkNotifiedResponse, Status> {
+        req.mark(&self.pool).await
+    }
+
+    async fn reserve(
+        &self,
+        req: &ReserveRequest,
+        timeout: Option<Duration>,
+    ) -> Result<ReserveStream, Status> {
+        req.reserve(&self.pool, self.fetch_size, timeout).await
+    }
+
+    async fn release(&self, req: &ReleaseRequest) -> Result<ReleaseResponse, Status> {
+        req.release(&self.pool).await
+    }
+
+    fn pool(&self) -> Option<&PgPool> {
+        Some(&self.pool)
+    }
+}
+
+/// fetch all the users of the query at once
+pub(crate) async fn fetch_users(
+    pool: &PgPool,
+    mut builder: QueryBuilder<'_, Postgres>,
+    timeout: Option<Duration>,
+) -> Result<Vec<User>, Status> {
+    info!("Query users: {}", builder.sql());
+    let to_status = |e: sqlx::Error| match timeout_status(&e) {
+        Some(status) => status,
+        None => match e {
+            sqlx::Error::Database(e) => Status::invalid_argument(format!("Invalid query: {}", e)),
+            e => Status::internal(format!("Failed to fetch data: {}", e)),
+        },
+    };
+    // the timeout only lives in the transaction
+    let mut ts = pool.begin().await.map_err(to_status)?;
+    set_statement_timeout(&mut ts, timeout)
+        .await
+        .map_err(to_status)?;
+    let users = builder
+        .build_query_as::<User>()
+        .fetch_all(&mut *ts)
+        .await
+        .map_err(to_status)?;
+    ts.commit().await.map_err(to_status)?;
+    Ok(users)
+}
@@ -0,0 +1,302 @@
+use std::ops::ControlFlow;
+
+use sqlparser::{
+    ast::{
+        visit_expressions, visit_relations, Expr, Ident, ObjectName, SelectItem, SetExpr,
+        Statement, TableFactor,
+    },
+    dialect::PostgreSqlDialect,
+    parser::Parser,
+};
+use tonic::Status;
+
+use super::query::{Column, USER_COLUMNS, USER_STATS_TABLE};
+use crate::pb::RawQueryRequest;
+
+/// read only functions which are allowed to be used in a raw query
+const ALLOWED_FUNCTIONS: &[&str] = &[
+    "abs",
+    "array_length",
+    "cardinality",
+    "coalesce",
+    "current_date",
+    "current_timestamp",
+    "date_trunc",
+    "greatest",
+    "least",
+    "length",
+    "lower",
+    "now",
+    "nullif",
+    "upper",
+];
+
+#[allow(clippy::result_large_err)]
+impl RawQueryRequest {
+    /// the sql statement without the trailing semicolon, so that it could be embedded
+    pub(crate) fn statement(&self) -> &str {
+        self.query.trim().trim_end_matches(';').trim_end()
+    }
+
+    /// make sure the query is a single SELECT against user_stats, and it projects the columns
+    /// that `User` can decode
+    pub fn validate(&self) -> Result<(), Status> {
+        let statements = Parser::parse_sql(&PostgreSqlDialect {}, &self.query)
+            .map_err(|e| invalid(format!("failed to parse query: {}", e)))?;
+
+        let statement = match statements.as_slice() {
+            [statement] => statement,
+            [] => return Err(invalid("query is empty")),
+            _ => return Err(invalid("only a single statement is allowed")),
+        };
+
+        let Statement::Query(query) = statement else {
+            return Err(invalid("only SELECT statement is allowed"));
+        };
+        if query.with.is_some() {
+            return Err(invalid("WITH clause is not allowed"));
+        }
+        if !query.locks.is_empty() {
+            return Err(invalid("locking clause (FOR UPDATE/SHARE) is not allowed"));
+        }
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            return Err(invalid(
+                "only a plain SELECT is allowed, UNION/INTERSECT/EXCEPT/VALUES are not supported",
+            ));
+        };
+        if select.into.is_some() {
+            return Err(invalid("SELECT INTO is not allowed"));
+        }
+
+        match select.from.as_slice() {
+            [table] if table.joins.is_empty() => match &table.relation {
+                TableFactor::Table { name, .. } if is_user_stats(name) => {}
+                _ => return Err(invalid(format!("only {} can be queried", USER_STATS_TABLE))),
+            },
+            [_] => return Err(invalid("JOIN is not allowed")),
+            _ => {
+                return Err(invalid(format!(
+                    "query must select FROM {}",
+                    USER_STATS_TABLE
+                )))
+            }
+        }
+
+        validate_projection(&select.projection)?;
+
+        // subqueries could reach other tables, so check every relation in the statement
+        if let ControlFlow::Break(name) = visit_relations(statement, |name| {
+            if is_user_stats(name) {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(name.to_string())
+            }
+        }) {
+            return Err(invalid(format!("table {} is not allowed", name)));
+        }
+
+        if let ControlFlow::Break(name) = visit_expressions(statement, |expr| match expr {
+            Expr::Function(func) if !is_allowed_function(&func.name) => {
+                ControlFlow::Break(func.name.to_string())
+            }
+            _ => ControlFlow::Continue(()),
+        }) {
+            return Err(invalid(format!("function {} is not allowed", name)));
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn validate_projection(projection: &[SelectItem]) -> Result<(), Status> {
+    let mut wildcard = false;
+    let mut columns = Vec::with_capacity(projection.len());
+    for item in projection {
+        match item {
+            SelectItem::Wildcard(_) => wildcard = true,
+            SelectItem::UnnamedExpr(Expr::Identifier(ident)) => columns.push(normalize(ident)),
+            SelectItem::UnnamedExpr(Expr::CompoundIdentifier(idents)) => {
+                let ident = idents.last().expect("compound identifier is not empty");
+                columns.push(normalize(ident))
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "projection {} is not allowed, only columns of {} can be selected",
+                    item, USER_STATS_TABLE
+                )))
+            }
+        }
+    }
+
+    if let Some(column) = columns.iter().find(|c| Column::find(c).is_none()) {
+        return Err(invalid(format!(
+            "column {} does not exist in {}",
+            column, USER_STATS_TABLE
+        )));
+    }
+
+    if wildcard {
+        return Ok(());
+    }
+
+    let missing = USER_COLUMNS
+        .iter()
+        .filter(|c| !columns.iter().any(|column| column == *c))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(invalid(format!(
+            "projection must include column(s): {}",
+            missing.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn is_user_stats(name: &ObjectName) -> bool {
+    match name.0.as_slice() {
+        [table] => normalize(table) == USER_STATS_TABLE,
+        [schema, table] => normalize(schema) == "public" && normalize(table) == USER_STATS_TABLE,
+        _ => false,
+    }
+}
+
+fn is_allowed_function(name: &ObjectName) -> bool {
+    match name.0.as_slice() {
+        [func] => ALLOWED_FUNCTIONS.contains(&func.value.to_lowercase().as_str()),
+        _ => false,
+    }
+}
+
+/// postgres folds unquoted identifiers to lower case
+fn normalize(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> Status {
+    Status::invalid_argument(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[allow(clippy::result_large_err)]
+    fn validate(query: &str) -> Result<(), Status> {
+        RawQueryRequest {
+            query: query.to_string(),
+            ..Default::default()
+        }
+        .validate()
+    }
+
+    #[test]
+    fn validate_select_should_work() {
+        let queries = [
+            "SELECT * FROM user_stats WHERE created_at > '2024-01-01' LIMIT 5",
+            "SELECT email, name, started_but_not_finished FROM user_stats",
+            "SELECT u.email, u.name, u.started_but_not_finished, u.gender FROM public.user_stats u WHERE array_length(u.finished, 1) > 3 ORDER BY u.email",
+            "SELECT * FROM user_stats WHERE last_visited_at > now() - interval '7 days' AND 269904 = ANY(viewed_but_not_started)",
+            "SELECT * FROM user_stats WHERE email IN (SELECT email FROM user_stats WHERE gender = 'male')",
+            "SELECT EMAIL, Name, started_but_not_finished FROM USER_STATS",
+        ];
+        for query in queries {
+            assert!(validate(query).is_ok(), "{} should be valid", query);
+        }
+    }
+
+    #[test]
+    fn validate_non_select_should_fail() {
+        let queries = [
+            ("", "query is empty"),
+            ("DROP TABLE user_stats", "only SELECT statement is allowed"),
+            (
+                "DELETE FROM user_stats WHERE email = 'a@b.c'",
+                "only SELECT statement is allowed",
+            ),
+            (
+                "SELECT * FROM user_stats; DROP TABLE user_stats",
+                "only a single statement is allowed",
+            ),
+            (
+                "SELECT * FROM user_stats UNION SELECT * FROM user_stats",
+                "only a plain SELECT is allowed, UNION/INTERSECT/EXCEPT/VALUES are not supported",
+            ),
+            (
+                "WITH t AS (SELECT * FROM user_stats) SELECT * FROM t",
+                "WITH clause is not allowed",
+            ),
+            (
+                "SELECT * FROM user_stats FOR UPDATE",
+                "locking clause (FOR UPDATE/SHARE) is not allowed",
+            ),
+            (
+                "SELECT * INTO backup FROM user_stats",
+                "SELECT INTO is not allowed",
+            ),
+        ];
+        for (query, msg) in queries {
+            let status = validate(query).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), msg);
+        }
+    }
+
+    #[test]
+    fn validate_relations_should_fail() {
+        let queries = [
+            ("SELECT * FROM pg_shadow", "only user_stats can be queried"),
+            (
+                "SELECT * FROM user_stats JOIN pg_shadow ON true",
+                "JOIN is not allowed",
+            ),
+            (
+                "SELECT * FROM user_stats, pg_shadow",
+                "query must select FROM user_stats",
+            ),
+            (
+                "SELECT * FROM user_stats WHERE email IN (SELECT usename FROM pg_shadow)",
+                "table pg_shadow is not allowed",
+            ),
+            (
+                "SELECT * FROM user_stats WHERE pg_sleep(100) IS NOT NULL",
+                "function pg_sleep is not allowed",
+            ),
+        ];
+        for (query, msg) in queries {
+            let status = validate(query).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), msg);
+        }
+    }
+
+    #[test]
+    fn validate_projection_should_fail() {
+        let queries = [
+            (
+                "SELECT email, name FROM user_stats",
+                "projection must include column(s): started_but_not_finished",
+            ),
+            (
+                "SELECT email, name, started_but_not_finished, password FROM user_stats",
+                "column password does not exist in user_stats",
+            ),
+            (
+                "SELECT email, name AS started_but_not_finished FROM user_stats",
+                "projection name AS started_but_not_finished is not allowed, only columns of user_stats can be selected",
+            ),
+        ];
+        for (query, msg) in queries {
+            let status = validate(query).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(status.message(), msg);
+        }
+    }
+}
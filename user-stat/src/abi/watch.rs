@@ -0,0 +1,320 @@
+use std::time::Duration;
+
+use itertools::Itertools;
+use sqlx::{postgres::PgListener, PgPool, Postgres, QueryBuilder};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError, error::TryRecvError},
+        mpsc, OnceCell,
+    },
+    time::sleep,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+use tracing::{info, warn};
+
+use super::query::{user_columns, USER_STATS_TABLE};
+use crate::{
+    pb::{QueryRequest, User},
+    ResponseStream,
+};
+
+/// the channel notified by the triggers of user_stats, the payload is the email of the user
+const USER_CHANGES_CHANNEL: &str = "user_stats_changes";
+/// number of changed emails buffered for the watches, a lagging one ends with data loss
+const CHANGES_BUFFER: usize = 1024;
+/// number of changed users buffered for the client
+const WATCH_BUFFER: usize = 128;
+
+/// the emails of the changed users, broadcast by the listener shared by the watches. None when
+/// the listener lost its connection, the changes until it reconnects are missed
+pub(crate) type Changes = OnceCell<broadcast::Sender<Option<String>>>;
+
+/// stream the users matching the query whenever they are inserted or updated.
+///
+/// The watch subscribes to the shared listener before the stream is returned, so no change
+/// after the request is missed. The changes are checked against the query with their emails,
+/// the ones arrived together in one query, and the user is only sent if it still matches.
+pub(crate) async fn watch_users(
+    pool: &PgPool,
+    changes: &Changes,
+    query: QueryRequest,
+) -> Result<ResponseStream, Status> {
+    // reject an invalid query before listening
+    query.push_select_emails(&mut QueryBuilder::new(""), vec![])?;
+
+    let mut changed = changes
+        .get_or_try_init(|| listen_changes(pool.clone()))
+        .await?
+        .subscribe();
+    info!("Watch users: {:?}", query);
+
+    let pool = pool.clone();
+    let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+    tokio::spawn(async move {
+        let closed = tx.closed();
+        let watch = forward_changes(&pool, &mut changed, &query, &tx);
+        tokio::select! {
+            ret = watch => {
+                if let Err(e) = ret {
+                    warn!("Failed to watch users: {:?}", e);
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+            _ = closed => info!("Watch is dropped by client"),
+        }
+    });
+
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+/// listen on the notifications of user_stats, and broadcast the emails of the changed users
+async fn listen_changes(pool: PgPool) -> Result<broadcast::Sender<Option<String>>, Status> {
+    let mut listener = PgListener::connect_with(&pool).await.map_err(to_status)?;
+    listener
+        .listen(USER_CHANGES_CHANNEL)
+        .await
+        .map_err(to_status)?;
+
+    let (tx, _) = broadcast::channel(CHANGES_BUFFER);
+    let changes = tx.clone();
+    tokio::spawn(async move {
+        loop {
+            // the listener reconnects on the next try_recv after losing the connection, the
+            // watches end as they miss the notifications in between. No watch is not an error
+            match listener.try_recv().await {
+                Ok(Some(notification)) => {
+                    let _ = tx.send(Some(notification.payload().to_string()));
+                }
+                Ok(None) => {
+                    warn!("Lost the connection listening on user changes");
+                    let _ = tx.send(None);
+                }
+                Err(e) => {
+                    warn!("Failed to listen on user changes: {:?}", e);
+                    let _ = tx.send(None);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    Ok(changes)
+}
+
+async fn forward_changes(
+    pool: &PgPool,
+    changed: &mut broadcast::Receiver<Option<String>>,
+    query: &QueryRequest,
+    tx: &mpsc::Sender<Result<User, Status>>,
+) -> Result<(), Status> {
+    loop {
+        let email = changed.recv().await.map_err(missed)?;
+        let mut emails = vec![email.ok_or_else(disconnected)?];
+        // the changes arrived meanwhile are checked in the same query
+        loop {
+            match changed.try_recv() {
+                Ok(Some(email)) => emails.push(email),
+                Ok(None) => return Err(disconnected()),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(n)) => return Err(missed(RecvError::Lagged(n))),
+                Err(TryRecvError::Closed) => return Err(missed(RecvError::Closed)),
+            }
+        }
+
+        let mut builder = QueryBuilder::new("");
+        query.push_select_emails(&mut builder, emails.into_iter().unique().collect())?;
+        let users = builder
+            .build_query_as::<User>()
+            .fetch_all(pool)
+            .await
+            .map_err(to_status)?;
+        for user in users {
+            if tx.send(Ok(user)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// the watch ends once it misses any change
+fn missed(e: RecvError) -> Status {
+    match e {
+        RecvError::Lagged(n) => Status::data_loss(format!("Watch missed {} changes of users", n)),
+        RecvError::Closed => Status::unavailable("Stopped listening on user changes"),
+    }
+}
+
+fn disconnected() -> Status {
+    Status::data_loss("Watch missed the changes of users while the listener reconnected")
+}
+
+#[allow(clippy::result_large_err)]
+impl QueryRequest {
+    /// select the users of the emails if they match the query in the order of the emails,
+    /// pagination is ignored
+    fn push_select_emails(
+        &self,
+        builder: &mut QueryBuilder<'static, Postgres>,
+        emails: Vec<String>,
+    ) -> Result<(), Status> {
+        builder.push(format!(
+            "SELECT {} FROM {}",
+            user_columns(),
+            USER_STATS_TABLE
+        ));
+        let mut conditions = self.push_conditions(builder)?;
+        conditions
+            .next()
+            .push("email = ANY(")
+            .push_bind(emails.clone())
+            .push(")");
+        builder
+            .push(" ORDER BY array_position(")
+            .push_bind(emails)
+            .push(", email)");
+        Ok(())
+    }
+}
+
+fn to_status(e: sqlx::Error) -> Status {
+    Status::internal(format!("Failed to watch users: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        pb::{Filter, Gender, QueryRequestBuilder},
+        test_utils::get_test_pool,
+    };
+
+    #[tokio::test]
+    async fn watch_users_should_stream_matched_changes() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let query = QueryRequestBuilder::default()
+            .filter(Filter::gender(Gender::Female))
+            .build()?;
+        let mut stream = watch_users(&pool, &Changes::default(), query).await?;
+
+        sqlx::query("UPDATE user_stats SET name = 'Changed' WHERE email = $1")
+            .bind("clifford.smjmlbu8@example.net")
+            .execute(&pool)
+            .await?;
+        sqlx::query("UPDATE user_stats SET name = 'Frederik' WHERE email = $1")
+            .bind("frederik.2r2jvb8l@example.org")
+            .execute(&pool)
+            .await?;
+        // nothing changed, no notification
+        sqlx::query("UPDATE user_stats SET name = name WHERE email = $1")
+            .bind("frederik.2r2jvb8l@example.org")
+            .execute(&pool)
+            .await?;
+        // the notification bookkeeping is not a change of the user
+        sqlx::query("UPDATE user_stats SET last_email_notification = now() WHERE email = $1")
+            .bind("frederik.2r2jvb8l@example.org")
+            .execute(&pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO user_stats(email, name, gender, started_but_not_finished) \
+             VALUES ('new@example.org', 'New', 'female', '{}')",
+        )
+        .execute(&pool)
+        .await?;
+
+        let wait = Duration::from_secs(5);
+        let user = timeout(wait, stream.next()).await?.unwrap()?;
+        assert_eq!(user.email, "frederik.2r2jvb8l@example.org");
+        assert_eq!(user.name, "Frederik");
+        assert_eq!(user.started_progress.len(), 3);
+        let user = timeout(wait, stream.next()).await?.unwrap()?;
+        assert_eq!(user.email, "new@example.org");
+        assert!(timeout(Duration::from_millis(200), stream.next())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watches_should_share_listener() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let changes = Changes::default();
+        let mut female = watch_users(
+            &pool,
+            &changes,
+            QueryRequestBuilder::default()
+                .filter(Filter::gender(Gender::Female))
+                .build()?,
+        )
+        .await?;
+        let mut all = watch_users(&pool, &changes, QueryRequest::default()).await?;
+        assert_eq!(changes.get().unwrap().receiver_count(), 2);
+
+        sqlx::query("UPDATE user_stats SET name = 'Frederik' WHERE email = $1")
+            .bind("frederik.2r2jvb8l@example.org")
+            .execute(&pool)
+            .await?;
+        let wait = Duration::from_secs(5);
+        for stream in [&mut female, &mut all] {
+            let user = timeout(wait, stream.next()).await?.unwrap()?;
+            assert_eq!(user.email, "frederik.2r2jvb8l@example.org");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_should_end_when_listener_reconnects() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let changes = Changes::default();
+        let mut stream = watch_users(&pool, &changes, QueryRequest::default()).await?;
+
+        let killed: Vec<bool> = sqlx::query_scalar(
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = current_database() AND query LIKE 'LISTEN%'",
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(killed, [true]);
+        let status = timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+
+        // a new watch gets the changes after the listener reconnected
+        let listening = "SELECT EXISTS (SELECT 1 FROM pg_stat_activity \
+                         WHERE datname = current_database() AND query LIKE 'LISTEN%')";
+        timeout(Duration::from_secs(5), async {
+            while !sqlx::query_scalar(listening).fetch_one(&pool).await? {
+                sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, sqlx::Error>(())
+        })
+        .await??;
+        let mut stream = watch_users(&pool, &changes, QueryRequest::default()).await?;
+        sqlx::query("UPDATE user_stats SET name = 'Frederik' WHERE email = $1")
+            .bind("frederik.2r2jvb8l@example.org")
+            .execute(&pool)
+            .await?;
+        let user = timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .unwrap()?;
+        assert_eq!(user.email, "frederik.2r2jvb8l@example.org");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_users_should_reject_invalid_query() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let query = QueryRequestBuilder::default()
+            .filter(Filter::is_null("password", true))
+            .build()?;
+        assert!(watch_users(&pool, &Changes::default(), query)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ use std::{env, fs::File};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub partition: PartitionConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +19,62 @@ pub struct AuthConfig {
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// number of rows fetched from the cursor at a time when streaming users
+    #[serde(default = "default_fetch_size")]
+    pub fetch_size: u32,
+    /// statement_timeout of the queries in milliseconds, a shorter grpc deadline of the request
+    /// takes precedence. 0 means no timeout
+    #[serde(default = "default_query_timeout_ms")]
+    pub query_timeout_ms: u64,
+    /// a raw query or cohorts are rejected if the estimated cost of the plan exceeds it, 0
+    /// means no limit
+    #[serde(default)]
+    pub max_raw_query_cost: f64,
+    /// a raw query or cohorts are rejected if the estimated rows of the plan exceeds it, 0
+    /// means no limit
+    #[serde(default)]
+    pub max_raw_query_rows: u64,
+}
+
+/// maintenance of the monthly partitions of user_stats
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartitionConfig {
+    /// partitions are created for the current month and the months ahead
+    #[serde(default = "default_months_ahead")]
+    pub months_ahead: u32,
+    /// partitions of the months older than it are detached, unless their users visited or
+    /// watched within it. 0 means they are kept
+    #[serde(default)]
+    pub retention_months: u32,
+    /// interval of the maintenance in seconds
+    #[serde(default = "default_maintenance_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> Self {
+        Self {
+            months_ahead: default_months_ahead(),
+            retention_months: 0,
+            interval_secs: default_maintenance_interval_secs(),
+        }
+    }
+}
+
+fn default_months_ahead() -> u32 {
+    3
+}
+
+fn default_maintenance_interval_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_fetch_size() -> u32 {
+    500
+}
+
+fn default_query_timeout_ms() -> u64 {
+    30_000
 }
 
 impl AppConfig {
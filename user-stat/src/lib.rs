@@ -4,58 +4,241 @@ pub mod pb;
 
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::Stream;
-use sqlx::PgPool;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 
+pub use abi::{MemoryStore, PartitionReport, PgStore, UserStore};
 pub use config::AppConfig;
-use pb::{user_stats_server::UserStats, QueryRequest, RawQueryRequest, User};
+use pb::{
+    user_stats_server::UserStats, CohortsRequest, CohortsResponse, CountResponse,
+    CreateSegmentRequest, DeleteSegmentRequest, DeleteSegmentResponse, DiffSnapshotsRequest,
+    EstimateRequest, EstimateResponse, ExportChunk, ExportRequest, GetSegmentRequest,
+    ImportProgress, ImportRequest, ListSegmentsRequest, ListSegmentsResponse, MarkNotifiedRequest,
+    MarkNotifiedResponse, MembershipDiff, QueryRequest, QuerySegmentRequest, RawQueryRequest,
+    RecordEventsResponse, ReleaseRequest, ReleaseResponse, ReserveRequest, ReserveResponse,
+    Segment, SegmentSnapshot, SnapshotSegmentRequest, UpdateSegmentRequest, User, UserEvent,
+    UserPage, UserProfile,
+};
 
 #[derive(Clone)]
 pub struct UserStatsService {
     inner: Arc<UserStatsServiceInner>,
+    /// the grpc deadline of the current request
+    deadline: Option<Duration>,
 }
 
 #[allow(unused)]
 pub struct UserStatsServiceInner {
     config: AppConfig,
-    pool: PgPool,
+    store: Box<dyn UserStore>,
+    /// the listener of the changed users shared by the watches, started by the first one
+    changes: abi::Changes,
 }
 
 pub type ServiceResult<T> = Result<Response<T>, Status>;
-pub type ResponseStream = Pin<Box<dyn Stream<Item = Result<User, Status>> + Send>>;
+pub type RowStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+pub type ResponseStream = RowStream<User>;
+pub type ProfileStream = RowStream<UserProfile>;
+pub type DiffStream = RowStream<MembershipDiff>;
+pub type ExportStream = RowStream<ExportChunk>;
+pub type ImportStream = RowStream<ImportProgress>;
+pub type ReserveStream = RowStream<ReserveResponse>;
 
 #[tonic::async_trait]
 impl UserStats for UserStatsService {
     type QueryStream = ResponseStream;
     type RawQueryStream = ResponseStream;
+    type QueryProfilesStream = ProfileStream;
+    type QuerySegmentStream = ResponseStream;
+    type WatchUsersStream = ResponseStream;
+    type DiffSnapshotsStream = DiffStream;
+    type ExportStream = ExportStream;
+    type ImportStream = ImportStream;
+    type ReserveStream = ReserveStream;
 
     async fn query(&self, request: Request<QueryRequest>) -> ServiceResult<Self::QueryStream> {
+        let svc = self.with_deadline(&request);
         let req = request.into_inner();
-        self.query(req).await
+        svc.query(req).await
+    }
+
+    async fn query_profiles(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> ServiceResult<Self::QueryProfilesStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.query_profiles(req).await
     }
 
     async fn raw_query(
         &self,
         request: Request<RawQueryRequest>,
     ) -> ServiceResult<Self::RawQueryStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.raw_query(req).await
+    }
+
+    async fn query_page(&self, request: Request<QueryRequest>) -> ServiceResult<UserPage> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.query_page(req).await
+    }
+
+    async fn raw_query_page(&self, request: Request<RawQueryRequest>) -> ServiceResult<UserPage> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.raw_query_page(req).await
+    }
+
+    async fn record_events(
+        &self,
+        request: Request<Streaming<UserEvent>>,
+    ) -> ServiceResult<RecordEventsResponse> {
+        let stream = request.into_inner();
+        self.record_events(stream).await
+    }
+
+    async fn export(&self, request: Request<ExportRequest>) -> ServiceResult<Self::ExportStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.export(req).await
+    }
+
+    async fn import(
+        &self,
+        request: Request<Streaming<ImportRequest>>,
+    ) -> ServiceResult<Self::ImportStream> {
+        let stream = request.into_inner();
+        self.import(stream).await
+    }
+
+    async fn mark_notified(
+        &self,
+        request: Request<MarkNotifiedRequest>,
+    ) -> ServiceResult<MarkNotifiedResponse> {
+        let req = request.into_inner();
+        self.mark_notified(req).await
+    }
+
+    async fn reserve(
+        &self,
+        request: Request<ReserveRequest>,
+    ) -> ServiceResult<Self::ReserveStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.reserve(req).await
+    }
+
+    async fn release(&self, request: Request<ReleaseRequest>) -> ServiceResult<ReleaseResponse> {
+        let req = request.into_inner();
+        self.release(req).await
+    }
+
+    async fn create_segment(
+        &self,
+        request: Request<CreateSegmentRequest>,
+    ) -> ServiceResult<Segment> {
+        let req = request.into_inner();
+        self.create_segment(req).await
+    }
+
+    async fn update_segment(
+        &self,
+        request: Request<UpdateSegmentRequest>,
+    ) -> ServiceResult<Segment> {
         let req = request.into_inner();
-        self.raw_query(req).await
+        self.update_segment(req).await
+    }
+
+    async fn get_segment(&self, request: Request<GetSegmentRequest>) -> ServiceResult<Segment> {
+        let req = request.into_inner();
+        self.get_segment(req).await
+    }
+
+    async fn list_segments(
+        &self,
+        _request: Request<ListSegmentsRequest>,
+    ) -> ServiceResult<ListSegmentsResponse> {
+        self.list_segments().await
+    }
+
+    async fn delete_segment(
+        &self,
+        request: Request<DeleteSegmentRequest>,
+    ) -> ServiceResult<DeleteSegmentResponse> {
+        let req = request.into_inner();
+        self.delete_segment(req).await
+    }
+
+    async fn query_segment(
+        &self,
+        request: Request<QuerySegmentRequest>,
+    ) -> ServiceResult<Self::QuerySegmentStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.query_segment(req).await
+    }
+
+    async fn snapshot_segment(
+        &self,
+        request: Request<SnapshotSegmentRequest>,
+    ) -> ServiceResult<SegmentSnapshot> {
+        let req = request.into_inner();
+        self.snapshot_segment(req).await
+    }
+
+    async fn diff_snapshots(
+        &self,
+        request: Request<DiffSnapshotsRequest>,
+    ) -> ServiceResult<Self::DiffSnapshotsStream> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.diff_snapshots(req).await
+    }
+
+    async fn watch_users(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> ServiceResult<Self::WatchUsersStream> {
+        let req = request.into_inner();
+        self.watch_users(req).await
+    }
+
+    async fn count(&self, request: Request<QueryRequest>) -> ServiceResult<CountResponse> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.count(req).await
+    }
+
+    async fn cohorts(&self, request: Request<CohortsRequest>) -> ServiceResult<CohortsResponse> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.cohorts(req).await
+    }
+
+    async fn estimate(&self, request: Request<EstimateRequest>) -> ServiceResult<EstimateResponse> {
+        let svc = self.with_deadline(&request);
+        let req = request.into_inner();
+        svc.estimate(req).await
     }
 }
 
 #[cfg(feature = "test_utils")]
 pub mod test_utils {
-    use std::{env, path::Path, sync::Arc};
+    use std::{env, path::Path};
 
     use anyhow::Result;
     use chrono::{Duration, TimeZone, Utc};
+    use futures::StreamExt;
     use prost_types::Timestamp;
 
     use crate::{
-        pb::{IdQuery, TimeQuery},
-        AppConfig, UserStatsService, UserStatsServiceInner,
+        pb::{IdQuery, ReserveResponse, TimeQuery},
+        AppConfig, PgStore, ReserveStream, UserStatsService,
     };
 
     use sqlx::{Executor, PgPool};
@@ -67,9 +250,8 @@ pub mod test_utils {
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
-            let svc = Self {
-                inner: Arc::new(UserStatsServiceInner { config, pool }),
-            };
+            let store = PgStore::new(pool, config.server.fetch_size);
+            let svc = Self::with_store(config, store);
             Ok((tdb, svc))
         }
     }
@@ -98,6 +280,15 @@ pub mod test_utils {
         (tdb, pool)
     }
 
+    /// the users of all the batches, with the other fields of the first one
+    pub async fn collect_reserved(mut batches: ReserveStream) -> Result<ReserveResponse> {
+        let mut ret = batches.next().await.expect("one batch at least")?;
+        while let Some(batch) = batches.next().await {
+            ret.users.extend(batch?.users);
+        }
+        Ok(ret)
+    }
+
     pub fn to_idquery(id: &[u32]) -> IdQuery {
         IdQuery { ids: id.to_vec() }
     }
@@ -106,6 +297,7 @@ pub mod test_utils {
         TimeQuery {
             lower: lower.map(days_to_timestamp),
             upper: upper.map(days_to_timestamp),
+            ..Default::default()
         }
     }
 
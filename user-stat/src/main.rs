@@ -1,19 +1,196 @@
-use anyhow::Result;
-use tonic::transport::Server;
-use tracing::{info, level_filters::LevelFilter};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::{stream, StreamExt};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+use tonic::{transport::Server, Status};
+use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
-use user_stat::{AppConfig, UserStatsService};
+use user_stat::{
+    pb::{ExportFormat, ExportRequest, GetSegmentRequest, ImportFormat, ImportRequest},
+    AppConfig, UserStatsService,
+};
+
+/// size of the chunks the file is read
+const IMPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Parser)]
+#[command(about = "user stats service")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// start the grpc server, this is the default
+    Serve,
+    /// upsert user_stats from a csv or jsonl file
+    Import {
+        path: PathBuf,
+        /// guessed from the extension of the file if not given
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+    },
+    /// export the users of a segment, or all the users, to a csv, jsonl or parquet file
+    Export {
+        path: PathBuf,
+        /// guessed from the extension of the file if not given
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// name of the saved segment
+        #[arg(long)]
+        segment: Option<String>,
+        /// version of the segment, the latest version if not given
+        #[arg(long, default_value_t = 0)]
+        version: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Csv,
+    Jsonl,
+    Parquet,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let layer = Layer::new().with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
+    let args = Args::parse();
     let config = AppConfig::load().expect("Failed to load config");
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Import { path, format } => import(config, &path, format).await,
+        Command::Export {
+            path,
+            format,
+            segment,
+            version,
+        } => export(config, &path, format, segment, version).await,
+    }
+}
+
+async fn serve(config: AppConfig) -> Result<()> {
     let addr = config.server.port;
     let addr = format!("[::1]:{}", addr).parse()?;
     info!("UserStatsService listening on {}", &addr);
-    let svc = UserStatsService::new(config).await.into_server();
-    Server::builder().add_service(svc).serve(addr).await?;
+    let interval = Duration::from_secs(config.partition.interval_secs.max(1));
+    let svc = UserStatsService::new(config).await;
+    tokio::spawn(maintain_partitions(svc.clone(), interval));
+    Server::builder()
+        .add_service(svc.into_server())
+        .serve(addr)
+        .await?;
     Ok(())
 }
+
+/// keep the partitions of user_stats, starting from the launch of the server
+async fn maintain_partitions(svc: UserStatsService, interval: Duration) {
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = svc.maintain_partitions().await {
+            warn!("Failed to maintain partitions: {}", e.message());
+        }
+    }
+}
+
+async fn import(config: AppConfig, path: &Path, format: Option<Format>) -> Result<()> {
+    let format = match format.or_else(|| guess_format(path)) {
+        Some(Format::Csv) => ImportFormat::Csv,
+        Some(Format::Jsonl) => ImportFormat::Jsonl,
+        Some(Format::Parquet) => bail!("Only csv and jsonl files could be imported"),
+        None => bail!("Unknown format of {}, use --format", path.display()),
+    };
+
+    let file = File::open(path).await?;
+    let reqs = stream::try_unfold(file, move |mut file| async move {
+        let mut data = vec![0; IMPORT_CHUNK_SIZE];
+        let n = file
+            .read(&mut data)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to read file: {}", e)))?;
+        if n == 0 {
+            return Ok(None);
+        }
+        data.truncate(n);
+        let req = ImportRequest {
+            format: format as i32,
+            data,
+        };
+        Ok(Some((req, file)))
+    });
+
+    let svc = UserStatsService::new(config).await;
+    let mut progress = svc.import(Box::pin(reqs)).await?.into_inner();
+    while let Some(ret) = progress.next().await {
+        let ret = ret?;
+        for e in &ret.errors {
+            eprintln!("line {}: {}", e.line, e.message);
+        }
+        info!(
+            "Read {} rows, imported {} rows",
+            ret.rows_read, ret.rows_imported
+        );
+    }
+    Ok(())
+}
+
+async fn export(
+    config: AppConfig,
+    path: &Path,
+    format: Option<Format>,
+    segment: Option<String>,
+    version: u32,
+) -> Result<()> {
+    let format = match format.or_else(|| guess_format(path)) {
+        Some(Format::Csv) => ExportFormat::Csv,
+        Some(Format::Jsonl) => ExportFormat::Jsonl,
+        Some(Format::Parquet) => ExportFormat::Parquet,
+        None => bail!("Unknown format of {}, use --format", path.display()),
+    };
+
+    let svc = UserStatsService::new(config).await;
+    let query = match segment {
+        Some(name) => {
+            let req = GetSegmentRequest { name, version };
+            svc.get_segment(req).await?.into_inner().query
+        }
+        None => None,
+    };
+    let req = ExportRequest {
+        query,
+        format: format as i32,
+    };
+    let mut chunks = svc.export(req).await?.into_inner();
+    let mut file = File::create(path).await?;
+    let mut size = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        size += chunk.data.len();
+        file.write_all(&chunk.data).await?;
+    }
+    file.flush().await?;
+    info!("Exported {} bytes to {}", size, path.display());
+    Ok(())
+}
+
+fn guess_format(path: &Path) -> Option<Format> {
+    match path.extension()?.to_str()? {
+        "csv" => Some(Format::Csv),
+        "jsonl" | "ndjson" => Some(Format::Jsonl),
+        "parquet" => Some(Format::Parquet),
+        _ => None,
+    }
+}
@@ -1,7 +1,7 @@
 // This file is @generated by prost-build.
 #[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
-#[derive(sqlx::FromRow, derive_builder::Builder)]
+#[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -14,6 +14,40 @@ pub struct User {
     pub name: ::prost::alloc::string::String,
     #[prost(int32, repeated, tag = "3")]
     pub started_but_not_finished: ::prost::alloc::vec::Vec<i32>,
+    /// watch progress in percent of each content in started_but_not_finished, in the same order
+    #[prost(int32, repeated, tag = "4")]
+    pub started_progress: ::prost::alloc::vec::Vec<i32>,
+}
+/// all the columns of user_stats
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UserProfile {
+    #[prost(string, tag = "1")]
+    pub email: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(enumeration = "Gender", tag = "3")]
+    pub gender: i32,
+    #[prost(message, optional, tag = "4")]
+    pub created_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "5")]
+    pub last_visited_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    pub last_watched_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(int32, repeated, tag = "7")]
+    pub recent_watched: ::prost::alloc::vec::Vec<i32>,
+    #[prost(int32, repeated, tag = "8")]
+    pub viewed_but_not_started: ::prost::alloc::vec::Vec<i32>,
+    #[prost(int32, repeated, tag = "9")]
+    pub started_but_not_finished: ::prost::alloc::vec::Vec<i32>,
+    #[prost(int32, repeated, tag = "10")]
+    pub finished: ::prost::alloc::vec::Vec<i32>,
+    #[prost(message, optional, tag = "11")]
+    pub last_email_notification: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "12")]
+    pub last_in_app_notification: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "13")]
+    pub last_sms_notification: ::core::option::Option<::prost_types::Timestamp>,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -27,6 +61,36 @@ pub struct QueryRequest {
     #[prost(map = "string, message", tag = "2")]
     #[builder(setter(each(name = "id", into)))]
     pub ids: ::std::collections::HashMap<::prost::alloc::string::String, IdQuery>,
+    /// max number of users to return, 0 means the default page size for paginated query,
+    /// or no limit for streaming query
+    #[prost(uint32, tag = "3")]
+    pub page_size: u32,
+    /// next_page_token returned by the previous page, users are returned after it (ordered by email)
+    #[prost(string, tag = "4")]
+    pub page_token: ::prost::alloc::string::String,
+    /// boolean expression to filter users, AND-ed with timestamps and ids
+    #[prost(message, optional, tag = "5")]
+    #[builder(setter(into, strip_option))]
+    pub filter: ::core::option::Option<Filter>,
+    /// fields of UserProfile to select and return, email is always returned,
+    /// empty means all the fields. only used by QueryProfiles
+    #[prost(message, optional, tag = "6")]
+    #[builder(setter(into, strip_option))]
+    pub field_mask: ::core::option::Option<::prost_types::FieldMask>,
+    /// only users notified less than max_count times in the window are returned
+    #[prost(message, repeated, tag = "7")]
+    #[builder(setter(each(name = "cap", into)))]
+    pub caps: ::prost::alloc::vec::Vec<FrequencyCap>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FrequencyCap {
+    #[prost(enumeration = "NotificationChannel", tag = "1")]
+    pub channel: i32,
+    #[prost(uint32, tag = "2")]
+    pub max_count: u32,
+    #[prost(message, optional, tag = "3")]
+    pub window: ::core::option::Option<::prost_types::Duration>,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -36,6 +100,375 @@ pub struct RawQueryRequest {
     #[prost(string, tag = "1")]
     #[builder(setter(into))]
     pub query: ::prost::alloc::string::String,
+    /// same as QueryRequest.page_size
+    #[prost(uint32, tag = "2")]
+    pub page_size: u32,
+    /// same as QueryRequest.page_token
+    #[prost(string, tag = "3")]
+    pub page_token: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UserPage {
+    #[prost(message, repeated, tag = "1")]
+    pub users: ::prost::alloc::vec::Vec<User>,
+    /// token to get the next page, empty if there are no more users
+    #[prost(string, tag = "2")]
+    pub next_page_token: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CountResponse {
+    #[prost(uint64, tag = "1")]
+    pub count: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EstimateRequest {
+    /// page_size and page_token are ignored, the whole segment is estimated
+    #[prost(message, optional, tag = "1")]
+    pub query: ::core::option::Option<QueryRequest>,
+    #[prost(enumeration = "EstimateMethod", tag = "2")]
+    pub method: i32,
+    /// percentage of the table to sample, in (0, 100], 0 means the default (1%)
+    #[prost(double, tag = "3")]
+    pub sample_percent: f64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EstimateResponse {
+    #[prost(uint64, tag = "1")]
+    pub count: u64,
+    #[prost(enumeration = "EstimateMethod", tag = "2")]
+    pub method: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UserEvent {
+    /// unique id of the event, an event is only applied once
+    #[prost(string, tag = "1")]
+    pub event_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub email: ::prost::alloc::string::String,
+    /// name of the user, only used when the user is created by the event
+    #[prost(string, tag = "3")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(enumeration = "EventType", tag = "4")]
+    pub event_type: i32,
+    /// required except for visit events
+    #[prost(uint32, tag = "5")]
+    pub content_id: u32,
+    /// defaults to the time the event is recorded
+    #[prost(message, optional, tag = "6")]
+    pub occurred_at: ::core::option::Option<::prost_types::Timestamp>,
+    /// watch progress in percent, only used by watch start events
+    #[prost(uint32, tag = "7")]
+    pub progress: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RecordEventsResponse {
+    /// number of events applied
+    #[prost(uint64, tag = "1")]
+    pub applied: u64,
+    /// number of events ignored as they were already applied
+    #[prost(uint64, tag = "2")]
+    pub duplicated: u64,
+}
+/// the user is notified through the channel
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Notified {
+    #[prost(string, tag = "1")]
+    pub email: ::prost::alloc::string::String,
+    #[prost(enumeration = "NotificationChannel", tag = "2")]
+    pub channel: i32,
+    /// defaults to the time the request is processed
+    #[prost(message, optional, tag = "3")]
+    pub notified_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MarkNotifiedRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub notifications: ::prost::alloc::vec::Vec<Notified>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MarkNotifiedResponse {
+    /// number of users updated, unknown users are ignored
+    #[prost(uint64, tag = "1")]
+    pub updated: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveRequest {
+    /// the users to notify, pagination is ignored. the caps are checked and the notification is
+    /// recorded in one transaction, so the concurrent reservations never exceed the caps
+    #[prost(message, optional, tag = "1")]
+    pub query: ::core::option::Option<QueryRequest>,
+    /// the channel of the notification to record
+    #[prost(enumeration = "NotificationChannel", tag = "2")]
+    pub channel: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReserveResponse {
+    /// a batch of the users reserved
+    #[prost(message, repeated, tag = "1")]
+    pub users: ::prost::alloc::vec::Vec<User>,
+    /// number of users matching the query but suppressed by the caps, the same in every response
+    #[prost(uint64, tag = "2")]
+    pub suppressed: u64,
+    /// the notified_at recorded for the reserved users, the same in every response. pass it to
+    /// MarkNotified after the delivery so the notification is recorded only once
+    #[prost(message, optional, tag = "3")]
+    pub reserved_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReleaseRequest {
+    /// the channel of the reservation
+    #[prost(enumeration = "NotificationChannel", tag = "1")]
+    pub channel: i32,
+    /// the reserved_at returned by Reserve
+    #[prost(message, optional, tag = "2")]
+    pub reserved_at: ::core::option::Option<::prost_types::Timestamp>,
+    /// the reserved users who were not notified
+    #[prost(string, repeated, tag = "3")]
+    pub emails: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReleaseResponse {
+    /// number of users released, the users not reserved at reserved_at are ignored
+    #[prost(uint64, tag = "1")]
+    pub released: u64,
+}
+/// a named, versioned query of users
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Segment {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub description: ::prost::alloc::string::String,
+    /// starts from 1, increased by every update
+    #[prost(uint32, tag = "3")]
+    pub version: u32,
+    /// page_size and page_token are not stored
+    #[prost(message, optional, tag = "4")]
+    pub query: ::core::option::Option<QueryRequest>,
+    #[prost(message, optional, tag = "5")]
+    pub created_at: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    pub updated_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateSegmentRequest {
+    /// lower case letters, digits, '_' and '-', at most 64 chars
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub description: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub query: ::core::option::Option<QueryRequest>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpdateSegmentRequest {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub description: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub query: ::core::option::Option<QueryRequest>,
+    /// if not 0, the update fails unless it is the current version of the segment
+    #[prost(uint32, tag = "4")]
+    pub expected_version: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetSegmentRequest {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// 0 means the latest version
+    #[prost(uint32, tag = "2")]
+    pub version: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListSegmentsRequest {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListSegmentsResponse {
+    /// latest version of all the segments, ordered by name
+    #[prost(message, repeated, tag = "1")]
+    pub segments: ::prost::alloc::vec::Vec<Segment>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteSegmentRequest {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteSegmentResponse {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct QuerySegmentRequest {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// 0 means the latest version
+    #[prost(uint32, tag = "2")]
+    pub version: u32,
+    /// same as QueryRequest.page_size
+    #[prost(uint32, tag = "3")]
+    pub page_size: u32,
+    /// same as QueryRequest.page_token
+    #[prost(string, tag = "4")]
+    pub page_token: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotSegmentRequest {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// 0 means the latest version
+    #[prost(uint32, tag = "2")]
+    pub version: u32,
+}
+/// the members of a segment at the time the snapshot is taken
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SegmentSnapshot {
+    #[prost(uint64, tag = "1")]
+    pub run_id: u64,
+    #[prost(string, tag = "2")]
+    pub segment: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub version: u32,
+    #[prost(uint64, tag = "4")]
+    pub member_count: u64,
+    #[prost(message, optional, tag = "5")]
+    pub created_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DiffSnapshotsRequest {
+    #[prost(string, tag = "1")]
+    pub segment: ::prost::alloc::string::String,
+    /// 0 means the snapshot taken right before to_run_id
+    #[prost(uint64, tag = "2")]
+    pub from_run_id: u64,
+    /// 0 means the latest snapshot of the segment
+    #[prost(uint64, tag = "3")]
+    pub to_run_id: u64,
+    /// only return the changes of the kind, unspecified means both
+    #[prost(enumeration = "MembershipChange", tag = "4")]
+    pub change: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MembershipDiff {
+    /// users left and then removed from user_stats only have the email
+    #[prost(message, optional, tag = "1")]
+    pub user: ::core::option::Option<User>,
+    #[prost(enumeration = "MembershipChange", tag = "2")]
+    pub change: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportRequest {
+    /// only the format of the first message is used
+    #[prost(enumeration = "ImportFormat", tag = "1")]
+    pub format: i32,
+    /// a chunk of the file, a row could be split across chunks
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportRowError {
+    /// starts from 1, the header of csv is line 1
+    #[prost(uint64, tag = "1")]
+    pub line: u64,
+    #[prost(string, tag = "2")]
+    pub message: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ImportProgress {
+    #[prost(uint64, tag = "1")]
+    pub rows_read: u64,
+    /// rows inserted or updated, rows with the same email in a batch are counted once
+    #[prost(uint64, tag = "2")]
+    pub rows_imported: u64,
+    /// invalid rows since the last progress, they are skipped
+    #[prost(message, repeated, tag = "3")]
+    pub errors: ::prost::alloc::vec::Vec<ImportRowError>,
+    /// the last progress of the import
+    #[prost(bool, tag = "4")]
+    pub done: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportRequest {
+    /// the columns are the fields of UserProfile selected by the field_mask of the query
+    #[prost(message, optional, tag = "1")]
+    pub query: ::core::option::Option<QueryRequest>,
+    #[prost(enumeration = "ExportFormat", tag = "2")]
+    pub format: i32,
+}
+/// the chunks of the file, concatenate them in order to get the whole file
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExportChunk {
+    #[prost(bytes = "vec", tag = "1")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CohortsRequest {
+    /// the users are grouped by the period of created_at
+    #[prost(enumeration = "CohortPeriod", tag = "1")]
+    pub period: i32,
+    /// only the users signed up in the range
+    #[prost(message, optional, tag = "2")]
+    pub created_at: ::core::option::Option<TimeQuery>,
+    /// number of periods to follow after the sign up, 0 means the default (12)
+    #[prost(uint32, tag = "3")]
+    pub periods: u32,
+    /// only the users matching the query, e.g. the ones notified by a campaign. page_size and
+    /// page_token are ignored
+    #[prost(message, optional, tag = "4")]
+    pub query: ::core::option::Option<QueryRequest>,
+}
+/// the users signed up in the same period
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Cohort {
+    /// start of the sign up period
+    #[prost(message, optional, tag = "1")]
+    pub start: ::core::option::Option<::prost_types::Timestamp>,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+    /// active\[i\] is the number of users who visited or watched in the i-th period since the sign
+    /// up period (0). The periods not reached yet are omitted
+    #[prost(uint64, repeated, tag = "3")]
+    pub active: ::prost::alloc::vec::Vec<u64>,
+    /// active\[i\] / size
+    #[prost(double, repeated, tag = "4")]
+    pub retention: ::prost::alloc::vec::Vec<f64>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CohortsResponse {
+    /// ordered by start
+    #[prost(message, repeated, tag = "1")]
+    pub cohorts: ::prost::alloc::vec::Vec<Cohort>,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -46,6 +479,13 @@ pub struct TimeQuery {
     pub lower: ::core::option::Option<::prost_types::Timestamp>,
     #[prost(message, optional, tag = "2")]
     pub upper: ::core::option::Option<::prost_types::Timestamp>,
+    /// bounds relative to the time the query runs, e.g. lower_ago of 30 days is 30 days ago.
+    /// a saved segment keeps them relative, so it slides with time. each side could be either
+    /// absolute or relative
+    #[prost(message, optional, tag = "3")]
+    pub lower_ago: ::core::option::Option<::prost_types::Duration>,
+    #[prost(message, optional, tag = "4")]
+    pub upper_ago: ::core::option::Option<::prost_types::Duration>,
 }
 #[derive(derive_builder::Builder)]
 #[builder(setter(into, strip_option), default)]
@@ -55,6 +495,407 @@ pub struct IdQuery {
     #[prost(uint32, repeated, tag = "1")]
     pub ids: ::prost::alloc::vec::Vec<u32>,
 }
+/// boolean expression tree to filter users
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Filter {
+    #[prost(oneof = "filter::Expr", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
+    pub expr: ::core::option::Option<filter::Expr>,
+}
+/// Nested message and enum types in `Filter`.
+pub mod filter {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Expr {
+        #[prost(message, tag = "1")]
+        And(super::FilterGroup),
+        #[prost(message, tag = "2")]
+        Or(super::FilterGroup),
+        #[prost(message, tag = "3")]
+        Not(::prost::alloc::boxed::Box<super::Filter>),
+        #[prost(message, tag = "4")]
+        Time(super::TimeFilter),
+        #[prost(message, tag = "5")]
+        Ids(super::IdFilter),
+        #[prost(message, tag = "6")]
+        Gender(super::GenderFilter),
+        #[prost(message, tag = "7")]
+        Null(super::NullFilter),
+        #[prost(message, tag = "8")]
+        ArrayLength(super::ArrayLengthFilter),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FilterGroup {
+    #[prost(message, repeated, tag = "1")]
+    pub filters: ::prost::alloc::vec::Vec<Filter>,
+}
+/// timestamp column (created_at, last_visited_at..) is in the range
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TimeFilter {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub range: ::core::option::Option<TimeQuery>,
+}
+/// content ids column (recent_watched, finished..) contains all / any of the ids
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct IdFilter {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(uint32, repeated, tag = "2")]
+    pub ids: ::prost::alloc::vec::Vec<u32>,
+    #[prost(enumeration = "IdOp", tag = "3")]
+    pub op: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GenderFilter {
+    #[prost(enumeration = "Gender", tag = "1")]
+    pub gender: i32,
+}
+/// timestamp column (last_email_notification..) is null or not
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NullFilter {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub is_null: bool,
+}
+/// compare the number of ids in a content ids column
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ArrayLengthFilter {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(enumeration = "CompareOp", tag = "2")]
+    pub op: i32,
+    #[prost(uint32, tag = "3")]
+    pub length: u32,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum EstimateMethod {
+    /// row estimate of the query plan, based on the statistics collected by ANALYZE
+    Planner = 0,
+    /// count a random sample of the table pages with TABLESAMPLE SYSTEM
+    Sample = 1,
+}
+impl EstimateMethod {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            EstimateMethod::Planner => "ESTIMATE_METHOD_PLANNER",
+            EstimateMethod::Sample => "ESTIMATE_METHOD_SAMPLE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ESTIMATE_METHOD_PLANNER" => Some(Self::Planner),
+            "ESTIMATE_METHOD_SAMPLE" => Some(Self::Sample),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum EventType {
+    Unspecified = 0,
+    Visit = 1,
+    View = 2,
+    WatchStart = 3,
+    WatchFinish = 4,
+}
+impl EventType {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            EventType::Unspecified => "EVENT_TYPE_UNSPECIFIED",
+            EventType::Visit => "EVENT_TYPE_VISIT",
+            EventType::View => "EVENT_TYPE_VIEW",
+            EventType::WatchStart => "EVENT_TYPE_WATCH_START",
+            EventType::WatchFinish => "EVENT_TYPE_WATCH_FINISH",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "EVENT_TYPE_UNSPECIFIED" => Some(Self::Unspecified),
+            "EVENT_TYPE_VISIT" => Some(Self::Visit),
+            "EVENT_TYPE_VIEW" => Some(Self::View),
+            "EVENT_TYPE_WATCH_START" => Some(Self::WatchStart),
+            "EVENT_TYPE_WATCH_FINISH" => Some(Self::WatchFinish),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum NotificationChannel {
+    Unspecified = 0,
+    Email = 1,
+    InApp = 2,
+    Sms = 3,
+}
+impl NotificationChannel {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            NotificationChannel::Unspecified => "NOTIFICATION_CHANNEL_UNSPECIFIED",
+            NotificationChannel::Email => "NOTIFICATION_CHANNEL_EMAIL",
+            NotificationChannel::InApp => "NOTIFICATION_CHANNEL_IN_APP",
+            NotificationChannel::Sms => "NOTIFICATION_CHANNEL_SMS",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "NOTIFICATION_CHANNEL_UNSPECIFIED" => Some(Self::Unspecified),
+            "NOTIFICATION_CHANNEL_EMAIL" => Some(Self::Email),
+            "NOTIFICATION_CHANNEL_IN_APP" => Some(Self::InApp),
+            "NOTIFICATION_CHANNEL_SMS" => Some(Self::Sms),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum MembershipChange {
+    Unspecified = 0,
+    /// in to_run_id, but not in from_run_id
+    Entered = 1,
+    /// in from_run_id, but not in to_run_id
+    Left = 2,
+}
+impl MembershipChange {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            MembershipChange::Unspecified => "MEMBERSHIP_CHANGE_UNSPECIFIED",
+            MembershipChange::Entered => "MEMBERSHIP_CHANGE_ENTERED",
+            MembershipChange::Left => "MEMBERSHIP_CHANGE_LEFT",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "MEMBERSHIP_CHANGE_UNSPECIFIED" => Some(Self::Unspecified),
+            "MEMBERSHIP_CHANGE_ENTERED" => Some(Self::Entered),
+            "MEMBERSHIP_CHANGE_LEFT" => Some(Self::Left),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ImportFormat {
+    Unspecified = 0,
+    /// the first line is the header, arrays are written as "{1,2,3}" or "1,2,3"
+    Csv = 1,
+    /// one json object per line
+    Jsonl = 2,
+}
+impl ImportFormat {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ImportFormat::Unspecified => "IMPORT_FORMAT_UNSPECIFIED",
+            ImportFormat::Csv => "IMPORT_FORMAT_CSV",
+            ImportFormat::Jsonl => "IMPORT_FORMAT_JSONL",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "IMPORT_FORMAT_UNSPECIFIED" => Some(Self::Unspecified),
+            "IMPORT_FORMAT_CSV" => Some(Self::Csv),
+            "IMPORT_FORMAT_JSONL" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ExportFormat {
+    Unspecified = 0,
+    /// with a header line, arrays are written as "{1,2,3}", timestamps as RFC 3339
+    Csv = 1,
+    /// one json object per line, keyed by the same columns as the csv header
+    Jsonl = 2,
+    Parquet = 3,
+}
+impl ExportFormat {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Unspecified => "EXPORT_FORMAT_UNSPECIFIED",
+            ExportFormat::Csv => "EXPORT_FORMAT_CSV",
+            ExportFormat::Jsonl => "EXPORT_FORMAT_JSONL",
+            ExportFormat::Parquet => "EXPORT_FORMAT_PARQUET",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "EXPORT_FORMAT_UNSPECIFIED" => Some(Self::Unspecified),
+            "EXPORT_FORMAT_CSV" => Some(Self::Csv),
+            "EXPORT_FORMAT_JSONL" => Some(Self::Jsonl),
+            "EXPORT_FORMAT_PARQUET" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CohortPeriod {
+    Unspecified = 0,
+    /// weeks start on monday, in UTC
+    Week = 1,
+    Month = 2,
+}
+impl CohortPeriod {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            CohortPeriod::Unspecified => "COHORT_PERIOD_UNSPECIFIED",
+            CohortPeriod::Week => "COHORT_PERIOD_WEEK",
+            CohortPeriod::Month => "COHORT_PERIOD_MONTH",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "COHORT_PERIOD_UNSPECIFIED" => Some(Self::Unspecified),
+            "COHORT_PERIOD_WEEK" => Some(Self::Week),
+            "COHORT_PERIOD_MONTH" => Some(Self::Month),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Gender {
+    Unspecified = 0,
+    Female = 1,
+    Male = 2,
+    Unknown = 3,
+}
+impl Gender {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Gender::Unspecified => "GENDER_UNSPECIFIED",
+            Gender::Female => "GENDER_FEMALE",
+            Gender::Male => "GENDER_MALE",
+            Gender::Unknown => "GENDER_UNKNOWN",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "GENDER_UNSPECIFIED" => Some(Self::Unspecified),
+            "GENDER_FEMALE" => Some(Self::Female),
+            "GENDER_MALE" => Some(Self::Male),
+            "GENDER_UNKNOWN" => Some(Self::Unknown),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum IdOp {
+    /// column contains all the ids (@>)
+    Contains = 0,
+    /// column contains any of the ids (&&)
+    Overlaps = 1,
+}
+impl IdOp {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            IdOp::Contains => "ID_OP_CONTAINS",
+            IdOp::Overlaps => "ID_OP_OVERLAPS",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ID_OP_CONTAINS" => Some(Self::Contains),
+            "ID_OP_OVERLAPS" => Some(Self::Overlaps),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CompareOp {
+    Eq = 0,
+    Ne = 1,
+    Lt = 2,
+    Le = 3,
+    Gt = 4,
+    Ge = 5,
+}
+impl CompareOp {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            CompareOp::Eq => "COMPARE_OP_EQ",
+            CompareOp::Ne => "COMPARE_OP_NE",
+            CompareOp::Lt => "COMPARE_OP_LT",
+            CompareOp::Le => "COMPARE_OP_LE",
+            CompareOp::Gt => "COMPARE_OP_GT",
+            CompareOp::Ge => "COMPARE_OP_GE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "COMPARE_OP_EQ" => Some(Self::Eq),
+            "COMPARE_OP_NE" => Some(Self::Ne),
+            "COMPARE_OP_LT" => Some(Self::Lt),
+            "COMPARE_OP_LE" => Some(Self::Le),
+            "COMPARE_OP_GT" => Some(Self::Gt),
+            "COMPARE_OP_GE" => Some(Self::Ge),
+            _ => None,
+        }
+    }
+}
 /// Generated client implementations.
 pub mod user_stats_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -175,124 +1016,1477 @@ pub mod user_stats_client {
                 .insert(GrpcMethod::new("user_stats.UserStats", "RawQuery"));
             self.inner.server_streaming(req, path, codec).await
         }
-    }
-}
-/// Generated server implementations.
-pub mod user_stats_server {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
-    use tonic::codegen::*;
-    /// Generated trait containing gRPC methods that should be implemented for use with UserStatsServer.
-    #[async_trait]
-    pub trait UserStats: Send + Sync + 'static {
-        /// Server streaming response type for the Query method.
-        type QueryStream: tonic::codegen::tokio_stream::Stream<
-                Item = std::result::Result<super::User, tonic::Status>,
-            > + Send
-            + 'static;
-        async fn query(
-            &self,
-            request: tonic::Request<super::QueryRequest>,
-        ) -> std::result::Result<tonic::Response<Self::QueryStream>, tonic::Status>;
-        /// Server streaming response type for the RawQuery method.
-        type RawQueryStream: tonic::codegen::tokio_stream::Stream<
-                Item = std::result::Result<super::User, tonic::Status>,
-            > + Send
-            + 'static;
-        async fn raw_query(
-            &self,
-            request: tonic::Request<super::RawQueryRequest>,
-        ) -> std::result::Result<tonic::Response<Self::RawQueryStream>, tonic::Status>;
-    }
-    #[derive(Debug)]
-    pub struct UserStatsServer<T: UserStats> {
-        inner: _Inner<T>,
-        accept_compression_encodings: EnabledCompressionEncodings,
-        send_compression_encodings: EnabledCompressionEncodings,
-        max_decoding_message_size: Option<usize>,
-        max_encoding_message_size: Option<usize>,
-    }
-    struct _Inner<T>(Arc<T>);
-    impl<T: UserStats> UserStatsServer<T> {
-        pub fn new(inner: T) -> Self {
-            Self::from_arc(Arc::new(inner))
+        /// same as Query, but return the fields of UserProfile selected by field_mask
+        pub async fn query_profiles(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::UserProfile>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/QueryProfiles");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "QueryProfiles"));
+            self.inner.server_streaming(req, path, codec).await
         }
-        pub fn from_arc(inner: Arc<T>) -> Self {
-            let inner = _Inner(inner);
-            Self {
-                inner,
-                accept_compression_encodings: Default::default(),
-                send_compression_encodings: Default::default(),
-                max_decoding_message_size: None,
-                max_encoding_message_size: None,
-            }
+        /// query users page by page, ordered by email
+        pub async fn query_page(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::UserPage>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/QueryPage");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "QueryPage"));
+            self.inner.unary(req, path, codec).await
         }
-        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
-        where
-            F: tonic::service::Interceptor,
+        pub async fn raw_query_page(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RawQueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::UserPage>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/RawQueryPage");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "RawQueryPage"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// stream the user whenever it is inserted or updated and matches the query, page_size and
+        /// page_token are ignored
+        pub async fn watch_users(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<tonic::codec::Streaming<super::User>>, tonic::Status>
         {
-            InterceptedService::new(Self::new(inner), interceptor)
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/WatchUsers");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "WatchUsers"));
+            self.inner.server_streaming(req, path, codec).await
         }
-        /// Enable decompressing requests with the given encoding.
-        #[must_use]
-        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.accept_compression_encodings.enable(encoding);
-            self
+        /// exact number of users matching the query, page_size and page_token are ignored
+        pub async fn count(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::CountResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Count");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Count"));
+            self.inner.unary(req, path, codec).await
         }
-        /// Compress responses with the given encoding, if the client supports it.
-        #[must_use]
-        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
-            self.send_compression_encodings.enable(encoding);
-            self
+        /// fast approximate number of users matching the query
+        pub async fn estimate(
+            &mut self,
+            request: impl tonic::IntoRequest<super::EstimateRequest>,
+        ) -> std::result::Result<tonic::Response<super::EstimateResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Estimate");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Estimate"));
+            self.inner.unary(req, path, codec).await
         }
-        /// Limits the maximum size of a decoded message.
-        ///
-        /// Default: `4MB`
-        #[must_use]
-        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
-            self.max_decoding_message_size = Some(limit);
-            self
+        /// retention of the users grouped by the week or month they signed up, the activity comes
+        /// from last_visited_at, last_watched_at and the recorded events
+        pub async fn cohorts(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CohortsRequest>,
+        ) -> std::result::Result<tonic::Response<super::CohortsResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Cohorts");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Cohorts"));
+            self.inner.unary(req, path, codec).await
         }
-        /// Limits the maximum size of an encoded message.
-        ///
-        /// Default: `usize::MAX`
-        #[must_use]
-        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
-            self.max_encoding_message_size = Some(limit);
-            self
+        /// apply user events to user_stats, each event is applied atomically and only once
+        pub async fn record_events(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::UserEvent>,
+        ) -> std::result::Result<tonic::Response<super::RecordEventsResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/RecordEvents");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "RecordEvents"));
+            self.inner.client_streaming(req, path, codec).await
         }
-    }
-    impl<T, B> tonic::codegen::Service<http::Request<B>> for UserStatsServer<T>
-    where
-        T: UserStats,
-        B: Body + Send + 'static,
-        B::Error: Into<StdError> + Send + 'static,
-    {
-        type Response = http::Response<tonic::body::BoxBody>;
-        type Error = std::convert::Infallible;
-        type Future = BoxFuture<Self::Response, Self::Error>;
-        fn poll_ready(
+        /// stream the users of the query as a csv, jsonl or parquet file
+        pub async fn export(
             &mut self,
-            _cx: &mut Context<'_>,
-        ) -> Poll<std::result::Result<(), Self::Error>> {
-            Poll::Ready(Ok(()))
+            request: impl tonic::IntoRequest<super::ExportRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ExportChunk>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Export");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Export"));
+            self.inner.server_streaming(req, path, codec).await
         }
-        fn call(&mut self, req: http::Request<B>) -> Self::Future {
-            let inner = self.inner.clone();
-            match req.uri().path() {
-                "/user_stats.UserStats/Query" => {
+        /// upsert user_stats by email from a csv or jsonl file, the progress is reported after every
+        /// batch
+        pub async fn import(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::ImportRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ImportProgress>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Import");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Import"));
+            self.inner.streaming(req, path, codec).await
+        }
+        /// update last_*_notification of the users with the time they are notified
+        pub async fn mark_notified(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MarkNotifiedRequest>,
+        ) -> std::result::Result<tonic::Response<super::MarkNotifiedResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/MarkNotified");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "MarkNotified"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// record the notification of the channel for the users of the query who are not capped, and
+        /// stream them with the number of users suppressed by the caps
+        pub async fn reserve(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReserveRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ReserveResponse>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Reserve");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Reserve"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// undo the reservation of the users who were not notified, so they are not counted by the caps
+        pub async fn release(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ReleaseRequest>,
+        ) -> std::result::Result<tonic::Response<super::ReleaseResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/Release");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "Release"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// saved segments, so that the same query could be shared by the campaigns
+        pub async fn create_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CreateSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/CreateSegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "CreateSegment"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn update_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpdateSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/UpdateSegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "UpdateSegment"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn get_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/GetSegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "GetSegment"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn list_segments(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListSegmentsRequest>,
+        ) -> std::result::Result<tonic::Response<super::ListSegmentsResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/ListSegments");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "ListSegments"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn delete_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DeleteSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::DeleteSegmentResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/DeleteSegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "DeleteSegment"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// stream the users of the segment
+        pub async fn query_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::QuerySegmentRequest>,
+        ) -> std::result::Result<tonic::Response<tonic::codec::Streaming<super::User>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/QuerySegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "QuerySegment"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// materialize the members of the segment, so they could be compared with later snapshots
+        pub async fn snapshot_segment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SnapshotSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::SegmentSnapshot>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/user_stats.UserStats/SnapshotSegment");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "SnapshotSegment"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// users who entered or left the segment between two snapshots, ordered by email
+        pub async fn diff_snapshots(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DiffSnapshotsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::MembershipDiff>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/user_stats.UserStats/DiffSnapshots");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("user_stats.UserStats", "DiffSnapshots"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod user_stats_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// Generated trait containing gRPC methods that should be implemented for use with UserStatsServer.
+    #[async_trait]
+    pub trait UserStats: Send + Sync + 'static {
+        /// Server streaming response type for the Query method.
+        type QueryStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::User, tonic::Status>,
+            > + Send
+            + 'static;
+        async fn query(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<Self::QueryStream>, tonic::Status>;
+        /// Server streaming response type for the RawQuery method.
+        type RawQueryStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::User, tonic::Status>,
+            > + Send
+            + 'static;
+        async fn raw_query(
+            &self,
+            request: tonic::Request<super::RawQueryRequest>,
+        ) -> std::result::Result<tonic::Response<Self::RawQueryStream>, tonic::Status>;
+        /// Server streaming response type for the QueryProfiles method.
+        type QueryProfilesStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::UserProfile, tonic::Status>,
+            > + Send
+            + 'static;
+        /// same as Query, but return the fields of UserProfile selected by field_mask
+        async fn query_profiles(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<Self::QueryProfilesStream>, tonic::Status>;
+        /// query users page by page, ordered by email
+        async fn query_page(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::UserPage>, tonic::Status>;
+        async fn raw_query_page(
+            &self,
+            request: tonic::Request<super::RawQueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::UserPage>, tonic::Status>;
+        /// Server streaming response type for the WatchUsers method.
+        type WatchUsersStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::User, tonic::Status>,
+            > + Send
+            + 'static;
+        /// stream the user whenever it is inserted or updated and matches the query, page_size and
+        /// page_token are ignored
+        async fn watch_users(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<Self::WatchUsersStream>, tonic::Status>;
+        /// exact number of users matching the query, page_size and page_token are ignored
+        async fn count(
+            &self,
+            request: tonic::Request<super::QueryRequest>,
+        ) -> std::result::Result<tonic::Response<super::CountResponse>, tonic::Status>;
+        /// fast approximate number of users matching the query
+        async fn estimate(
+            &self,
+            request: tonic::Request<super::EstimateRequest>,
+        ) -> std::result::Result<tonic::Response<super::EstimateResponse>, tonic::Status>;
+        /// retention of the users grouped by the week or month they signed up, the activity comes
+        /// from last_visited_at, last_watched_at and the recorded events
+        async fn cohorts(
+            &self,
+            request: tonic::Request<super::CohortsRequest>,
+        ) -> std::result::Result<tonic::Response<super::CohortsResponse>, tonic::Status>;
+        /// apply user events to user_stats, each event is applied atomically and only once
+        async fn record_events(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::UserEvent>>,
+        ) -> std::result::Result<tonic::Response<super::RecordEventsResponse>, tonic::Status>;
+        /// Server streaming response type for the Export method.
+        type ExportStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ExportChunk, tonic::Status>,
+            > + Send
+            + 'static;
+        /// stream the users of the query as a csv, jsonl or parquet file
+        async fn export(
+            &self,
+            request: tonic::Request<super::ExportRequest>,
+        ) -> std::result::Result<tonic::Response<Self::ExportStream>, tonic::Status>;
+        /// Server streaming response type for the Import method.
+        type ImportStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ImportProgress, tonic::Status>,
+            > + Send
+            + 'static;
+        /// upsert user_stats by email from a csv or jsonl file, the progress is reported after every
+        /// batch
+        async fn import(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::ImportRequest>>,
+        ) -> std::result::Result<tonic::Response<Self::ImportStream>, tonic::Status>;
+        /// update last_*_notification of the users with the time they are notified
+        async fn mark_notified(
+            &self,
+            request: tonic::Request<super::MarkNotifiedRequest>,
+        ) -> std::result::Result<tonic::Response<super::MarkNotifiedResponse>, tonic::Status>;
+        /// Server streaming response type for the Reserve method.
+        type ReserveStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ReserveResponse, tonic::Status>,
+            > + Send
+            + 'static;
+        /// record the notification of the channel for the users of the query who are not capped, and
+        /// stream them with the number of users suppressed by the caps
+        async fn reserve(
+            &self,
+            request: tonic::Request<super::ReserveRequest>,
+        ) -> std::result::Result<tonic::Response<Self::ReserveStream>, tonic::Status>;
+        /// undo the reservation of the users who were not notified, so they are not counted by the caps
+        async fn release(
+            &self,
+            request: tonic::Request<super::ReleaseRequest>,
+        ) -> std::result::Result<tonic::Response<super::ReleaseResponse>, tonic::Status>;
+        /// saved segments, so that the same query could be shared by the campaigns
+        async fn create_segment(
+            &self,
+            request: tonic::Request<super::CreateSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status>;
+        async fn update_segment(
+            &self,
+            request: tonic::Request<super::UpdateSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status>;
+        async fn get_segment(
+            &self,
+            request: tonic::Request<super::GetSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::Segment>, tonic::Status>;
+        async fn list_segments(
+            &self,
+            request: tonic::Request<super::ListSegmentsRequest>,
+        ) -> std::result::Result<tonic::Response<super::ListSegmentsResponse>, tonic::Status>;
+        async fn delete_segment(
+            &self,
+            request: tonic::Request<super::DeleteSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::DeleteSegmentResponse>, tonic::Status>;
+        /// Server streaming response type for the QuerySegment method.
+        type QuerySegmentStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::User, tonic::Status>,
+            > + Send
+            + 'static;
+        /// stream the users of the segment
+        async fn query_segment(
+            &self,
+            request: tonic::Request<super::QuerySegmentRequest>,
+        ) -> std::result::Result<tonic::Response<Self::QuerySegmentStream>, tonic::Status>;
+        /// materialize the members of the segment, so they could be compared with later snapshots
+        async fn snapshot_segment(
+            &self,
+            request: tonic::Request<super::SnapshotSegmentRequest>,
+        ) -> std::result::Result<tonic::Response<super::SegmentSnapshot>, tonic::Status>;
+        /// Server streaming response type for the DiffSnapshots method.
+        type DiffSnapshotsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::MembershipDiff, tonic::Status>,
+            > + Send
+            + 'static;
+        /// users who entered or left the segment between two snapshots, ordered by email
+        async fn diff_snapshots(
+            &self,
+            request: tonic::Request<super::DiffSnapshotsRequest>,
+        ) -> std::result::Result<tonic::Response<Self::DiffSnapshotsStream>, tonic::Status>;
+    }
+    #[derive(Debug)]
+    pub struct UserStatsServer<T: UserStats> {
+        inner: _Inner<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: UserStats> UserStatsServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+                max_decoding_message_size: None,
+                max_encoding_message_size: None,
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+        /// Enable decompressing requests with the given encoding.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.accept_compression_encodings.enable(encoding);
+            self
+        }
+        /// Compress responses with the given encoding, if the client supports it.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.send_compression_encodings.enable(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.max_decoding_message_size = Some(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.max_encoding_message_size = Some(limit);
+            self
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for UserStatsServer<T>
+    where
+        T: UserStats,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/user_stats.UserStats/Query" => {
+                    #[allow(non_camel_case_types)]
+                    struct QuerySvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::QueryRequest> for QuerySvc<T> {
+                        type Response = super::User;
+                        type ResponseStream = T::QueryStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { <T as UserStats>::query(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = QuerySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/RawQuery" => {
+                    #[allow(non_camel_case_types)]
+                    struct RawQuerySvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::RawQueryRequest>
+                        for RawQuerySvc<T>
+                    {
+                        type Response = super::User;
+                        type ResponseStream = T::RawQueryStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RawQueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::raw_query(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RawQuerySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/QueryProfiles" => {
+                    #[allow(non_camel_case_types)]
+                    struct QueryProfilesSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::QueryRequest>
+                        for QueryProfilesSvc<T>
+                    {
+                        type Response = super::UserProfile;
+                        type ResponseStream = T::QueryProfilesStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::query_profiles(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = QueryProfilesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/QueryPage" => {
+                    #[allow(non_camel_case_types)]
+                    struct QueryPageSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::QueryRequest> for QueryPageSvc<T> {
+                        type Response = super::UserPage;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::query_page(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = QueryPageSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/RawQueryPage" => {
+                    #[allow(non_camel_case_types)]
+                    struct RawQueryPageSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::RawQueryRequest> for RawQueryPageSvc<T> {
+                        type Response = super::UserPage;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RawQueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::raw_query_page(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RawQueryPageSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/WatchUsers" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchUsersSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::QueryRequest> for WatchUsersSvc<T> {
+                        type Response = super::User;
+                        type ResponseStream = T::WatchUsersStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::watch_users(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchUsersSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Count" => {
+                    #[allow(non_camel_case_types)]
+                    struct CountSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::QueryRequest> for CountSvc<T> {
+                        type Response = super::CountResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::QueryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { <T as UserStats>::count(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CountSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Estimate" => {
+                    #[allow(non_camel_case_types)]
+                    struct EstimateSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::EstimateRequest> for EstimateSvc<T> {
+                        type Response = super::EstimateResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::EstimateRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::estimate(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = EstimateSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Cohorts" => {
+                    #[allow(non_camel_case_types)]
+                    struct CohortsSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::CohortsRequest> for CohortsSvc<T> {
+                        type Response = super::CohortsResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CohortsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::cohorts(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CohortsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/RecordEvents" => {
+                    #[allow(non_camel_case_types)]
+                    struct RecordEventsSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ClientStreamingService<super::UserEvent> for RecordEventsSvc<T> {
+                        type Response = super::RecordEventsResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::UserEvent>>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::record_events(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RecordEventsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.client_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Export" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExportSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::ExportRequest> for ExportSvc<T> {
+                        type Response = super::ExportChunk;
+                        type ResponseStream = T::ExportStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ExportRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::export(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExportSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Import" => {
+                    #[allow(non_camel_case_types)]
+                    struct ImportSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::StreamingService<super::ImportRequest> for ImportSvc<T> {
+                        type Response = super::ImportProgress;
+                        type ResponseStream = T::ImportStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::ImportRequest>>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::import(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ImportSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/MarkNotified" => {
+                    #[allow(non_camel_case_types)]
+                    struct MarkNotifiedSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::MarkNotifiedRequest> for MarkNotifiedSvc<T> {
+                        type Response = super::MarkNotifiedResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MarkNotifiedRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::mark_notified(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = MarkNotifiedSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Reserve" => {
+                    #[allow(non_camel_case_types)]
+                    struct ReserveSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::ServerStreamingService<super::ReserveRequest> for ReserveSvc<T> {
+                        type Response = super::ReserveResponse;
+                        type ResponseStream = T::ReserveStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReserveRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::reserve(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ReserveSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/Release" => {
                     #[allow(non_camel_case_types)]
-                    struct QuerySvc<T: UserStats>(pub Arc<T>);
-                    impl<T: UserStats> tonic::server::ServerStreamingService<super::QueryRequest> for QuerySvc<T> {
+                    struct ReleaseSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::ReleaseRequest> for ReleaseSvc<T> {
+                        type Response = super::ReleaseResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ReleaseRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::release(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ReleaseSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/CreateSegment" => {
+                    #[allow(non_camel_case_types)]
+                    struct CreateSegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::CreateSegmentRequest>
+                        for CreateSegmentSvc<T>
+                    {
+                        type Response = super::Segment;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CreateSegmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::create_segment(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CreateSegmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/UpdateSegment" => {
+                    #[allow(non_camel_case_types)]
+                    struct UpdateSegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::UpdateSegmentRequest>
+                        for UpdateSegmentSvc<T>
+                    {
+                        type Response = super::Segment;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UpdateSegmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::update_segment(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = UpdateSegmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/GetSegment" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetSegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::GetSegmentRequest> for GetSegmentSvc<T> {
+                        type Response = super::Segment;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetSegmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserStats>::get_segment(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetSegmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/ListSegments" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListSegmentsSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::ListSegmentsRequest> for ListSegmentsSvc<T> {
+                        type Response = super::ListSegmentsResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListSegmentsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::list_segments(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListSegmentsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/DeleteSegment" => {
+                    #[allow(non_camel_case_types)]
+                    struct DeleteSegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::DeleteSegmentRequest>
+                        for DeleteSegmentSvc<T>
+                    {
+                        type Response = super::DeleteSegmentResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DeleteSegmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::delete_segment(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = DeleteSegmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/QuerySegment" => {
+                    #[allow(non_camel_case_types)]
+                    struct QuerySegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats>
+                        tonic::server::ServerStreamingService<super::QuerySegmentRequest>
+                        for QuerySegmentSvc<T>
+                    {
                         type Response = super::User;
-                        type ResponseStream = T::QueryStream;
+                        type ResponseStream = T::QuerySegmentStream;
                         type Future =
                             BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::QueryRequest>,
+                            request: tonic::Request<super::QuerySegmentRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
-                            let fut = async move { <T as UserStats>::query(&inner, request).await };
+                            let fut = async move {
+                                <T as UserStats>::query_segment(&inner, request).await
+                            };
                             Box::pin(fut)
                         }
                     }
@@ -303,7 +2497,7 @@ pub mod user_stats_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = QuerySvc(inner);
+                        let method = QuerySegmentSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -319,23 +2513,67 @@ pub mod user_stats_server {
                     };
                     Box::pin(fut)
                 }
-                "/user_stats.UserStats/RawQuery" => {
+                "/user_stats.UserStats/SnapshotSegment" => {
                     #[allow(non_camel_case_types)]
-                    struct RawQuerySvc<T: UserStats>(pub Arc<T>);
-                    impl<T: UserStats> tonic::server::ServerStreamingService<super::RawQueryRequest>
-                        for RawQuerySvc<T>
+                    struct SnapshotSegmentSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats> tonic::server::UnaryService<super::SnapshotSegmentRequest>
+                        for SnapshotSegmentSvc<T>
                     {
-                        type Response = super::User;
-                        type ResponseStream = T::RawQueryStream;
+                        type Response = super::SegmentSnapshot;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SnapshotSegmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as UserStats>::snapshot_segment(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SnapshotSegmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/user_stats.UserStats/DiffSnapshots" => {
+                    #[allow(non_camel_case_types)]
+                    struct DiffSnapshotsSvc<T: UserStats>(pub Arc<T>);
+                    impl<T: UserStats>
+                        tonic::server::ServerStreamingService<super::DiffSnapshotsRequest>
+                        for DiffSnapshotsSvc<T>
+                    {
+                        type Response = super::MembershipDiff;
+                        type ResponseStream = T::DiffSnapshotsStream;
                         type Future =
                             BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::RawQueryRequest>,
+                            request: tonic::Request<super::DiffSnapshotsRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
-                            let fut =
-                                async move { <T as UserStats>::raw_query(&inner, request).await };
+                            let fut = async move {
+                                <T as UserStats>::diff_snapshots(&inner, request).await
+                            };
                             Box::pin(fut)
                         }
                     }
@@ -346,7 +2584,7 @@ pub mod user_stats_server {
                     let inner = self.inner.clone();
                     let fut = async move {
                         let inner = inner.0;
-                        let method = RawQuerySvc(inner);
+                        let method = DiffSnapshotsSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -1,14 +1,22 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
 
 use anyhow::Result;
+use chrono::Utc;
 use futures::StreamExt;
+use prost_types::FieldMask;
 use sqlx_db_tester::TestPg;
 use tokio::time::sleep;
-use tonic::transport::Server;
+use tonic::{transport::Server, Code};
 use user_stat::{
-    pb::{user_stats_client::UserStatsClient, QueryRequestBuilder, RawQueryRequestBuilder},
+    pb::{
+        user_stats_client::UserStatsClient, CohortPeriod, CohortsRequest, CreateSegmentRequest,
+        DiffSnapshotsRequest, EstimateMethod, EstimateRequest, EventType, ExportFormat,
+        ExportRequest, Filter, Gender, ImportFormat, ImportProgress, ImportRequest,
+        QueryRequestBuilder, QuerySegmentRequest, RawQueryRequestBuilder, SnapshotSegmentRequest,
+        UserEvent, UserProfile,
+    },
     test_utils::{to_idquery, to_timequery},
-    AppConfig, UserStatsService,
+    AppConfig, MemoryStore, UserStatsService,
 };
 
 #[tokio::test]
@@ -27,6 +35,19 @@ async fn raw_query_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn raw_query_with_invalid_sql_should_fail() -> Result<()> {
+    let (_tdb, addr) = start_server(300).await?;
+    let req = RawQueryRequestBuilder::default()
+        .query("DROP TABLE user_stats")
+        .build()?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+    let status = client.raw_query(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(status.message(), "only SELECT statement is allowed");
+    Ok(())
+}
+
 #[tokio::test]
 async fn query_should_work() -> Result<()> {
     let (_tdb, addr) = start_server(200).await?;
@@ -46,6 +67,381 @@ async fn query_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn query_with_unknown_column_should_fail() -> Result<()> {
+    let (_tdb, addr) = start_server(400).await?;
+    let req = QueryRequestBuilder::default()
+        .timestamp((
+            "created_at > now() OR 1=1 --".to_string(),
+            to_timequery(Some(220), None),
+        ))
+        .build()?;
+
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+    let status = client.query(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    Ok(())
+}
+
+#[tokio::test]
+async fn query_page_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(500).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let mut emails = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let req = QueryRequestBuilder::default()
+            .page_size(30u32)
+            .page_token(page_token)
+            .build()?;
+        let page = client.query_page(req).await?.into_inner();
+        assert!(page.users.len() <= 30);
+        emails.extend(page.users.into_iter().map(|u| u.email));
+        if page.next_page_token.is_empty() {
+            break;
+        }
+        page_token = page.next_page_token;
+    }
+    assert_eq!(emails.len(), 100);
+    assert_eq!(emails.iter().collect::<HashSet<_>>().len(), 100);
+
+    // resume from the middle with the raw query
+    let req = RawQueryRequestBuilder::default()
+        .query("SELECT * FROM user_stats")
+        .page_size(50u32)
+        .build()?;
+    let page = client.raw_query_page(req).await?.into_inner();
+    let req = RawQueryRequestBuilder::default()
+        .query("SELECT * FROM user_stats")
+        .page_token(page.next_page_token)
+        .build()?;
+    let rest = client
+        .raw_query(req)
+        .await?
+        .into_inner()
+        .then(|res| async move { res.unwrap().email })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(rest, emails[50..]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn count_and_estimate_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(600).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let query = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Female))
+        .build()?;
+    let count = client.count(query.clone()).await?.into_inner().count;
+    let users = client
+        .query(query.clone())
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(count, users.len() as u64);
+
+    let req = EstimateRequest {
+        query: Some(query),
+        method: EstimateMethod::Sample as i32,
+        sample_percent: 100.0,
+    };
+    let estimate = client.estimate(req).await?.into_inner();
+    assert_eq!(estimate.count, count);
+    Ok(())
+}
+
+#[tokio::test]
+async fn query_profiles_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(700).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let req = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Male))
+        .field_mask(FieldMask {
+            paths: vec!["gender".to_string(), "last_sms_notification".to_string()],
+        })
+        .build()?;
+    let profiles = client
+        .query_profiles(req)
+        .await?
+        .into_inner()
+        .then(|res| async move { res.unwrap() })
+        .collect::<Vec<_>>()
+        .await;
+    assert!(!profiles.is_empty());
+    for profile in profiles {
+        assert!(!profile.email.is_empty());
+        assert_eq!(profile.gender, Gender::Male as i32);
+        assert!(profile.last_sms_notification.is_some());
+        assert!(profile.name.is_empty());
+        assert!(profile.finished.is_empty());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn record_events_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(800).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let email = "new.user@acme.org";
+    let now = Utc::now();
+    let events = vec![
+        UserEvent::new("e1", email, EventType::View, 42, now),
+        UserEvent::new("e2", email, EventType::WatchStart, 42, now),
+        UserEvent::new("e2", email, EventType::WatchStart, 42, now),
+    ];
+    let ret = client
+        .record_events(futures::stream::iter(events))
+        .await?
+        .into_inner();
+    assert_eq!(ret.applied, 2);
+    assert_eq!(ret.duplicated, 1);
+
+    let req = QueryRequestBuilder::default()
+        .id(("started_but_not_finished".to_string(), to_idquery(&[42])))
+        .build()?;
+    let users = client
+        .query(req)
+        .await?
+        .into_inner()
+        .then(|res| async move { res.unwrap() })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].email, email);
+    Ok(())
+}
+
+#[tokio::test]
+async fn query_segment_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(900).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let query = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Male))
+        .build()?;
+    let req = CreateSegmentRequest {
+        name: "male".to_string(),
+        description: "male users".to_string(),
+        query: Some(query.clone()),
+    };
+    let segment = client.create_segment(req).await?.into_inner();
+    assert_eq!(segment.version, 1);
+
+    let count = client.count(query).await?.into_inner().count;
+    let req = QuerySegmentRequest {
+        name: "male".to_string(),
+        ..Default::default()
+    };
+    let users = client
+        .query_segment(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(users.len() as u64, count);
+
+    let req = QuerySegmentRequest {
+        name: "female".to_string(),
+        ..Default::default()
+    };
+    let status = client.query_segment(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    Ok(())
+}
+
+#[tokio::test]
+async fn diff_snapshots_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(1000).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let query = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Male))
+        .build()?;
+    let req = CreateSegmentRequest {
+        name: "male".to_string(),
+        query: Some(query),
+        ..Default::default()
+    };
+    client.create_segment(req).await?;
+    let req = SnapshotSegmentRequest {
+        name: "male".to_string(),
+        version: 0,
+    };
+    let first = client.snapshot_segment(req.clone()).await?.into_inner();
+    let second = client.snapshot_segment(req).await?.into_inner();
+    assert!(second.run_id > first.run_id);
+    assert_eq!(second.member_count, first.member_count);
+
+    // nothing changed between the snapshots
+    let req = DiffSnapshotsRequest {
+        segment: "male".to_string(),
+        ..Default::default()
+    };
+    let diffs = client
+        .diff_snapshots(req)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert!(diffs.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(1100).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let data = "email,name,gender\ntyr@acme.org,Tyr,male\nalice,Alice,female\n";
+    let reqs = data.as_bytes().chunks(10).map(|chunk| ImportRequest {
+        format: ImportFormat::Csv as i32,
+        data: chunk.to_vec(),
+    });
+    let mut stream = client
+        .import(futures::stream::iter(reqs))
+        .await?
+        .into_inner();
+    let mut progress = ImportProgress::default();
+    while let Some(ret) = stream.next().await {
+        progress = ret?;
+    }
+    assert!(progress.done);
+    assert_eq!(progress.rows_read, 2);
+    assert_eq!(progress.rows_imported, 1);
+    assert_eq!(progress.errors[0].line, 3);
+
+    let query = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Male))
+        .build()?;
+    let users = client
+        .query(query)
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    let user = users
+        .into_iter()
+        .map(|u| u.unwrap())
+        .find(|u| u.email == "tyr@acme.org")
+        .unwrap();
+    assert_eq!(user.name, "Tyr");
+    assert!(user.started_but_not_finished.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(1200).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+
+    let query = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Female))
+        .build()?;
+    let count = client.count(query.clone()).await?.into_inner().count;
+    let req = ExportRequest {
+        query: Some(query),
+        format: ExportFormat::Jsonl as i32,
+    };
+    let mut chunks = client.export(req).await?.into_inner();
+    let mut data = vec![];
+    while let Some(chunk) = chunks.next().await {
+        data.extend(chunk?.data);
+    }
+    let data = String::from_utf8(data)?;
+    assert_eq!(data.lines().count() as u64, count);
+    assert!(data.contains(r#""email":"frederik.2r2jvb8l@example.org""#));
+    assert!(data
+        .lines()
+        .all(|line| line.contains(r#""gender":"female""#)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_store_should_serve_without_postgres() -> Result<()> {
+    let config = AppConfig::load()?;
+    let addr = format!("[::1]:{}", config.server.port + 1300).parse::<SocketAddr>()?;
+    let users = ["alice@acme.org", "bob@acme.org"].map(|email| UserProfile {
+        email: email.to_string(),
+        gender: Gender::Female as i32,
+        ..Default::default()
+    });
+    let svc = UserStatsService::with_store(config, MemoryStore::new(users));
+    tokio::spawn(async move {
+        Server::builder()
+            .add_service(svc.into_server())
+            .serve(addr)
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(10)).await;
+
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+    let req = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Female))
+        .build()?;
+    let users = client
+        .query(req.clone())
+        .await?
+        .into_inner()
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(users.len(), 2);
+    assert_eq!(client.count(req).await?.into_inner().count, 2);
+
+    let req = RawQueryRequestBuilder::default()
+        .query("SELECT * FROM user_stats")
+        .build()?;
+    let status = client.raw_query(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch_users_should_stream_changes() -> Result<()> {
+    let (tdb, addr) = start_server(1400).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+    let req = QueryRequestBuilder::default()
+        .filter(Filter::gender(Gender::Female))
+        .build()?;
+    let mut stream = client.watch_users(req).await?.into_inner();
+
+    let pool = tdb.get_pool().await;
+    sqlx::query("UPDATE user_stats SET name = 'Frederik' WHERE email = $1")
+        .bind("frederik.2r2jvb8l@example.org")
+        .execute(&pool)
+        .await?;
+    let user = tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await?
+        .unwrap()?;
+    assert_eq!(user.email, "frederik.2r2jvb8l@example.org");
+    assert_eq!(user.name, "Frederik");
+    Ok(())
+}
+
+#[tokio::test]
+async fn cohorts_should_work() -> Result<()> {
+    let (_tdb, addr) = start_server(1500).await?;
+    let mut client = UserStatsClient::connect(format!("http://{addr}")).await?;
+    let req = CohortsRequest {
+        period: CohortPeriod::Month as i32,
+        periods: 2,
+        ..Default::default()
+    };
+    let cohorts = client.cohorts(req).await?.into_inner().cohorts;
+    assert_eq!(cohorts.iter().map(|c| c.size).sum::<u64>(), 100);
+    assert!(cohorts.iter().all(|c| c.active.len() == 3));
+
+    let status = client.cohorts(CohortsRequest::default()).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    Ok(())
+}
+
 async fn start_server(port: u16) -> Result<(TestPg, SocketAddr)> {
     let config = AppConfig::load()?;
     let addr = config.server.port + port; // 避免测试端口冲突，此方法不是特别好